@@ -0,0 +1,171 @@
+//! Parsing of EPUB Canonical Fragment Identifiers, the locations other
+//! readers exchange: `epubcfi(/6/4[chap01ref]!/4[body01]/10/2:3)`.
+//!
+//! Only what's needed to find a place is supported: a path through the
+//! package document to a spine item, one indirection into its content, and
+//! an optional character offset. Ranges and nested indirections are
+//! rejected; temporal and spatial offsets are ignored.
+
+/// One `/n[assertion]` step. Even indexes are elements (the nth child
+/// element is `2n`), odd ones the text between them.
+pub struct Step {
+    pub index: u32,
+    /// The id asserted for the node stepped to, if any
+    pub assertion: Option<String>,
+}
+
+pub struct Cfi {
+    /// Steps through the package document, ending at a spine `<itemref>`
+    pub package_steps: Vec<Step>,
+    /// Steps through the spine item's document, after the `!`
+    pub content_steps: Vec<Step>,
+    /// `:n`, a character offset into the text node stepped to
+    pub offset: Option<u32>,
+    /// The content path as written, from after the `!` to the end
+    pub content_path: String,
+}
+
+impl Cfi {
+    /// Index into the spine of the `<itemref>` the package steps end at.
+    pub fn spine_position(&self) -> Option<usize> {
+        let step = self.package_steps.last()?;
+        (step.index >= 2 && step.index % 2 == 0).then(|| step.index as usize / 2 - 1)
+    }
+}
+
+pub fn parse(cfi: &str) -> Result<Cfi, String> {
+    let malformed = |reason: &str| format!("Malformed CFI {}: {}", cfi, reason);
+    let cfi = cfi.trim();
+    let body = cfi.strip_prefix("epubcfi(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .unwrap_or(cfi);
+    if body.is_empty() {
+        return Err(malformed("empty path"));
+    }
+
+    if split_unescaped(body, ',').len() > 1 {
+        return Err(malformed("ranges are not supported"));
+    }
+    let (package_path, content_path) = match split_unescaped(body, '!').as_slice() {
+        [package] => (*package, ""),
+        [package, content] => (*package, *content),
+        _ => return Err(malformed("nested indirection is not supported")),
+    };
+
+    let (package_steps, package_offset) = parse_path(package_path).map_err(|reason| malformed(&reason))?;
+    if package_steps.len() < 2 || package_offset.is_some() {
+        return Err(malformed("the package path must step to a spine item"));
+    }
+    let (content_steps, offset) = parse_path(content_path).map_err(|reason| malformed(&reason))?;
+
+    Ok(Cfi { package_steps, content_steps, offset, content_path: content_path.to_string() })
+}
+
+/// `path` split at each `separator` outside assertions and not escaped
+/// with `^`.
+fn split_unescaped(path: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_assertion = false;
+    let mut chars = path.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '^' => {
+                chars.next();
+            }
+            '[' => in_assertion = true,
+            ']' => in_assertion = false,
+            _ if c == separator && !in_assertion => {
+                parts.push(&path[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&path[start..]);
+    parts
+}
+
+/// The steps of a path and its character offset, if it ends with one.
+fn parse_path(path: &str) -> Result<(Vec<Step>, Option<u32>), String> {
+    let mut steps = Vec::new();
+    let mut offset = None;
+    let mut rest = path;
+    // Set once a terminal offset has been read; nothing but more offsets
+    // may follow
+    let mut terminated = false;
+    while let Some(marker) = rest.chars().next() {
+        let (number, after) = split_number(&rest[marker.len_utf8()..]);
+        if number.is_empty() {
+            return Err(format!("'{}' must be followed by a number", marker));
+        }
+        rest = after;
+
+        match marker {
+            '/' if terminated => return Err("a step follows the terminal offset".to_string()),
+            '/' => {
+                let (assertion, after) = read_assertion(rest)?;
+                rest = after;
+                let index = number.parse().map_err(|_| format!("step {} is out of range", number))?;
+                steps.push(Step { index, assertion });
+            }
+            ':' if offset.is_some() => return Err("more than one character offset".to_string()),
+            ':' => {
+                // The text location assertion isn't needed to find the place
+                let (_, after) = read_assertion(rest)?;
+                rest = after;
+                offset = Some(number.parse().map_err(|_| format!("offset {} is out of range", number))?);
+                terminated = true;
+            }
+            // Temporal (`~s`) and spatial (`@x:y`) offsets place media and
+            // images, which the reader doesn't need
+            '~' => terminated = true,
+            '@' => {
+                let (y, after) = rest.strip_prefix(':').map(split_number).unwrap_or(("", rest));
+                if y.is_empty() {
+                    return Err("a spatial offset needs x and y".to_string());
+                }
+                rest = after;
+                terminated = true;
+            }
+            _ => return Err(format!("unexpected '{}'", marker)),
+        }
+    }
+    Ok((steps, offset))
+}
+
+/// The leading digits of `text` (with a decimal part, as in `@50.5`) and
+/// what follows them.
+fn split_number(text: &str) -> (&str, &str) {
+    let end = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    text.split_at(end)
+}
+
+/// A leading `[...]` assertion of `text`, unescaped, and what follows it.
+/// Text location assertions (`[before,after]`) and parameters (`;s=b`) are
+/// only kept up to the first unescaped `,` or `;`.
+fn read_assertion(text: &str) -> Result<(Option<String>, &str), String> {
+    let Some(body) = text.strip_prefix('[') else { return Ok((None, text)) };
+    let mut value = String::new();
+    let mut ended_value = false;
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '^' => {
+                if let Some((_, escaped)) = chars.next() {
+                    if !ended_value {
+                        value.push(escaped);
+                    }
+                }
+            }
+            ']' => {
+                let value = Some(value).filter(|value| !value.is_empty());
+                return Ok((value, &body[i + 1..]));
+            }
+            ',' | ';' => ended_value = true,
+            _ if !ended_value => value.push(c),
+            _ => {}
+        }
+    }
+    Err("unterminated assertion".to_string())
+}
@@ -0,0 +1,65 @@
+//! The error type commands return. It serializes as `{ code, message }`, so
+//! the frontend can branch on `code` and still show or log `message`.
+
+use serde::ser::SerializeStruct;
+
+#[derive(Debug)]
+pub enum AppError {
+    /// No book is loaded under this key
+    BookNotFound(String),
+    /// The book has nothing at this archive path
+    ResourceNotFound(String),
+    /// The file isn't a usable EPUB or its package is malformed
+    InvalidEpub(String),
+    /// A command argument is out of range or malformed
+    InvalidArgument(String),
+    /// Reading or writing a file outside the book failed
+    Io(String),
+    /// An internal failure the frontend can't act on, such as encoding output
+    Other(String),
+}
+
+impl AppError {
+    pub fn book_not_found(book_key: &str) -> AppError {
+        AppError::BookNotFound(book_key.to_string())
+    }
+
+    pub fn resource_not_found(path: &str) -> AppError {
+        AppError::ResourceNotFound(path.to_string())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BookNotFound(_) => "book-not-found",
+            AppError::ResourceNotFound(_) => "resource-not-found",
+            AppError::InvalidEpub(_) => "invalid-epub",
+            AppError::InvalidArgument(_) => "invalid-argument",
+            AppError::Io(_) => "io",
+            AppError::Other(_) => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::BookNotFound(book_key) => write!(f, "Book not found: {}", book_key),
+            AppError::ResourceNotFound(path) => write!(f, "Resource not found: {}", path),
+            AppError::InvalidEpub(message)
+            | AppError::InvalidArgument(message)
+            | AppError::Io(message)
+            | AppError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl serde::Serialize for AppError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut error = serializer.serialize_struct("AppError", 2)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
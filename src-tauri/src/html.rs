@@ -0,0 +1,895 @@
+//! Minimal HTML/XHTML tokenizer for inspecting and rewriting chapter markup.
+//!
+//! This is not a full HTML5 parser. It walks the markup once and yields tags,
+//! text and comments together with their byte ranges, so callers can splice
+//! edits back into the original document without reserializing it.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// An attribute on a start tag, borrowed from the source document.
+pub struct Attribute<'a> {
+    pub name: &'a str,
+    /// Raw value as written in the source (entities are not decoded)
+    pub value: Option<&'a str>,
+    /// The attribute exactly as written, e.g. `href="ch2.xhtml"`
+    pub raw: &'a str,
+}
+
+pub struct Tag<'a> {
+    pub name: &'a str,
+    pub attrs: Vec<Attribute<'a>>,
+    pub self_closing: bool,
+}
+
+impl<'a> Tag<'a> {
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Raw (undecoded) value of the first attribute called `name`.
+    pub fn attr(&self, name: &str) -> Option<&'a str> {
+        self.attrs
+            .iter()
+            .find(|a| a.name.eq_ignore_ascii_case(name))
+            .and_then(|a| a.value)
+    }
+
+    /// Rebuilds the tag with `set` attributes added or replaced and `remove`
+    /// attributes dropped. Untouched attributes keep their original spelling.
+    pub fn rewrite(&self, set: &[(&str, &str)], remove: &[&str]) -> String {
+        let mut out = format!("<{}", self.name);
+        for attr in &self.attrs {
+            let replaced = set.iter().any(|(n, _)| attr.name.eq_ignore_ascii_case(n));
+            let removed = remove.iter().any(|n| attr.name.eq_ignore_ascii_case(n));
+            if !replaced && !removed {
+                out.push(' ');
+                out.push_str(attr.raw);
+            }
+        }
+        for (name, value) in set {
+            out.push_str(&format!(" {}=\"{}\"", name, escape_attr(value)));
+        }
+        out.push_str(if self.self_closing { " />" } else { ">" });
+        out
+    }
+}
+
+/// A markup token. Text, comments and other markup are described by their
+/// byte range alone; slice the source with it to get the raw text.
+pub enum Token<'a> {
+    StartTag(Tag<'a>),
+    /// Closing tag, with its element name
+    EndTag(&'a str),
+    Text,
+    Comment,
+    /// Doctype, XML declaration, processing instruction or CDATA section
+    Other,
+}
+
+pub struct Tokenizer<'a> {
+    src: &'a str,
+    pos: usize,
+    /// Set after `<script>`/`<style>` so their bodies are returned as raw text
+    raw_text_until: Option<&'static str>,
+}
+
+pub fn tokenize(src: &str) -> Tokenizer<'_> {
+    Tokenizer { src, pos: 0, raw_text_until: None }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = (Token<'a>, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let src = self.src;
+        let start = self.pos;
+        if start >= src.len() {
+            return None;
+        }
+
+        if let Some(close) = self.raw_text_until.take() {
+            let end = find_ignore_case(src, start, close).unwrap_or(src.len());
+            if end > start {
+                self.pos = end;
+                return Some((Token::Text, start..end));
+            }
+        }
+
+        let rest = &src[start..];
+        if !rest.starts_with('<') {
+            let end = rest.find('<').map(|i| start + i).unwrap_or(src.len());
+            self.pos = end;
+            return Some((Token::Text, start..end));
+        }
+
+        if let Some(body) = rest.strip_prefix("<!--") {
+            let end = body.find("-->").map(|i| start + 4 + i + 3).unwrap_or(src.len());
+            self.pos = end;
+            return Some((Token::Comment, start..end));
+        }
+
+        if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").map(|i| start + i + 3).unwrap_or(src.len());
+            self.pos = end;
+            return Some((Token::Other, start..end));
+        }
+
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            let end = rest.find('>').map(|i| start + i + 1).unwrap_or(src.len());
+            self.pos = end;
+            return Some((Token::Other, start..end));
+        }
+
+        if let Some(after) = rest.strip_prefix("</") {
+            let name_len = after
+                .find(|c: char| c.is_whitespace() || c == '>')
+                .unwrap_or(after.len());
+            let end = rest.find('>').map(|i| start + i + 1).unwrap_or(src.len());
+            self.pos = end;
+            return Some((Token::EndTag(&after[..name_len]), start..end));
+        }
+
+        match parse_start_tag(src, start) {
+            Some((tag, end)) => {
+                self.pos = end;
+                if !tag.self_closing {
+                    if tag.is("script") {
+                        self.raw_text_until = Some("</script");
+                    } else if tag.is("style") {
+                        self.raw_text_until = Some("</style");
+                    }
+                }
+                Some((Token::StartTag(tag), start..end))
+            }
+            None => {
+                // A stray `<` that doesn't open a tag is just text
+                let end = rest[1..].find('<').map(|i| start + 1 + i).unwrap_or(src.len());
+                self.pos = end;
+                Some((Token::Text, start..end))
+            }
+        }
+    }
+}
+
+fn find_ignore_case(src: &str, from: usize, needle: &str) -> Option<usize> {
+    let hay = src.as_bytes();
+    let needle = needle.as_bytes();
+    if needle.len() > hay.len() {
+        return None;
+    }
+    (from..=hay.len() - needle.len()).find(|&i| hay[i..i + needle.len()].eq_ignore_ascii_case(needle))
+}
+
+fn parse_start_tag(src: &str, start: usize) -> Option<(Tag<'_>, usize)> {
+    let bytes = src.as_bytes();
+    let mut i = start + 1;
+    if i >= bytes.len() || !bytes[i].is_ascii_alphabetic() {
+        return None;
+    }
+    let name_start = i;
+    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' && bytes[i] != b'/' {
+        i += 1;
+    }
+    let name = &src[name_start..i];
+    let mut attrs = Vec::new();
+    let mut self_closing = false;
+
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            return Some((Tag { name, attrs, self_closing }, bytes.len()));
+        }
+        match bytes[i] {
+            b'>' => return Some((Tag { name, attrs, self_closing }, i + 1)),
+            b'/' => {
+                self_closing = true;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        self_closing = false;
+
+        let attr_start = i;
+        while i < bytes.len()
+            && !bytes[i].is_ascii_whitespace()
+            && !matches!(bytes[i], b'=' | b'>')
+            && (bytes[i] != b'/' || bytes.get(i + 1) != Some(&b'>'))
+        {
+            i += 1;
+        }
+        let attr_name = &src[attr_start..i];
+        let mut j = i;
+        while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+            j += 1;
+        }
+        let mut value = None;
+        if j < bytes.len() && bytes[j] == b'=' {
+            j += 1;
+            while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                j += 1;
+            }
+            if j < bytes.len() && (bytes[j] == b'"' || bytes[j] == b'\'') {
+                let quote = bytes[j];
+                let value_start = j + 1;
+                let value_end = src[value_start..]
+                    .find(quote as char)
+                    .map(|p| value_start + p)
+                    .unwrap_or(bytes.len());
+                value = Some(&src[value_start..value_end]);
+                i = (value_end + 1).min(bytes.len());
+            } else {
+                let value_start = j;
+                while j < bytes.len() && !bytes[j].is_ascii_whitespace() && bytes[j] != b'>' {
+                    j += 1;
+                }
+                value = Some(&src[value_start..j]);
+                i = j;
+            }
+        }
+        if attr_name.is_empty() {
+            // Malformed input such as a lone `=`; skip the byte and carry on
+            i += 1;
+            continue;
+        }
+        attrs.push(Attribute { name: attr_name, value, raw: &src[attr_start..i] });
+    }
+}
+
+/// Rewrites start tags in `html`. For every start tag the callback may return
+/// replacement markup; tags for which it returns `None` are left untouched.
+pub fn rewrite_start_tags<F>(html: &str, mut f: F) -> String
+where
+    F: FnMut(&Tag) -> Option<String>,
+{
+    let mut out = String::with_capacity(html.len());
+    let mut last = 0;
+    for (token, range) in tokenize(html) {
+        if let Token::StartTag(tag) = token {
+            if let Some(replacement) = f(&tag) {
+                out.push_str(&html[last..range.start]);
+                out.push_str(&replacement);
+                last = range.end;
+            }
+        }
+    }
+    out.push_str(&html[last..]);
+    out
+}
+
+pub fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Elements kept by `sanitize`; anything else is unwrapped to its content.
+const SAFE_ELEMENTS: &[&str] = &[
+    "a", "abbr", "address", "article", "aside", "b", "bdi", "bdo", "blockquote", "br",
+    "caption", "cite", "code", "col", "colgroup", "dd", "del", "details", "dfn", "div",
+    "dl", "dt", "em", "figcaption", "figure", "footer", "h1", "h2", "h3", "h4", "h5",
+    "h6", "header", "hr", "i", "img", "ins", "kbd", "li", "main", "mark", "nav", "ol",
+    "p", "pre", "q", "rp", "rt", "ruby", "s", "samp", "section", "small", "span",
+    "strong", "sub", "summary", "sup", "table", "tbody", "td", "tfoot", "th", "thead",
+    "time", "tr", "u", "ul", "var", "wbr",
+];
+
+/// Elements `sanitize` drops together with their content.
+const UNSAFE_ELEMENTS: &[&str] = &[
+    "applet", "base", "button", "embed", "form", "frame", "frameset", "head", "iframe",
+    "input", "link", "math", "meta", "noscript", "object", "script", "select", "style",
+    "svg", "template", "textarea", "title",
+];
+
+/// Attributes kept by `sanitize`, besides `aria-*` and URL attributes.
+const SAFE_ATTRIBUTES: &[&str] = &[
+    "abbr", "alt", "class", "colspan", "datetime", "dir", "epub:type", "headers",
+    "height", "id", "lang", "reversed", "role", "rowspan", "scope", "span", "start",
+    "title", "type", "value", "width", "xml:lang",
+];
+
+/// Attributes holding a URL, passed through the callback of `sanitize`.
+const URL_ATTRIBUTES: &[&str] = &["cite", "href", "src"];
+
+/// A safe subset of `html` for injecting into the app's own DOM: the body
+/// content with an allowlist of elements and attributes. Scripts, styles,
+/// embedded objects, forms, event handlers and inline styles are removed.
+/// Each URL attribute's decoded value goes through `url`, which returns the
+/// URL to use or `None` to drop the attribute.
+pub fn sanitize<F>(html: &str, mut url: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let contains = |list: &[&str], name: &str| list.iter().any(|n| n.eq_ignore_ascii_case(name));
+
+    let mut out = String::with_capacity(html.len());
+    // Name and nesting depth of the unsafe element being skipped
+    let mut skipping: Option<(&str, usize)> = None;
+    for (token, range) in tokenize(html) {
+        if let Some((name, depth)) = skipping.as_mut() {
+            match &token {
+                Token::StartTag(tag) if tag.is(name) && !tag.self_closing => *depth += 1,
+                Token::EndTag(end) if end.eq_ignore_ascii_case(name) => {
+                    *depth -= 1;
+                    if *depth == 0 {
+                        skipping = None;
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match token {
+            Token::StartTag(tag) => {
+                let is_void = VOID_ELEMENTS.iter().any(|v| tag.is(v));
+                if contains(UNSAFE_ELEMENTS, tag.name) {
+                    if !tag.self_closing && !is_void {
+                        skipping = Some((tag.name, 1));
+                    }
+                    continue;
+                }
+                if !contains(SAFE_ELEMENTS, tag.name) {
+                    continue;
+                }
+
+                let name = tag.name.to_ascii_lowercase();
+                out.push('<');
+                out.push_str(&name);
+                for attr in &tag.attrs {
+                    let attr_name = attr.name.to_ascii_lowercase();
+                    let value = decode_entities(attr.value.unwrap_or(""));
+                    let value = if contains(URL_ATTRIBUTES, &attr_name) {
+                        match url(value.trim()) {
+                            Some(value) => value,
+                            None => continue,
+                        }
+                    } else if contains(SAFE_ATTRIBUTES, &attr_name) || attr_name.starts_with("aria-") {
+                        value.into_owned()
+                    } else {
+                        continue;
+                    };
+                    out.push_str(&format!(" {}=\"{}\"", attr_name, escape_attr(&value)));
+                }
+                out.push('>');
+                if tag.self_closing && !is_void {
+                    out.push_str(&format!("</{}>", name));
+                }
+            }
+            Token::EndTag(name) => {
+                if contains(SAFE_ELEMENTS, name) && !VOID_ELEMENTS.iter().any(|v| v.eq_ignore_ascii_case(name)) {
+                    out.push_str(&format!("</{}>", name.to_ascii_lowercase()));
+                }
+            }
+            Token::Text => out.push_str(&escape_attr(&decode_entities(&html[range]))),
+            Token::Comment | Token::Other => {}
+        }
+    }
+    out
+}
+
+/// Elements whose content is never visible text.
+const HIDDEN_ELEMENTS: &[&str] = &["head", "script", "style", "noscript", "template"];
+
+/// Elements that start a new line of text when extracted.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "br", "dd", "div", "dl", "dt",
+    "figcaption", "figure", "footer", "h1", "h2", "h3", "h4", "h5", "h6", "header",
+    "hr", "li", "main", "nav", "ol", "p", "pre", "section", "table", "td", "th",
+    "tr", "ul",
+];
+
+fn is_block(name: &str) -> bool {
+    BLOCK_ELEMENTS.iter().any(|b| b.eq_ignore_ascii_case(name))
+}
+
+/// Visible text of a document: tags stripped, entities decoded, whitespace
+/// collapsed, with one line per paragraph/block element.
+pub fn extract_text(html: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    let mut hidden_until: Option<&str> = None;
+
+    fn flush(line: &mut String, lines: &mut Vec<String>) {
+        let trimmed = line.trim_end();
+        if !trimmed.is_empty() {
+            lines.push(trimmed.to_string());
+        }
+        line.clear();
+    }
+
+    for (token, range) in tokenize(html) {
+        if let Some(hidden) = hidden_until {
+            if let Token::EndTag(name) = token {
+                if name.eq_ignore_ascii_case(hidden) {
+                    hidden_until = None;
+                }
+            }
+            continue;
+        }
+
+        match token {
+            Token::StartTag(tag) => {
+                if !tag.self_closing && HIDDEN_ELEMENTS.iter().any(|h| tag.is(h)) {
+                    hidden_until = Some(tag.name);
+                } else if is_block(tag.name) {
+                    flush(&mut line, &mut lines);
+                }
+            }
+            Token::EndTag(name) if is_block(name) => flush(&mut line, &mut lines),
+            Token::Text => {
+                for c in decode_entities(&html[range]).chars() {
+                    if c.is_whitespace() {
+                        if !line.is_empty() && !line.ends_with(' ') {
+                            line.push(' ');
+                        }
+                    } else {
+                        line.push(c);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    flush(&mut line, &mut lines);
+
+    lines.join("\n")
+}
+
+/// Decodes character references (`&amp;`, `&#8217;`, `&#x2014;`) and the
+/// named entities common in EPUB content. Unknown references are kept as-is.
+pub fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let after = &rest[amp + 1..];
+        let decoded = after
+            .find(';')
+            .filter(|&end| end > 0 && end <= 32)
+            .and_then(|end| decode_entity(&after[..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "thinsp" => '\u{2009}',
+        "shy" => '\u{ad}',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "sbquo" => '‚',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "bdquo" => '„',
+        "laquo" => '«',
+        "raquo" => '»',
+        "bull" => '•',
+        "middot" => '·',
+        "dagger" => '†',
+        "Dagger" => '‡',
+        "sect" => '§',
+        "para" => '¶',
+        "deg" => '°',
+        "times" => '×',
+        "divide" => '÷',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "aacute" => 'á',
+        "agrave" => 'à',
+        "acirc" => 'â',
+        "auml" => 'ä',
+        "aring" => 'å',
+        "aelig" => 'æ',
+        "ccedil" => 'ç',
+        "eacute" => 'é',
+        "Eacute" => 'É',
+        "egrave" => 'è',
+        "ecirc" => 'ê',
+        "euml" => 'ë',
+        "iacute" => 'í',
+        "icirc" => 'î',
+        "iuml" => 'ï',
+        "ntilde" => 'ñ',
+        "oacute" => 'ó',
+        "ocirc" => 'ô',
+        "ouml" => 'ö',
+        "oslash" => 'ø',
+        "oelig" => 'œ',
+        "szlig" => 'ß',
+        "uacute" => 'ú',
+        "ugrave" => 'ù',
+        "ucirc" => 'û',
+        "uuml" => 'ü',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Elements that never have content or an end tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta",
+    "source", "track", "wbr",
+];
+
+/// Byte range of the content of the element with `id="..."`. When the id sits
+/// on an anchor inside a paragraph-like element (`<p><a id="fn1">1</a> ...</p>`),
+/// the enclosing element's content is returned instead.
+pub fn element_content_by_id(html: &str, id: &str) -> Option<Range<usize>> {
+    const ANCHOR_PARENTS: &[&str] = &["p", "li", "div", "aside", "dd", "dt", "span", "section", "td"];
+
+    // Open elements: name and the offset where their content starts
+    let mut stack: Vec<(&str, usize)> = Vec::new();
+    let mut target_depth: Option<usize> = None;
+
+    for (token, range) in tokenize(html) {
+        match token {
+            Token::StartTag(tag) => {
+                let is_void = tag.self_closing || VOID_ELEMENTS.iter().any(|v| tag.is(v));
+                if target_depth.is_none() && tag.attr("id") == Some(id) {
+                    let parent_depth = stack.len().checked_sub(1).filter(|&depth| {
+                        ANCHOR_PARENTS.iter().any(|p| stack[depth].0.eq_ignore_ascii_case(p))
+                    });
+                    if tag.is("a") && parent_depth.is_some() {
+                        target_depth = parent_depth;
+                    } else if is_void {
+                        return None;
+                    } else {
+                        target_depth = Some(stack.len());
+                    }
+                }
+                if !is_void {
+                    stack.push((tag.name, range.end));
+                }
+            }
+            Token::EndTag(name) => {
+                if let Some(pos) = stack.iter().rposition(|(open, _)| open.eq_ignore_ascii_case(name)) {
+                    if let Some(depth) = target_depth {
+                        if pos <= depth {
+                            return Some(stack[depth].1..range.start);
+                        }
+                    }
+                    stack.truncate(pos);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Unclosed target: runs to the end of the document
+    target_depth.and_then(|depth| stack.get(depth)).map(|(_, start)| *start..html.len())
+}
+
+/// A piece of a document's body, cut after a block element.
+pub struct BodySlice {
+    /// Markup of the slice. Elements open where it starts are reopened and
+    /// those still open where it ends are closed, so it stands alone.
+    pub markup: String,
+    /// Offset the slice starts at, moved forward to the body if before it
+    pub start: usize,
+    /// Offset of the next slice, `None` when the slice reaches the body's end
+    pub next: Option<usize>,
+}
+
+/// Cuts the body of `html` from byte offset `start` into a slice of at least
+/// `max_len` bytes of markup (unless the body runs out first), ending after
+/// the first block element that closes past that length and any end tags
+/// straight after it. Returns `None` if `start` falls inside a tag or past the
+/// body.
+pub fn slice_body(html: &str, start: usize, max_len: usize) -> Option<BodySlice> {
+    let tokens: Vec<(Token, Range<usize>)> = tokenize(html).collect();
+    let body_start = tokens.iter()
+        .find_map(|(token, range)| match token {
+            Token::StartTag(tag) if tag.is("body") => Some(range.end),
+            _ => None,
+        })
+        .unwrap_or(0);
+    let body_end = tokens.iter()
+        .rev()
+        .find_map(|(token, range)| match token {
+            Token::EndTag(name) if name.eq_ignore_ascii_case("body") => Some(range.start),
+            _ => None,
+        })
+        .filter(|&end| end >= body_start)
+        .unwrap_or(html.len());
+
+    let start = start.max(body_start);
+    if start > body_end || tokens.iter().any(|(_, range)| range.start < start && start < range.end) {
+        return None;
+    }
+    if start == body_end {
+        return Some(BodySlice { markup: String::new(), start, next: None });
+    }
+
+    // Open elements inside the body: name and start tag
+    let mut stack: Vec<(&str, &str)> = Vec::new();
+    let mut reopen = String::new();
+    let mut end = None;
+    for (token, range) in tokens.iter().filter(|(_, range)| range.start >= body_start && range.end <= body_end) {
+        if range.start == start {
+            reopen = stack.iter().map(|(_, markup)| *markup).collect();
+        }
+        // Past the cut, only take the end tags that directly follow it
+        if end.is_some() && !matches!(token, Token::EndTag(_)) {
+            if matches!(token, Token::Text) && html[range.clone()].trim().is_empty() {
+                continue;
+            }
+            break;
+        }
+        match token {
+            Token::StartTag(tag) if !tag.self_closing && !VOID_ELEMENTS.iter().any(|v| tag.is(v)) => {
+                stack.push((tag.name, &html[range.clone()]));
+            }
+            Token::EndTag(name) => {
+                if let Some(pos) = stack.iter().rposition(|(open, _)| open.eq_ignore_ascii_case(name)) {
+                    stack.truncate(pos);
+                }
+                if end.is_some() || (range.start >= start && range.end - start >= max_len && is_block(name)) {
+                    end = Some(range.end);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut markup = reopen;
+    markup.push_str(&html[start..end.unwrap_or(body_end)]);
+    for (name, _) in stack.iter().rev() {
+        markup.push_str(&format!("</{}>", name));
+    }
+    Some(BodySlice { markup, start, next: end.filter(|&end| end < body_end) })
+}
+
+/// Text of the document's `<title>`, whitespace-collapsed.
+pub fn document_title(html: &str) -> Option<String> {
+    let mut in_title = false;
+    let mut title = String::new();
+    for (token, range) in tokenize(html) {
+        match token {
+            Token::StartTag(tag) if tag.is("title") => in_title = !tag.self_closing,
+            Token::Text if in_title => title.push_str(&decode_entities(&html[range])),
+            Token::EndTag(name) if in_title && name.eq_ignore_ascii_case("title") => break,
+            _ => {}
+        }
+    }
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() { None } else { Some(title) }
+}
+
+/// Text of the document's first `<h1>` or `<h2>`, whitespace-collapsed.
+/// Headings without text (image-only ones) are skipped.
+pub fn first_heading(html: &str) -> Option<String> {
+    let mut heading: Option<(&str, String)> = None;
+    for (token, range) in tokenize(html) {
+        match token {
+            Token::StartTag(tag) if heading.is_none() && !tag.self_closing && (tag.is("h1") || tag.is("h2")) => {
+                heading = Some((tag.name, String::new()));
+            }
+            Token::Text => {
+                if let Some((_, text)) = &mut heading {
+                    text.push_str(&decode_entities(&html[range]));
+                }
+            }
+            Token::EndTag(name) if heading.as_ref().is_some_and(|(open, _)| open.eq_ignore_ascii_case(name)) => {
+                let (_, text) = heading.take()?;
+                let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !text.is_empty() {
+                    return Some(text);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Width and height from `<meta name="viewport" content="width=1200, height=1800">`,
+/// which fixed-layout pages use to give their size in CSS pixels. `None`
+/// unless both are numbers (`device-width` and the like don't count).
+pub fn viewport_size(html: &str) -> Option<(u32, u32)> {
+    let content = tokenize(html).find_map(|(token, _)| match token {
+        Token::StartTag(tag) if tag.is("meta") && tag.attr("name").is_some_and(|name| name.trim().eq_ignore_ascii_case("viewport")) => {
+            tag.attr("content")
+        }
+        _ => None,
+    })?;
+
+    let dimension = |key: &str| {
+        content.split([',', ';']).find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case(key) {
+                return None;
+            }
+            let value = value.trim().trim_end_matches("px");
+            value.parse::<f64>().ok().filter(|v| *v > 0.0).map(|v| v.round() as u32)
+        })
+    };
+    Some((dimension("width")?, dimension("height")?))
+}
+
+/// The encoding a document's bytes are in: the one named by a byte order
+/// mark, the XML declaration, a `<meta charset>` or a stylesheet's
+/// `@charset`. Undeclared documents are UTF-8, or Windows-1252 when they
+/// aren't valid UTF-8.
+pub fn document_encoding(bytes: &[u8]) -> &'static encoding_rs::Encoding {
+    if let Some((encoding, _)) = encoding_rs::Encoding::for_bom(bytes) {
+        return encoding;
+    }
+
+    // Declarations are ASCII-compatible and near the start
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    let declared = declared_encoding(&head).and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()));
+    match declared {
+        Some(encoding) if encoding != encoding_rs::UTF_8 => encoding,
+        _ if std::str::from_utf8(bytes).is_ok() => encoding_rs::UTF_8,
+        _ => encoding_rs::WINDOWS_1252,
+    }
+}
+
+/// Decodes a document's bytes using the encoding `document_encoding` finds.
+pub fn decode_document(bytes: &[u8]) -> Cow<'_, str> {
+    let encoding = document_encoding(bytes);
+    if encoding_rs::Encoding::for_bom(bytes).is_some() {
+        return encoding.decode_with_bom_removal(bytes).0;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) if encoding == encoding_rs::UTF_8 => Cow::Borrowed(text),
+        _ => encoding.decode_without_bom_handling(bytes).0,
+    }
+}
+
+/// Encoding label from a stylesheet's leading `@charset "...";`,
+/// `<?xml encoding="...">`, `<meta charset="...">` or
+/// `<meta http-equiv="Content-Type" content="...; charset=...">`.
+fn declared_encoding(head: &str) -> Option<String> {
+    if let Some(rest) = head.strip_prefix("@charset \"") {
+        return rest.split('"').next().map(str::to_string);
+    }
+    encoding_declarations(head).into_iter().next().map(|(label, _, _)| label)
+}
+
+/// Every encoding declaration in a document's head: the declared label, the
+/// declaration's byte range and a replacement for it declaring UTF-8.
+fn encoding_declarations(head: &str) -> Vec<(String, Range<usize>, String)> {
+    let mut declarations = Vec::new();
+    for (token, range) in tokenize(head) {
+        match token {
+            Token::Other if head[range.clone()].starts_with("<?xml") => {
+                if let Some((label, utf8)) = xml_declaration_encoding(&head[range.clone()]) {
+                    declarations.push((label, range, utf8));
+                }
+            }
+            Token::StartTag(tag) if tag.is("meta") => {
+                if let Some(charset) = tag.attr("charset") {
+                    declarations.push((charset.trim().to_string(), range, tag.rewrite(&[("charset", "utf-8")], &[])));
+                    continue;
+                }
+                let content = tag.attr("content").unwrap_or("");
+                if let Some(start) = content.to_ascii_lowercase().find("charset=") {
+                    let label = content[start + "charset=".len()..].split(';').next().unwrap_or("");
+                    let utf8 = format!("{}charset=utf-8", &content[..start]);
+                    declarations.push((label.trim().to_string(), range, tag.rewrite(&[("content", &utf8)], &[])));
+                }
+            }
+            Token::StartTag(tag) if tag.is("body") => break,
+            _ => {}
+        }
+    }
+    declarations
+}
+
+/// The `encoding` of an XML declaration, and the declaration naming UTF-8.
+fn xml_declaration_encoding(declaration: &str) -> Option<(String, String)> {
+    let start = declaration.find("encoding")? + "encoding".len();
+    let value = declaration[start..].trim_start().strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let label = value[1..].split(quote).next()?;
+    let label_start = declaration.len() - value.len() + 1;
+    let utf8 = format!("{}UTF-8{}", &declaration[..label_start], &declaration[label_start + label.len()..]);
+    Some((label.to_string(), utf8))
+}
+
+/// The document as UTF-8 text, decoded as `decode_document` does, with any
+/// encoding declarations naming another encoding rewritten to match.
+pub fn to_utf8_document(bytes: &[u8]) -> Cow<'_, str> {
+    let text = decode_document(bytes);
+    let head_len = (text.len().min(1024)..=text.len()).find(|i| text.is_char_boundary(*i)).unwrap_or(text.len());
+    let stale: Vec<(Range<usize>, String)> = encoding_declarations(&text[..head_len])
+        .into_iter()
+        .filter(|(label, _, _)| encoding_rs::Encoding::for_label(label.as_bytes()) != Some(encoding_rs::UTF_8))
+        .map(|(_, range, utf8)| (range, utf8))
+        .collect();
+    if stale.is_empty() {
+        return text;
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (range, utf8) in stale {
+        out.push_str(&text[last..range.start]);
+        out.push_str(&utf8);
+        last = range.end;
+    }
+    out.push_str(&text[last..]);
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latin1_document_becomes_utf8_with_corrected_meta() {
+        let mut bytes = b"<html><head><meta charset=\"iso-8859-1\"/></head><body><p>Caf".to_vec();
+        bytes.extend_from_slice(&[0xe9, b' ', 0xbd]);
+        bytes.extend_from_slice(b"</p></body></html>");
+
+        let text = to_utf8_document(&bytes);
+        assert!(text.contains("<p>Café ½</p>"));
+        assert!(text.contains("<meta charset=\"utf-8\" />"));
+        assert!(!text.contains("iso-8859-1"));
+    }
+
+    #[test]
+    fn undeclared_invalid_utf8_is_read_as_windows_1252() {
+        let bytes = b"<html><body><p>\x93quoted\x94</p></body></html>";
+        assert!(to_utf8_document(bytes).contains("<p>\u{201c}quoted\u{201d}</p>"));
+    }
+
+    #[test]
+    fn xml_declaration_encoding_is_rewritten() {
+        let mut bytes = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>\n<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>T</title></head><body><p>".to_vec();
+        bytes.push(0xfc);
+        bytes.extend_from_slice(b"ber</p></body></html>");
+
+        let text = to_utf8_document(&bytes);
+        assert!(text.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+        assert!(text.contains("<p>über</p>"));
+    }
+
+    #[test]
+    fn utf8_document_is_borrowed_unchanged() {
+        let bytes = "<?xml version=\"1.0\" encoding=\"utf-8\"?><html><body><p>ß</p></body></html>".as_bytes();
+        assert!(matches!(to_utf8_document(bytes), Cow::Borrowed(text) if text.as_bytes() == bytes));
+    }
+}
@@ -1,378 +1,8440 @@
 use http::response::Builder as ResponseBuilder;
 use epub::doc::EpubDoc;
-use serde::Serialize;
+use error::AppError;
+use library::Library;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use base64::Engine;
+use tauri::{Emitter, Manager};
 
-struct LibraryState(Arc<Mutex<HashMap<String, EpubDoc<BufReader<File>>>>>);
+mod cfi;
+mod error;
+mod html;
+mod library;
+mod opf;
+mod palette;
+mod readability;
+mod share_card;
+mod smil;
+mod text;
+mod watcher;
 
-#[derive(Serialize, Clone)]
-struct TocItem {
-    label: String,
-    content: String,
-    play_order: usize,
-    children: Vec<TocItem>,
+struct LibraryState(Arc<Mutex<Library>>);
+
+/// A book's document (see `Library::handle`), to lock once the library lock
+/// is released.
+fn book_handle(books: &Mutex<Library>, book_key: &str) -> Result<Arc<Mutex<library::Book>>, AppError> {
+    Library::handle(books, book_key).ok_or_else(|| AppError::book_not_found(book_key))
 }
 
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
-#[tauri::command]
-fn greet(name: &str) -> String {
-    format!("Hello, {}! You've been greeted from Rust!", name)
+/// Directory scanned for `.epub` files; book keys come from paths within it
+/// (see `book_key_for`).
+/// `None` until one is configured or found at startup.
+struct LibraryDirState(Arc<Mutex<Option<PathBuf>>>);
+
+/// Watch on the library directory, replaced when the directory changes.
+struct LibraryWatcherState(Mutex<Option<notify::RecommendedWatcher>>);
+
+/// A book the app was asked to open, kept until the frontend takes it with
+/// `take_opened_book` in case it wasn't listening for `open-book` yet.
+struct OpenedBookState(Mutex<Option<String>>);
+
+/// Payload of the `book-added`, `book-removed` and `open-book` events.
+#[derive(Serialize, Clone)]
+struct LibraryChange {
+    book_key: String,
 }
 
-#[tauri::command]
-fn all_book_covers(state: tauri::State<LibraryState>) -> Vec<(String, String, String)> {
-    state.0.lock().unwrap().iter().filter_map(|book_entry | {
-        let book_key = book_entry.0.clone();
-        let book_title = book_entry.1.mdata("title").unwrap_or(book_key.replace(".epub", ""));
+/// The library directory chosen with `set_library_directory`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct LibrarySettings {
+    directory: Option<PathBuf>,
+}
 
-        // Try to get the cover image directly
-        if let Ok(cover_id) = book_entry.1.get_cover_id() {
-            // Get the actual cover resource (could be image or HTML)
-            if let Some(cover_resource) = book_entry.1.resources.get(&cover_id) {
-                let cover_path = cover_resource.0.to_str().unwrap();
-                let mime_type = &cover_resource.1;
+/// Reader presentation settings injected as CSS into every chapter.
+///
+/// Every field is optional: `None` keeps the built-in default, which also lets
+/// the same struct act as a sparse per-book override.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct ReadingTheme {
+    /// Maximum line length in characters (CSS `ch` units)
+    max_width_ch: Option<u32>,
+    /// How paragraphs are separated, overriding the book's own styling
+    paragraph_style: Option<ParagraphStyle>,
+    /// Automatic hyphenation in the book's language
+    hyphenate: Option<bool>,
+    text_align: Option<TextAlign>,
+    /// Newspaper-style columns per page; `None` means one
+    columns: Option<usize>,
+    /// How images are sized against the text column; `None` means fit
+    image_fit: Option<ImageFit>,
+    /// Page background as `#rgb` or `#rrggbb`; `None` follows the system's
+    /// light or dark appearance, as do the other colors
+    background: Option<String>,
+    /// Text color
+    foreground: Option<String>,
+    /// Link color
+    link: Option<String>,
+    /// CSS font family list, e.g. `Georgia, serif`
+    font_family: Option<String>,
+    /// Root text size in percent of the default; the book's relative sizes scale with it
+    font_size: Option<u32>,
+    /// Line height as a multiple of the font size
+    line_height: Option<f64>,
+    /// E-ink mode: no motion or shadows, black text on white (see `EINK_CSS`)
+    eink: Option<bool>,
+}
 
-                // If it's already an image, use it directly
-                if mime_type.starts_with("image/") {
-                    let cover_uri = format!("epub://{}/{}", book_key, cover_path);
-                    return Some((book_key, book_title, cover_uri));
-                }
+impl ReadingTheme {
+    /// Layers `overrides` on top of `self`; fields set in `overrides` win.
+    fn merged(&self, overrides: &ReadingTheme) -> ReadingTheme {
+        ReadingTheme {
+            max_width_ch: overrides.max_width_ch.or(self.max_width_ch),
+            paragraph_style: overrides.paragraph_style.or(self.paragraph_style),
+            hyphenate: overrides.hyphenate.or(self.hyphenate),
+            text_align: overrides.text_align.or(self.text_align),
+            columns: overrides.columns.or(self.columns),
+            image_fit: overrides.image_fit.or(self.image_fit),
+            background: overrides.background.clone().or_else(|| self.background.clone()),
+            foreground: overrides.foreground.clone().or_else(|| self.foreground.clone()),
+            link: overrides.link.clone().or_else(|| self.link.clone()),
+            font_family: overrides.font_family.clone().or_else(|| self.font_family.clone()),
+            font_size: overrides.font_size.or(self.font_size),
+            line_height: overrides.line_height.or(self.line_height),
+            eink: overrides.eink.or(self.eink),
+        }
+    }
 
-                // If it's HTML/XHTML, try to find the actual image in resources
-                // Look for common cover image patterns
-                for (res_path, (path_buf, res_mime)) in book_entry.1.resources.iter() {
-                    if res_mime.starts_with("image/") &&
-                       (res_path.contains("cover") || res_path.contains("Cover")) {
-                        let image_path = path_buf.to_str().unwrap();
-                        let cover_uri = format!("epub://{}/{}", book_key, image_path);
-                        return Some((book_key, book_title, cover_uri));
-                    }
-                }
+    /// True when no field is set, i.e. a per-book override that changes nothing.
+    fn is_empty(&self) -> bool {
+        self.max_width_ch.is_none()
+            && self.paragraph_style.is_none()
+            && self.hyphenate.is_none()
+            && self.text_align.is_none()
+            && self.columns.is_none()
+            && self.image_fit.is_none()
+            && self.background.is_none()
+            && self.foreground.is_none()
+            && self.link.is_none()
+            && self.font_family.is_none()
+            && self.font_size.is_none()
+            && self.line_height.is_none()
+            && self.eink.is_none()
+    }
 
-                // Fallback: use the HTML cover page
-                let cover_uri = format!("epub://{}/{}", book_key, cover_path);
-                return Some((book_key, book_title, cover_uri));
+    fn validate(&self) -> Result<(), AppError> {
+        validate_reading_width(self.max_width_ch)?;
+        validate_columns(self.columns)?;
+        for (name, color) in [("Background", &self.background), ("Text", &self.foreground), ("Link", &self.link)] {
+            if let Some(color) = color {
+                validate_color(name, color)?;
+            }
+        }
+        if let Some(font_family) = &self.font_family {
+            let is_safe = font_family.chars().all(|c| c.is_alphanumeric() || " ,-_'\"".contains(c));
+            if font_family.trim().is_empty() || !is_safe {
+                return Err(AppError::InvalidArgument(format!("Invalid font family: {}", font_family)));
             }
         }
+        match self.font_size {
+            Some(size) if !(50..=300).contains(&size) => {
+                return Err(AppError::InvalidArgument(format!("Font size must be between 50% and 300%, got {}%", size)));
+            }
+            _ => {}
+        }
+        match self.line_height {
+            Some(height) if !(1.0..=3.0).contains(&height) => {
+                Err(AppError::InvalidArgument(format!("Line height must be between 1.0 and 3.0, got {}", height)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
 
-        // If no cover found, skip this book
-        None
-    }).collect()
+/// Accepts `#rgb` and `#rrggbb` colors, the only forms the theme's CSS takes.
+fn validate_color(name: &str, color: &str) -> Result<(), AppError> {
+    let is_hex = color.strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()));
+    if is_hex {
+        Ok(())
+    } else {
+        Err(AppError::InvalidArgument(format!("{} color must be #rgb or #rrggbb, got {}", name, color)))
+    }
 }
 
-#[tauri::command]
-fn get_book_title(book_key: String, state: tauri::State<LibraryState>) -> Result<String, String> {
-    let books = state.0.lock().unwrap();
+/// Built-in color schemes for the reading theme.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum ThemePreset {
+    Light,
+    Dark,
+    Sepia,
+    HighContrast,
+    /// Black on white for e-ink panels, which also turns on e-ink mode
+    #[serde(rename = "eink")]
+    Eink,
+}
 
-    if let Some(book) = books.get(&book_key) {
-        let title = book.mdata("title").unwrap_or(book_key.replace(".epub", ""));
-        Ok(title)
-    } else {
-        Err(format!("Book not found: {}", book_key))
+impl ThemePreset {
+    /// Background, text and link colors.
+    fn colors(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            ThemePreset::Light => ("#ffffff", "#1a1a1a", "#0066cc"),
+            ThemePreset::Dark => ("#1e1e1e", "#e4e4e4", "#58a6ff"),
+            ThemePreset::Sepia => ("#f4ecd8", "#5b4636", "#8b5a2b"),
+            ThemePreset::HighContrast => ("#000000", "#ffffff", "#ffff00"),
+            ThemePreset::Eink => ("#ffffff", "#000000", "#000000"),
+        }
     }
 }
 
-#[tauri::command]
-fn get_book_toc(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<TocItem>, String> {
-    let mut books = state.0.lock().unwrap();
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ParagraphStyle {
+    /// First-line indents, no space between paragraphs
+    Indent,
+    /// Blank space between paragraphs, no indents
+    Spaced,
+}
 
-    if let Some(book) = books.get_mut(&book_key) {
-        // Get the table of contents from the EPUB
-        let toc = &book.toc;
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TextAlign {
+    Justify,
+    Left,
+}
 
-        // Convert NavPoint to TocItem recursively
-        fn convert_nav_points(nav_points: &[epub::doc::NavPoint]) -> Vec<TocItem> {
-            nav_points.iter().map(|nav_point| {
-                TocItem {
-                    label: nav_point.label.clone(),
-                    content: nav_point.content.to_str().unwrap_or("").to_string(),
-                    play_order: nav_point.play_order,
-                    children: convert_nav_points(&nav_point.children),
-                }
-            }).collect()
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ImageFit {
+    /// Scaled down to the column width, keeping the aspect ratio
+    Fit,
+    /// Fit, and centered on a line of their own
+    Center,
+    /// The book's own sizing, for full-bleed art
+    Natural,
+}
+
+/// What the reader adds to served chapters. Books with carefully designed
+/// CSS can be read without the reader's styling, or entirely as shipped.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum InjectionMode {
+    /// Default and theme styles plus the pagination and link script
+    #[default]
+    Full,
+    /// The script without any styles, so the book's own CSS rules
+    ScriptOnly,
+    /// The styles without the script
+    StyleOnly,
+    /// Nothing; chapters are served as the book has them
+    None,
+}
+
+/// Every user setting, persisted together as one file so a crash can never
+/// leave settings half-written. Missing fields load as defaults, so files
+/// written by older versions keep working as settings are added.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Preferences {
+    theme: ReadingTheme,
+    book_overrides: HashMap<String, ReadingTheme>,
+    /// Reading speed for time estimates; `None` uses the default
+    words_per_minute: Option<u32>,
+    injection_mode: InjectionMode,
+    /// Remote (`http(s)://`) resources are stripped from served chapters
+    privacy_mode: bool,
+}
+
+impl Preferences {
+    fn for_book(&self, book_key: &str) -> ReadingTheme {
+        match self.book_overrides.get(book_key) {
+            Some(overrides) => self.theme.merged(overrides),
+            None => self.theme.clone(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        for theme in std::iter::once(&self.theme).chain(self.book_overrides.values()) {
+            theme.validate()?;
         }
+        self.words_per_minute.map_or(Ok(()), validate_words_per_minute)
+    }
+}
 
-        Ok(convert_nav_points(toc))
+fn validate_words_per_minute(words_per_minute: u32) -> Result<(), AppError> {
+    if (50..=2000).contains(&words_per_minute) {
+        Ok(())
     } else {
-        Err(format!("Book not found: {}", book_key))
+        Err(AppError::InvalidArgument(format!("Reading speed must be between 50 and 2000 words per minute, got {}", words_per_minute)))
     }
 }
 
-#[tauri::command]
-fn get_spine(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<String>, String> {
-    let books = state.0.lock().unwrap();
+struct PreferencesState(Arc<Mutex<Preferences>>);
 
-    if let Some(book) = books.get(&book_key) {
-        // spine is Vec<String> of resource IDs
-        // Convert to content paths using resources map
-        let spine_paths: Vec<String> = book.spine.iter()
-            .filter_map(|id| {
-                book.resources.get(id).map(|(path, _)| {
-                    path.to_str().unwrap_or("").to_string()
-                })
-            })
-            .collect();
+const PREFERENCES_FILE: &str = "preferences.json";
+const LIBRARY_FILE: &str = "library.json";
+/// Theme-only settings file used before preferences were unified
+const LEGACY_THEME_FILE: &str = "reading-theme.json";
 
-        Ok(spine_paths)
-    } else {
-        Err(format!("Book not found: {}", book_key))
-    }
+/// Downscaled cover PNGs keyed by (book key, max dimension).
+type ThumbnailMap = HashMap<(String, u32), Arc<Vec<u8>>>;
+
+struct CoverCache {
+    thumbnails: Mutex<ThumbnailMap>,
+    /// Each book's cover size and color, measured once from the full cover
+    info: Mutex<HashMap<String, CoverInfo>>,
+    /// Where thumbnails are kept between runs, under the app cache directory;
+    /// `None` until the app has started or if there's no cache directory
+    disk_directory: Mutex<Option<PathBuf>>,
+    /// Last sprite sheet built, reused while the library's books are unchanged
+    sprite: Mutex<Option<CachedSprite>>,
+    /// Bumped to cancel in-flight prewarm jobs
+    prewarm_generation: AtomicU64,
 }
 
-#[tauri::command]
-fn get_current_spine_index(
-    book_key: String,
-    content_path: String,
-    state: tauri::State<LibraryState>
-) -> Result<Option<usize>, String> {
-    let books = state.0.lock().unwrap();
-
-    if let Some(book) = books.get(&book_key) {
-        // Find index in spine where resource path matches content_path
-        let index = book.spine.iter().position(|id| {
-            book.resources.get(id)
-                .map(|(path, _)| path.to_str().unwrap_or("") == content_path)
-                .unwrap_or(false)
-        });
+struct CachedSprite {
+    max_dimension: u32,
+    columns: u32,
+    /// Sorted keys of the books in the library when it was built
+    book_keys: Vec<String>,
+    sprite: CoverSprite,
+}
 
-        Ok(index)
-    } else {
-        Err(format!("Book not found: {}", book_key))
-    }
+#[derive(Serialize, Clone)]
+struct SpriteRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
 }
 
-#[tauri::command]
-fn get_spine_item(
-    book_key: String,
-    index: usize,
-    state: tauri::State<LibraryState>
-) -> Result<Option<String>, String> {
-    let books = state.0.lock().unwrap();
+#[derive(Serialize, Clone)]
+struct CoverSprite {
+    /// `data:image/png;base64,...` URI of the sheet
+    image: String,
+    width: u32,
+    height: u32,
+    /// Where each book's cover sits on the sheet; books without a cover are absent
+    covers: HashMap<String, SpriteRect>,
+}
 
-    if let Some(book) = books.get(&book_key) {
-        if let Some(resource_id) = book.spine.get(index) {
-            if let Some((path, _)) = book.resources.get(resource_id) {
-                return Ok(Some(path.to_str().unwrap_or("").to_string()));
-            }
-        }
-        Ok(None)
-    } else {
-        Err(format!("Book not found: {}", book_key))
-    }
+struct CoverCacheState(Arc<CoverCache>);
+
+/// A book as the library grid shows it.
+#[derive(Serialize)]
+struct BookCover {
+    key: String,
+    title: String,
+    author: Option<String>,
+    /// `epub://` URI of the cover; `None` when the book has none
+    cover_uri: Option<String>,
+    /// Colors for the tile drawn in place of a missing cover, derived from
+    /// the title so they don't change; `None` when there's a cover
+    placeholder: Option<PlaceholderColors>,
+    /// The cover's size and color; `None` until it has been measured, by
+    /// `prewarm_covers` or `get_cover_image`
+    cover_info: Option<CoverInfo>,
 }
 
-fn inject_link_handler_script(html_content: Vec<u8>) -> Vec<u8> {
-    // Convert bytes to string
-    let html_str = match String::from_utf8(html_content.clone()) {
-        Ok(s) => s,
-        Err(_) => {
-            eprintln!("Failed to parse HTML as UTF-8, serving without injection");
-            return html_content;
-        }
-    };
+/// What the grid needs to lay out a cover's tile and fill it while the image
+/// loads.
+#[derive(Serialize, Deserialize, Clone)]
+struct CoverInfo {
+    /// Size of the full cover in pixels
+    width: u32,
+    height: u32,
+    /// Dominant color as `#rrggbb`
+    color: String,
+}
 
-    // Default CSS for EPUB content with dark mode support
-    let default_css = r#"<style>
-/* Default styling for EPUB content - applied before EPUB's own CSS */
-:root {
-    color-scheme: light dark;
+#[derive(Serialize)]
+struct PlaceholderColors {
+    background: String,
+    foreground: String,
 }
 
-html, body {
-    background-color: #ffffff;
-    color: #1a1a1a;
+#[derive(Serialize)]
+struct LibraryPage {
+    books: Vec<BookCover>,
+    /// Books in the whole library, for sizing the scrollable grid
+    total: usize,
+}
 
-    /* Responsive margins for comfortable reading */
-    padding-left: max(16px, min(5vw, 80px));
-    padding-right: max(16px, min(5vw, 80px));
-    padding-top: max(16px, min(3vh, 48px));
-    padding-bottom: max(32px, min(5vh, 64px));
+#[derive(Serialize)]
+struct CoverImage {
+    mime_type: String,
+    data_base64: String,
+    /// Size in pixels; `None` for SVG covers
+    width: Option<u32>,
+    height: Option<u32>,
+    /// The cover's dominant color as `#rrggbb`; `None` for SVG covers
+    color: Option<String>,
+}
 
-    /* Optimal line width for readability */
-    max-width: 50rem;
-    margin-left: auto;
-    margin-right: auto;
+#[derive(Serialize, Clone)]
+struct CoverReady {
+    key: String,
+    max_dimension: u32,
+}
 
-    /* Improve text rendering */
-    line-height: 1.6;
+#[derive(Serialize)]
+struct BookIdentifier {
+    /// The element's `id` attribute, if any
+    id: Option<String>,
+    /// ISBN, UUID, DOI, ... when declared or recognizable from the value
+    scheme: Option<String>,
+    value: String,
 }
 
-a {
-    color: #0066cc;
+#[derive(Serialize)]
+struct BookIdentifiers {
+    /// The identifier referenced by the package's `unique-identifier` attribute
+    unique_identifier: Option<BookIdentifier>,
+    identifiers: Vec<BookIdentifier>,
 }
 
-a:visited {
-    color: #551a8b;
+/// Where the reader left off in a book.
+#[derive(Serialize, Deserialize, Clone)]
+struct Progress {
+    spine_index: usize,
+    /// Position within the chapter, 0.0–1.0
+    scroll_fraction: f64,
+    /// Unix time in milliseconds
+    updated_at: u64,
+    #[serde(flatten)]
+    anchor: PositionAnchor,
 }
 
-a:hover {
-    color: #003d7a;
+/// What a saved position pointed at, so it can be found again after the
+/// book file is replaced with an edited version. Absent from positions
+/// saved by older versions.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct PositionAnchor {
+    /// Archive path of the chapter
+    spine_href: Option<String>,
+    /// A few words of the text at the position
+    context: Option<String>,
+    /// `spine_signature` of the book when the position was saved
+    spine_signature: Option<String>,
 }
 
-@media (prefers-color-scheme: dark) {
-    html, body {
-        background-color: #1e1e1e;
-        color: #e4e4e4;
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RepairStatus {
+    /// The book's spine hasn't changed (or nothing was saved)
+    Unchanged,
+    /// Found again by its text or chapter
+    Relocated,
+    /// Not found; moved to the start of the book
+    Reset,
+}
 
-        /* Responsive margins for comfortable reading */
-        padding-left: max(16px, min(5vw, 80px));
-        padding-right: max(16px, min(5vw, 80px));
-        padding-top: max(16px, min(3vh, 48px));
-        padding-bottom: max(32px, min(5vh, 64px));
+#[derive(Serialize)]
+struct PositionRepair {
+    status: RepairStatus,
+    progress: Option<Progress>,
+}
 
-        /* Optimal line width for readability */
-        max-width: 50rem;
-        margin-left: auto;
-        margin-right: auto;
+struct ProgressState(Arc<Mutex<HashMap<String, Progress>>>);
 
-        /* Improve text rendering */
-        line-height: 1.6;
-    }
+const PROGRESS_FILE: &str = "progress.json";
 
-    a {
-        color: #58a6ff;
-    }
+/// Keys of the books the reader has finished.
+struct FinishedState(Arc<Mutex<std::collections::HashSet<String>>>);
 
-    a:visited {
-        color: #d2a8ff;
-    }
+const FINISHED_FILE: &str = "finished.json";
 
-    a:hover {
-        color: #79b8ff;
-    }
+/// When a book joined the library and when it was last opened, in ms since
+/// the Unix epoch, for the "New arrivals" and "Continue reading" shelves.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct BookDates {
+    /// The file's modification time when the book was first found
+    added_at: Option<u64>,
+    last_opened_at: Option<u64>,
 }
 
-/* ============================================
-   PAGINATION: CSS Multi-Column Layout
-   ============================================ */
+struct RecentsState(Arc<Mutex<HashMap<String, BookDates>>>);
 
-/* Base styles for html/body to ensure full height */
-html {
-    height: 100%;
-    margin: 0;
-    padding: 0;
-    overflow: hidden;
+const RECENTS_FILE: &str = "recents.json";
+
+#[derive(Serialize)]
+struct RecentBook {
+    book_key: String,
+    title: Option<String>,
+    author: Option<String>,
+    added_at: Option<u64>,
+    last_opened_at: Option<u64>,
 }
 
-body {
-    height: 100%;
-    margin: 0;
-    padding: 0;
-    overflow: hidden;
+/// A passage the reader marked, located by character offsets into the
+/// chapter's text as `get_chapter_text` returns it, so it survives
+/// re-rendering with a different theme or layout. Highlights saved before
+/// offsets existed have none and are located by their text alone.
+#[derive(Serialize, Deserialize, Clone)]
+struct Highlight {
+    /// Derived from `created_at`, like bookmark ids
+    #[serde(default)]
+    id: String,
+    /// Archive path of the chapter
+    spine_href: String,
+    #[serde(default)]
+    spine_index: Option<usize>,
+    #[serde(default)]
+    start_offset: Option<usize>,
+    /// Exclusive
+    #[serde(default)]
+    end_offset: Option<usize>,
+    /// The highlighted text as displayed
+    text: String,
+    /// `#rgb` or `#rrggbb`
+    #[serde(default)]
+    color: Option<String>,
+    note: Option<String>,
+    /// Unix time in milliseconds
+    created_at: u64,
 }
 
-body.paginated {
-    max-width: none !important;
-    /* column-width and width set by JavaScript, not CSS */
-    column-gap: 0;
-    column-fill: auto;
-    height: 100%;              /* Changed from 100vh - uses iframe height */
-    /* width is set by JavaScript to match pageWidth */
-    overflow-x: hidden;        /* Hide horizontal scrollbar */
-    overflow-y: hidden;        /* Hide vertical scrollbar */
-    padding: 0 !important;
-    margin: 0 !important;
-    scroll-behavior: smooth;
+#[derive(Serialize, Deserialize, Clone)]
+struct Bookmark {
+    /// Derived from `created_at`, so bookmarks saved before ids existed get
+    /// the same one every time
+    #[serde(default)]
+    id: String,
+    spine_index: usize,
+    /// Position within the chapter, 0.0–1.0
+    scroll_fraction: f64,
+    label: Option<String>,
+    /// Unix time in milliseconds
+    created_at: u64,
 }
 
-body.paginated > * {
-    padding-left: max(16px, min(5vw, 80px));
-    padding-right: max(16px, min(5vw, 80px));
-    padding-top: max(16px, min(3vh, 48px));
-    padding-bottom: max(32px, min(5vh, 64px));
+/// A book's highlights and bookmarks.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Annotations {
+    highlights: Vec<Highlight>,
+    bookmarks: Vec<Bookmark>,
 }
 
-/* Prevent images and tables from breaking across columns */
-body.paginated img,
-body.paginated table {
-    -webkit-column-break-inside: avoid;
-    break-inside: avoid;
-    max-width: 100%;
+struct AnnotationsState(Arc<Mutex<HashMap<String, Annotations>>>);
+
+const ANNOTATIONS_FILE: &str = "annotations.json";
+
+/// Text measurements of one spine document; non-HTML items are all zero.
+#[derive(Clone, Copy, Default)]
+struct ChapterStats {
+    /// Visible-text length in chars
+    chars: usize,
+    words: usize,
 }
 
-/* Dark mode pagination */
-@media (prefers-color-scheme: dark) {
-    body.paginated {
-        background-color: #1e1e1e;
-        color: #e4e4e4;
-    }
+/// Per-chapter text stats of every spine document, computed once per book.
+struct TextStatsState(Arc<Mutex<HashMap<String, Arc<Vec<ChapterStats>>>>>);
+
+/// Structural features of a book, compact enough to compare many books
+/// when suggesting similar ones.
+#[derive(Serialize, Clone)]
+struct BookFingerprint {
+    word_count: usize,
+    /// Spine documents containing text
+    chapter_count: usize,
+    average_chapter_words: usize,
+    image_count: usize,
+    fixed_layout: bool,
+    language: Option<String>,
+    /// Scripts making up a noticeable share of the letters, most used first
+    scripts: Vec<String>,
 }
-</style>"#;
 
-    // JavaScript to inject - includes pagination and link handling
-    let script = r#"<script>
-//<![CDATA[
-(function() {
-    'use strict';
+struct FingerprintState(Arc<Mutex<HashMap<String, BookFingerprint>>>);
 
-    // ==========================================
-    // PAGINATION STATE
-    // ==========================================
-    let paginationEnabled = false;
-    let currentPage = 0;
-    let totalPages = 0;
-    let pageWidth = 0;
+/// Colors for theming the reader UI, as `#rrggbb`.
+#[derive(Serialize, Clone)]
+struct CoverPalette {
+    dominant: String,
+    vibrant: String,
+    muted: String,
+    light: String,
+    dark: String,
+    /// `cover`, or `title` when the book has no decodable cover
+    source: String,
+}
 
-    // ==========================================
-    // INITIALIZATION
-    // ==========================================
-    function initializePagination() {
-        // Wait for DOM to be ready before enabling pagination
-        function init() {
-            // Enable pagination by default
-            enablePagination();
+struct CoverPaletteState(Arc<Mutex<HashMap<String, CoverPalette>>>);
 
-            // Listen for messages from parent
-            window.addEventListener('message', handleParentMessage);
+/// What `prepare_book` did ahead of the book being opened.
+#[derive(Serialize, Clone)]
+struct PreparedBook {
+    title: String,
+    /// Archive path of the cover image
+    cover_href: Option<String>,
+    toc_entries: usize,
+    /// Where the reader will open: the saved position, else the first chapter
+    resume: BookLocation,
+    /// Bytes of the resume chapter and its stylesheets and images read ahead
+    prefetched_bytes: u64,
+}
 
-            // Recalculate on resize
-            window.addEventListener('resize', debounce(calculatePages, 250));
+struct PreparedState(Arc<Mutex<HashMap<String, PreparedBook>>>);
 
-            // Initial calculation after content loads
-            setTimeout(calculatePages, 100);
-        }
+/// Most bytes `prepare_book` reads ahead for one book, into the resource
+/// cache.
+const MAX_PREFETCH_BYTES: u64 = 8 * 1024 * 1024;
 
-        // Check if DOM is ready
-        if (document.readyState === 'loading') {
-            document.addEventListener('DOMContentLoaded', init);
-        } else {
-            // DOM is already ready
-            init();
-        }
+/// Bytes served by the `epub://` handler or read ahead for it by
+/// `prepare_book`, keyed by book key and a second key (the archive path for
+/// raw resources). Least recently used entries are evicted once the total
+/// size is over budget.
+struct ResourceCache {
+    /// Bytes and the tick they were last used at
+    entries: HashMap<(String, String), (Vec<u8>, u64)>,
+    size: usize,
+    budget: usize,
+    tick: u64,
+}
+
+impl ResourceCache {
+    fn new(budget: usize) -> ResourceCache {
+        ResourceCache { entries: HashMap::new(), size: 0, budget, tick: 0 }
     }
 
-    // ==========================================
-    // PAGINATION CONTROL
-    // ==========================================
-    function enablePagination() {
-        if (paginationEnabled) return;
-        if (!document.body) {
-            console.error('[Pagination] document.body is not available yet');
+    fn get(&mut self, key: &(String, String)) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let (bytes, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.tick;
+        Some(bytes.clone())
+    }
+
+    /// Resources bigger than the whole budget aren't cached.
+    fn insert(&mut self, key: (String, String), bytes: Vec<u8>) {
+        if bytes.len() > self.budget {
             return;
         }
-
-        paginationEnabled = true;
-        document.body.classList.add('paginated');
-
-        setTimeout(function() {
-            calculatePages();
-            sendPaginationUpdate();
-        }, 100);
+        self.tick += 1;
+        self.size += bytes.len();
+        if let Some((old, _)) = self.entries.insert(key, (bytes, self.tick)) {
+            self.size -= old.len();
+        }
+        self.evict_to(self.budget);
     }
 
-    function disablePagination() {
-        if (!paginationEnabled) return;
-        if (!document.body) return;
-
-        paginationEnabled = false;
-        document.body.classList.remove('paginated');
-        document.body.scrollLeft = 0;
+    /// Drops `book_key`'s resources, or everything when `None`.
+    fn clear(&mut self, book_key: Option<&str>) {
+        self.entries.retain(|(key, _), _| book_key.is_some_and(|book_key| book_key != key));
+        self.size = self.entries.values().map(|(bytes, _)| bytes.len()).sum();
     }
 
-    // ==========================================
+    /// Evicts least recently used entries until at most `budget` bytes are
+    /// cached, returning the bytes evicted.
+    fn evict_to(&mut self, budget: usize) -> usize {
+        let mut by_age: Vec<((String, String), u64)> = self.entries.iter()
+            .map(|(key, (_, last_used))| (key.clone(), *last_used))
+            .collect();
+        by_age.sort_by_key(|(_, last_used)| *last_used);
+
+        let mut evicted = 0;
+        for (key, _) in by_age {
+            if self.size <= budget {
+                break;
+            }
+            if let Some((bytes, _)) = self.entries.remove(&key) {
+                self.size -= bytes.len();
+                evicted += bytes.len();
+            }
+        }
+        evicted
+    }
+}
+
+struct ResourceCacheState(Arc<Mutex<ResourceCache>>);
+
+/// Starting budget of the resource cache; adjustable with `set_cache_budget`.
+const DEFAULT_CACHE_BUDGET: usize = 32 * 1024 * 1024;
+
+/// Chapters as the `epub://` handler last served them, content modes and
+/// theme applied, so going back and forth between chapters doesn't redo
+/// the injection. Keyed by `chapter_cache_key`.
+struct ChapterCacheState(Arc<Mutex<ResourceCache>>);
+
+const CHAPTER_CACHE_BUDGET: usize = 16 * 1024 * 1024;
+
+/// URL-safe stand-ins for a book's archive paths, in both directions.
+#[derive(Default)]
+struct VirtualPaths {
+    to_virtual: HashMap<String, String>,
+    to_archive: HashMap<String, String>,
+}
+
+/// Virtual path maps, built per book on first use.
+type VirtualPathMap = HashMap<String, Arc<VirtualPaths>>;
+
+struct VirtualPathState(Arc<Mutex<VirtualPathMap>>);
+
+/// Archive path of each referenced resource to the spine documents that
+/// reference it, in spine order.
+type ReferenceMap = HashMap<String, Vec<String>>;
+
+/// Per-book reference maps, built on the first `find_containing_chapter`.
+struct ReferenceMapState(Arc<Mutex<HashMap<String, Arc<ReferenceMap>>>>);
+
+/// Chapter peeks keyed by (book key, archive path).
+struct ChapterPeekState(Arc<Mutex<HashMap<(String, String), String>>>);
+
+/// Length of a chapter peek, in characters.
+const PEEK_CHARS: usize = 100;
+
+/// Bumped to cancel an in-flight streaming search.
+struct SearchState(Arc<AtomicU64>);
+
+/// Most chapter matches reported per book.
+const MAX_SEARCH_HITS_PER_BOOK: usize = 20;
+/// Most matches reported per chapter when searching a single book.
+const MAX_SEARCH_HITS_PER_CHAPTER: usize = 10;
+/// Characters of context on each side of a match in a snippet.
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+#[derive(Serialize, Clone)]
+struct SearchHit {
+    spine_index: usize,
+    href: String,
+    /// The match with some surrounding text
+    snippet: String,
+    /// Offset of the match in characters into the chapter's extracted text
+    offset: usize,
+}
+
+#[derive(Serialize)]
+struct BookSearchHits {
+    hits: Vec<SearchHit>,
+    /// Every match in the book, including those beyond the per-chapter cap
+    total: usize,
+}
+
+#[derive(Serialize, Clone)]
+struct BookSearchResult {
+    book_key: String,
+    title: String,
+    /// The title or an author matched
+    metadata_match: bool,
+    hits: Vec<SearchHit>,
+}
+
+#[derive(Serialize, Clone)]
+struct SearchResultEvent {
+    search_id: u64,
+    result: BookSearchResult,
+}
+
+#[derive(Serialize, Clone)]
+struct SearchComplete {
+    search_id: u64,
+    cancelled: bool,
+    matched_books: usize,
+}
+
+/// Reading speed assumed until the user sets their own.
+const DEFAULT_WORDS_PER_MINUTE: u32 = 250;
+
+#[derive(Serialize)]
+struct ChapterTime {
+    spine_index: usize,
+    href: String,
+    words: usize,
+    minutes: f64,
+}
+
+#[derive(Serialize)]
+struct ChapterTimes {
+    words_per_minute: u32,
+    total_minutes: f64,
+    chapters: Vec<ChapterTime>,
+}
+
+#[derive(Serialize)]
+struct ReadingEstimates {
+    words_per_minute: u32,
+    total_words: usize,
+    total_minutes: f64,
+    chapters: Vec<ChapterTime>,
+}
+
+/// Reading across the whole library, for a stats view.
+#[derive(Serialize)]
+struct LibraryStats {
+    total_books: usize,
+    /// Books with a saved position or marked finished
+    started: usize,
+    /// Marked finished, or read to `FINISHED_FRACTION` of their text
+    finished: usize,
+    total_words: usize,
+    words_per_minute: u32,
+    /// Reading time of every book in the library
+    total_minutes: f64,
+    /// Reading time left in the started books that aren't finished
+    remaining_minutes: f64,
+}
+
+/// Share of a book's text read for it to count as finished in the stats.
+const FINISHED_FRACTION: f64 = 0.99;
+
+/// Words per printed page assumed by page estimates.
+const DEFAULT_WORDS_PER_PAGE: usize = 300;
+
+#[derive(Serialize)]
+struct PageEstimate {
+    pages: usize,
+    /// `page-list` when the book maps its print pages, else `estimate`
+    source: String,
+    /// Words per page used for an estimate
+    words_per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct BookMetadata {
+    book_key: String,
+    title: String,
+    /// Every `dc:creator`, e.g. each author
+    creator: Vec<String>,
+    publisher: Option<String>,
+    language: Option<String>,
+    description: Option<String>,
+    /// `dc:date` as written in the package
+    date: Option<String>,
+    identifier: Option<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    tags: Vec<String>,
+    rating: Option<f64>,
+    estimated_pages: PageEstimate,
+    audience: Option<Audience>,
+    /// Declared EPUB version, `2.0` or `3.0` (or whatever else the package says)
+    version: Option<String>,
+    /// `ltr` or `rtl`, from the language and the package's writing mode
+    text_direction: String,
+    /// `vertical-rl` or `vertical-lr` for vertical text, else `None`
+    writing_mode: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Audience {
+    /// Declared age range, e.g. `8-12` or `14+`
+    age_range: Option<String>,
+    /// Free-form audience, e.g. `Young Adult`
+    label: Option<String>,
+}
+
+impl From<(Option<String>, Option<String>)> for Audience {
+    fn from((age_range, label): (Option<String>, Option<String>)) -> Audience {
+        Audience { age_range, label }
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct BookLocation {
+    spine_index: usize,
+    /// Archive path of the spine document
+    href: String,
+    /// `epub://` URI ready to load in the viewer
+    uri: String,
+    /// Position within the chapter to scroll to, 0.0–1.0
+    scroll_fraction: f64,
+}
+
+#[derive(Serialize)]
+struct LinkTarget {
+    spine_index: usize,
+    /// Archive path of the spine document
+    href: String,
+    /// `epub://` URI ready to load in the viewer
+    uri: String,
+    /// Anchor to scroll to once the document has loaded
+    fragment: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmbeddedFont {
+    /// Archive path of the font resource
+    path: String,
+    mime: String,
+    size: usize,
+    /// Family name from the font's `name` table; `None` for WOFF/WOFF2 and
+    /// otherwise unparseable fonts
+    family: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BookFonts {
+    book_key: String,
+    title: String,
+    fonts: Vec<EmbeddedFont>,
+}
+
+#[derive(Serialize)]
+struct ResourceCompression {
+    path: String,
+    /// Zip compression method, e.g. `stored` or `deflated`
+    method: String,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    /// Stored without compression although it would deflate well
+    recompressible: bool,
+    /// Bytes saved by deflating a recompressible resource
+    potential_savings: u64,
+}
+
+#[derive(Serialize)]
+struct CompressionReport {
+    file_size: u64,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    /// `compressed_size / uncompressed_size`; 1.0 for an empty archive
+    ratio: f64,
+    potential_savings: u64,
+    /// Largest resources by uncompressed size
+    largest: Vec<ResourceCompression>,
+}
+
+#[derive(Serialize)]
+struct ChangedResource {
+    path: String,
+    /// Modification time stored in the zip entry, in milliseconds since the
+    /// Unix epoch (zip times carry no time zone and are read as UTC)
+    modified: u64,
+}
+
+#[derive(Serialize)]
+struct ResourceChanges {
+    /// False when the entry times can't be trusted; every resource is then
+    /// reported as changed
+    timestamps_reliable: bool,
+    resources: Vec<ChangedResource>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ReadStatus {
+    Unread,
+    Reading,
+    Finished,
+}
+
+/// Criteria for `query_books`. Every field is optional; the ones given must
+/// all match. Text comparisons are case-insensitive.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct BookFilter {
+    author: Option<String>,
+    series: Option<String>,
+    tag: Option<String>,
+    /// Language code prefix, so `en` also matches `en-GB`
+    language: Option<String>,
+    /// Minimum star rating, 0–5
+    min_rating: Option<f64>,
+    read_status: Option<ReadStatus>,
+    /// `false` lists only books not marked finished
+    finished: Option<bool>,
+    /// Substring of the title, authors, series or tags
+    text: Option<String>,
+    /// `title` (default), `author`, `series`, `rating` or `recent`
+    sort: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BookSummary {
+    book_key: String,
+    title: String,
+    authors: Vec<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    language: Option<String>,
+    tags: Vec<String>,
+    rating: Option<f64>,
+    read_status: ReadStatus,
+    finished: bool,
+    /// When the book was last read (ms since the Unix epoch)
+    last_read: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct DuplicateBook {
+    book_key: String,
+    title: String,
+    /// Size of the `.epub` file in bytes, 0 if it can't be read
+    file_size: u64,
+}
+
+#[derive(Serialize)]
+struct DuplicateCluster {
+    /// What the books have in common: `identifier` or `title_author`
+    matched_by: String,
+    /// The shared (normalized) identifier or title/author
+    key: String,
+    books: Vec<DuplicateBook>,
+}
+
+#[derive(Serialize)]
+struct CatalogEntry {
+    title: String,
+    authors: Vec<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    language: Option<String>,
+    publisher: Option<String>,
+    year: Option<String>,
+    tags: Vec<String>,
+    identifier: Option<String>,
+    file_path: String,
+}
+
+#[derive(Serialize)]
+struct SpineItem {
+    /// Manifest id
+    id: String,
+    /// Archive path, as returned by `get_spine`
+    href: String,
+    media_type: String,
+    /// Manifest properties, e.g. `nav`, `scripted`, `mathml`, `svg`, `remote-resources`
+    properties: Vec<String>,
+    /// Part of the linear reading order, i.e. not `linear="no"`
+    linear: bool,
+}
+
+#[derive(Serialize)]
+struct BookPart {
+    /// Label of the top-level TOC entry; `None` for the unsectioned documents
+    /// before the first part
+    part_label: Option<String>,
+    chapters: Vec<SpineItem>,
+}
+
+#[derive(Serialize)]
+struct ScriptedChapter {
+    spine_index: usize,
+    href: String,
+    /// `manifest` when the item declares the `scripted` property,
+    /// `inline-script` when the markup contains `<script>`; possibly both
+    reasons: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct UnsupportedFeature {
+    /// `media-overlays`, `scripted-content`, `encryption`,
+    /// `remote-resources` or `fixed-layout`
+    feature: String,
+    explanation: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum BookStatus {
+    Loaded {
+        /// Some spine items are missing from the manifest
+        partial: bool,
+        /// The content is DRM-protected and can't be displayed
+        drm: bool,
+        /// Why the file looks damaged, if it does; some chapters may not load
+        damage: Option<String>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+#[derive(Serialize)]
+struct BookState {
+    book_key: String,
+    #[serde(flatten)]
+    status: BookStatus,
+}
+
+#[derive(Serialize)]
+struct TextChunk {
+    index: usize,
+    text: String,
+    /// `[start, end)` offsets in characters into the chapter's extracted text
+    char_range: (usize, usize),
+}
+
+#[derive(Serialize)]
+struct FrontMatterSection {
+    /// Semantic type, e.g. `dedication`, `epigraph`, `preface` or `foreword`
+    kind: String,
+    title: Option<String>,
+    /// Archive path, with a fragment when the section starts mid-document
+    href: String,
+    spine_index: Option<usize>,
+    /// Where it was found: `landmarks`, `guide`, `content` or `title`
+    source: String,
+}
+
+#[derive(Serialize)]
+struct Readability {
+    sentences: usize,
+    words: usize,
+    syllables: usize,
+    /// Flesch Reading Ease (higher is easier, roughly 0–100); `None` without text
+    flesch_reading_ease: Option<f64>,
+    /// Flesch–Kincaid US grade level; `None` without text
+    flesch_kincaid_grade: Option<f64>,
+    /// False when there is too little text for the scores to mean much
+    reliable: bool,
+}
+
+impl From<readability::TextCounts> for Readability {
+    fn from(counts: readability::TextCounts) -> Readability {
+        Readability {
+            sentences: counts.sentences,
+            words: counts.words,
+            syllables: counts.syllables,
+            flesch_reading_ease: counts.flesch_reading_ease(),
+            flesch_kincaid_grade: counts.flesch_kincaid_grade(),
+            reliable: counts.words >= readability::MIN_RELIABLE_WORDS,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PageSide {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Serialize)]
+struct SpreadPage {
+    spine_index: usize,
+    href: String,
+    side: PageSide,
+}
+
+#[derive(Serialize)]
+struct RenditionProperties {
+    /// `rendition:layout` is `pre-paginated`
+    fixed_layout: bool,
+    /// `rendition:spread` (`auto` when not declared)
+    spread: String,
+    /// `rendition:orientation` (`auto` when not declared)
+    orientation: String,
+    pages: Vec<PageRendition>,
+}
+
+/// One spine item's rendition, with its itemref overrides applied.
+#[derive(Serialize)]
+struct PageRendition {
+    spine_index: usize,
+    href: String,
+    fixed_layout: bool,
+    /// Set when the itemref overrides the book's spread or orientation
+    spread: Option<String>,
+    orientation: Option<String>,
+    page_spread: Option<PageSide>,
+    /// Size from the page's `<meta name="viewport">`, for fixed-layout pages
+    viewport: Option<Viewport>,
+}
+
+#[derive(Serialize)]
+struct Viewport {
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct PageSpreads {
+    /// `rendition:layout` is `pre-paginated`
+    fixed_layout: bool,
+    /// `rendition:spread` (`auto` when not declared)
+    spread: String,
+    /// `ltr` or `rtl`
+    page_progression: String,
+    /// Pages shown together, in visual left-to-right order
+    spreads: Vec<Vec<SpreadPage>>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize)]
+struct ConformanceIssue {
+    severity: Severity,
+    /// Short stable identifier of the check, e.g. `mimetype-first`
+    rule: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ValidationReport {
+    /// No errors were found, so the book should load
+    valid: bool,
+    /// Declared EPUB version, e.g. `3.0`, when the package could be read
+    version: Option<String>,
+    issues: Vec<ConformanceIssue>,
+}
+
+#[derive(Serialize)]
+struct SeriesEntry {
+    book_key: String,
+    title: String,
+    series_index: Option<f64>,
+    progress: Option<Progress>,
+}
+
+#[derive(Serialize)]
+struct ChapterStyles {
+    /// Contents of each `<style>` block, in document order
+    style_blocks: Vec<String>,
+    /// Archive paths of stylesheets linked with `<link rel="stylesheet">`
+    stylesheets: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SizedResource {
+    /// Archive path
+    href: String,
+    /// Uncompressed size, or `None` if the book doesn't contain it
+    bytes: Option<u64>,
+}
+
+/// What the webview has to load before a chapter can paint.
+#[derive(Serialize)]
+struct ChapterLoad {
+    html_bytes: u64,
+    /// Linked stylesheets plus the chapter's own `<style>` blocks
+    css_bytes: u64,
+    stylesheets: Vec<SizedResource>,
+    /// The first few images in document order, as a stand-in for what's
+    /// visible on first paint
+    above_fold_images: Vec<SizedResource>,
+    above_fold_image_bytes: u64,
+    total_bytes: u64,
+}
+
+/// An image resolved for the full-screen viewer.
+#[derive(Serialize)]
+struct FullImage {
+    /// Archive path
+    href: String,
+    /// `epub://` URI of the original file
+    uri: String,
+    mime: String,
+    /// Intrinsic size in pixels; `None` for SVG and undecodable images
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct NavDocument {
+    /// Archive path
+    href: String,
+    mime_type: String,
+    data_base64: String,
+    /// `nav` for an EPUB3 navigation document, `ncx` for an EPUB2 NCX
+    kind: String,
+}
+
+#[derive(Serialize)]
+struct TocBreadcrumb {
+    /// Labels from the top-level TOC entry down to the matched entry
+    labels: Vec<String>,
+    /// Content href of the matched TOC entry
+    content: String,
+    /// False when the href has no TOC entry and the nearest preceding one was used
+    exact: bool,
+}
+
+#[derive(Serialize)]
+struct BrokenTocEntry {
+    /// Labels from the top-level TOC entry down to the broken one
+    labels: Vec<String>,
+    content: String,
+    /// `missing-resource`, `not-in-spine` or `missing-anchor`
+    reason: String,
+}
+
+#[derive(Serialize, Clone)]
+struct TocItem {
+    label: String,
+    content: String,
+    play_order: usize,
+    children: Vec<TocItem>,
+    /// Generated from the spine because the book has no TOC of its own
+    synthesized: bool,
+}
+
+#[derive(Serialize)]
+struct FlatTocItem {
+    label: String,
+    content: String,
+    play_order: usize,
+    /// 0 for top-level entries, 1 for their children, ...
+    depth: usize,
+    /// Spine document the entry points at; `None` if it isn't in the spine
+    spine_index: Option<usize>,
+    synthesized: bool,
+}
+
+#[derive(Serialize)]
+struct TocDepth {
+    /// Nesting depth of the deepest entry; 1 for a flat TOC, 0 for none
+    max_depth: usize,
+    /// Number of entries at each level, top level first
+    entries_per_level: Vec<usize>,
+}
+
+/// Runs a command's book work on a blocking-task thread, so decoding a large
+/// chapter or cover doesn't stall the thread the command was invoked on.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AppError> + Send + 'static
+) -> Result<T, AppError> {
+    tauri::async_runtime::spawn_blocking(f).await
+        .map_err(|e| AppError::Other(format!("Background task failed: {}", e)))?
+}
+
+#[derive(Serialize)]
+struct AppInfo {
+    version: String,
+    /// Books in the library, open or not
+    book_count: usize,
+    library_directory: Option<String>,
+}
+
+/// For the about dialog and diagnostics panel.
+#[tauri::command]
+fn app_info(state: tauri::State<LibraryState>, library_dir: tauri::State<LibraryDirState>) -> AppInfo {
+    AppInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        book_count: state.0.lock().keys().count(),
+        library_directory: library_dir.0.lock().as_ref().map(|dir| dir.to_string_lossy().into_owned()),
+    }
+}
+
+/// Log file in the app log directory; rotated, keeping one old file, once it
+/// reaches `LOG_MAX_FILE_SIZE`.
+const LOG_FILE_NAME: &str = "epub-reader";
+const LOG_MAX_FILE_SIZE: u128 = 5 * 1024 * 1024;
+/// Environment variable overriding the log level, e.g. `debug` or `warn`.
+const LOG_LEVEL_VAR: &str = "EPUB_READER_LOG";
+
+fn log_level() -> log::LevelFilter {
+    std::env::var(LOG_LEVEL_VAR).ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(log::LevelFilter::Info)
+}
+
+fn log_plugin() -> tauri::plugin::TauriPlugin<tauri::Wry> {
+    use tauri_plugin_log::{RotationStrategy, Target, TargetKind};
+
+    tauri_plugin_log::Builder::new()
+        .level(log_level())
+        .targets([
+            Target::new(TargetKind::Stdout),
+            Target::new(TargetKind::LogDir { file_name: Some(LOG_FILE_NAME.to_string()) }),
+        ])
+        .max_file_size(LOG_MAX_FILE_SIZE)
+        .rotation_strategy(RotationStrategy::KeepOne)
+        .build()
+}
+
+/// The current log file, for attaching to bug reports.
+#[tauri::command]
+fn get_log_path(app: tauri::AppHandle) -> Result<String, AppError> {
+    let directory = app.path().app_log_dir()
+        .map_err(|e| AppError::Io(format!("Log directory unavailable: {}", e)))?;
+    Ok(directory.join(format!("{}.log", LOG_FILE_NAME)).to_string_lossy().into_owned())
+}
+
+/// Case-insensitive comparison where runs of digits compare by value, so
+/// "Chapter 2" sorts before "Chapter 10".
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    fn digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+        std::iter::from_fn(|| chars.next_if(char::is_ascii_digit)).collect()
+    }
+
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ordering = match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (digits(&mut a), digits(&mut b));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => {
+                a.next();
+                b.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// `epub://` URI of the cover the library grid shows for a book, from its
+/// descriptor; `None` when it has none.
+fn cover_uri(book_key: &str, descriptor: &library::BookDescriptor) -> Option<String> {
+    descriptor.cover_href.as_ref().map(|cover_href| format!("epub://{}/{}", library::book_host(book_key), cover_href))
+}
+
+/// One book's grid cover URI, as `all_book_covers` gives it, for refreshing
+/// a single tile (after `add_book`, say) without listing the library.
+#[tauri::command]
+fn get_book_cover(book_key: String, state: tauri::State<LibraryState>) -> Result<Option<String>, AppError> {
+    let books = state.0.lock();
+    let descriptor = books.descriptor(&book_key).ok_or_else(|| AppError::book_not_found(&book_key))?;
+    Ok(cover_uri(&book_key, descriptor))
+}
+
+/// The library grid's books with their titles and covers, sorted by `sort`
+/// (`title`, `author` or `date-added`; default `title`) and paged with
+/// `offset`/`limit`. Books without a cover get a placeholder rather than
+/// being left out.
+#[tauri::command]
+fn all_book_covers(
+    sort: Option<String>,
+    descending: Option<bool>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    state: tauri::State<LibraryState>,
+    covers: tauri::State<CoverCacheState>,
+    recents: tauri::State<RecentsState>
+) -> Result<LibraryPage, AppError> {
+    // Read from the descriptors, so listing the library opens no books
+    let mut books: Vec<BookCover> = state.0.lock().descriptors().map(|(book_key, descriptor)| {
+        let title = descriptor.title.clone().unwrap_or(book_key.replace(".epub", ""));
+        let cover_uri = cover_uri(book_key, descriptor);
+        let placeholder = cover_uri.is_none().then(|| {
+            let colors = palette::from_title(&title);
+            PlaceholderColors { background: hex_color(colors.dark), foreground: hex_color(colors.light) }
+        });
+        BookCover {
+            key: book_key.clone(),
+            title,
+            author: descriptor.authors.first().cloned(),
+            cover_uri,
+            placeholder,
+            cover_info: None,
+        }
+    }).collect();
+
+    let by_title = |a: &BookCover, b: &BookCover| natural_cmp(&a.title, &b.title).then_with(|| a.key.cmp(&b.key));
+    match sort.as_deref().unwrap_or("title") {
+        "title" => books.sort_by(by_title),
+        // Books without an author go last
+        "author" => books.sort_by(|a, b| match (&a.author, &b.author) {
+            (Some(x), Some(y)) => natural_cmp(x, y),
+            (x, y) => x.is_none().cmp(&y.is_none()),
+        }.then_with(|| by_title(a, b))),
+        // The date the "New arrivals" shelf uses, kept across re-copies
+        "date-added" => {
+            let recents = recents.0.lock();
+            let mut keyed: Vec<(Option<u64>, BookCover)> = books.into_iter()
+                .map(|book| (recents.get(&book.key).and_then(|dates| dates.added_at), book))
+                .collect();
+            keyed.sort_by(|(a_added, a), (b_added, b)| a_added.cmp(b_added).then_with(|| by_title(a, b)));
+            books = keyed.into_iter().map(|(_, book)| book).collect();
+        }
+        other => return Err(AppError::InvalidArgument(format!("Unknown sort key: {}", other))),
+    }
+    if descending.unwrap_or(false) {
+        books.reverse();
+    }
+
+    let total = books.len();
+    let books = books.into_iter()
+        .skip(offset.unwrap_or(0))
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|book| BookCover {
+            cover_info: book.cover_uri.as_ref().and_then(|_| cached_cover_info(&state.0, &covers.0, &book.key)),
+            ..book
+        })
+        .collect();
+    Ok(LibraryPage { books, total })
+}
+
+/// Every file found by the library scan and whether it loaded, for a UI
+/// that needs a snapshot rather than the log. Sorted by book key.
+#[tauri::command]
+fn get_book_states(state: tauri::State<LibraryState>) -> Vec<BookState> {
+    let books = state.0.lock();
+    let loaded = books.descriptors().map(|(book_key, descriptor)| BookState {
+        book_key: book_key.clone(),
+        status: BookStatus::Loaded { partial: descriptor.partial, drm: descriptor.drm, damage: descriptor.damage.clone() },
+    });
+    let failed = books.failures().map(|(book_key, reason)| BookState {
+        book_key: book_key.clone(),
+        status: BookStatus::Failed { reason: reason.clone() },
+    });
+
+    let mut states: Vec<BookState> = loaded.chain(failed).collect();
+    states.sort_by(|a, b| a.book_key.cmp(&b.book_key));
+    states
+}
+
+#[derive(Serialize)]
+struct LoadError {
+    file_name: String,
+    message: String,
+}
+
+/// The library files that failed to load and why, for a "books failed to
+/// load" banner. Sorted by file name.
+#[tauri::command]
+fn get_load_errors(state: tauri::State<LibraryState>) -> Vec<LoadError> {
+    let mut errors: Vec<LoadError> = state.0.lock().failures()
+        .map(|(file_name, message)| LoadError { file_name: file_name.clone(), message: message.clone() })
+        .collect();
+    errors.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    errors
+}
+
+/// First image referenced by an HTML page (`<img src>` or SVG `<image href>`).
+fn first_image_src(page: &str) -> Option<String> {
+    html::tokenize(page).find_map(|(token, _)| match token {
+        html::Token::StartTag(tag) if tag.is("img") => tag.attr("src").map(str::to_string),
+        html::Token::StartTag(tag) if tag.is("image") => tag.attr("xlink:href")
+            .or_else(|| tag.attr("href"))
+            .map(str::to_string),
+        _ => None,
+    })
+}
+
+/// Archive path of the book's cover image. HTML cover pages are followed to
+/// the image they display; books without cover metadata fall back to an image
+/// resource whose path mentions "cover".
+fn cover_image_path(book: &mut EpubDoc<BufReader<File>>) -> Option<String> {
+    if let Ok(cover_id) = book.get_cover_id() {
+        if let Some((path, mime)) = book.resources.get(&cover_id).cloned() {
+            let cover_path = path.to_str().unwrap_or("").to_string();
+            if mime.starts_with("image/") {
+                return Some(cover_path);
+            }
+
+            if let Some(src) = book.get_resource_str_by_path(&path).ok().and_then(|page| first_image_src(&page)) {
+                let (image_path, _) = resolve_href(&cover_path, &src);
+                let is_image = book.get_resource_mime_by_path(&image_path)
+                    .map(|mime| mime.starts_with("image/"))
+                    .unwrap_or(false);
+                if is_image {
+                    return Some(image_path);
+                }
+            }
+        }
+    }
+
+    book.resources.values()
+        .find(|(path, mime)| {
+            mime.starts_with("image/") && path.to_string_lossy().to_lowercase().contains("cover")
+        })
+        .map(|(path, _)| path.to_string_lossy().into_owned())
+}
+
+/// Decodes an image and scales it to fit within `max_dimension`, as PNG.
+fn make_thumbnail(bytes: &[u8], max_dimension: u32) -> Result<Vec<u8>, AppError> {
+    let image = image::load_from_memory(bytes).map_err(|e| AppError::InvalidEpub(format!("Failed to decode cover: {}", e)))?;
+    let image = if image.width() > max_dimension || image.height() > max_dimension {
+        image.thumbnail(max_dimension, max_dimension)
+    } else {
+        image
+    };
+
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|e| AppError::Other(format!("Failed to encode thumbnail: {}", e)))?;
+    Ok(png.into_inner())
+}
+
+/// Subdirectory of the app cache directory holding cover thumbnails.
+const COVER_CACHE_DIR: &str = "covers";
+
+/// Where a book's cover file `suffix` is cached on disk:
+/// `<identity>-<modified>-<suffix>`, where the identity is a hash of the
+/// book's identifier (else its key) and `modified` the file's modification
+/// time. A changed file gets a new name, so a stale thumbnail is never read
+/// back. `None` if the file's time can't be read.
+fn disk_cover_path(directory: &std::path::Path, books: &Library, book_key: &str, suffix: &str) -> Option<PathBuf> {
+    let identity = books.descriptor(book_key)?.identifier.clone().unwrap_or_else(|| book_key.to_string());
+    let identity = library::fnv1a(identity.trim().bytes());
+    let modified = std::fs::metadata(books.path(book_key)?).and_then(|metadata| metadata.modified()).ok()?;
+    let modified = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_nanos();
+    Some(directory.join(format!("{:016x}-{:x}-{}", identity, modified, suffix)))
+}
+
+/// The disk cache path of a book's thumbnail at `max_dimension`.
+fn disk_thumbnail_path(directory: &std::path::Path, books: &Library, book_key: &str, max_dimension: u32) -> Option<PathBuf> {
+    disk_cover_path(directory, books, book_key, &format!("{}.png", max_dimension))
+}
+
+/// Saves a file to the cover disk cache, first deleting the book's files
+/// made from an older version of it. Failures only cost a regeneration next
+/// time, so they're ignored.
+fn store_disk_cover_file(path: &std::path::Path, contents: &[u8]) {
+    let (Some(directory), Some(file_name)) = (path.parent(), path.file_name().and_then(|name| name.to_str())) else { return };
+    let mut parts = file_name.splitn(3, '-');
+    let (Some(identity), Some(modified)) = (parts.next(), parts.next()) else { return };
+    if std::fs::create_dir_all(directory).is_err() {
+        return;
+    }
+
+    if let Ok(entries) = directory.read_dir() {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let mut parts = name.splitn(3, '-');
+            if parts.next() == Some(identity) && parts.next() != Some(modified) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+    let temp_path = path.with_file_name(format!("{}.tmp", file_name));
+    if std::fs::write(&temp_path, contents).is_ok() {
+        let _ = std::fs::rename(&temp_path, path);
+    }
+}
+
+/// Cached cover thumbnail for a book, from memory, else from the disk cache,
+/// else generated. The book is only locked while the cover bytes are read;
+/// decoding happens outside.
+fn cover_thumbnail(
+    books: &Mutex<Library>,
+    covers: &CoverCache,
+    book_key: &str,
+    max_dimension: u32,
+) -> Result<Arc<Vec<u8>>, AppError> {
+    let cache_key = (book_key.to_string(), max_dimension);
+    if let Some(thumbnail) = covers.thumbnails.lock().get(&cache_key) {
+        return Ok(thumbnail.clone());
+    }
+
+    let disk_directory = covers.disk_directory.lock().clone();
+    let disk_path = disk_directory.and_then(|directory| {
+        disk_thumbnail_path(&directory, &books.lock(), book_key, max_dimension)
+    });
+    if let Some(thumbnail) = disk_path.as_deref().and_then(|path| std::fs::read(path).ok()) {
+        let thumbnail = Arc::new(thumbnail);
+        covers.thumbnails.lock().insert(cache_key, thumbnail.clone());
+        return Ok(thumbnail);
+    }
+
+    let cover = read_cover(books, book_key)?;
+    let thumbnail = Arc::new(make_thumbnail(&cover, max_dimension)?);
+    if let Some(path) = &disk_path {
+        store_disk_cover_file(path, &thumbnail);
+    }
+    covers.thumbnails.lock().insert(cache_key, thumbnail.clone());
+    // The full cover is at hand, so measure it now too
+    if cached_cover_info(books, covers, book_key).is_none() {
+        measure_cover(books, covers, book_key, &cover);
+    }
+    Ok(thumbnail)
+}
+
+/// The bytes of a book's cover image.
+fn read_cover(books: &Mutex<Library>, book_key: &str) -> Result<Vec<u8>, AppError> {
+    let book = book_handle(books, book_key)?;
+    let book = &mut *book.lock();
+    let path = cover_image_path(book).ok_or_else(|| AppError::ResourceNotFound(format!("cover image of {}", book_key)))?;
+    book.get_resource_by_path(&path).map_err(|e| AppError::InvalidEpub(format!("Failed to read cover: {}", e)))
+}
+
+/// Disk cache file holding a book's `CoverInfo`.
+const COVER_INFO_SUFFIX: &str = "info.json";
+
+/// A book's cover size and color if already measured, from memory, else
+/// from the disk cache. Never opens the book.
+fn cached_cover_info(books: &Mutex<Library>, covers: &CoverCache, book_key: &str) -> Option<CoverInfo> {
+    if let Some(info) = covers.info.lock().get(book_key) {
+        return Some(info.clone());
+    }
+
+    let directory = covers.disk_directory.lock().clone()?;
+    let path = disk_cover_path(&directory, &books.lock(), book_key, COVER_INFO_SUFFIX)?;
+    let info: CoverInfo = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    covers.info.lock().insert(book_key.to_string(), info.clone());
+    Some(info)
+}
+
+/// Measures a book's cover: its size from the image header and its dominant
+/// color from a downscaled sample. Cached in memory and on disk. `None` if
+/// the image can't be decoded, as with SVG covers.
+fn measure_cover(books: &Mutex<Library>, covers: &CoverCache, book_key: &str, cover: &[u8]) -> Option<CoverInfo> {
+    let (width, height) = image::ImageReader::new(std::io::Cursor::new(cover))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    let colors = palette::from_image(cover)?;
+    let info = CoverInfo { width, height, color: hex_color(colors.dominant) };
+
+    let disk_directory = covers.disk_directory.lock().clone();
+    let disk_path = disk_directory.and_then(|directory| {
+        disk_cover_path(&directory, &books.lock(), book_key, COVER_INFO_SUFFIX)
+    });
+    if let (Some(path), Ok(json)) = (disk_path, serde_json::to_vec(&info)) {
+        store_disk_cover_file(&path, &json);
+    }
+    covers.info.lock().insert(book_key.to_string(), info.clone());
+    Some(info)
+}
+
+/// Drops every cached cover thumbnail, in memory and on disk, so they're
+/// generated afresh.
+#[tauri::command]
+fn clear_cover_cache(covers: tauri::State<CoverCacheState>) -> Result<(), AppError> {
+    covers.0.thumbnails.lock().clear();
+    covers.0.info.lock().clear();
+    *covers.0.sprite.lock() = None;
+    let Some(directory) = covers.0.disk_directory.lock().clone() else { return Ok(()) };
+    match std::fs::remove_dir_all(&directory) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(AppError::Io(format!("Failed to remove {}: {}", directory.display(), e)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_max_dimension(max_dimension: u32) -> Result<(), AppError> {
+    if (16..=4096).contains(&max_dimension) {
+        Ok(())
+    } else {
+        Err(AppError::InvalidArgument(format!("Thumbnail size must be between 16 and 4096 pixels, got {}", max_dimension)))
+    }
+}
+
+fn hex_color(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Dominant, vibrant, muted, light and dark colors of a book's cover for
+/// theming the reader around it. Books without a usable cover get a palette
+/// derived from their title, so it's stable across launches. Cached per book.
+#[tauri::command]
+fn get_cover_palette(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    palettes: tauri::State<CoverPaletteState>
+) -> Result<CoverPalette, AppError> {
+    if let Some(palette) = palettes.0.lock().get(&book_key) {
+        return Ok(palette.clone());
+    }
+
+    let (cover, title) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let cover = cover_image_path(book).and_then(|path| book.get_resource_by_path(&path).ok());
+        (cover, book.mdata("title").unwrap_or(book_key.replace(".epub", "")))
+    };
+
+    // Decoding happens outside the library lock
+    let (colors, source) = match cover.as_deref().and_then(palette::from_image) {
+        Some(colors) => (colors, "cover"),
+        None => (palette::from_title(&title), "title"),
+    };
+    let palette = CoverPalette {
+        dominant: hex_color(colors.dominant),
+        vibrant: hex_color(colors.vibrant),
+        muted: hex_color(colors.muted),
+        light: hex_color(colors.light),
+        dark: hex_color(colors.dark),
+        source: source.to_string(),
+    };
+
+    palettes.0.lock().insert(book_key, palette.clone());
+    Ok(palette)
+}
+
+/// Returns a book's cover thumbnail as a `data:image/png;base64,...` URI.
+#[tauri::command]
+fn get_cover_thumbnail(
+    book_key: String,
+    max_dimension: u32,
+    state: tauri::State<LibraryState>,
+    covers: tauri::State<CoverCacheState>
+) -> Result<String, AppError> {
+    validate_max_dimension(max_dimension)?;
+    let thumbnail = cover_thumbnail(&state.0, &covers.0, &book_key, max_dimension)?;
+    Ok(format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(thumbnail.as_slice())))
+}
+
+/// A book's cover as image bytes, never an HTML cover page: pages are
+/// followed to the image they show. With `max_dim` the cover is scaled to fit
+/// (as PNG, cached like thumbnails); without it the original file is returned.
+#[tauri::command]
+async fn get_cover_image(
+    book_key: String,
+    max_dim: Option<u32>,
+    state: tauri::State<'_, LibraryState>,
+    covers: tauri::State<'_, CoverCacheState>
+) -> Result<CoverImage, AppError> {
+    let books = Arc::clone(&state.0);
+    let covers = Arc::clone(&covers.0);
+    run_blocking(move || {
+        let (mime_type, bytes) = match max_dim {
+            Some(max_dimension) => {
+                validate_max_dimension(max_dimension)?;
+                let thumbnail = cover_thumbnail(&books, &covers, &book_key, max_dimension)?;
+                ("image/png".to_string(), thumbnail.to_vec())
+            }
+            None => {
+                let book_handle = book_handle(&books, &book_key)?;
+                let book = &mut *book_handle.lock();
+                let path = cover_image_path(book).ok_or_else(|| AppError::ResourceNotFound(format!("cover image of {}", book_key)))?;
+                let mime = book.get_resource_mime_by_path(&path).map_err(|_| AppError::resource_not_found(&path))?;
+                let bytes = book.get_resource_by_path(&path).map_err(|e| AppError::InvalidEpub(format!("Failed to read cover: {}", e)))?;
+                (mime, bytes)
+            }
+        };
+
+        let dimensions = image::ImageReader::new(std::io::Cursor::new(&bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        // Measured once per book; without `max_dim` the full cover is at hand
+        let info = cached_cover_info(&books, &covers, &book_key).or_else(|| match max_dim {
+            Some(_) => read_cover(&books, &book_key).ok()
+                .and_then(|cover| measure_cover(&books, &covers, &book_key, &cover)),
+            None => measure_cover(&books, &covers, &book_key, &bytes),
+        });
+        Ok(CoverImage {
+            mime_type,
+            data_base64: base64::engine::general_purpose::STANDARD.encode(&bytes),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            color: info.map(|info| info.color),
+        })
+    }).await
+}
+
+/// The cover of an EPUB file that isn't in the library, for previewing an
+/// import. The file is opened just for this and closed again; nothing is
+/// cached. `max_dim` scales the cover as for `get_cover_image`.
+#[tauri::command]
+async fn peek_cover(path: String, max_dim: Option<u32>) -> Result<CoverImage, AppError> {
+    if let Some(max_dimension) = max_dim {
+        validate_max_dimension(max_dimension)?;
+    }
+    run_blocking(move || {
+        let (mime, cover) = {
+            let mut book = open_import(std::path::Path::new(&path))?;
+            let cover_path = cover_image_path(&mut book).ok_or_else(|| AppError::ResourceNotFound(format!("cover image of {}", path)))?;
+            let mime = book.get_resource_mime_by_path(&cover_path).map_err(|_| AppError::resource_not_found(&cover_path))?;
+            let cover = book.get_resource_by_path(&cover_path).map_err(|e| AppError::InvalidEpub(format!("Failed to read cover: {}", e)))?;
+            (mime, cover)
+        };
+
+        let (mime_type, bytes) = match max_dim {
+            Some(max_dimension) => ("image/png".to_string(), make_thumbnail(&cover, max_dimension)?),
+            None => (mime, cover.clone()),
+        };
+        let dimensions = image::ImageReader::new(std::io::Cursor::new(&bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        Ok(CoverImage {
+            mime_type,
+            data_base64: base64::engine::general_purpose::STANDARD.encode(&bytes),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            color: palette::from_image(&cover).map(|colors| hex_color(colors.dominant)),
+        })
+    }).await
+}
+
+/// Longest side of a cover sprite sheet, in pixels.
+const MAX_SPRITE_SIDE: u64 = 16384;
+
+/// Most pixels in a cover sprite sheet: 256 MiB as RGBA.
+const MAX_SPRITE_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Width and height of a sprite sheet of `count` `max_dimension`-square
+/// cells, `columns` to a row.
+fn sprite_size(count: usize, columns: u32, max_dimension: u32) -> (u64, u64) {
+    let count = count.max(1) as u64;
+    let columns = u64::from(columns);
+    (columns.min(count) * u64::from(max_dimension), count.div_ceil(columns) * u64::from(max_dimension))
+}
+
+/// Every book's cover thumbnail packed into one sprite sheet, so a large
+/// library grid loads a single image. Covers sit top-left in
+/// `max_dimension`-square cells, `columns` to a row, in book key order. The
+/// sheet is rebuilt once books are added, removed or changed.
+#[tauri::command]
+async fn generate_cover_sprite(
+    max_dimension: u32,
+    columns: u32,
+    state: tauri::State<'_, LibraryState>,
+    covers: tauri::State<'_, CoverCacheState>
+) -> Result<CoverSprite, AppError> {
+    validate_max_dimension(max_dimension)?;
+    if !(1..=64).contains(&columns) {
+        return Err(AppError::InvalidArgument(format!("Sprite columns must be between 1 and 64, got {}", columns)));
+    }
+
+    let books = Arc::clone(&state.0);
+    let covers = Arc::clone(&covers.0);
+    run_blocking(move || {
+        let mut book_keys: Vec<String> = books.lock().keys().cloned().collect();
+        book_keys.sort();
+        // Checked for every book having a cover, before any are read
+        let (width, height) = sprite_size(book_keys.len(), columns, max_dimension);
+        if width > MAX_SPRITE_SIDE || height > MAX_SPRITE_SIDE || width * height > MAX_SPRITE_PIXELS {
+            return Err(AppError::InvalidArgument(format!(
+                "A {}x{} sprite sheet is too large; use fewer columns or a smaller max_dimension",
+                width, height
+            )));
+        }
+        if let Some(cached) = covers.sprite.lock().as_ref() {
+            if cached.max_dimension == max_dimension && cached.columns == columns && cached.book_keys == book_keys {
+                return Ok(cached.sprite.clone());
+            }
+        }
+
+        let thumbnails: Vec<(&String, image::RgbaImage)> = book_keys.iter()
+            .filter_map(|book_key| {
+                let thumbnail = cover_thumbnail(&books, &covers, book_key, max_dimension).ok()?;
+                let image = image::load_from_memory(&thumbnail).ok()?.to_rgba8();
+                Some((book_key, image))
+            })
+            .collect();
+
+        let (width, height) = sprite_size(thumbnails.len(), columns, max_dimension);
+        let (width, height) = (width as u32, height as u32);
+        let mut sheet = image::RgbaImage::new(width, height);
+        let mut rects = HashMap::new();
+        for (index, (book_key, thumbnail)) in thumbnails.into_iter().enumerate() {
+            let x = index as u32 % columns * max_dimension;
+            let y = index as u32 / columns * max_dimension;
+            image::imageops::overlay(&mut sheet, &thumbnail, x as i64, y as i64);
+            rects.insert(book_key.clone(), SpriteRect { x, y, width: thumbnail.width(), height: thumbnail.height() });
+        }
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        sheet.write_to(&mut png, image::ImageFormat::Png)
+            .map_err(|e| AppError::Other(format!("Failed to encode sprite sheet: {}", e)))?;
+        let sprite = CoverSprite {
+            image: format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(png.into_inner())),
+            width,
+            height,
+            covers: rects,
+        };
+
+        *covers.sprite.lock() = Some(CachedSprite { max_dimension, columns, book_keys, sprite: sprite.clone() });
+        Ok(sprite)
+    }).await
+}
+
+/// Generates cover thumbnails for every book in the background, emitting
+/// `cover-ready { key, max_dimension }` as each one lands in the cache.
+/// Books already cached at this size are skipped. Returns the number of
+/// books queued. Starting a new prewarm cancels any previous one.
+#[tauri::command]
+fn prewarm_covers(
+    max_dimension: u32,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    covers: tauri::State<CoverCacheState>
+) -> Result<usize, AppError> {
+    validate_max_dimension(max_dimension)?;
+
+    let pending: Vec<String> = {
+        let books = state.0.lock();
+        let thumbnails = covers.0.thumbnails.lock();
+        books.keys()
+            .filter(|key| !thumbnails.contains_key(&((*key).clone(), max_dimension)))
+            .cloned()
+            .collect()
+    };
+    let queued = pending.len();
+
+    let generation = covers.0.prewarm_generation.fetch_add(1, Ordering::SeqCst) + 1;
+    let queue = Arc::new(Mutex::new(pending));
+    let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2).min(4);
+
+    for _ in 0..workers {
+        let queue = Arc::clone(&queue);
+        let books = Arc::clone(&state.0);
+        let covers = Arc::clone(&covers.0);
+        let app = app.clone();
+
+        std::thread::spawn(move || loop {
+            if covers.prewarm_generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            let Some(book_key) = queue.lock().pop() else {
+                return;
+            };
+            match cover_thumbnail(&books, &covers, &book_key, max_dimension) {
+                Ok(_) => {
+                    let _ = app.emit("cover-ready", CoverReady { key: book_key, max_dimension });
+                }
+                Err(e) => log::warn!("Cover prewarm skipped {}: {}", book_key, e),
+            }
+        });
+    }
+
+    Ok(queued)
+}
+
+/// Stops any running cover prewarm; thumbnails already generated stay cached.
+#[tauri::command]
+fn cancel_cover_prewarm(covers: tauri::State<CoverCacheState>) {
+    covers.0.prewarm_generation.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Byte offsets of case-insensitive occurrences of `query_lower` in `text`.
+fn find_matches(text: &str, query_lower: &str) -> Vec<usize> {
+    let mut matches = Vec::new();
+    let mut skip_until = 0;
+    for (start, _) in text.char_indices() {
+        if start < skip_until {
+            continue;
+        }
+        let mut haystack = text[start..].chars().flat_map(char::to_lowercase);
+        if query_lower.chars().all(|q| haystack.next() == Some(q)) {
+            matches.push(start);
+            skip_until = start + query_lower.len();
+        }
+    }
+    matches
+}
+
+/// `text` around the byte offset `at`, trimmed to whole words where possible.
+fn snippet_around(text: &str, at: usize, match_len: usize) -> String {
+    let before: String = text[..at].chars().rev().take(SNIPPET_CONTEXT_CHARS).collect::<Vec<_>>().into_iter().rev().collect();
+    let end = (at + match_len).min(text.len());
+    let end = (end..=text.len()).find(|i| text.is_char_boundary(*i)).unwrap_or(text.len());
+    let after: String = text[end..].chars().take(SNIPPET_CONTEXT_CHARS).collect();
+
+    let lead = if before.len() < at { "…" } else { "" };
+    let trail = if end + after.len() < text.len() { "…" } else { "" };
+    let snippet = format!("{}{}{}", before, &text[at..end], after);
+    format!("{}{}{}", lead, snippet.split_whitespace().collect::<Vec<_>>().join(" "), trail)
+}
+
+/// Full-text (and title/author) search of one book. `None` when nothing matches.
+fn search_library_book(book_key: &str, book: &mut EpubDoc<BufReader<File>>, query_lower: &str) -> Option<BookSearchResult> {
+    let title = book.mdata("title").unwrap_or(book_key.replace(".epub", ""));
+    let metadata_match = title.to_lowercase().contains(query_lower)
+        || library::metadata_values(book, "creator").iter().any(|a| a.to_lowercase().contains(query_lower));
+
+    let spine_paths: Vec<(usize, PathBuf, String)> = book.spine.iter()
+        .enumerate()
+        .filter_map(|(spine_index, id)| book.resources.get(id).map(|(path, mime)| (spine_index, path.clone(), mime.clone())))
+        .collect();
+
+    let mut hits = Vec::new();
+    for (spine_index, path, mime) in spine_paths {
+        if hits.len() >= MAX_SEARCH_HITS_PER_BOOK {
+            break;
+        }
+        if !is_html_mime(&mime) {
+            continue;
+        }
+        let Ok(bytes) = book.get_resource_by_path(&path) else { continue };
+        let text = html::extract_text(&html::decode_document(&bytes));
+        let href = path.to_str().unwrap_or("").to_string();
+        for at in find_matches(&text, query_lower).into_iter().take(MAX_SEARCH_HITS_PER_BOOK - hits.len()) {
+            hits.push(SearchHit {
+                spine_index,
+                href: href.clone(),
+                snippet: snippet_around(&text, at, query_lower.len()),
+                offset: text[..at].chars().count(),
+            });
+        }
+    }
+
+    if hits.is_empty() && !metadata_match {
+        return None;
+    }
+    Some(BookSearchResult { book_key: book_key.to_string(), title, metadata_match, hits })
+}
+
+fn normalized_query(query: &str) -> Result<String, AppError> {
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        Err(AppError::InvalidArgument("Search query is empty".to_string()))
+    } else {
+        Ok(query)
+    }
+}
+
+/// Finds `query` in one book's chapters, case-insensitively unless
+/// `case_sensitive` is set. Chapters are searched as extracted text, so a
+/// phrase split by inline markup (`<em>`, `<a>`, ...) still matches. At most
+/// `MAX_SEARCH_HITS_PER_CHAPTER` hits are returned per chapter, with the
+/// total number of matches alongside.
+#[tauri::command]
+async fn search_book(
+    book_key: String,
+    query: String,
+    case_sensitive: Option<bool>,
+    state: tauri::State<'_, LibraryState>
+) -> Result<BookSearchHits, AppError> {
+    let case_sensitive = case_sensitive.unwrap_or(false);
+    let query = if case_sensitive { query.trim().to_string() } else { normalized_query(&query)? };
+    if query.is_empty() {
+        return Err(AppError::InvalidArgument("Search query is empty".to_string()));
+    }
+
+    let books = Arc::clone(&state.0);
+    run_blocking(move || {
+        let book_handle = book_handle(&books, &book_key)?;
+        let book = &mut *book_handle.lock();
+        let spine_paths: Vec<(usize, PathBuf, String)> = book.spine.iter()
+            .enumerate()
+            .filter_map(|(spine_index, id)| book.resources.get(id).map(|(path, mime)| (spine_index, path.clone(), mime.clone())))
+            .collect();
+
+        let mut hits = Vec::new();
+        let mut total = 0;
+        for (spine_index, path, mime) in spine_paths {
+            if !is_html_mime(&mime) {
+                continue;
+            }
+            let Ok(bytes) = book.get_resource_by_path(&path) else { continue };
+            let text = html::extract_text(&html::decode_document(&bytes));
+            let matches: Vec<usize> = if case_sensitive {
+                text.match_indices(query.as_str()).map(|(at, _)| at).collect()
+            } else {
+                find_matches(&text, &query)
+            };
+            total += matches.len();
+
+            let href = path.to_str().unwrap_or("").to_string();
+            for at in matches.into_iter().take(MAX_SEARCH_HITS_PER_CHAPTER) {
+                hits.push(SearchHit {
+                    spine_index,
+                    href: href.clone(),
+                    snippet: snippet_around(&text, at, query.len()),
+                    offset: text[..at].chars().count(),
+                });
+            }
+        }
+
+        Ok(BookSearchHits { hits, total })
+    }).await
+}
+
+/// Searches every book's text, title and authors, returning once the whole
+/// library has been searched.
+#[tauri::command]
+async fn search_library(query: String, state: tauri::State<'_, LibraryState>) -> Result<Vec<BookSearchResult>, AppError> {
+    let query = normalized_query(&query)?;
+    let books = Arc::clone(&state.0);
+    run_blocking(move || {
+        let mut results: Vec<BookSearchResult> = Library::filter_map_books(&books, |_| true, |book_key, book| search_library_book(book_key, book, &query));
+        results.sort_by(|a, b| a.book_key.cmp(&b.book_key));
+        Ok(results)
+    }).await
+}
+
+/// Like `search_library`, but searches in the background and emits a
+/// `search-result` event per matching book as it's found, then
+/// `search-complete`. Starting a new search cancels the previous one.
+/// Returns the id carried by this search's events.
+#[tauri::command]
+fn search_library_streaming(
+    query: String,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    search: tauri::State<SearchState>
+) -> Result<u64, AppError> {
+    let query = normalized_query(&query)?;
+    let mut book_keys: Vec<String> = state.0.lock().keys().cloned().collect();
+    book_keys.sort();
+
+    let search_id = search.0.fetch_add(1, Ordering::SeqCst) + 1;
+    let generation = Arc::clone(&search.0);
+    let books = Arc::clone(&state.0);
+
+    std::thread::spawn(move || {
+        let mut matched_books = 0;
+        for book_key in book_keys {
+            if generation.load(Ordering::SeqCst) != search_id {
+                let _ = app.emit("search-complete", SearchComplete { search_id, cancelled: true, matched_books });
+                return;
+            }
+            // Lock per book so other commands aren't starved during long searches
+            let result = Library::scan_handle(&books, &book_key)
+                .and_then(|book| search_library_book(&book_key, &mut book.lock(), &query));
+            if let Some(result) = result {
+                matched_books += 1;
+                let _ = app.emit("search-result", SearchResultEvent { search_id, result });
+            }
+        }
+        let _ = app.emit("search-complete", SearchComplete { search_id, cancelled: false, matched_books });
+    });
+
+    Ok(search_id)
+}
+
+/// Aborts the in-flight streaming search, if any.
+#[tauri::command]
+fn cancel_search(search: tauri::State<SearchState>) {
+    search.0.fetch_add(1, Ordering::SeqCst);
+}
+
+/// A "currently reading" image for sharing: the cover with the title and
+/// author in a band along the bottom, or a text-only card when the book has
+/// no cover. Returned as a PNG data URI.
+#[tauri::command]
+fn render_share_cover(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    let (cover, title, author) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let cover = cover_image_path(book).and_then(|path| book.get_resource_by_path(&path).ok());
+        let title = book.mdata("title").unwrap_or(book_key.replace(".epub", ""));
+        let author = book.metadata.get("creator").map(|creators| creators.join(", ")).unwrap_or_default();
+        (cover, title, author)
+    };
+
+    let png = share_card::render(cover.as_deref(), &title, &author).map_err(AppError::Other)?;
+    Ok(format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(png)))
+}
+
+/// Shorter lines (headings, epigraph credits, copyright notices) aren't the
+/// opening paragraph a text cover shows.
+const MIN_EXCERPT_WORDS: usize = 12;
+
+/// A cover for books without one, for library tiles: the title and author on
+/// the title-derived palette (as `get_cover_palette` gives coverless books)
+/// over the opening paragraph of the first chapter with prose. Returned as a
+/// PNG data URI.
+#[tauri::command]
+fn render_text_cover(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    let (title, author, excerpt) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let title = book.mdata("title").unwrap_or(book_key.replace(".epub", ""));
+        let author = book.metadata.get("creator").map(|creators| creators.join(", ")).unwrap_or_default();
+
+        let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+            .filter_map(|id| book.resources.get(id).cloned())
+            .collect();
+        let excerpt = spine_paths.iter()
+            .filter(|(_, mime)| is_html_mime(mime))
+            .filter_map(|(path, _)| book.get_resource_by_path(path).ok())
+            .find_map(|bytes| {
+                html::extract_text(&html::decode_document(&bytes))
+                    .lines()
+                    .find(|line| line.split_whitespace().count() >= MIN_EXCERPT_WORDS)
+                    .map(str::to_string)
+            })
+            .unwrap_or_default();
+        (title, author, excerpt)
+    };
+
+    let colors = palette::from_title(&title);
+    let png = share_card::render_text_cover(&title, &author, &excerpt, colors.light, colors.dark).map_err(AppError::Other)?;
+    Ok(format!("data:image/png;base64,{}", base64::engine::general_purpose::STANDARD.encode(png)))
+}
+
+#[tauri::command]
+fn get_book_title(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    let books = state.0.lock();
+
+    if let Some(descriptor) = books.descriptor(&book_key) {
+        let title = descriptor.title.clone().unwrap_or(book_key.replace(".epub", ""));
+        Ok(title)
+    } else {
+        Err(AppError::book_not_found(&book_key))
+    }
+}
+
+#[tauri::command]
+async fn get_book_toc(book_key: String, state: tauri::State<'_, LibraryState>) -> Result<Vec<TocItem>, AppError> {
+    let books = Arc::clone(&state.0);
+    run_blocking(move || {
+        let book_handle = book_handle(&books, &book_key)?;
+        let book = &mut *book_handle.lock();
+        Ok(book_toc(book))
+    }).await
+}
+
+// Convert NavPoint to TocItem recursively
+fn convert_nav_points(nav_points: &[epub::doc::NavPoint]) -> Vec<TocItem> {
+    nav_points.iter().map(|nav_point| {
+        TocItem {
+            label: nav_point.label.clone(),
+            content: nav_point.content.to_str().unwrap_or("").to_string(),
+            play_order: nav_point.play_order,
+            children: convert_nav_points(&nav_point.children),
+            synthesized: false,
+        }
+    }).collect()
+}
+
+/// The book's TOC, or for books without an NCX or nav document one entry
+/// per spine document, labelled with its first `<h1>`/`<h2>`, else its
+/// `<title>`, else "Chapter N". Headings come first because many books
+/// repeat the book's title in every document's `<title>`.
+fn book_toc(book: &mut EpubDoc<BufReader<File>>) -> Vec<TocItem> {
+    if !book.toc.is_empty() {
+        return convert_nav_points(&book.toc);
+    }
+
+    let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+        .filter_map(|id| book.resources.get(id).cloned())
+        .collect();
+    spine_paths.into_iter()
+        .filter(|(_, mime)| is_html_mime(mime))
+        .enumerate()
+        .map(|(index, (path, _))| {
+            let label = book.get_resource_by_path(&path).ok()
+                .and_then(|bytes| {
+                    let markup = html::decode_document(&bytes);
+                    html::first_heading(&markup).or_else(|| html::document_title(&markup))
+                })
+                .unwrap_or_else(|| format!("Chapter {}", index + 1));
+            TocItem {
+                label,
+                content: path.to_str().unwrap_or("").to_string(),
+                play_order: index + 1,
+                children: Vec::new(),
+                synthesized: true,
+            }
+        })
+        .collect()
+}
+
+/// Path of the navigation page the `epub://` handler generates, as in
+/// `epub://<host>/__nav`. A resource of the book's own at this path wins.
+const NAV_DOCUMENT_PATH: &str = "__nav";
+
+/// A navigation page the reader controls, served at `NAV_DOCUMENT_PATH` and
+/// injected like any chapter: the TOC from `get_book_toc` as nested lists,
+/// then the nav document's landmarks (or the EPUB2 guide). Links are
+/// absolute archive paths resolved as `resolve_link` does, so they work
+/// from the page's own URL and with `links=route`.
+fn nav_document(book: &mut library::Book) -> String {
+    fn link(path: &str, fragment: Option<String>, label: &str) -> String {
+        let fragment = fragment.filter(|fragment| !fragment.is_empty()).map(|fragment| format!("#{}", fragment)).unwrap_or_default();
+        format!("<a href=\"/{}{}\">{}</a>", html::escape_attr(path), html::escape_attr(&fragment), html::escape_attr(label.trim()))
+    }
+    fn toc_list(items: &[TocItem], out: &mut String) {
+        out.push_str("<ol>\n");
+        for item in items {
+            let (path, fragment) = resolve_href("", &item.content);
+            out.push_str("<li>");
+            out.push_str(&link(&path, fragment, &item.label));
+            if !item.children.is_empty() {
+                out.push('\n');
+                toc_list(&item.children, out);
+            }
+            out.push_str("</li>\n");
+        }
+        out.push_str("</ol>\n");
+    }
+
+    let title = book.mdata("title").unwrap_or_default();
+    let toc = book_toc(book);
+    let mut body = String::from("<nav epub:type=\"toc\" id=\"toc\">\n<h1>Contents</h1>\n");
+    toc_list(&toc, &mut body);
+    body.push_str("</nav>\n");
+
+    let root_file = book.root_file.to_str().unwrap_or("").to_string();
+    let package = read_package(book).ok();
+    let nav_path = package.as_ref()
+        .and_then(|package| package.manifest.iter().find(|item| item.has_property("nav")))
+        .map(|nav| resolve_href(&root_file, &nav.href).0);
+    let mut landmarks: Vec<String> = nav_path
+        .and_then(|nav_path| read_document(book, &nav_path).ok())
+        .map(|(nav_path, markup)| {
+            nav_links(&markup, "landmarks").into_iter()
+                .map(|nav_link| {
+                    let (path, fragment) = resolve_href(&nav_path, &nav_link.href);
+                    link(&path, fragment, &nav_link.label)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if landmarks.is_empty() {
+        landmarks = package.iter()
+            .flat_map(|package| &package.guide)
+            .map(|reference| {
+                let (path, fragment) = resolve_href(&root_file, &reference.href);
+                link(&path, fragment, reference.title.as_deref().unwrap_or(&reference.kind))
+            })
+            .collect();
+    }
+    if !landmarks.is_empty() {
+        body.push_str("<nav epub:type=\"landmarks\" id=\"landmarks\">\n<h2>Guide</h2>\n<ol>\n");
+        for landmark in landmarks {
+            body.push_str(&format!("<li>{}</li>\n", landmark));
+        }
+        body.push_str("</ol>\n</nav>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+        html::escape_attr(&title),
+        body,
+    )
+}
+
+/// The same entries as `get_book_toc` in document order, each tagged with
+/// its depth and spine position, for UIs showing a flat numbered list.
+#[tauri::command]
+fn get_book_toc_flat(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<FlatTocItem>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    fn flatten(items: Vec<TocItem>, depth: usize, book: &EpubDoc<BufReader<File>>, out: &mut Vec<FlatTocItem>) {
+        for item in items {
+            out.push(FlatTocItem {
+                spine_index: spine_index_for_path(book, &resolve_href("", &item.content).0),
+                label: item.label,
+                content: item.content,
+                play_order: item.play_order,
+                depth,
+                synthesized: item.synthesized,
+            });
+            flatten(item.children, depth + 1, book, out);
+        }
+    }
+
+    let mut entries = Vec::new();
+    flatten(book_toc(book), 0, book, &mut entries);
+    Ok(entries)
+}
+
+/// Flattens the TOC in document order, pairing each entry's content href
+/// with the chain of labels leading to it.
+fn collect_toc_chains(
+    items: &[TocItem],
+    ancestors: &mut Vec<String>,
+    out: &mut Vec<(Vec<String>, String)>,
+) {
+    for item in items {
+        ancestors.push(item.label.clone());
+        out.push((ancestors.clone(), item.content.clone()));
+        collect_toc_chains(&item.children, ancestors, out);
+        ancestors.pop();
+    }
+}
+
+/// How deeply the TOC nests, so deep TOCs can start collapsed.
+#[tauri::command]
+fn get_toc_depth(book_key: String, state: tauri::State<LibraryState>) -> Result<TocDepth, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    Ok(toc_depth(&book_toc(book)))
+}
+
+/// Entries at each nesting level of `toc`.
+fn toc_depth(toc: &[TocItem]) -> TocDepth {
+    let mut entries = Vec::new();
+    collect_toc_chains(toc, &mut Vec::new(), &mut entries);
+    let mut entries_per_level: Vec<usize> = Vec::new();
+    for (labels, _) in &entries {
+        if entries_per_level.len() < labels.len() {
+            entries_per_level.resize(labels.len(), 0);
+        }
+        entries_per_level[labels.len() - 1] += 1;
+    }
+
+    TocDepth { max_depth: entries_per_level.len(), entries_per_level }
+}
+
+/// Breadcrumb trail ("Part II › Chapter 5") for a spine document. Hrefs that
+/// have no TOC entry of their own attach to the nearest preceding entry in
+/// reading order. Returns `None` when no TOC entry precedes the document.
+#[tauri::command]
+fn get_toc_breadcrumb(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<Option<TocBreadcrumb>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (target, _) = resolve_href("", &spine_href);
+
+    let mut entries = Vec::new();
+    collect_toc_chains(&book_toc(book), &mut Vec::new(), &mut entries);
+
+    // Prefer an entry for the whole document over one for a fragment of it
+    let exact = entries.iter()
+        .filter(|(_, content)| resolve_href("", content).0 == target)
+        .min_by_key(|(_, content)| content.contains('#'));
+    if let Some((labels, content)) = exact {
+        return Ok(Some(TocBreadcrumb { labels: labels.clone(), content: content.clone(), exact: true }));
+    }
+
+    let target_index = spine_index_for_path(book, &target)
+        .ok_or_else(|| AppError::InvalidArgument(format!("Not a spine document: {}", spine_href)))?;
+    let preceding = entries.iter()
+        .filter_map(|entry| {
+            spine_index_for_path(book, &resolve_href("", &entry.1).0)
+                .filter(|index| *index <= target_index)
+                .map(|index| (index, entry))
+        })
+        .max_by_key(|(index, _)| *index);
+
+    Ok(preceding.map(|(_, (labels, content))| TocBreadcrumb {
+        labels: labels.clone(),
+        content: content.clone(),
+        exact: false,
+    }))
+}
+
+/// True when the document has an element with `id` (or an `<a name>`) equal
+/// to `fragment`.
+fn has_anchor(markup: &str, fragment: &str) -> bool {
+    html::tokenize(markup).any(|(token, _)| match token {
+        html::Token::StartTag(tag) => {
+            tag.attr("id").map(|id| html::decode_entities(id) == fragment).unwrap_or(false)
+                || (tag.is("a") && tag.attr("name").map(|name| html::decode_entities(name) == fragment).unwrap_or(false))
+        }
+        _ => false,
+    })
+}
+
+/// TOC entries that would lead nowhere: targets missing from the book,
+/// documents outside the spine, and fragments with no matching anchor.
+/// An empty list means every entry resolves.
+#[tauri::command]
+fn validate_toc(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<BrokenTocEntry>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    let mut entries = Vec::new();
+    collect_toc_chains(&convert_nav_points(&book.toc), &mut Vec::new(), &mut entries);
+    // Each target document is read at most once
+    let mut documents: HashMap<String, Option<String>> = HashMap::new();
+
+    let mut broken = Vec::new();
+    for (labels, content) in entries {
+        let (path, fragment) = resolve_href("", &content);
+        let document = documents.entry(path.clone())
+            .or_insert_with(|| read_document(book, &path).ok().map(|(_, markup)| markup));
+
+        let reason = if document.is_none() {
+            Some("missing-resource")
+        } else if spine_index_for_path(book, &path).is_none() {
+            Some("not-in-spine")
+        } else {
+            let fragment = fragment.map(|f| percent_encoding::percent_decode_str(&f).decode_utf8_lossy().into_owned());
+            match (fragment, document.as_deref()) {
+                (Some(fragment), Some(markup)) if !fragment.is_empty() && !has_anchor(markup, &fragment) => {
+                    Some("missing-anchor")
+                }
+                _ => None,
+            }
+        };
+        if let Some(reason) = reason {
+            broken.push(BrokenTocEntry { labels, content, reason: reason.to_string() });
+        }
+    }
+
+    Ok(broken)
+}
+
+/// Up to `max_chars` of `text`, cut at a word boundary with an ellipsis.
+fn truncate_text(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max_chars).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if space > 0 => &cut[..space],
+        _ => cut.as_str(),
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// First ~100 characters of a chapter's text for "next: ..." previews on
+/// the navigation buttons. Image-only chapters fall back to their TOC label
+/// or `<title>`; the result is empty when there's neither.
+#[tauri::command]
+fn get_chapter_peek(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>,
+    peeks: tauri::State<ChapterPeekState>
+) -> Result<String, AppError> {
+    let (path, _) = resolve_href("", &spine_href);
+    let cache_key = (book_key.clone(), path);
+    if let Some(peek) = peeks.0.lock().get(&cache_key) {
+        return Ok(peek.clone());
+    }
+
+    let peek = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let (path, markup) = read_document(book, &spine_href)?;
+
+        let text = html::extract_text(&markup).split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            truncate_text(&text, PEEK_CHARS)
+        } else {
+            let mut entries = Vec::new();
+            collect_toc_chains(&convert_nav_points(&book.toc), &mut Vec::new(), &mut entries);
+            entries.iter()
+                .filter(|(_, content)| resolve_href("", content).0 == path)
+                .min_by_key(|(_, content)| content.contains('#'))
+                .and_then(|(labels, _)| labels.last().cloned())
+                .or_else(|| html::document_title(&markup))
+                .unwrap_or_default()
+        }
+    };
+
+    peeks.0.lock().insert(cache_key, peek.clone());
+    Ok(peek)
+}
+
+#[derive(Serialize)]
+struct ChapterSlice {
+    /// Self-contained body markup; relative URLs resolve against `href`
+    html: String,
+    href: String,
+    start_offset: usize,
+    /// Pass back as `start_offset` for the following slice; `None` at the end
+    next_offset: Option<usize>,
+    /// Length of the chapter document, for progress through it
+    total_len: usize,
+}
+
+/// Part of a chapter's body for virtualizing very long chapters. Offsets are
+/// bytes into the chapter document: start at 0 and follow `next_offset`.
+/// Slices hold at least `max_chars` bytes of markup and end after a block
+/// element, with the elements open across the cut closed and reopened.
+#[tauri::command]
+fn get_chapter_slice(
+    book_key: String,
+    spine_href: String,
+    start_offset: usize,
+    max_chars: usize,
+    state: tauri::State<LibraryState>
+) -> Result<ChapterSlice, AppError> {
+    if max_chars == 0 {
+        return Err(AppError::InvalidArgument("max_chars must be at least 1".to_string()));
+    }
+
+    let (path, markup) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        read_document(book, &spine_href)?
+    };
+
+    let slice = html::slice_body(&markup, start_offset, max_chars)
+        .ok_or_else(|| AppError::InvalidArgument(format!("Offset {} is not a slice boundary in {}", start_offset, path)))?;
+    Ok(ChapterSlice {
+        html: slice.markup,
+        href: path,
+        start_offset: slice.start,
+        next_offset: slice.next,
+        total_len: markup.len(),
+    })
+}
+
+/// Visible text of one spine document for text-to-speech and word counts:
+/// tags stripped, entities decoded, whitespace collapsed, and one line per
+/// paragraph or other block element. Items without text give an empty string.
+#[tauri::command]
+async fn get_chapter_text(
+    book_key: String,
+    spine_index: usize,
+    state: tauri::State<'_, LibraryState>
+) -> Result<String, AppError> {
+    let books = Arc::clone(&state.0);
+    run_blocking(move || {
+        let book_handle = book_handle(&books, &book_key)?;
+        let book = &mut *book_handle.lock();
+        let (path, mime) = book.spine.get(spine_index)
+            .and_then(|id| book.resources.get(id).cloned())
+            .ok_or_else(|| AppError::InvalidArgument(format!("Spine index out of range: {}", spine_index)))?;
+        if !is_html_mime(&mime) {
+            return Ok(String::new());
+        }
+
+        let bytes = book.get_resource_by_path(&path)
+            .map_err(|_| AppError::resource_not_found(&path.display().to_string()))?;
+        Ok(html::extract_text(&html::decode_document(&bytes)))
+    }).await
+}
+
+/// The whole book as plain text in spine order, each chapter introduced by a
+/// `# Title` line. Spine items without text (images, image-only pages) are
+/// skipped and listed at the end.
+#[tauri::command]
+fn export_book_text(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    let mut toc_entries = Vec::new();
+    collect_toc_chains(&convert_nav_points(&book.toc), &mut Vec::new(), &mut toc_entries);
+    let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+        .filter_map(|id| book.resources.get(id).cloned())
+        .collect();
+
+    let mut chapters: Vec<String> = Vec::new();
+    let mut skipped: Vec<String> = Vec::new();
+    for (spine_index, (path, mime)) in spine_paths.into_iter().enumerate() {
+        let path = path.to_str().unwrap_or("").to_string();
+        if !is_html_mime(&mime) {
+            skipped.push(format!("{} ({})", path, mime));
+            continue;
+        }
+        let Ok(bytes) = book.get_resource_by_path(&path) else {
+            skipped.push(format!("{} (unreadable)", path));
+            continue;
+        };
+
+        let markup = html::decode_document(&bytes);
+        let text = html::extract_text(&markup);
+        if text.is_empty() {
+            skipped.push(format!("{} (no text)", path));
+            continue;
+        }
+        let title = toc_entries.iter()
+            .filter(|(_, content)| resolve_href("", content).0 == path)
+            .min_by_key(|(_, content)| content.contains('#'))
+            .and_then(|(labels, _)| labels.last().cloned())
+            .or_else(|| html::document_title(&markup))
+            .unwrap_or_else(|| format!("Section {}", spine_index + 1));
+        chapters.push(format!("# {}\n\n{}", title, text));
+    }
+
+    let mut out = chapters.join("\n\n");
+    if !skipped.is_empty() {
+        out.push_str(&format!("\n\n---\nSkipped {} spine items without text:\n", skipped.len()));
+        for item in &skipped {
+            out.push_str(&format!("- {}\n", item));
+        }
+    }
+    Ok(out)
+}
+
+/// Share of a book's letters a script needs to be listed in its fingerprint.
+const MIN_SCRIPT_SHARE: f64 = 0.05;
+
+/// Structural feature vector for similarity: length, chapter layout, images,
+/// layout mode, language and scripts. Computed once per book.
+#[tauri::command]
+fn get_book_fingerprint(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    fingerprints: tauri::State<FingerprintState>
+) -> Result<BookFingerprint, AppError> {
+    if let Some(fingerprint) = fingerprints.0.lock().get(&book_key) {
+        return Ok(fingerprint.clone());
+    }
+
+    let fingerprint = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+
+        let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+            .filter_map(|id| book.resources.get(id).cloned())
+            .filter(|(_, mime)| is_html_mime(mime))
+            .collect();
+        let mut word_count = 0;
+        let mut chapter_count = 0;
+        let mut script_counts: HashMap<&'static str, usize> = HashMap::new();
+        for (path, _) in spine_paths {
+            let Ok(bytes) = book.get_resource_by_path(&path) else {
+                continue;
+            };
+            let content = html::extract_text(&html::decode_document(&bytes));
+            let words = text::word_count(&content);
+            if words > 0 {
+                word_count += words;
+                chapter_count += 1;
+                text::count_scripts(&content, &mut script_counts);
+            }
+        }
+
+        let letters: usize = script_counts.values().sum();
+        let mut scripts: Vec<(&str, usize)> = script_counts.into_iter()
+            .filter(|(_, count)| *count as f64 >= letters as f64 * MIN_SCRIPT_SHARE)
+            .collect();
+        scripts.sort_by_key(|(name, count)| (std::cmp::Reverse(*count), *name));
+
+        let fixed_layout = read_package(book).ok()
+            .and_then(|package| package.property("rendition:layout").map(|layout| layout.trim() == "pre-paginated"))
+            .unwrap_or(false);
+        BookFingerprint {
+            word_count,
+            chapter_count,
+            average_chapter_words: word_count.checked_div(chapter_count).unwrap_or(0),
+            image_count: book.resources.values().filter(|(_, mime)| mime.starts_with("image/")).count(),
+            fixed_layout,
+            language: book.mdata("language").map(|language| language.trim().to_string()).filter(|l| !l.is_empty()),
+            scripts: scripts.into_iter().map(|(name, _)| name.to_string()).collect(),
+        }
+    };
+
+    fingerprints.0.lock().insert(book_key, fingerprint.clone());
+    Ok(fingerprint)
+}
+
+/// Front-matter types recognized by `get_front_matter`, with the TOC titles
+/// that identify them when a book has no semantic markup.
+const FRONT_MATTER_TYPES: &[(&str, &[&str])] = &[
+    ("titlepage", &["title page"]),
+    ("copyright-page", &["copyright"]),
+    ("dedication", &["dedication"]),
+    ("epigraph", &["epigraph"]),
+    ("foreword", &["foreword"]),
+    ("preface", &["preface"]),
+    ("acknowledgments", &["acknowledgments", "acknowledgements"]),
+    ("introduction", &["introduction"]),
+    ("prologue", &["prologue"]),
+];
+
+/// The front-matter type named by an `epub:type` value or EPUB2 guide type.
+fn front_matter_type(value: &str) -> Option<&'static str> {
+    value.split_whitespace().find_map(|token| {
+        let token = token.rsplit(':').next().unwrap_or(token).to_ascii_lowercase();
+        let token = match token.as_str() {
+            "title-page" => "titlepage",
+            "acknowledgements" => "acknowledgments",
+            other => other,
+        };
+        FRONT_MATTER_TYPES.iter().map(|(kind, _)| *kind).find(|kind| *kind == token)
+    })
+}
+
+/// A link inside one of the navigation document's `<nav>` elements.
+struct NavLink {
+    epub_type: Option<String>,
+    href: String,
+    label: String,
+}
+
+/// Links in the nav document's `<nav epub:type="...">` of the given type,
+/// e.g. `landmarks` or `page-list`.
+fn nav_links(markup: &str, nav_type: &str) -> Vec<NavLink> {
+    let mut links = Vec::new();
+    let mut nav_depth = 0usize;
+    let mut target_depth: Option<usize> = None;
+    let mut current: Option<NavLink> = None;
+
+    for (token, range) in html::tokenize(markup) {
+        match token {
+            html::Token::StartTag(tag) if tag.is("nav") && !tag.self_closing => {
+                nav_depth += 1;
+                let is_target = tag.attr("epub:type")
+                    .map(|t| t.split_whitespace().any(|v| v == nav_type))
+                    .unwrap_or(false);
+                if is_target && target_depth.is_none() {
+                    target_depth = Some(nav_depth);
+                }
+            }
+            html::Token::EndTag(name) if name.eq_ignore_ascii_case("nav") => {
+                if target_depth == Some(nav_depth) {
+                    target_depth = None;
+                }
+                nav_depth = nav_depth.saturating_sub(1);
+            }
+            html::Token::StartTag(tag) if tag.is("a") && target_depth.is_some() => {
+                if let Some(href) = tag.attr("href") {
+                    current = Some(NavLink {
+                        epub_type: tag.attr("epub:type").map(str::to_string),
+                        href: html::decode_entities(href).into_owned(),
+                        label: String::new(),
+                    });
+                }
+            }
+            html::Token::Text => {
+                if let Some(link) = current.as_mut() {
+                    link.label.push_str(&html::decode_entities(&markup[range]));
+                }
+            }
+            html::Token::EndTag(name) if name.eq_ignore_ascii_case("a") => {
+                if let Some(mut link) = current.take() {
+                    link.label = link.label.split_whitespace().collect::<Vec<_>>().join(" ");
+                    links.push(link);
+                }
+            }
+            _ => {}
+        }
+    }
+    links
+}
+
+/// The front-matter type declared on one of a document's outer sections.
+fn content_front_matter_type(markup: &str) -> Option<&'static str> {
+    const CONTAINERS: &[&str] = &["body", "section", "div", "article"];
+    html::tokenize(markup)
+        .filter_map(|(token, _)| match token {
+            html::Token::StartTag(tag) if CONTAINERS.iter().any(|c| tag.is(c)) => Some(tag),
+            _ => None,
+        })
+        // The body and its first couple of wrappers
+        .take(3)
+        .find_map(|tag| tag.attr("epub:type").and_then(front_matter_type))
+}
+
+/// Adds a section for `href` (relative to `base`) unless that href or kind
+/// was already found by a more authoritative source.
+fn add_front_matter(
+    sections: &mut Vec<FrontMatterSection>,
+    book: &EpubDoc<BufReader<File>>,
+    kind: &str,
+    base: &str,
+    href: &str,
+    title: Option<String>,
+    source: &str,
+) {
+    let (path, fragment) = resolve_href(base, href);
+    let href = match fragment {
+        Some(fragment) => format!("{}#{}", path, fragment),
+        None => path.clone(),
+    };
+    if sections.iter().any(|s| s.href == href || s.kind == kind) {
+        return;
+    }
+    sections.push(FrontMatterSection {
+        kind: kind.to_string(),
+        title: title.filter(|t| !t.is_empty()),
+        href,
+        spine_index: spine_index_for_path(book, &path),
+        source: source.to_string(),
+    });
+}
+
+/// Front-matter sections (dedication, epigraph, preface, foreword, ...) for a
+/// "front matter" menu. Semantic sources are used first: the EPUB3
+/// landmarks nav, the EPUB2 guide and `epub:type` on the chapters
+/// themselves. Only when none of those yield anything are TOC entries
+/// matched by title.
+#[tauri::command]
+fn get_front_matter(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<FrontMatterSection>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+    let root_file = book.root_file.to_str().unwrap_or("").to_string();
+
+    let mut sections: Vec<FrontMatterSection> = Vec::new();
+
+    if let Some(nav) = package.manifest.iter().find(|item| item.has_property("nav")) {
+        if let Ok((nav_path, markup)) = read_document(book, &resolve_href(&root_file, &nav.href).0) {
+            for link in nav_links(&markup, "landmarks") {
+                if let Some(kind) = link.epub_type.as_deref().and_then(front_matter_type) {
+                    add_front_matter(&mut sections, book, kind, &nav_path, &link.href, Some(link.label), "landmarks");
+                }
+            }
+        }
+    }
+
+    for reference in &package.guide {
+        if let Some(kind) = front_matter_type(&reference.kind) {
+            add_front_matter(&mut sections, book, kind, &root_file, &reference.href, reference.title.clone(), "guide");
+        }
+    }
+
+    let spine_paths: Vec<String> = book.spine.iter()
+        .filter_map(|id| book.resources.get(id))
+        .filter(|(_, mime)| is_html_mime(mime))
+        .map(|(path, _)| path.to_str().unwrap_or("").to_string())
+        .collect();
+    for path in spine_paths {
+        if let Ok((path, markup)) = read_document(book, &path) {
+            if let Some(kind) = content_front_matter_type(&markup) {
+                add_front_matter(&mut sections, book, kind, "", &path, html::document_title(&markup), "content");
+            }
+        }
+    }
+
+    if sections.is_empty() {
+        let mut entries = Vec::new();
+        collect_toc_chains(&convert_nav_points(&book.toc), &mut Vec::new(), &mut entries);
+        for (labels, content) in entries {
+            let Some(label) = labels.last() else { continue };
+            let normalized = label.trim().to_lowercase();
+            let kind = FRONT_MATTER_TYPES.iter()
+                .find(|(_, titles)| titles.iter().any(|title| normalized == *title || normalized.starts_with(&format!("{}:", title))))
+                .map(|(kind, _)| *kind);
+            if let Some(kind) = kind {
+                add_front_matter(&mut sections, book, kind, "", &content, Some(label.clone()), "title");
+            }
+        }
+    }
+
+    sections.sort_by_key(|section| section.spine_index.unwrap_or(usize::MAX));
+    Ok(sections)
+}
+
+/// The unescaped `href` of a `<link>` whose `rel` includes `stylesheet`.
+fn stylesheet_href(tag: &html::Tag) -> Option<String> {
+    let is_stylesheet = tag.is("link") && tag.attr("rel")
+        .is_some_and(|rel| rel.split_whitespace().any(|r| r.eq_ignore_ascii_case("stylesheet")));
+    tag.attr("href")
+        .filter(|_| is_stylesheet)
+        .map(|href| html::decode_entities(href).into_owned())
+}
+
+/// Archive paths of a chapter's linked stylesheets and local images, in
+/// document order.
+fn linked_resources(page: &str, chapter_path: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for (token, _) in html::tokenize(page) {
+        let html::Token::StartTag(tag) = token else { continue };
+        let href = if tag.is("link") {
+            stylesheet_href(&tag)
+        } else if tag.is("img") || tag.is("image") {
+            tag.attr("src").or_else(|| tag.attr("href")).or_else(|| tag.attr("xlink:href"))
+                .map(|src| html::decode_entities(src).into_owned())
+        } else {
+            None
+        };
+        let Some(href) = href else { continue };
+        if href.starts_with("data:") || is_external_href(&href) {
+            continue;
+        }
+        let (path, _) = resolve_href(chapter_path, &href);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Archive paths of every local resource a chapter refers to: images, media,
+/// stylesheets, embedded objects and linked documents.
+fn referenced_resources(page: &str, chapter_path: &str) -> Vec<String> {
+    const URL_ATTRIBUTES: &[&str] = &["src", "href", "xlink:href", "poster", "data"];
+
+    let mut paths: Vec<String> = Vec::new();
+    for (token, _) in html::tokenize(page) {
+        let html::Token::StartTag(tag) = token else { continue };
+        for href in URL_ATTRIBUTES.iter().filter_map(|name| tag.attr(name)) {
+            let href = html::decode_entities(href);
+            if href.is_empty() || href.starts_with('#') || href.starts_with("data:") || is_external_href(&href) {
+                continue;
+            }
+            let (path, _) = resolve_href(chapter_path, &href);
+            if path != chapter_path && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+/// Spine documents that reference `resource_href` (an archive path), e.g.
+/// to jump to where an image appears or to find resources nothing uses.
+/// Links count as references, so a chapter's result lists the chapters
+/// linking to it. The map is built from one scan of the book and cached.
+#[tauri::command]
+fn find_containing_chapter(
+    book_key: String,
+    resource_href: String,
+    state: tauri::State<LibraryState>,
+    references: tauri::State<ReferenceMapState>
+) -> Result<Vec<String>, AppError> {
+    let (resource, _) = resolve_href("", &resource_href);
+    let cached = references.0.lock().get(&book_key).cloned();
+    let map = match cached {
+        Some(map) => map,
+        None => {
+            let book = book_handle(&state.0, &book_key)?;
+            let book = &mut *book.lock();
+            let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+                .filter_map(|id| book.resources.get(id).cloned())
+                .collect();
+
+            let mut map = ReferenceMap::new();
+            for (path, mime) in spine_paths {
+                let path = path.to_str().unwrap_or("").to_string();
+                if !is_html_mime(&mime) {
+                    continue;
+                }
+                let Ok(bytes) = book.get_resource_by_path(&path) else { continue };
+                for target in referenced_resources(&html::decode_document(&bytes), &path) {
+                    let chapters = map.entry(target).or_default();
+                    if !chapters.contains(&path) {
+                        chapters.push(path.clone());
+                    }
+                }
+            }
+
+            let map = Arc::new(map);
+            references.0.lock().insert(book_key, map.clone());
+            map
+        }
+    };
+
+    Ok(map.get(&resource).cloned().unwrap_or_default())
+}
+
+/// Warms a book up before it's opened, e.g. on hover in the library:
+/// resolves the cover and the resume position, computes the text stats used
+/// by page estimates and seeking, and reads the resume chapter with its
+/// stylesheets and images ahead into the `epub://` handler's resource cache,
+/// where they count against its budget like anything it serves. Cheap once
+/// prepared, until the saved position moves to another chapter.
+#[tauri::command]
+fn prepare_book(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>,
+    text_stats: tauri::State<TextStatsState>,
+    prepared: tauri::State<PreparedState>,
+    cache: tauri::State<ResourceCacheState>
+) -> Result<PreparedBook, AppError> {
+    let saved = progress.0.lock().get(&book_key).cloned();
+    if let Some(book) = prepared.0.lock().get(&book_key) {
+        if saved.as_ref().map(|p| p.spine_index).unwrap_or(0) == book.resume.spine_index {
+            return Ok(book.clone());
+        }
+    }
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    if book.spine.is_empty() {
+        return Err(AppError::InvalidEpub(format!("Book has an empty spine: {}", book_key)));
+    }
+
+    let mut toc_entries = Vec::new();
+    collect_toc_chains(&book_toc(book), &mut Vec::new(), &mut toc_entries);
+    cached_text_stats(&text_stats, &book_key, book);
+
+    let (spine_index, scroll_fraction) = match &saved {
+        Some(p) if p.spine_index < book.spine.len() => (p.spine_index, p.scroll_fraction),
+        _ => (0, 0.0),
+    };
+    let href = book.spine.get(spine_index)
+        .and_then(|id| book.resources.get(id))
+        .map(|(path, _)| path.to_str().unwrap_or("").to_string())
+        .unwrap_or_default();
+
+    let mut prefetched_bytes = 0u64;
+    if let Ok((path, page)) = read_document(book, &href) {
+        let mut cache = cache.0.lock();
+        for path in std::iter::once(path.clone()).chain(linked_resources(&page, &path)) {
+            if prefetched_bytes >= MAX_PREFETCH_BYTES {
+                break;
+            }
+            let Ok(bytes) = book.get_resource_by_path(&path) else { continue };
+            prefetched_bytes += bytes.len() as u64;
+            cache.insert((book_key.clone(), path), bytes);
+        }
+    }
+
+    let prepared_book = PreparedBook {
+        title: book.mdata("title").unwrap_or(book_key.replace(".epub", "")),
+        cover_href: cover_image_path(book),
+        toc_entries: toc_entries.len(),
+        resume: BookLocation {
+            spine_index,
+            uri: format!("epub://{}/{}", library::book_host(&book_key), href),
+            href,
+            scroll_fraction,
+        },
+        prefetched_bytes,
+    };
+    prepared.0.lock().insert(book_key, prepared_book.clone());
+    Ok(prepared_book)
+}
+
+/// Resizes the `epub://` resource cache, evicting least recently used
+/// resources straight away when the budget shrinks. Returns the bytes
+/// evicted. The budget lasts until the app quits.
+#[tauri::command]
+fn set_cache_budget(bytes: usize, cache: tauri::State<ResourceCacheState>) -> usize {
+    let mut cache = cache.0.lock();
+    cache.budget = bytes;
+    cache.evict_to(bytes)
+}
+
+/// The resource cache budget in bytes.
+#[tauri::command]
+fn get_cache_budget(cache: tauri::State<ResourceCacheState>) -> usize {
+    cache.0.lock().budget
+}
+
+/// Reads a document by href (any fragment is ignored), decoding it in its
+/// declared encoding (see `html::decode_document`). Returns the normalized
+/// archive path along with the markup.
+fn read_document(book: &mut EpubDoc<BufReader<File>>, href: &str) -> Result<(String, String), AppError> {
+    let (path, _) = resolve_href("", href);
+    let bytes = book.get_resource_by_path(&path)
+        .map_err(|_| AppError::resource_not_found(&path))?;
+    let markup = html::decode_document(&bytes).into_owned();
+    Ok((path, markup))
+}
+
+/// Debug aid for layout reports: the chapter's own `<style>` blocks and the
+/// stylesheets it links. Both lists are empty when the chapter is unstyled.
+#[tauri::command]
+fn get_chapter_styles(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<ChapterStyles, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (path, page) = read_document(book, &spine_href)?;
+    let mut styles = ChapterStyles { style_blocks: Vec::new(), stylesheets: Vec::new() };
+    let mut in_style = false;
+
+    for (token, range) in html::tokenize(&page) {
+        match token {
+            html::Token::StartTag(tag) if tag.is("style") => in_style = !tag.self_closing,
+            html::Token::StartTag(tag) if tag.is("link") => {
+                if let Some(href) = stylesheet_href(&tag) {
+                    styles.stylesheets.push(resolve_href(&path, &href).0);
+                }
+            }
+            html::Token::Text if in_style => {
+                let css = page[range].trim();
+                if !css.is_empty() {
+                    styles.style_blocks.push(css.to_string());
+                }
+            }
+            _ => in_style = false,
+        }
+    }
+
+    Ok(styles)
+}
+
+/// Images counted as above the fold by `analyze_chapter_load`.
+const ABOVE_FOLD_IMAGES: usize = 3;
+
+/// Size breakdown of a chapter's render-critical resources, for the debug
+/// overlay: the markup, its CSS, and the first few images.
+#[tauri::command]
+fn analyze_chapter_load(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<ChapterLoad, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (path, page) = read_document(book, &spine_href)?;
+    let mut stylesheet_paths: Vec<String> = Vec::new();
+    let mut image_paths: Vec<String> = Vec::new();
+    let mut inline_css_bytes = 0u64;
+    let mut in_style = false;
+
+    for (token, range) in html::tokenize(&page) {
+        match token {
+            html::Token::StartTag(tag) if tag.is("style") => in_style = !tag.self_closing,
+            html::Token::StartTag(tag) if tag.is("link") => {
+                if let Some(href) = stylesheet_href(&tag) {
+                    let (css_path, _) = resolve_href(&path, &href);
+                    if !stylesheet_paths.contains(&css_path) {
+                        stylesheet_paths.push(css_path);
+                    }
+                }
+            }
+            html::Token::StartTag(tag) if tag.is("img") || tag.is("image") => {
+                // SVG `<image>`s use `href` or the older `xlink:href`
+                let src = tag.attr("src").or_else(|| tag.attr("href")).or_else(|| tag.attr("xlink:href"));
+                if let Some(src) = src {
+                    let src = html::decode_entities(src);
+                    if image_paths.len() < ABOVE_FOLD_IMAGES && !src.starts_with("data:") && !is_external_href(&src) {
+                        let (image_path, _) = resolve_href(&path, &src);
+                        if !image_paths.contains(&image_path) {
+                            image_paths.push(image_path);
+                        }
+                    }
+                }
+            }
+            html::Token::Text if in_style => inline_css_bytes += range.len() as u64,
+            _ => in_style = false,
+        }
+    }
+
+    let mut sized = |href: String| {
+        let bytes = book.get_resource_by_path(&href).ok().map(|data| data.len() as u64);
+        SizedResource { href, bytes }
+    };
+    let stylesheets: Vec<SizedResource> = stylesheet_paths.into_iter().map(&mut sized).collect();
+    let above_fold_images: Vec<SizedResource> = image_paths.into_iter().map(&mut sized).collect();
+
+    let html_bytes = page.len() as u64;
+    let css_bytes = inline_css_bytes + stylesheets.iter().filter_map(|s| s.bytes).sum::<u64>();
+    let above_fold_image_bytes = above_fold_images.iter().filter_map(|i| i.bytes).sum::<u64>();
+    Ok(ChapterLoad {
+        html_bytes,
+        css_bytes,
+        stylesheets,
+        above_fold_images,
+        above_fold_image_bytes,
+        total_bytes: html_bytes + css_bytes + above_fold_image_bytes,
+    })
+}
+
+/// Resolves an image clicked in a chapter to its original file for the
+/// lightbox: the `epub://` URI plus its intrinsic dimensions. `src_href` is
+/// taken relative to the chapter at `spine_href`.
+#[tauri::command]
+fn get_image_full(
+    book_key: String,
+    spine_href: String,
+    src_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<FullImage, AppError> {
+    let src = html::decode_entities(src_href.trim()).into_owned();
+    if src.is_empty() || src.starts_with("data:") || is_external_href(&src) {
+        return Err(AppError::InvalidArgument(format!("Not an image in the book: {}", src_href)));
+    }
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    // `epub://` URLs as seen by the iframe are already archive paths
+    let epub_prefix = format!("epub://{}/", library::book_host(&book_key));
+    let (href, _) = match src.strip_prefix(&epub_prefix) {
+        Some(own) => resolve_href("", own),
+        None => resolve_href(&resolve_href("", &spine_href).0, &src),
+    };
+    let mime = book.get_resource_mime_by_path(&href)
+        .map_err(|_| AppError::resource_not_found(&href))?;
+    if !mime.starts_with("image/") {
+        return Err(AppError::InvalidArgument(format!("Not an image: {} ({})", href, mime)));
+    }
+
+    let bytes = book.get_resource_by_path(&href).map_err(|_| AppError::resource_not_found(&href))?;
+    let dimensions = image::ImageReader::new(std::io::Cursor::new(&bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+
+    Ok(FullImage {
+        uri: format!("epub://{}/{}", library::book_host(&book_key), href),
+        href,
+        mime,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+    })
+}
+
+/// `data:` URI holding a resource from the book, or `None` if it can't be read.
+fn resource_data_uri(book: &mut EpubDoc<BufReader<File>>, path: &str) -> Option<String> {
+    let mime = book.get_resource_mime_by_path(path).ok()?;
+    let mut bytes = book.get_resource_by_path(path).ok()?;
+    if is_font_resource(std::path::Path::new(path), &mime) {
+        deobfuscate_font(book, path, &mut bytes);
+    }
+    Some(format!("data:{};base64,{}", mime, base64::engine::general_purpose::STANDARD.encode(bytes)))
+}
+
+/// Replaces the `url(...)`s in a stylesheet located at `css_path` with data URIs.
+fn inline_css_urls(css: &str, css_path: &str, book: &mut EpubDoc<BufReader<File>>) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("url(") {
+        out.push_str(&rest[..start + 4]);
+        let after = &rest[start + 4..];
+        let Some(close) = after.find(')') else {
+            rest = after;
+            break;
+        };
+
+        let url = after[..close].trim().trim_matches(|c| c == '"' || c == '\'');
+        let inlined = if url.is_empty() || url.starts_with("data:") || url.starts_with('#') || is_external_href(url) {
+            None
+        } else {
+            resource_data_uri(book, &resolve_href(css_path, url).0)
+        };
+        match inlined {
+            Some(uri) => {
+                out.push('"');
+                out.push_str(&uri);
+                out.push('"');
+            }
+            None => out.push_str(&after[..close]),
+        }
+        out.push(')');
+        rest = &after[close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Makes a chapter self-contained: linked stylesheets become `<style>` blocks
+/// and images, fonts and other `url()` references become data URIs.
+fn inline_chapter_resources(html_str: &str, chapter_path: &str, book: &mut EpubDoc<BufReader<File>>) -> String {
+    let mut out = String::with_capacity(html_str.len());
+    let mut copied_to = 0;
+    let mut in_style = false;
+
+    for (token, range) in html::tokenize(html_str) {
+        let replacement = match token {
+            html::Token::StartTag(tag) if tag.is("style") => {
+                in_style = !tag.self_closing;
+                None
+            }
+            html::Token::StartTag(tag) if tag.is("link") => {
+                match stylesheet_href(&tag) {
+                    Some(href) if !is_external_href(&href) => {
+                        let (css_path, _) = resolve_href(chapter_path, &href);
+                        read_document(book, &css_path).ok().map(|(css_path, css)| {
+                            format!("<style>{}</style>", inline_css_urls(&css, &css_path, book))
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            html::Token::StartTag(tag) if tag.is("img") || tag.is("image") => {
+                let attr = ["src", "href", "xlink:href"].into_iter()
+                    .find_map(|name| tag.attr(name).map(|value| (name, value)));
+                match attr {
+                    Some((name, value)) if !value.starts_with("data:") && !is_external_href(value) => {
+                        let (path, _) = resolve_href(chapter_path, &html::decode_entities(value));
+                        resource_data_uri(book, &path).map(|uri| tag.rewrite(&[(name, &uri)], &[]))
+                    }
+                    _ => None,
+                }
+            }
+            html::Token::Text if in_style => Some(inline_css_urls(&html_str[range.clone()], chapter_path, book)),
+            html::Token::EndTag(_) => {
+                in_style = false;
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(replacement) = replacement {
+            out.push_str(&html_str[copied_to..range.start]);
+            out.push_str(&replacement);
+            copied_to = range.end;
+        }
+    }
+    out.push_str(&html_str[copied_to..]);
+    out
+}
+
+/// Undoes the reader's fixed-height viewport rules so a standalone document
+/// scrolls and prints in full.
+const STANDALONE_CSS: &str = "<style>html, body { height: auto !important; overflow: visible !important; }</style>";
+
+/// A chapter as a standalone document for export/print: the book's own
+/// styles, then the reader defaults and the reading theme (with per-book
+/// overrides) on top, and every resource inlined as a data URI.
+#[tauri::command]
+fn render_chapter_themed(
+    book_key: String,
+    spine_href: String,
+    preferences: tauri::State<PreferencesState>,
+    state: tauri::State<LibraryState>
+) -> Result<String, AppError> {
+    // Snapshot the theme before taking the library lock
+    let theme = preferences.0.lock().for_book(&book_key);
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (path, markup) = read_document(book, &spine_href)?;
+    let direction = TextDirection::of(book);
+    let markup = String::from_utf8_lossy(&set_document_direction(markup.into_bytes(), &direction)).into_owned();
+
+    let inlined = inline_chapter_resources(&markup, &path, book);
+    let injection = format!("{}\n{}\n{}\n{}", DEFAULT_CONTENT_CSS, theme_css(&theme), direction.css(), STANDALONE_CSS);
+    Ok(insert_at_head_end(&inlined, &injection))
+}
+
+/// Spine item `spine_index` made self-contained by `inline_chapter_resources`,
+/// as UTF-8 markup, with its archive path.
+fn standalone_chapter(book: &mut EpubDoc<BufReader<File>>, spine_index: usize) -> Result<(String, String), AppError> {
+    let (path, mime) = book.spine.get(spine_index)
+        .and_then(|id| book.resources.get(id).cloned())
+        .ok_or_else(|| AppError::InvalidArgument(format!("Spine index out of range: {}", spine_index)))?;
+    let path = path.to_string_lossy().into_owned();
+    if !is_html_mime(&mime) {
+        return Err(AppError::InvalidArgument(format!("Not an HTML chapter: {}", path)));
+    }
+    let bytes = book.get_resource_by_path(&path).map_err(|_| AppError::resource_not_found(&path))?;
+    let markup = html::to_utf8_document(&bytes).into_owned();
+    Ok((inline_chapter_resources(&markup, &path, book), path))
+}
+
+/// The `<style>` elements of a document, as written.
+fn style_elements(markup: &str) -> Vec<&str> {
+    let mut styles = Vec::new();
+    let mut open = None;
+    for (token, range) in html::tokenize(markup) {
+        match token {
+            html::Token::StartTag(tag) if tag.is("style") && !tag.self_closing => open = Some(range.start),
+            html::Token::EndTag(name) if name.eq_ignore_ascii_case("style") => {
+                if let Some(start) = open.take() {
+                    styles.push(&markup[start..range.end]);
+                }
+            }
+            _ => {}
+        }
+    }
+    styles
+}
+
+/// Saves a chapter as one self-contained HTML file at `out_path`: linked
+/// stylesheets inlined and images and fonts embedded as data URIs. The
+/// chapter is taken from the book as is, without the reader's injected
+/// script and styles. References to resources the book lacks are left as
+/// they are.
+#[tauri::command]
+fn export_chapter_html(
+    book_key: String,
+    spine_index: usize,
+    out_path: String,
+    state: tauri::State<LibraryState>
+) -> Result<(), AppError> {
+    let (markup, _) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        standalone_chapter(book, spine_index)?
+    };
+    std::fs::write(&out_path, insert_at_head_end(&markup, STANDALONE_CSS))
+        .map_err(|e| AppError::Io(format!("Failed to write {}: {}", out_path, e)))
+}
+
+/// Saves the whole book as one self-contained HTML file at `out_path`: every
+/// HTML chapter's body in spine order, each in a `<section>`, under the
+/// chapters' styles (each distinct one once). Resources are embedded as in
+/// `export_chapter_html`; links between chapters are left as they are.
+#[tauri::command]
+fn export_book_html(book_key: String, out_path: String, state: tauri::State<LibraryState>) -> Result<(), AppError> {
+    let (title, styles, sections) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let title = book.mdata("title").unwrap_or_else(|| book_key.replace(".epub", ""));
+
+        let mut styles: Vec<String> = Vec::new();
+        let mut sections = String::new();
+        for spine_index in 0..book.spine.len() {
+            let (markup, path) = match standalone_chapter(book, spine_index) {
+                Ok(chapter) => chapter,
+                // Image and SVG spine items, and chapters missing from the archive
+                Err(_) => continue,
+            };
+            for style in style_elements(&markup) {
+                if !styles.iter().any(|existing| existing == style) {
+                    styles.push(style.to_string());
+                }
+            }
+            let body = html::slice_body(&markup, 0, usize::MAX).map(|slice| slice.markup).unwrap_or_default();
+            sections.push_str(&format!("<section data-href=\"{}\">\n{}\n</section>\n", html::escape_attr(&path), body));
+        }
+        (title, styles, sections)
+    };
+
+    let document = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n<title>{}</title>\n{}\n{}\n</head>\n<body>\n{}</body>\n</html>\n",
+        html::escape_attr(&title),
+        styles.join("\n"),
+        STANDALONE_CSS,
+        sections,
+    );
+    std::fs::write(&out_path, document).map_err(|e| AppError::Io(format!("Failed to write {}: {}", out_path, e)))
+}
+
+/// The chapter's body reduced to a safe subset of HTML for injecting into the
+/// app's own DOM rather than an isolated iframe: scripts, styles, embedded
+/// content, forms and event handlers are stripped, relative URLs point at
+/// `epub://` and only `http`, `https` and `mailto` links are kept otherwise.
+#[tauri::command]
+fn get_chapter_sanitized(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<String, AppError> {
+    let (path, markup) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        read_document(book, &spine_href)?
+    };
+
+    Ok(html::sanitize(&markup, |url| {
+        if url.is_empty() {
+            None
+        } else if url.starts_with('#') {
+            Some(url.to_string())
+        } else if is_external_href(url) {
+            let scheme = url.split_once(':').map(|(scheme, _)| scheme.to_ascii_lowercase()).unwrap_or_default();
+            matches!(scheme.as_str(), "http" | "https" | "mailto").then(|| url.to_string())
+        } else {
+            let (target, fragment) = resolve_href(&path, url);
+            Some(match fragment {
+                Some(fragment) => format!("epub://{}/{}#{}", library::book_host(&book_key), target, fragment),
+                None => format!("epub://{}/{}", library::book_host(&book_key), target),
+            })
+        }
+    }))
+}
+
+#[derive(Serialize)]
+struct ChapterContent {
+    spine_href: String,
+    html: String,
+}
+
+#[derive(Serialize)]
+struct BookContent {
+    /// Spine documents in reading order, possibly cut short
+    chapters: Vec<ChapterContent>,
+    /// Set when the book exceeded the size cap and later chapters are missing
+    warning: Option<String>,
+}
+
+/// Chapters past this much markup are left out of `get_book_content`.
+const MAX_BOOK_CONTENT_BYTES: usize = 32 * 1024 * 1024;
+
+/// Every HTML chapter of a book in one call, for continuous-scroll rendering
+/// and whole-book features. Chapters go through the same injection as the
+/// `epub://` handler, with content modes from `query` (e.g.
+/// `links=route&notes=inline`), unless `raw` is set. Stops with a warning
+/// once `MAX_BOOK_CONTENT_BYTES` of markup has been collected.
+#[tauri::command]
+async fn get_book_content(
+    book_key: String,
+    raw: Option<bool>,
+    query: Option<String>,
+    state: tauri::State<'_, LibraryState>,
+    preferences: tauri::State<'_, PreferencesState>
+) -> Result<BookContent, AppError> {
+    let (theme, injection, block_remote) = {
+        let preferences = preferences.0.lock();
+        (preferences.for_book(&book_key), preferences.injection_mode, preferences.privacy_mode)
+    };
+    let options = InjectionOptions { injection, block_remote, ..InjectionOptions::from_query(query.as_deref()) };
+
+    let books = Arc::clone(&state.0);
+    run_blocking(move || {
+        let book = book_handle(&books, &book_key)?;
+        let book = &mut *book.lock();
+        let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+            .filter_map(|id| book.resources.get(id).cloned())
+            .collect();
+
+        let mut chapters = Vec::new();
+        let mut total_bytes = 0;
+        let mut warning = None;
+        for (path, mime) in spine_paths {
+            if !is_html_mime(&mime) {
+                continue;
+            }
+            let spine_href = path.to_str().unwrap_or("").to_string();
+            let Ok(resource) = book.get_resource_by_path(&path) else { continue };
+            let resource = if raw == Some(true) {
+                resource
+            } else {
+                prepare_chapter(resource, &spine_href, &book_key, book, &theme, &options)
+            };
+
+            total_bytes += resource.len();
+            if total_bytes > MAX_BOOK_CONTENT_BYTES {
+                warning = Some(format!(
+                    "Book is too large to load at once; stopped before {} after {} chapters",
+                    spine_href,
+                    chapters.len()
+                ));
+                break;
+            }
+            chapters.push(ChapterContent { spine_href, html: String::from_utf8_lossy(&resource).into_owned() });
+        }
+
+        Ok(BookContent { chapters, warning })
+    }).await
+}
+
+/// A chapter for previewing, e.g. from search results, injected as usual but
+/// in preview mode (as `epub://` serves it with `?mode=preview`). Nothing is
+/// recorded: progress only changes through `save_progress` and the `goto_*`
+/// commands, which the frontend shouldn't call for previews.
+#[tauri::command]
+fn preview_chapter(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>,
+    preferences: tauri::State<PreferencesState>
+) -> Result<String, AppError> {
+    let (theme, injection, block_remote) = {
+        let preferences = preferences.0.lock();
+        (preferences.for_book(&book_key), preferences.injection_mode, preferences.privacy_mode)
+    };
+    let options = InjectionOptions { preview: true, injection, block_remote, ..InjectionOptions::default() };
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    // Raw bytes, so `prepare_chapter` decodes them as the served chapter is
+    let (path, _) = resolve_href("", &spine_href);
+    let bytes = book.get_resource_by_path(&path)
+        .map_err(|_| AppError::resource_not_found(&path))?;
+    let html = prepare_chapter(bytes, &path, &book_key, book, &theme, &options);
+    Ok(String::from_utf8_lossy(&html).into_owned())
+}
+
+#[derive(Serialize)]
+struct ChapterLink {
+    href: String,
+    link_type: LinkType,
+}
+
+/// Every link in a chapter, in document order, classified as internal,
+/// external, footnote or anchor the same way the served chapter's
+/// `data-link-type` attributes are.
+#[tauri::command]
+fn get_chapter_links(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<Vec<ChapterLink>, AppError> {
+    let (path, markup) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        read_document(book, &spine_href)?
+    };
+
+    Ok(html::tokenize(&markup)
+        .filter_map(|(token, _)| match token {
+            html::Token::StartTag(tag) => {
+                let link_type = classify_link(&tag, &path)?;
+                Some(ChapterLink { href: tag.attr("href")?.trim().to_string(), link_type })
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Splits a chapter's text into overlapping chunks for embedding pipelines.
+/// Token counts are approximated as 4 tokens per 3 words; chunks break at
+/// sentence boundaries where possible and share roughly an eighth of their
+/// length with the next chunk.
+#[tauri::command]
+fn get_chapter_chunks(
+    book_key: String,
+    spine_href: String,
+    max_tokens: usize,
+    state: tauri::State<LibraryState>
+) -> Result<Vec<TextChunk>, AppError> {
+    if max_tokens < 8 {
+        return Err(AppError::InvalidArgument(format!("max_tokens must be at least 8, got {}", max_tokens)));
+    }
+
+    let text = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let (_, markup) = read_document(book, &spine_href)?;
+        html::extract_text(&markup)
+    };
+
+    let max_words = max_tokens * 3 / 4;
+    let char_offset = |byte: usize| text[..byte].chars().count();
+
+    let chunks = text::chunks(&text, max_words, max_words / 8)
+        .into_iter()
+        .enumerate()
+        .map(|(index, range)| TextChunk {
+            index,
+            text: text[range.clone()].to_string(),
+            char_range: (char_offset(range.start), char_offset(range.end)),
+        })
+        .collect();
+
+    Ok(chunks)
+}
+
+/// Flesch reading-ease and grade-level scores for one chapter, with the
+/// sentence, word and syllable counts they were computed from.
+#[tauri::command]
+fn get_readability(
+    book_key: String,
+    spine_href: String,
+    state: tauri::State<LibraryState>
+) -> Result<Readability, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (_, markup) = read_document(book, &spine_href)?;
+
+    Ok(readability::TextCounts::of(&html::extract_text(&markup)).into())
+}
+
+/// Readability of the whole book: counts summed over every spine document
+/// before scoring, so long chapters weigh more than short ones.
+#[tauri::command]
+fn get_book_readability(book_key: String, state: tauri::State<LibraryState>) -> Result<Readability, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    let spine_paths: Vec<(PathBuf, String)> = book.spine.iter()
+        .filter_map(|id| book.resources.get(id).cloned())
+        .collect();
+
+    let mut totals = readability::TextCounts::default();
+    for (path, mime) in spine_paths {
+        if !is_html_mime(&mime) {
+            continue;
+        }
+        if let Ok(bytes) = book.get_resource_by_path(&path) {
+            totals.add(readability::TextCounts::of(&html::extract_text(&String::from_utf8_lossy(&bytes))));
+        }
+    }
+
+    Ok(totals.into())
+}
+
+/// Whether a resource of this type is text, and so needs its charset
+/// declared: `text/*`, XML types (XHTML, SVG, NCX, ...), scripts and JSON.
+fn is_text_mime(mime: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("/xml")
+        || matches!(mime, "application/javascript" | "application/ecmascript" | "application/json")
+}
+
+/// The `Content-Type` to serve a resource with: text types get the charset
+/// of `body` added, unless `mime` already names one. Chapters that went
+/// through `prepare_chapter` are always UTF-8.
+fn content_type(mime: &str, body: &[u8], prepared: bool) -> String {
+    if !is_text_mime(mime) || mime.to_ascii_lowercase().contains("charset=") {
+        return mime.to_string();
+    }
+    let charset = if prepared { "utf-8".to_string() } else { html::document_encoding(body).name().to_ascii_lowercase() };
+    format!("{}; charset={}", mime, charset)
+}
+
+fn is_html_mime(mime: &str) -> bool {
+    mime == "text/html"
+        || mime == "application/xhtml+xml"
+        || mime == "application/xhtml"
+        || mime == "text/xhtml"
+}
+
+/// Stats of every spine item, indexed like the spine; items missing from the
+/// manifest are all zero.
+fn spine_text_stats(book: &mut EpubDoc<BufReader<File>>) -> Vec<ChapterStats> {
+    let spine_paths: Vec<Option<(PathBuf, String)>> = book.spine.iter()
+        .map(|id| book.resources.get(id).cloned())
+        .collect();
+
+    spine_paths.into_iter()
+        .map(|resource| {
+            let Some((path, _)) = resource.filter(|(_, mime)| is_html_mime(mime)) else {
+                return ChapterStats::default();
+            };
+            book.get_resource_by_path(&path)
+                .map(|bytes| {
+                    let text = html::extract_text(&String::from_utf8_lossy(&bytes));
+                    ChapterStats { chars: text.chars().count(), words: text::word_count(&text) }
+                })
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn cached_text_stats(
+    text_stats: &TextStatsState,
+    book_key: &str,
+    book: &mut EpubDoc<BufReader<File>>,
+) -> Arc<Vec<ChapterStats>> {
+    if let Some(stats) = text_stats.0.lock().get(book_key) {
+        return stats.clone();
+    }
+    let stats = Arc::new(spine_text_stats(book));
+    text_stats.0.lock().insert(book_key.to_string(), stats.clone());
+    stats
+}
+
+/// The publisher's own navigation document, for frontends that render it
+/// directly: the EPUB3 nav when there is one, else the NCX, unmodified.
+#[tauri::command]
+fn get_nav_document(book_key: String, state: tauri::State<LibraryState>) -> Result<NavDocument, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+    let root_file = book.root_file.to_str().unwrap_or("").to_string();
+
+    let (item, kind) = match package.manifest.iter().find(|item| item.has_property("nav")) {
+        Some(nav) => (nav, "nav"),
+        None => {
+            let ncx = package.spine_toc.as_deref()
+                .and_then(|id| package.manifest_item(id))
+                .ok_or_else(|| AppError::InvalidEpub(format!("Book has no navigation document: {}", book_key)))?;
+            (ncx, "ncx")
+        }
+    };
+
+    let (href, _) = resolve_href(&root_file, &item.href);
+    let data = book.get_resource_by_path(&href).map_err(|_| AppError::resource_not_found(&href))?;
+    Ok(NavDocument {
+        mime_type: item.media_type.clone(),
+        data_base64: base64::engine::general_purpose::STANDARD.encode(data),
+        kind: kind.to_string(),
+        href,
+    })
+}
+
+/// Number of print pages in the book's page-list (EPUB3 nav or EPUB2 NCX).
+fn page_list_count(book: &mut EpubDoc<BufReader<File>>, package: &opf::Package) -> Option<usize> {
+    let root_file = book.root_file.to_str().unwrap_or("").to_string();
+
+    if let Some(nav) = package.manifest.iter().find(|item| item.has_property("nav")) {
+        if let Ok((_, markup)) = read_document(book, &resolve_href(&root_file, &nav.href).0) {
+            let pages = nav_links(&markup, "page-list").len();
+            if pages > 0 {
+                return Some(pages);
+            }
+        }
+    }
+
+    let ncx = package.spine_toc.as_deref().and_then(|id| package.manifest_item(id))?;
+    let (_, markup) = read_document(book, &resolve_href(&root_file, &ncx.href).0).ok()?;
+    let pages = html::tokenize(&markup)
+        .filter(|(token, _)| matches!(token, html::Token::StartTag(tag) if tag.is("pageTarget")))
+        .count();
+    if pages > 0 { Some(pages) } else { None }
+}
+
+fn estimate_pages(
+    text_stats: &TextStatsState,
+    book_key: &str,
+    book: &mut EpubDoc<BufReader<File>>,
+    package: Option<&opf::Package>,
+    words_per_page: usize,
+) -> PageEstimate {
+    if let Some(pages) = package.and_then(|package| page_list_count(book, package)) {
+        return PageEstimate { pages, source: "page-list".to_string(), words_per_page: None };
+    }
+
+    let words: usize = cached_text_stats(text_stats, book_key, book).iter().map(|stats| stats.words).sum();
+    PageEstimate {
+        pages: words.div_ceil(words_per_page).max(1),
+        source: "estimate".to_string(),
+        words_per_page: Some(words_per_page),
+    }
+}
+
+/// How far through a book `position` is, by text length (0.0–1.0).
+fn read_fraction(chapters: &[ChapterStats], position: &Progress) -> f64 {
+    let total: usize = chapters.iter().map(|stats| stats.chars).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let before: usize = chapters.iter().take(position.spine_index).map(|stats| stats.chars).sum();
+    let current = chapters.get(position.spine_index).map_or(0, |stats| stats.chars);
+    ((before as f64 + current as f64 * position.scroll_fraction) / total as f64).clamp(0.0, 1.0)
+}
+
+/// Totals across the library from saved progress and each book's word
+/// count. Word counts come from the per-book text stats cache, so only books
+/// not measured yet are read; the first call on a large library is the slow
+/// one.
+#[tauri::command]
+async fn get_library_stats(
+    state: tauri::State<'_, LibraryState>,
+    text_stats: tauri::State<'_, TextStatsState>,
+    progress: tauri::State<'_, ProgressState>,
+    finished: tauri::State<'_, FinishedState>,
+    preferences: tauri::State<'_, PreferencesState>
+) -> Result<LibraryStats, AppError> {
+    let words_per_minute = preferences.0.lock().words_per_minute.unwrap_or(DEFAULT_WORDS_PER_MINUTE);
+    let positions = progress.0.lock().clone();
+    let finished_books = finished.0.lock().clone();
+    let books = Arc::clone(&state.0);
+    let text_stats = TextStatsState(Arc::clone(&text_stats.0));
+    run_blocking(move || {
+        let book_keys: Vec<String> = books.lock().keys().cloned().collect();
+        let mut stats = LibraryStats {
+            total_books: book_keys.len(),
+            started: 0,
+            finished: 0,
+            total_words: 0,
+            words_per_minute,
+            total_minutes: 0.0,
+            remaining_minutes: 0.0,
+        };
+
+        for book_key in &book_keys {
+            let cached = text_stats.0.lock().get(book_key).cloned();
+            // Books are opened one at a time, and only when not measured yet
+            let chapters = match cached {
+                Some(chapters) => chapters,
+                None => {
+                    let Some(book_handle) = Library::handle(&books, book_key) else { continue };
+                    let book = &mut *book_handle.lock();
+                    cached_text_stats(&text_stats, book_key, book)
+                }
+            };
+            let words: usize = chapters.iter().map(|chapter| chapter.words).sum();
+            stats.total_words += words;
+
+            let fraction = positions.get(book_key).map(|position| read_fraction(&chapters, position));
+            let is_finished = finished_books.contains(book_key) || fraction.is_some_and(|fraction| fraction >= FINISHED_FRACTION);
+            if is_finished {
+                stats.started += 1;
+                stats.finished += 1;
+            } else if let Some(fraction) = fraction {
+                stats.started += 1;
+                stats.remaining_minutes += words as f64 * (1.0 - fraction) / words_per_minute as f64;
+            }
+        }
+
+        stats.total_minutes = stats.total_words as f64 / words_per_minute as f64;
+        Ok(stats)
+    }).await
+}
+
+/// Estimated reading time of every spine item at the user's reading speed,
+/// for a chapter picker that shows "5 min". Items without text take 0 minutes.
+#[tauri::command]
+fn get_chapter_times(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    text_stats: tauri::State<TextStatsState>,
+    preferences: tauri::State<PreferencesState>
+) -> Result<ChapterTimes, AppError> {
+    let words_per_minute = preferences.0.lock().words_per_minute.unwrap_or(DEFAULT_WORDS_PER_MINUTE);
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let chapters = chapter_times(&text_stats, &book_key, book, words_per_minute);
+
+    Ok(ChapterTimes {
+        words_per_minute,
+        total_minutes: chapters.iter().map(|chapter| chapter.minutes).sum(),
+        chapters,
+    })
+}
+
+/// Word count and reading time of each spine item at `words_per_minute`,
+/// from the cached text stats.
+fn chapter_times(
+    text_stats: &TextStatsState,
+    book_key: &str,
+    book: &mut EpubDoc<BufReader<File>>,
+    words_per_minute: u32,
+) -> Vec<ChapterTime> {
+    let stats = cached_text_stats(text_stats, book_key, book);
+
+    book.spine.iter()
+        .zip(stats.iter())
+        .enumerate()
+        .map(|(spine_index, (id, stats))| ChapterTime {
+            spine_index,
+            href: book.resources.get(id).and_then(|(path, _)| path.to_str()).unwrap_or("").to_string(),
+            words: stats.words,
+            minutes: stats.words as f64 / words_per_minute as f64,
+        })
+        .collect()
+}
+
+/// Word count and reading time of every chapter and of the whole book, at
+/// `words_per_minute` if given, else the user's reading speed. Text is
+/// extracted once per book and cached.
+#[tauri::command]
+fn get_reading_estimates(
+    book_key: String,
+    words_per_minute: Option<u32>,
+    state: tauri::State<LibraryState>,
+    text_stats: tauri::State<TextStatsState>,
+    preferences: tauri::State<PreferencesState>
+) -> Result<ReadingEstimates, AppError> {
+    if let Some(words_per_minute) = words_per_minute {
+        validate_words_per_minute(words_per_minute)?;
+    }
+    let words_per_minute = words_per_minute
+        .or(preferences.0.lock().words_per_minute)
+        .unwrap_or(DEFAULT_WORDS_PER_MINUTE);
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let chapters = chapter_times(&text_stats, &book_key, book, words_per_minute);
+
+    let total_words = chapters.iter().map(|chapter| chapter.words).sum();
+    Ok(ReadingEstimates {
+        words_per_minute,
+        total_words,
+        total_minutes: total_words as f64 / words_per_minute as f64,
+        chapters,
+    })
+}
+
+/// Sets the reading speed used for time estimates and saves it; `None`
+/// goes back to the default.
+#[tauri::command]
+fn set_words_per_minute(
+    words_per_minute: Option<u32>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    if let Some(words_per_minute) = words_per_minute {
+        validate_words_per_minute(words_per_minute)?;
+    }
+    let mut preferences = state.0.lock();
+    preferences.words_per_minute = words_per_minute;
+    save_preferences(&app, &preferences)
+}
+
+/// Length of each spine item's text in characters (markup stripped, so not
+/// bytes), one per spine item, for "page 34 of 412" style positions from
+/// cumulative sums. Non-HTML items and items missing from the manifest are 0.
+/// Computed once per book.
+#[tauri::command]
+fn get_spine_sizes(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    text_stats: tauri::State<TextStatsState>
+) -> Result<Vec<usize>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    Ok(cached_text_stats(&text_stats, &book_key, book).iter().map(|stats| stats.chars).collect())
+}
+
+/// Print-equivalent page count for a "412 pages" display: the book's own
+/// page-list when it has one, otherwise total words / `words_per_page`
+/// (default 300).
+#[tauri::command]
+fn get_estimated_pages(
+    book_key: String,
+    words_per_page: Option<usize>,
+    state: tauri::State<LibraryState>,
+    text_stats: tauri::State<TextStatsState>
+) -> Result<PageEstimate, AppError> {
+    let words_per_page = words_per_page.unwrap_or(DEFAULT_WORDS_PER_PAGE);
+    if words_per_page == 0 {
+        return Err(AppError::InvalidArgument("words_per_page must be greater than 0".to_string()));
+    }
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book).ok();
+    Ok(estimate_pages(&text_stats, &book_key, book, package.as_ref(), words_per_page))
+}
+
+#[tauri::command]
+fn get_book_metadata(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    text_stats: tauri::State<TextStatsState>
+) -> Result<BookMetadata, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book).ok();
+    let direction = TextDirection::of(book);
+    let (series, series_index) = match package.as_ref().and_then(|package| package.series()) {
+        Some((series, index)) => (Some(series), index),
+        None => (None, None),
+    };
+
+    Ok(BookMetadata {
+        title: book.mdata("title").unwrap_or(book_key.replace(".epub", "")),
+        creator: library::metadata_values(book, "creator"),
+        publisher: book.mdata("publisher"),
+        language: book.mdata("language"),
+        description: book.mdata("description"),
+        date: book.mdata("date"),
+        identifier: book.unique_identifier.clone().or_else(|| book.mdata("identifier")),
+        series,
+        series_index,
+        tags: library::metadata_values(book, "subject"),
+        rating: package.as_ref().and_then(|package| package.rating()),
+        estimated_pages: estimate_pages(&text_stats, &book_key, book, package.as_ref(), DEFAULT_WORDS_PER_PAGE),
+        audience: package.as_ref().and_then(|package| package.audience()).map(Audience::from),
+        version: package.as_ref().and_then(|package| package.epub_version()),
+        text_direction: if direction.rtl { "rtl" } else { "ltr" }.to_string(),
+        writing_mode: direction.writing_mode.map(str::to_string),
+        book_key,
+    })
+}
+
+/// The book's declared target audience (age range and/or label) for
+/// family-library filtering, or `None` when it declares none.
+#[tauri::command]
+fn get_audience(book_key: String, state: tauri::State<LibraryState>) -> Result<Option<Audience>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    Ok(read_package(book)?.audience().map(Audience::from))
+}
+
+/// Seeks to a whole-book percentage (0–100, weighted by each chapter's text
+/// length) for scrubber dragging. Records the result as the book's current
+/// position and returns the chapter to load plus the fraction to scroll to.
+#[tauri::command]
+fn goto_percent(
+    book_key: String,
+    percent: f64,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    text_stats: tauri::State<TextStatsState>,
+    progress: tauri::State<ProgressState>
+) -> Result<BookLocation, AppError> {
+    if !percent.is_finite() {
+        return Err(AppError::InvalidArgument(format!("Invalid percentage: {}", percent)));
+    }
+    let percent = percent.clamp(0.0, 100.0);
+
+    let (location, position) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        if book.spine.is_empty() {
+            return Err(AppError::InvalidEpub(format!("Book has an empty spine: {}", book_key)));
+        }
+
+        let lengths: Vec<usize> = cached_text_stats(&text_stats, &book_key, book).iter()
+            .map(|stats| stats.chars)
+            .collect();
+        let total: usize = lengths.iter().sum();
+        let target = percent / 100.0 * total as f64;
+
+        // Last chapter with text, so 100% lands at its end rather than on trailing images
+        let mut spine_index = lengths.iter().rposition(|len| *len > 0).unwrap_or(0);
+        let mut scroll_fraction = if total == 0 { 0.0 } else { 1.0 };
+        let mut consumed = 0usize;
+        for (index, len) in lengths.iter().enumerate() {
+            if *len > 0 && (consumed + len) as f64 > target {
+                spine_index = index;
+                scroll_fraction = (target - consumed as f64) / *len as f64;
+                break;
+            }
+            consumed += len;
+        }
+
+        book.set_current_page(spine_index).map_err(|e| AppError::InvalidArgument(e.to_string()))?;
+        let href = book.spine.get(spine_index)
+            .and_then(|id| book.resources.get(id))
+            .map(|(path, _)| path.to_str().unwrap_or("").to_string())
+            .unwrap_or_default();
+
+        let location = BookLocation {
+            spine_index,
+            uri: format!("epub://{}/{}", library::book_host(&book_key), href),
+            href,
+            scroll_fraction,
+        };
+        (location, progress_at(book, spine_index, scroll_fraction))
+    };
+
+    record_progress(&app, &progress, book_key, position)?;
+    Ok(location)
+}
+
+/// What a position locator names the book by: its unique identifier, else
+/// its title, so the same edition matches across devices and file names.
+fn locator_book_id(descriptor: &library::BookDescriptor, book_key: &str) -> String {
+    descriptor.identifier.clone()
+        .or_else(|| descriptor.title.clone())
+        .unwrap_or(book_key.replace(".epub", ""))
+        .trim()
+        .to_string()
+}
+
+/// The saved reading position as a compact string for sharing or syncing,
+/// `<book identifier>#<chapter href>@<fraction>`. Chapters are named by href
+/// rather than spine index so locators survive reordered spines.
+#[tauri::command]
+fn get_position_locator(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>
+) -> Result<String, AppError> {
+    let saved = progress.0.lock().get(&book_key).cloned();
+    let book_id = state.0.lock().descriptor(&book_key)
+        .map(|descriptor| locator_book_id(descriptor, &book_key))
+        .ok_or_else(|| AppError::book_not_found(&book_key))?;
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    let (spine_index, scroll_fraction) = match saved {
+        Some(p) if p.spine_index < book.spine.len() => (p.spine_index, p.scroll_fraction),
+        _ => (0, 0.0),
+    };
+    let href = book.spine.get(spine_index)
+        .and_then(|id| book.resources.get(id))
+        .map(|(path, _)| path.to_str().unwrap_or("").to_string())
+        .ok_or_else(|| AppError::InvalidEpub(format!("Book has an empty spine: {}", book_key)))?;
+
+    Ok(format!("{}#{}@{:.4}", book_id, href, scroll_fraction))
+}
+
+/// Jumps to a locator from `get_position_locator`, recording it as the
+/// book's current position. Fails if the locator is malformed, names
+/// another book or a chapter this book doesn't have.
+#[tauri::command]
+fn goto_locator(
+    book_key: String,
+    locator: String,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>
+) -> Result<BookLocation, AppError> {
+    let invalid = || AppError::InvalidArgument(format!("Invalid locator: {}", locator));
+    let (rest, fraction) = locator.rsplit_once('@').ok_or_else(invalid)?;
+    let (book_id, href) = rest.rsplit_once('#').ok_or_else(invalid)?;
+    let scroll_fraction: f64 = fraction.parse().ok().filter(|f: &f64| f.is_finite()).ok_or_else(invalid)?;
+
+    let (location, position) = {
+        let expected = state.0.lock().descriptor(&book_key)
+            .map(|descriptor| locator_book_id(descriptor, &book_key))
+            .ok_or_else(|| AppError::book_not_found(&book_key))?;
+        if book_id != expected {
+            return Err(AppError::InvalidArgument(format!("Locator is for another book ({}), not {}", book_id, expected)));
+        }
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let spine_index = spine_index_for_path(book, href)
+            .ok_or_else(|| AppError::InvalidArgument(format!("Locator chapter not in this book: {}", href)))?;
+        book.set_current_page(spine_index).map_err(|e| AppError::InvalidArgument(e.to_string()))?;
+
+        let location = BookLocation {
+            spine_index,
+            uri: format!("epub://{}/{}", library::book_host(&book_key), href),
+            href: href.to_string(),
+            scroll_fraction: scroll_fraction.clamp(0.0, 1.0),
+        };
+        (location, progress_at(book, spine_index, scroll_fraction))
+    };
+
+    record_progress(&app, &progress, book_key, position)?;
+    Ok(location)
+}
+
+#[tauri::command]
+fn get_spine(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<String>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    // spine is Vec<String> of resource IDs
+    // Convert to content paths using resources map
+    let spine_paths: Vec<String> = book.spine.iter()
+        .filter_map(|id| {
+            book.resources.get(id).map(|(path, _)| {
+                path.to_str().unwrap_or("").to_string()
+            })
+        })
+        .collect();
+
+    Ok(spine_paths)
+}
+
+/// The spine with each item's manifest entry, so the frontend can tell which
+/// chapters are scripted or contain SVG/MathML before loading them, and
+/// which are outside the linear reading order.
+#[tauri::command]
+fn get_spine_items(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<SpineItem>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+
+    let items = book.spine.iter()
+        .filter_map(|id| spine_item(book, &package, id))
+        .collect();
+
+    Ok(items)
+}
+
+/// The reading order for turning pages: every spine document in order, with
+/// its path, id and whether it's linear. The same items as `get_spine_items`.
+#[tauri::command]
+fn get_book_spine(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<SpineItem>, AppError> {
+    get_spine_items(book_key, state)
+}
+
+fn spine_item(book: &EpubDoc<BufReader<File>>, package: &opf::Package, id: &str) -> Option<SpineItem> {
+    let (path, mime) = book.resources.get(id)?;
+    let properties = package.manifest_item(id)
+        .and_then(|item| item.properties.as_deref())
+        .map(|p| p.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+    Some(SpineItem {
+        id: id.to_string(),
+        href: path.to_str().unwrap_or("").to_string(),
+        media_type: mime.clone(),
+        properties,
+        linear: package.spine.iter().find(|item| item.idref == id).is_none_or(|item| item.linear),
+    })
+}
+
+/// Archive path of the linear spine document after `current_path`, for
+/// turning the page; `None` at the end of the book.
+#[tauri::command]
+fn next_resource(book_key: String, current_path: String, state: tauri::State<LibraryState>) -> Result<Option<String>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    adjacent_linear_resource(book, &current_path, true)
+}
+
+/// Archive path of the linear spine document before `current_path`; `None`
+/// at the start of the book.
+#[tauri::command]
+fn prev_resource(book_key: String, current_path: String, state: tauri::State<LibraryState>) -> Result<Option<String>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    adjacent_linear_resource(book, &current_path, false)
+}
+
+/// The nearest spine document after (or before) `current_path` that's in
+/// the linear reading order, skipping `linear="no"` items. `current_path`
+/// may itself be non-linear, e.g. a note opened from the text.
+fn adjacent_linear_resource(book: &mut library::Book, current_path: &str, forward: bool) -> Result<Option<String>, AppError> {
+    let (path, _) = resolve_href("", current_path);
+    let index = spine_index_for_path(book, &path).ok_or_else(|| AppError::resource_not_found(&path))?;
+    // Without a readable package every item counts as linear
+    let package = read_package(book).ok();
+    let is_linear = |id: &str| {
+        package.as_ref()
+            .and_then(|package| package.spine.iter().find(|item| item.idref == id))
+            .is_none_or(|item| item.linear)
+    };
+
+    let neighbors: Box<dyn Iterator<Item = &String>> = if forward {
+        Box::new(book.spine.iter().skip(index + 1))
+    } else {
+        Box::new(book.spine.iter().take(index).rev())
+    };
+    let neighbor = neighbors
+        .filter(|id| is_linear(id))
+        .find_map(|id| book.resources.get(id))
+        .map(|(path, _)| path.to_string_lossy().into_owned());
+    Ok(neighbor)
+}
+
+/// The spine partitioned by the top level of the TOC, for a two-level
+/// chapter navigator: each part runs from its top-level entry's document to
+/// the next one's. Documents before the first part form a leading group
+/// without a label.
+#[tauri::command]
+fn get_book_parts(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<BookPart>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+
+    // Where each part starts; entries sharing a document (or pointing
+    // outside the spine) don't start a part of their own
+    let mut starts: Vec<(usize, String)> = Vec::new();
+    for item in book_toc(book) {
+        let (path, _) = resolve_href("", &item.content);
+        if let Some(index) = spine_index_for_path(book, &path) {
+            if !starts.iter().any(|(start, _)| *start == index) {
+                starts.push((index, item.label.trim().to_string()));
+            }
+        }
+    }
+    starts.sort_by_key(|(index, _)| *index);
+
+    let mut parts: Vec<BookPart> = Vec::new();
+    for (spine_index, id) in book.spine.iter().enumerate() {
+        let Some(item) = spine_item(book, &package, id) else { continue };
+        match starts.iter().find(|(start, _)| *start == spine_index) {
+            Some((_, label)) => parts.push(BookPart { part_label: Some(label.clone()), chapters: vec![item] }),
+            None => match parts.last_mut() {
+                Some(part) => part.chapters.push(item),
+                None => parts.push(BookPart { part_label: None, chapters: vec![item] }),
+            },
+        }
+    }
+
+    Ok(parts)
+}
+
+/// Spine documents with active content: those the manifest marks `scripted`
+/// and those whose markup contains `<script>` elements, with the reason for each.
+#[tauri::command]
+fn list_scripted_chapters(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<ScriptedChapter>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+
+    let spine: Vec<(usize, String, PathBuf, String)> = book.spine.iter()
+        .enumerate()
+        .filter_map(|(spine_index, id)| book.resources.get(id).map(|(path, mime)| (spine_index, id.clone(), path.clone(), mime.clone())))
+        .collect();
+
+    let mut chapters = Vec::new();
+    for (spine_index, id, path, mime) in spine {
+        let mut reasons = Vec::new();
+        if package.manifest_item(&id).map(|item| item.has_property("scripted")).unwrap_or(false) {
+            reasons.push("manifest".to_string());
+        }
+        if is_html_mime(&mime) || mime == "image/svg+xml" {
+            let has_script = book.get_resource_by_path(&path)
+                .map(|bytes| {
+                    html::tokenize(&html::decode_document(&bytes))
+                        .any(|(token, _)| matches!(token, html::Token::StartTag(tag) if tag.is("script")))
+                })
+                .unwrap_or(false);
+            if has_script {
+                reasons.push("inline-script".to_string());
+            }
+        }
+
+        if !reasons.is_empty() {
+            chapters.push(ScriptedChapter {
+                spine_index,
+                href: path.to_str().unwrap_or("").to_string(),
+                reasons,
+            });
+        }
+    }
+
+    Ok(chapters)
+}
+
+/// EPUB features the book uses that the reader doesn't fully handle, with a
+/// short explanation of each, so the frontend can warn before opening it.
+/// Detected from the manifest, the package metadata and `encryption.xml`.
+#[tauri::command]
+fn get_unsupported_features(
+    book_key: String,
+    state: tauri::State<LibraryState>
+) -> Result<Vec<UnsupportedFeature>, AppError> {
+    let package = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        read_package(book)?
+    };
+    let algorithms = encryption_algorithms(&mut open_book_archive(&state, &book_key)?);
+
+    let mut features = Vec::new();
+    let mut report = |feature: &str, explanation: &str| {
+        features.push(UnsupportedFeature { feature: feature.to_string(), explanation: explanation.to_string() });
+    };
+
+    if package.manifest.iter().any(|item| item.media_type == "application/smil+xml") {
+        report("media-overlays", "Read-aloud narration synchronized with the text isn't played.");
+    }
+    if package.manifest.iter().any(|item| item.has_property("scripted")) {
+        report("scripted-content", "Some chapters rely on JavaScript, which may not run as the book expects.");
+    }
+    if algorithms.iter().any(|algorithm| !FONT_OBFUSCATION_ALGORITHMS.contains(&algorithm.as_str())) {
+        report("encryption", "Some content is encrypted (DRM) and can't be displayed.");
+    }
+    let remote = package.manifest.iter().any(|item| {
+        item.has_property("remote-resources") || is_external_href(&item.href)
+    });
+    if remote {
+        report("remote-resources", "Some content is loaded from the web and is missing offline.");
+    }
+    if package.property("rendition:layout").map(str::trim) == Some("pre-paginated") {
+        report("fixed-layout", "Pages are laid out for a fixed screen size and may not scale well.");
+    }
+
+    Ok(features)
+}
+
+#[tauri::command]
+fn get_current_spine_index(
+    book_key: String,
+    content_path: String,
+    state: tauri::State<LibraryState>
+) -> Result<Option<usize>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    // Find index in spine where resource path matches content_path
+    let index = book.spine.iter().position(|id| {
+        book.resources.get(id)
+            .map(|(path, _)| path.to_str().unwrap_or("") == content_path)
+            .unwrap_or(false)
+    });
+
+    Ok(index)
+}
+
+#[tauri::command]
+fn get_spine_item(
+    book_key: String,
+    index: usize,
+    state: tauri::State<LibraryState>
+) -> Result<Option<String>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    if let Some(resource_id) = book.spine.get(index) {
+        if let Some((path, _)) = book.resources.get(resource_id) {
+            return Ok(Some(path.to_str().unwrap_or("").to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Parses the book's OPF package document.
+fn read_package(book: &mut EpubDoc<BufReader<File>>) -> Result<opf::Package, AppError> {
+    let root_file = book.root_file.clone();
+    let xml = book.get_resource_by_path(&root_file)
+        .map_err(|e| AppError::InvalidEpub(format!("Failed to read package document: {}", e)))?;
+    opf::Package::parse(&xml).map_err(AppError::InvalidEpub)
+}
+
+/// One narrated fragment of a media overlay.
+#[derive(Serialize)]
+struct MediaOverlayClip {
+    /// Id of the element in the chapter the audio reads
+    text_fragment_id: String,
+    /// `epub://` URI of the audio file; `None` for text with no narration
+    audio_src: Option<String>,
+    /// Start and end within the audio file in seconds; `None` for its start
+    /// and end respectively
+    clip_begin: Option<f64>,
+    clip_end: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct MediaOverlay {
+    /// Archive path of the SMIL document
+    href: String,
+    /// Class the book asks to be set on the fragment being read
+    /// (`media:active-class`)
+    active_class: Option<String>,
+    /// In playback order
+    clips: Vec<MediaOverlayClip>,
+}
+
+/// The read-along narration of a spine item: its media overlay's text
+/// fragments with the audio clips that read them. `None` when the item has
+/// no overlay.
+#[tauri::command]
+fn get_media_overlay(
+    book_key: String,
+    spine_index: usize,
+    state: tauri::State<LibraryState>
+) -> Result<Option<MediaOverlay>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let id = book.spine.get(spine_index).cloned()
+        .ok_or_else(|| AppError::InvalidArgument(format!("Spine index out of range: {}", spine_index)))?;
+    let package = read_package(book)?;
+    let Some(overlay_id) = package.manifest_item(&id).and_then(|item| item.media_overlay.clone()) else {
+        return Ok(None);
+    };
+    let (path, _) = book.resources.get(&overlay_id).cloned()
+        .ok_or_else(|| AppError::InvalidEpub(format!("Media overlay not in the manifest: {}", overlay_id)))?;
+    let href = path.to_string_lossy().into_owned();
+    let xml = book.get_resource_by_path(&href).map_err(|_| AppError::resource_not_found(&href))?;
+    let pars = smil::parse(&xml).map_err(AppError::InvalidEpub)?;
+
+    let clips = pars.into_iter()
+        .filter_map(|par| {
+            let (_, fragment) = resolve_href(&href, &par.text_src);
+            Some(MediaOverlayClip {
+                text_fragment_id: fragment.filter(|fragment| !fragment.is_empty())?,
+                audio_src: par.audio_src.map(|src| {
+                    let (audio_path, _) = resolve_href(&href, &src);
+                    format!("epub://{}/{}", library::book_host(&book_key), audio_path)
+                }),
+                clip_begin: par.clip_begin,
+                clip_end: par.clip_end,
+            })
+        })
+        .collect();
+    Ok(Some(MediaOverlay {
+        href,
+        active_class: package.property("media:active-class").map(str::to_string),
+        clips,
+    }))
+}
+
+/// The book's OPF package document as stored, for diagnosing metadata and
+/// spine problems.
+#[tauri::command]
+fn get_package_document(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let root_file = book.root_file.to_string_lossy().into_owned();
+    let xml = book.get_resource_by_path(&root_file).map_err(|_| AppError::resource_not_found(&root_file))?;
+    Ok(String::from_utf8_lossy(&xml).into_owned())
+}
+
+/// The book's `META-INF/container.xml` as stored.
+#[tauri::command]
+fn get_container_xml(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    let mut archive = open_book_archive(&state, &book_key)?;
+    let xml = read_archive_entry(&mut archive, "META-INF/container.xml")
+        .ok_or_else(|| AppError::resource_not_found("META-INF/container.xml"))?;
+    Ok(String::from_utf8_lossy(&xml).into_owned())
+}
+
+#[tauri::command]
+fn get_book_identifiers(book_key: String, state: tauri::State<LibraryState>) -> Result<BookIdentifiers, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+
+    let identifiers: Vec<BookIdentifier> = package.elements("identifier")
+        .filter(|element| !element.text.is_empty())
+        .map(|element| BookIdentifier {
+            id: element.attr("id").map(str::to_string),
+            scheme: package.identifier_scheme(element),
+            value: element.text.clone(),
+        })
+        .collect();
+
+    let unique_identifier = package.unique_identifier.as_deref().and_then(|unique_id| {
+        identifiers.iter()
+            .find(|identifier| identifier.id.as_deref() == Some(unique_id))
+            .map(|identifier| BookIdentifier {
+                id: identifier.id.clone(),
+                scheme: identifier.scheme.clone(),
+                value: identifier.value.clone(),
+            })
+    });
+
+    Ok(BookIdentifiers { unique_identifier, identifiers })
+}
+
+fn is_font_resource(path: &std::path::Path, mime: &str) -> bool {
+    let mime = mime.to_ascii_lowercase();
+    if mime.starts_with("font/") || mime.contains("font-") || mime.contains("opentype") || mime.contains("truetype") {
+        return true;
+    }
+    matches!(
+        path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref(),
+        Some("ttf" | "otf" | "ttc" | "woff" | "woff2")
+    )
+}
+
+/// Family name of a TrueType/OpenType font, preferring the typographic
+/// family over the legacy (style-linked) family name.
+fn font_family_name(data: &[u8]) -> Option<String> {
+    let face = ttf_parser::Face::parse(data, 0).ok()?;
+    let names: Vec<ttf_parser::name::Name> = face.names().into_iter().collect();
+    [ttf_parser::name_id::TYPOGRAPHIC_FAMILY, ttf_parser::name_id::FAMILY]
+        .iter()
+        .find_map(|id| {
+            names.iter()
+                .filter(|name| name.name_id == *id)
+                .find_map(|name| name.to_string())
+                .filter(|family| !family.trim().is_empty())
+        })
+}
+
+#[tauri::command]
+async fn list_embedded_fonts(state: tauri::State<'_, LibraryState>) -> Result<Vec<BookFonts>, AppError> {
+    let books = Arc::clone(&state.0);
+    run_blocking(move || {
+        let mut result: Vec<BookFonts> = Library::filter_map_books(&books, |descriptor| descriptor.has_fonts, |book_key, book| {
+            let mut font_paths: Vec<(PathBuf, String)> = book.resources.values()
+                .filter(|(path, mime)| is_font_resource(path, mime))
+                .cloned()
+                .collect();
+            font_paths.sort();
+
+            let fonts = font_paths.into_iter()
+                .filter_map(|(path, mime)| {
+                    let mut data = book.get_resource_by_path(&path).ok()?;
+                    deobfuscate_font(book, path.to_str().unwrap_or(""), &mut data);
+                    Some(EmbeddedFont {
+                        path: path.to_str().unwrap_or("").to_string(),
+                        mime,
+                        size: data.len(),
+                        family: font_family_name(&data),
+                    })
+                })
+                .collect();
+
+            Some(BookFonts {
+                book_key: book_key.clone(),
+                title: book.mdata("title").unwrap_or(book_key.replace(".epub", "")),
+                fonts,
+            })
+        });
+        // Books whose manifest lists no fonts aren't opened
+        result.extend(books.lock().descriptors()
+            .filter(|(_, descriptor)| !descriptor.has_fonts)
+            .map(|(book_key, descriptor)| BookFonts {
+                book_key: book_key.clone(),
+                title: descriptor.title.clone().unwrap_or(book_key.replace(".epub", "")),
+                fonts: Vec::new(),
+            }));
+
+        result.sort_by(|a, b| a.book_key.cmp(&b.book_key));
+        Ok(result)
+    }).await
+}
+
+/// The file the book was loaded from, which may be in a subdirectory of
+/// the library.
+fn book_file_path(state: &LibraryState, book_key: &str) -> Result<PathBuf, AppError> {
+    state.0.lock().path(book_key)
+        .map(|path| path.to_path_buf())
+        .ok_or_else(|| AppError::book_not_found(book_key))
+}
+
+/// Stored entries smaller than this aren't worth flagging.
+const MIN_RECOMPRESS_SIZE: u64 = 1024;
+
+/// Resources that deflate well: markup, styles, scripts and uncompressed fonts.
+fn is_compressible_entry(name: &str) -> bool {
+    let extension = name.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default();
+    matches!(
+        extension.as_str(),
+        "xhtml" | "html" | "htm" | "xml" | "opf" | "ncx" | "css" | "js" | "svg" | "txt" | "json" | "smil" | "ttf" | "otf"
+    )
+}
+
+/// Size of `data` after deflating it at the default level.
+fn deflated_size(data: &[u8]) -> Result<u64, AppError> {
+    use std::io::Write;
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).map_err(|e| AppError::Other(e.to_string()))?;
+    Ok(encoder.finish().map_err(|e| AppError::Other(e.to_string()))?.len() as u64)
+}
+
+#[derive(Serialize)]
+struct ResourceInfo {
+    /// Manifest id
+    id: String,
+    /// Archive path
+    path: String,
+    mime_type: String,
+    /// Uncompressed size from the archive directory; `None` when the entry
+    /// is missing from the archive
+    size: Option<u64>,
+}
+
+/// Every manifest resource of a book, sorted by path. Sizes come from the
+/// archive's directory, so nothing is decompressed.
+#[tauri::command]
+fn list_resources(book_key: String, state: tauri::State<LibraryState>) -> Result<Vec<ResourceInfo>, AppError> {
+    let book_path = state.0.lock().path(&book_key).map(|path| path.to_path_buf());
+    let mut archive = book_path
+        .and_then(|path| File::open(path).ok())
+        .and_then(|file| zip::ZipArchive::new(BufReader::new(file)).ok());
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+
+    let mut resources: Vec<ResourceInfo> = book.resources.iter()
+        .map(|(id, (path, mime_type))| {
+            let path = path.to_string_lossy().into_owned();
+            let size = archive.as_mut().and_then(|archive| archive.by_name(&path).ok().map(|entry| entry.size()));
+            ResourceInfo { id: id.clone(), path, mime_type: mime_type.clone(), size }
+        })
+        .collect();
+    resources.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(resources)
+}
+
+/// Writes one resource of a book (an image, stylesheet, font, ...) to
+/// `out_path` exactly as stored in the archive. `resource_path` is resolved
+/// like an `epub://` request: an archive path, percent-encoded or not, a
+/// virtual path or the whole `epub://` URI.
+#[tauri::command]
+fn export_resource(
+    book_key: String,
+    resource_path: String,
+    out_path: String,
+    state: tauri::State<LibraryState>,
+    virtual_paths: tauri::State<VirtualPathState>
+) -> Result<(), AppError> {
+    let out = std::path::Path::new(&out_path);
+    if let Some(parent) = out.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if !parent.is_dir() {
+            return Err(AppError::InvalidArgument(format!("Directory does not exist: {}", parent.display())));
+        }
+    }
+
+    let bytes = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let epub_prefix = format!("epub://{}/", library::book_host(&book_key));
+        let requested = resource_path.strip_prefix(&epub_prefix).unwrap_or(&resource_path);
+        let requested = requested.strip_prefix('/').unwrap_or(requested);
+        let path = archive_path_for_request(book, &cached_virtual_paths(&virtual_paths.0, &book_key, book), requested);
+        book.get_resource_by_path(&path).map_err(|_| AppError::resource_not_found(&path))?
+    };
+    std::fs::write(out, bytes).map_err(|e| AppError::Io(format!("Failed to write {}: {}", out_path, e)))
+}
+
+/// Storage report for one book: overall compression ratio, the largest
+/// resources (`limit`, default 20) and text resources stored uncompressed
+/// that deflate would shrink. Informational only; nothing is rewritten.
+#[tauri::command]
+fn analyze_compression(
+    book_key: String,
+    limit: Option<usize>,
+    state: tauri::State<LibraryState>
+) -> Result<CompressionReport, AppError> {
+    use std::io::Read;
+
+    let file_size = std::fs::metadata(book_file_path(&state, &book_key)?).map(|m| m.len()).unwrap_or(0);
+    let mut archive = open_book_archive(&state, &book_key)?;
+
+    let mut resources = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| AppError::InvalidEpub(format!("Invalid EPUB archive: {}", e)))?;
+        if entry.is_dir() {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let stored = entry.compression() == zip::CompressionMethod::Stored;
+
+        // The mimetype entry must stay stored, as the first entry of the archive
+        let recompressible = stored
+            && name != "mimetype"
+            && entry.size() >= MIN_RECOMPRESS_SIZE
+            && is_compressible_entry(&name);
+        let mut potential_savings = 0;
+        if recompressible {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).map_err(|e| AppError::InvalidEpub(format!("Failed to read {}: {}", name, e)))?;
+            potential_savings = entry.size().saturating_sub(deflated_size(&data)?);
+        }
+
+        resources.push(ResourceCompression {
+            path: name,
+            method: entry.compression().to_string().to_lowercase(),
+            compressed_size: entry.compressed_size(),
+            uncompressed_size: entry.size(),
+            recompressible: recompressible && potential_savings > 0,
+            potential_savings,
+        });
+    }
+
+    let compressed_size: u64 = resources.iter().map(|r| r.compressed_size).sum();
+    let uncompressed_size: u64 = resources.iter().map(|r| r.uncompressed_size).sum();
+    let potential_savings: u64 = resources.iter().map(|r| r.potential_savings).sum();
+
+    resources.sort_by_key(|r| std::cmp::Reverse(r.uncompressed_size));
+    resources.truncate(limit.unwrap_or(20));
+
+    Ok(CompressionReport {
+        file_size,
+        compressed_size,
+        uncompressed_size,
+        ratio: if uncompressed_size == 0 { 1.0 } else { compressed_size as f64 / uncompressed_size as f64 },
+        potential_savings,
+        largest: resources,
+    })
+}
+
+/// Milliseconds since the Unix epoch of a zip entry's MS-DOS timestamp.
+fn zip_time_millis(time: zip::DateTime) -> u64 {
+    // Days from 1970-01-01 to the civil date (Howard Hinnant's algorithm)
+    let (month, day) = (time.month() as i64, time.day() as i64);
+    let year = time.year() as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    seconds.max(0) as u64 * 1000
+}
+
+/// Resources whose zip entries were modified after `timestamp` (milliseconds
+/// since the Unix epoch), for incremental export of books edited in place.
+/// Archives whose entries all share one time, or carry the MS-DOS epoch or
+/// times in the future, have no usable timestamps and report everything.
+#[tauri::command]
+fn resources_changed_since(
+    book_key: String,
+    timestamp: u64,
+    state: tauri::State<LibraryState>
+) -> Result<ResourceChanges, AppError> {
+    if !state.0.lock().contains_key(&book_key) {
+        return Err(AppError::book_not_found(&book_key));
+    }
+
+    let mut archive = open_book_archive(&state, &book_key)?;
+    let mut resources = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index).map_err(|e| AppError::InvalidEpub(format!("Invalid EPUB archive: {}", e)))?;
+        if entry.is_dir() {
+            continue;
+        }
+        resources.push(ChangedResource {
+            path: entry.name().to_string(),
+            modified: zip_time_millis(entry.last_modified()),
+        });
+    }
+
+    let dos_epoch = zip_time_millis(zip::DateTime::default());
+    // Zip times are local time read as UTC, so allow a day of skew
+    let latest_plausible = now_millis() + 24 * 60 * 60 * 1000;
+    let timestamps_reliable = resources.iter().all(|r| r.modified > dos_epoch && r.modified <= latest_plausible)
+        && (resources.len() < 2 || resources.iter().any(|r| r.modified != resources[0].modified));
+    if timestamps_reliable {
+        resources.retain(|r| r.modified > timestamp);
+    }
+
+    Ok(ResourceChanges { timestamps_reliable, resources })
+}
+
+type BookArchive = zip::ZipArchive<BufReader<File>>;
+
+fn open_book_archive(state: &LibraryState, book_key: &str) -> Result<BookArchive, AppError> {
+    let path = book_file_path(state, book_key)?;
+    let file = File::open(&path).map_err(|e| AppError::Io(format!("Failed to open {}: {}", path.display(), e)))?;
+    zip::ZipArchive::new(BufReader::new(file)).map_err(|e| AppError::InvalidEpub(format!("Invalid EPUB archive: {}", e)))
+}
+
+fn read_archive_entry(archive: &mut BookArchive, name: &str) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    let mut entry = archive.by_name(name).ok()?;
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data).ok()?;
+    Some(data)
+}
+
+/// Quick feedback for authors: a pragmatic subset of EPUBCheck's rules
+/// covering the `mimetype` entry, `container.xml`, manifest/spine consistency
+/// and the declared EPUB version against the features it requires. An empty
+/// list means no problems were found, not full conformance.
+#[tauri::command]
+fn check_conformance(
+    book_key: String,
+    state: tauri::State<LibraryState>
+) -> Result<Vec<ConformanceIssue>, AppError> {
+    let fallback_root_file = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        book.root_file.to_str().unwrap_or("").to_string()
+    };
+
+    let mut archive = open_book_archive(&state, &book_key)?;
+    let mut issues = Vec::new();
+    let mut report = |severity: Severity, rule: &'static str, message: String| {
+        issues.push(ConformanceIssue { severity, rule, message });
+    };
+
+    let entry_names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    let in_archive = |path: &str| entry_names.iter().any(|name| name == path);
+
+    // mimetype: first entry, stored, exact content
+    match archive.by_index_raw(0) {
+        Ok(first) if first.name() == "mimetype" => {
+            if first.compression() != zip::CompressionMethod::Stored {
+                report(Severity::Error, "mimetype-stored", "The mimetype entry must be stored without compression".to_string());
+            }
+        }
+        _ if in_archive("mimetype") => {
+            report(Severity::Error, "mimetype-first", "The mimetype entry must be the first entry in the archive".to_string());
+        }
+        _ => report(Severity::Error, "mimetype-missing", "The archive has no mimetype entry".to_string()),
+    }
+    if let Some(content) = read_archive_entry(&mut archive, "mimetype") {
+        if content != b"application/epub+zip" {
+            report(
+                Severity::Error,
+                "mimetype-content",
+                format!("The mimetype entry must contain exactly \"application/epub+zip\", found {:?}", String::from_utf8_lossy(&content)),
+            );
+        }
+    }
+
+    // container.xml and its rootfile
+    let mut root_file = None;
+    match read_archive_entry(&mut archive, "META-INF/container.xml") {
+        None => report(Severity::Error, "container-missing", "META-INF/container.xml is missing".to_string()),
+        Some(xml) => match opf::container_rootfiles(&xml) {
+            Err(e) => report(Severity::Error, "container-invalid", e),
+            Ok(rootfiles) => match rootfiles.into_iter().next() {
+                None => report(Severity::Error, "container-rootfile", "container.xml declares no rootfile".to_string()),
+                Some((full_path, media_type)) => {
+                    if !in_archive(&full_path) {
+                        report(Severity::Error, "container-rootfile", format!("Rootfile {} does not exist in the archive", full_path));
+                    } else {
+                        root_file = Some(full_path);
+                    }
+                    if media_type != "application/oebps-package+xml" {
+                        report(
+                            Severity::Warning,
+                            "container-rootfile",
+                            format!("Rootfile media-type should be application/oebps-package+xml, found {:?}", media_type),
+                        );
+                    }
+                }
+            },
+        },
+    }
+
+    let root_file = root_file.unwrap_or(fallback_root_file);
+    let package = match read_archive_entry(&mut archive, &root_file).map(|xml| opf::Package::parse(&xml)) {
+        Some(Ok(package)) => package,
+        Some(Err(e)) => {
+            report(Severity::Error, "package-invalid", e);
+            return Ok(issues);
+        }
+        None => {
+            report(Severity::Error, "package-missing", format!("Package document {} could not be read", root_file));
+            return Ok(issues);
+        }
+    };
+
+    // Manifest: unique ids, every local href present
+    let mut manifest_paths = Vec::new();
+    for (index, item) in package.manifest.iter().enumerate() {
+        if item.id.is_empty() {
+            report(Severity::Error, "manifest-id", format!("Manifest item {} has no id", item.href));
+        } else if package.manifest[..index].iter().any(|other| other.id == item.id) {
+            report(Severity::Error, "manifest-id", format!("Duplicate manifest id {}", item.id));
+        }
+        if is_external_href(&item.href) {
+            continue;
+        }
+        let (path, _) = resolve_href(&root_file, &item.href);
+        if !in_archive(&path) {
+            report(Severity::Error, "manifest-href", format!("Manifest item {} refers to missing file {}", item.id, path));
+        }
+        manifest_paths.push(path);
+    }
+    for name in &entry_names {
+        let is_container_file = name == "mimetype" || name.starts_with("META-INF/") || *name == root_file;
+        if !name.ends_with('/') && !is_container_file && !manifest_paths.contains(name) {
+            report(Severity::Warning, "manifest-unlisted", format!("{} is in the archive but not in the manifest", name));
+        }
+    }
+
+    // Spine: non-empty, every idref in the manifest, content documents only
+    if package.spine.is_empty() {
+        report(Severity::Error, "spine-empty", "The spine has no itemrefs".to_string());
+    }
+    for idref in package.spine.iter().map(|item| &item.idref) {
+        match package.manifest_item(idref) {
+            None => report(Severity::Error, "spine-idref", format!("Spine itemref {} is not in the manifest", idref)),
+            Some(item) if item.media_type != "application/xhtml+xml" && item.media_type != "image/svg+xml" => {
+                report(
+                    Severity::Warning,
+                    "spine-content-type",
+                    format!("Spine item {} has media-type {}, which needs a content document fallback", idref, item.media_type),
+                );
+            }
+            Some(_) => {}
+        }
+    }
+
+    // Declared version vs the features it requires
+    let version = package.version.clone().unwrap_or_default();
+    if version.starts_with('3') {
+        if !package.manifest.iter().any(|item| item.has_property("nav")) {
+            report(Severity::Error, "nav-missing", "EPUB 3 requires a navigation document (manifest item with properties=\"nav\")".to_string());
+        }
+        if package.property("dcterms:modified").map(str::is_empty).unwrap_or(true) {
+            report(Severity::Error, "modified-missing", "EPUB 3 requires a dcterms:modified meta".to_string());
+        }
+    } else if version.starts_with('2') {
+        let has_ncx = package.spine_toc.as_deref()
+            .and_then(|toc| package.manifest_item(toc))
+            .map(|item| item.media_type == "application/x-dtbncx+xml")
+            .unwrap_or(false);
+        if !has_ncx {
+            report(Severity::Error, "ncx-missing", "EPUB 2 requires an NCX referenced by the spine toc attribute".to_string());
+        }
+        let uses_epub3 = package.manifest.iter().any(|item| item.properties.is_some())
+            || package.elements("meta").any(|m| m.attr("property").is_some());
+        if uses_epub3 {
+            report(
+                Severity::Warning,
+                "version-features",
+                "Package declares EPUB 2.0 but uses EPUB 3 properties".to_string(),
+            );
+        }
+    } else {
+        report(Severity::Error, "package-version", format!("Unsupported package version {:?}", version));
+    }
+
+    Ok(issues)
+}
+
+/// Health check for any EPUB file, in the library or not: whether it opens,
+/// and has a container, a reachable package document, a non-empty spine
+/// and a cover. Problems with the book are reported rather than returned as
+/// errors, so the report also explains why a book didn't load.
+#[tauri::command]
+fn validate_epub(path: String) -> Result<ValidationReport, AppError> {
+    let source = PathBuf::from(&path);
+    let file = File::open(&source).map_err(|e| AppError::Io(format!("Failed to open {}: {}", path, e)))?;
+
+    let mut issues = Vec::new();
+    let package = validate_archive(file, &mut issues);
+    if !source.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("epub")) {
+        issues.push(ConformanceIssue {
+            severity: Severity::Warning,
+            rule: "file-extension",
+            message: "The file name doesn't end in .epub, so the library won't pick it up".to_string(),
+        });
+    }
+    if let Some(problem) = drm_problem(&source) {
+        issues.push(ConformanceIssue { severity: Severity::Error, rule: "drm", message: problem });
+    }
+
+    let mut valid = !issues.iter().any(|issue| matches!(issue.severity, Severity::Error));
+    // The epub crate is stricter in places than the checks above
+    if valid {
+        if let Err(e) = EpubDoc::new(&source) {
+            issues.push(ConformanceIssue { severity: Severity::Error, rule: "open-failed", message: format!("The book could not be opened: {}", e) });
+            valid = false;
+        }
+    }
+
+    Ok(ValidationReport { valid, version: package.and_then(|package| package.epub_version()), issues })
+}
+
+/// The structural checks of `validate_epub`, stopping at the first problem
+/// that leaves nothing further to check. Returns the package if it could be
+/// read.
+fn validate_archive(file: File, issues: &mut Vec<ConformanceIssue>) -> Option<opf::Package> {
+    let mut report = |severity: Severity, rule: &'static str, message: String| {
+        issues.push(ConformanceIssue { severity, rule, message });
+    };
+
+    let mut archive = match zip::ZipArchive::new(BufReader::new(file)) {
+        Ok(archive) => archive,
+        Err(e) => {
+            report(Severity::Error, "archive-invalid", format!("Not a zip archive: {}", e));
+            return None;
+        }
+    };
+    let entry_names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    let in_archive = |path: &str| entry_names.iter().any(|name| name == path);
+
+    let Some(container) = read_archive_entry(&mut archive, "META-INF/container.xml") else {
+        report(Severity::Error, "container-missing", "META-INF/container.xml is missing".to_string());
+        return None;
+    };
+    let root_file = match opf::container_rootfiles(&container).map(|rootfiles| rootfiles.into_iter().next()) {
+        Ok(Some((full_path, _))) => full_path,
+        Ok(None) => {
+            report(Severity::Error, "container-rootfile", "container.xml declares no rootfile".to_string());
+            return None;
+        }
+        Err(e) => {
+            report(Severity::Error, "container-invalid", e);
+            return None;
+        }
+    };
+    let package = match read_archive_entry(&mut archive, &root_file).map(|xml| opf::Package::parse(&xml)) {
+        Some(Ok(package)) => package,
+        Some(Err(e)) => {
+            report(Severity::Error, "package-invalid", e);
+            return None;
+        }
+        None => {
+            report(Severity::Error, "package-missing", format!("Package document {} does not exist in the archive", root_file));
+            return None;
+        }
+    };
+
+    if package.spine.is_empty() {
+        report(Severity::Error, "spine-empty", "The spine has no itemrefs, so there is nothing to read".to_string());
+    }
+    for idref in package.spine.iter().map(|item| &item.idref) {
+        match package.manifest_item(idref) {
+            None => report(Severity::Warning, "spine-idref", format!("Spine itemref {} is not in the manifest; the chapter will be missing", idref)),
+            Some(item) => {
+                let (path, _) = resolve_href(&root_file, &item.href);
+                if !in_archive(&path) {
+                    report(Severity::Warning, "spine-missing", format!("Spine item {} refers to missing file {}", idref, path));
+                }
+            }
+        }
+    }
+
+    // EPUB 3 marks the cover in the manifest, EPUB 2 with a cover meta
+    let cover = package.manifest.iter().find(|item| item.has_property("cover-image")).or_else(|| {
+        package.elements("meta")
+            .find(|meta| meta.attr("name") == Some("cover"))
+            .and_then(|meta| meta.attr("content"))
+            .and_then(|id| package.manifest_item(id))
+    });
+    match cover {
+        None => report(Severity::Warning, "cover-missing", "No cover image is declared".to_string()),
+        Some(item) => {
+            let (path, _) = resolve_href(&root_file, &item.href);
+            if !in_archive(&path) {
+                report(Severity::Warning, "cover-missing", format!("Cover {} refers to missing file {}", item.id, path));
+            } else if !item.media_type.starts_with("image/") {
+                report(Severity::Warning, "cover-type", format!("Cover {} has media-type {}, not an image", item.id, item.media_type));
+            }
+        }
+    }
+
+    Some(package)
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Near the end of the last chapter, where a book counts as read.
+fn is_at_end(spine_len: usize, spine_index: usize, scroll_fraction: f64) -> bool {
+    spine_index + 1 >= spine_len && scroll_fraction >= 0.95
+}
+
+/// Characters of text kept around a saved position to find it again.
+const ANCHOR_CONTEXT_CHARS: usize = 80;
+
+/// Hash of the spine's document paths in order (see `library::fnv1a`),
+/// which changes when chapters are added, removed, renamed or reordered.
+fn spine_signature(book: &library::Book) -> String {
+    let hash = library::fnv1a(book.spine.iter()
+        .filter_map(|id| book.resources.get(id))
+        .flat_map(|(path, _)| path.to_string_lossy().into_owned().into_bytes().into_iter().chain([b'\n'])));
+    format!("{:016x}", hash)
+}
+
+/// Whole words of `text` from around the character offset `at`.
+fn context_at(text: &str, at: usize) -> Option<String> {
+    let chars = text.chars().count();
+    let start = at.min(chars.saturating_sub(ANCHOR_CONTEXT_CHARS));
+    let window: String = text.chars().skip(start).take(ANCHOR_CONTEXT_CHARS).collect();
+    let mut words: Vec<&str> = window.split_whitespace().collect();
+    // Drop words the window cut into
+    if words.len() > 4 {
+        if start > 0 {
+            words.remove(0);
+        }
+        if start + ANCHOR_CONTEXT_CHARS < chars {
+            words.pop();
+        }
+    }
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+/// The position as saved progress, with what it points at.
+fn progress_at(book: &mut library::Book, spine_index: usize, scroll_fraction: f64) -> Progress {
+    let scroll_fraction = scroll_fraction.clamp(0.0, 1.0);
+    let chapter = book.spine.get(spine_index).and_then(|id| book.resources.get(id)).cloned();
+    let context = chapter.as_ref()
+        .filter(|(_, mime)| is_html_mime(mime))
+        .and_then(|(path, _)| book.get_resource_by_path(path).ok())
+        .and_then(|bytes| {
+            let text = html::extract_text(&html::decode_document(&bytes));
+            let at = (text.chars().count() as f64 * scroll_fraction) as usize;
+            context_at(&text, at)
+        });
+
+    Progress {
+        spine_index,
+        scroll_fraction,
+        updated_at: now_millis(),
+        anchor: PositionAnchor {
+            spine_href: chapter.map(|(path, _)| path.to_str().unwrap_or("").to_string()),
+            context,
+            spine_signature: Some(spine_signature(book)),
+        },
+    }
+}
+
+fn record_progress(
+    app: &tauri::AppHandle,
+    progress: &ProgressState,
+    book_key: String,
+    position: Progress,
+) -> Result<(), AppError> {
+    let mut progress = progress.0.lock();
+    progress.insert(book_key, position);
+    save_json_file(&config_file_path(app, PROGRESS_FILE)?, &*progress)
+}
+
+/// Saves the reader's position. `reached_end` is set by the reader when it
+/// turns past the book's last page, which marks the book finished if the
+/// position is at the end; merely saving a position there (or jumping to
+/// it) doesn't.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn save_progress(
+    book_key: String,
+    spine_index: usize,
+    scroll_fraction: f64,
+    reached_end: Option<bool>,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>,
+    finished: tauri::State<FinishedState>
+) -> Result<(), AppError> {
+    let (spine_len, position) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        (book.spine.len(), progress_at(book, spine_index, scroll_fraction))
+    };
+
+    if reached_end == Some(true) && is_at_end(spine_len, position.spine_index, position.scroll_fraction) {
+        let mut finished = finished.0.lock();
+        if finished.insert(book_key.clone()) {
+            save_json_file(&config_file_path(&app, FINISHED_FILE)?, &*finished)?;
+        }
+    }
+    record_progress(&app, &progress, book_key.clone(), position)?;
+    record_opened(&app, &book_key)
+}
+
+/// Marks the book as opened just now.
+fn record_opened(app: &tauri::AppHandle, book_key: &str) -> Result<(), AppError> {
+    let recents = app.state::<RecentsState>();
+    let mut recents = recents.0.lock();
+    recents.entry(book_key.to_string()).or_default().last_opened_at = Some(now_millis());
+    save_json_file(&config_file_path(app, RECENTS_FILE)?, &*recents)
+}
+
+/// Brings the recents in line with the library after a scan: books seen for
+/// the first time get their file's modification time as `added_at`, and
+/// books no longer in the library are dropped. Left alone while the library
+/// directory is missing (an unmounted drive, say), so its books keep their
+/// dates until it's back.
+fn sync_recents(app: &tauri::AppHandle) {
+    if app.state::<LibraryDirState>().0.lock().as_deref().is_some_and(|directory| !directory.is_dir()) {
+        return;
+    }
+
+    let books = app.state::<LibraryState>();
+    let books = books.0.lock();
+    let recents = app.state::<RecentsState>();
+    let mut recents = recents.0.lock();
+    let before = recents.len();
+    recents.retain(|key, _| books.contains_key(key));
+    let mut changed = recents.len() != before;
+    for key in books.keys() {
+        let dates = recents.entry(key.clone()).or_default();
+        if dates.added_at.is_none() {
+            dates.added_at = books.path(key)
+                .and_then(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|modified| modified.as_millis() as u64);
+            changed = true;
+        }
+    }
+    drop(books);
+
+    if changed {
+        let saved = config_file_path(app, RECENTS_FILE).and_then(|path| save_json_file(&path, &*recents));
+        if let Err(e) = saved {
+            log::warn!("Failed to save recent books: {}", e);
+        }
+    }
+}
+
+/// Opens a book for reading: loads its document ahead of the first request
+/// and records it as last opened now.
+#[tauri::command]
+fn open_book(book_key: String, app: tauri::AppHandle, state: tauri::State<LibraryState>) -> Result<(), AppError> {
+    book_handle(&state.0, &book_key)?;
+    record_opened(&app, &book_key)
+}
+
+/// Books by when they were added (`added`) or last opened (`opened`), most
+/// recent first, for the home screen shelves. Books never opened are left
+/// out of `opened`.
+#[tauri::command]
+fn get_recent_books(
+    order: String,
+    limit: Option<usize>,
+    state: tauri::State<LibraryState>,
+    recents: tauri::State<RecentsState>
+) -> Result<Vec<RecentBook>, AppError> {
+    let date: fn(&BookDates) -> Option<u64> = match order.as_str() {
+        "added" => |dates| dates.added_at,
+        "opened" => |dates| dates.last_opened_at,
+        other => return Err(AppError::InvalidArgument(format!("Unknown recent books order: {}", other))),
+    };
+
+    let books = state.0.lock();
+    let recents = recents.0.lock();
+    let mut dated: Vec<(u64, RecentBook)> = books.descriptors()
+        .filter_map(|(key, descriptor)| {
+            let dates = recents.get(key)?;
+            let book = RecentBook {
+                book_key: key.clone(),
+                title: descriptor.title.clone(),
+                author: descriptor.authors.first().cloned(),
+                added_at: dates.added_at,
+                last_opened_at: dates.last_opened_at,
+            };
+            Some((date(dates)?, book))
+        })
+        .collect();
+    dated.sort_by(|(a_date, a), (b_date, b)| b_date.cmp(a_date).then_with(|| a.book_key.cmp(&b.book_key)));
+    dated.truncate(limit.unwrap_or(usize::MAX));
+    Ok(dated.into_iter().map(|(_, book)| book).collect())
+}
+
+/// Where `saved` is in the book's current content (see `repair_positions`).
+fn relocate_position(book: &mut EpubDoc<BufReader<File>>, saved: &Progress) -> (RepairStatus, Progress) {
+    // Real spine indexes, as items missing from the manifest are skipped
+    let spine: Vec<(usize, PathBuf, String)> = book.spine.iter()
+        .enumerate()
+        .filter_map(|(spine_index, id)| book.resources.get(id).map(|(path, mime)| (spine_index, path.clone(), mime.clone())))
+        .collect();
+    let same_chapter = saved.anchor.spine_href.as_deref()
+        .and_then(|href| spine.iter().position(|(_, path, _)| path.to_str() == Some(href)));
+
+    // Search the chapter it was in first, then the rest in order
+    let mut found = None;
+    if let Some(context) = &saved.anchor.context {
+        let order = same_chapter.into_iter().chain((0..spine.len()).filter(|entry| Some(*entry) != same_chapter));
+        for entry in order {
+            let (spine_index, path, mime) = &spine[entry];
+            if !is_html_mime(mime) {
+                continue;
+            }
+            let Ok(bytes) = book.get_resource_by_path(path) else { continue };
+            let text = html::extract_text(&html::decode_document(&bytes));
+            if let Some(range) = find_text(&text, context, 0) {
+                let chars = text.chars().count().max(1);
+                found = Some((*spine_index, text[..range.start].chars().count() as f64 / chars as f64));
+                break;
+            }
+        }
+    }
+
+    match (found, same_chapter.map(|entry| spine[entry].0)) {
+        (Some((spine_index, fraction)), _) => (RepairStatus::Relocated, progress_at(book, spine_index, fraction)),
+        (None, Some(spine_index)) => (RepairStatus::Relocated, progress_at(book, spine_index, saved.scroll_fraction)),
+        (None, None) => (RepairStatus::Reset, progress_at(book, 0, 0.0)),
+    }
+}
+
+/// After a book file is replaced with an edited version, moves its saved
+/// position to the same spot in the new content: where the saved context
+/// text now appears, else the same chapter by path, else the start of the
+/// book. Positions in books whose spine hasn't changed are left alone.
+#[tauri::command]
+fn repair_positions(
+    book_key: String,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>
+) -> Result<PositionRepair, AppError> {
+    let Some(saved) = progress.0.lock().get(&book_key).cloned() else {
+        return Ok(PositionRepair { status: RepairStatus::Unchanged, progress: None });
+    };
+
+    let (status, repaired) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let unchanged = match &saved.anchor.spine_signature {
+            Some(signature) => *signature == spine_signature(book),
+            None => saved.spine_index < book.spine.len(),
+        };
+        if unchanged {
+            return Ok(PositionRepair { status: RepairStatus::Unchanged, progress: Some(saved) });
+        }
+        relocate_position(book, &saved)
+    };
+
+    let mut progress = progress.0.lock();
+    progress.insert(book_key, repaired.clone());
+    save_json_file(&config_file_path(&app, PROGRESS_FILE)?, &*progress)?;
+    Ok(PositionRepair { status, progress: Some(repaired) })
+}
+
+/// Marks a book finished or not. Turning past the end of a book marks it
+/// finished automatically (see `save_progress`); this is for doing so by
+/// hand, or undoing it.
+#[tauri::command]
+fn set_book_finished(
+    book_key: String,
+    finished: bool,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    finished_books: tauri::State<FinishedState>
+) -> Result<(), AppError> {
+    if !state.0.lock().contains_key(&book_key) {
+        return Err(AppError::book_not_found(&book_key));
+    }
+
+    let mut finished_books = finished_books.0.lock();
+    let changed = if finished { finished_books.insert(book_key) } else { finished_books.remove(&book_key) };
+    if changed {
+        save_json_file(&config_file_path(&app, FINISHED_FILE)?, &*finished_books)?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn get_progress(book_key: String, progress: tauri::State<ProgressState>) -> Option<Progress> {
+    progress.0.lock().get(&book_key).cloned()
+}
+
+#[tauri::command]
+fn get_annotations(book_key: String, annotations: tauri::State<AnnotationsState>) -> Annotations {
+    annotations.0.lock().get(&book_key).cloned().unwrap_or_default()
+}
+
+/// Gives bookmarks or highlights without an id one: `created_at` in hex,
+/// with `-2`, `-3`, ... appended for ones created in the same millisecond.
+/// `fields` gives an item's id and creation time.
+fn assign_ids<T>(items: &mut [T], fields: impl Fn(&mut T) -> (&mut String, u64)) {
+    let mut taken: std::collections::HashSet<String> = items.iter_mut()
+        .map(|item| fields(item).0.clone())
+        .filter(|id| !id.is_empty())
+        .collect();
+    for item in items.iter_mut() {
+        let (id, created_at) = fields(item);
+        if !id.is_empty() {
+            continue;
+        }
+        let base = format!("{:x}", created_at);
+        let mut candidate = base.clone();
+        let mut suffix = 1;
+        while taken.contains(&candidate) {
+            suffix += 1;
+            candidate = format!("{}-{}", base, suffix);
+        }
+        taken.insert(candidate.clone());
+        *id = candidate;
+    }
+}
+
+fn assign_bookmark_ids(bookmarks: &mut [Bookmark]) {
+    assign_ids(bookmarks, |bookmark| (&mut bookmark.id, bookmark.created_at));
+}
+
+fn assign_highlight_ids(highlights: &mut [Highlight]) {
+    assign_ids(highlights, |highlight| (&mut highlight.id, highlight.created_at));
+}
+
+/// Bookmarks a position in a book and returns the new bookmark.
+#[tauri::command]
+fn add_bookmark(
+    book_key: String,
+    spine_index: usize,
+    scroll_fraction: f64,
+    label: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    annotations: tauri::State<AnnotationsState>
+) -> Result<Bookmark, AppError> {
+    let spine_len = book_handle(&state.0, &book_key)?.lock().spine.len();
+    if spine_index >= spine_len {
+        return Err(AppError::InvalidArgument(format!("Spine index out of range: {}", spine_index)));
+    }
+
+    let mut annotations = annotations.0.lock();
+    let bookmarks = &mut annotations.entry(book_key).or_default().bookmarks;
+    bookmarks.push(Bookmark {
+        id: String::new(),
+        spine_index,
+        scroll_fraction: scroll_fraction.clamp(0.0, 1.0),
+        label: label.map(|label| label.trim().to_string()).filter(|label| !label.is_empty()),
+        created_at: now_millis(),
+    });
+    assign_bookmark_ids(bookmarks);
+    let bookmark = bookmarks.last().cloned().unwrap();
+
+    save_json_file(&config_file_path(&app, ANNOTATIONS_FILE)?, &*annotations)?;
+    Ok(bookmark)
+}
+
+/// A book's bookmarks in reading order.
+#[tauri::command]
+fn list_bookmarks(book_key: String, annotations: tauri::State<AnnotationsState>) -> Vec<Bookmark> {
+    let mut bookmarks = annotations.0.lock()
+        .get(&book_key)
+        .map(|book_annotations| book_annotations.bookmarks.clone())
+        .unwrap_or_default();
+    assign_bookmark_ids(&mut bookmarks);
+    bookmarks.sort_by(|a, b| {
+        a.spine_index.cmp(&b.spine_index).then(a.scroll_fraction.total_cmp(&b.scroll_fraction))
+    });
+    bookmarks
+}
+
+/// Deletes a bookmark by id. Returns whether it existed.
+#[tauri::command]
+fn remove_bookmark(
+    book_key: String,
+    bookmark_id: String,
+    app: tauri::AppHandle,
+    annotations: tauri::State<AnnotationsState>
+) -> Result<bool, AppError> {
+    let mut annotations = annotations.0.lock();
+    let Some(book_annotations) = annotations.get_mut(&book_key) else { return Ok(false) };
+    assign_bookmark_ids(&mut book_annotations.bookmarks);
+    let before = book_annotations.bookmarks.len();
+    book_annotations.bookmarks.retain(|bookmark| bookmark.id != bookmark_id);
+    if book_annotations.bookmarks.len() == before {
+        return Ok(false);
+    }
+
+    if book_annotations.highlights.is_empty() && book_annotations.bookmarks.is_empty() {
+        annotations.remove(&book_key);
+    }
+    save_json_file(&config_file_path(&app, ANNOTATIONS_FILE)?, &*annotations)?;
+    Ok(true)
+}
+
+/// Highlights the characters `start_offset..end_offset` of a chapter's text
+/// (as `get_chapter_text` returns it) and returns the new highlight.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn add_highlight(
+    book_key: String,
+    spine_index: usize,
+    start_offset: usize,
+    end_offset: usize,
+    color: String,
+    note: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    annotations: tauri::State<AnnotationsState>
+) -> Result<Highlight, AppError> {
+    if start_offset >= end_offset {
+        return Err(AppError::InvalidArgument(format!("Highlight must end after it starts, got {}..{}", start_offset, end_offset)));
+    }
+    validate_color("Highlight", &color)?;
+
+    let (spine_href, text) = {
+        let book = book_handle(&state.0, &book_key)?;
+        let book = &mut *book.lock();
+        let (path, mime) = book.spine.get(spine_index)
+            .and_then(|id| book.resources.get(id).cloned())
+            .ok_or_else(|| AppError::InvalidArgument(format!("Spine index out of range: {}", spine_index)))?;
+        let chapter_text = if is_html_mime(&mime) {
+            let bytes = book.get_resource_by_path(&path)
+                .map_err(|_| AppError::resource_not_found(&path.display().to_string()))?;
+            html::extract_text(&html::decode_document(&bytes))
+        } else {
+            String::new()
+        };
+        let chapter_chars = chapter_text.chars().count();
+        if end_offset > chapter_chars {
+            return Err(AppError::InvalidArgument(format!(
+                "Highlight {}..{} is past the end of the chapter's {} characters",
+                start_offset, end_offset, chapter_chars
+            )));
+        }
+        let text: String = chapter_text.chars().skip(start_offset).take(end_offset - start_offset).collect();
+        (path.to_str().unwrap_or("").to_string(), text)
+    };
+
+    let mut annotations = annotations.0.lock();
+    let highlights = &mut annotations.entry(book_key).or_default().highlights;
+    highlights.push(Highlight {
+        id: String::new(),
+        spine_href,
+        spine_index: Some(spine_index),
+        start_offset: Some(start_offset),
+        end_offset: Some(end_offset),
+        text,
+        color: Some(color),
+        note: note.map(|note| note.trim().to_string()).filter(|note| !note.is_empty()),
+        created_at: now_millis(),
+    });
+    assign_highlight_ids(highlights);
+    let highlight = highlights.last().cloned().unwrap();
+
+    save_json_file(&config_file_path(&app, ANNOTATIONS_FILE)?, &*annotations)?;
+    Ok(highlight)
+}
+
+/// A book's highlights in reading order. Highlights without a spine index
+/// get one from their chapter's path while the book is loaded.
+#[tauri::command]
+fn list_highlights(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    annotations: tauri::State<AnnotationsState>
+) -> Vec<Highlight> {
+    let mut highlights = annotations.0.lock()
+        .get(&book_key)
+        .map(|book_annotations| book_annotations.highlights.clone())
+        .unwrap_or_default();
+    assign_highlight_ids(&mut highlights);
+    if highlights.iter().any(|highlight| highlight.spine_index.is_none()) {
+        if let Some(book) = Library::handle(&state.0, &book_key) {
+            let book = book.lock();
+            for highlight in highlights.iter_mut().filter(|highlight| highlight.spine_index.is_none()) {
+                highlight.spine_index = spine_index_for_path(&book, &highlight.spine_href);
+            }
+        }
+    }
+    // Unplaced highlights go last
+    highlights.sort_by_key(|highlight| {
+        (highlight.spine_index.unwrap_or(usize::MAX), highlight.start_offset.unwrap_or(0), highlight.created_at)
+    });
+    highlights
+}
+
+/// Deletes a highlight by id. Returns whether it existed.
+#[tauri::command]
+fn remove_highlight(
+    book_key: String,
+    highlight_id: String,
+    app: tauri::AppHandle,
+    annotations: tauri::State<AnnotationsState>
+) -> Result<bool, AppError> {
+    let mut annotations = annotations.0.lock();
+    let Some(book_annotations) = annotations.get_mut(&book_key) else { return Ok(false) };
+    assign_highlight_ids(&mut book_annotations.highlights);
+    let before = book_annotations.highlights.len();
+    book_annotations.highlights.retain(|highlight| highlight.id != highlight_id);
+    if book_annotations.highlights.len() == before {
+        return Ok(false);
+    }
+
+    if book_annotations.highlights.is_empty() && book_annotations.bookmarks.is_empty() {
+        annotations.remove(&book_key);
+    }
+    save_json_file(&config_file_path(&app, ANNOTATIONS_FILE)?, &*annotations)?;
+    Ok(true)
+}
+
+/// Replaces a book's highlights and bookmarks.
+#[tauri::command]
+fn set_annotations(
+    book_key: String,
+    mut book_annotations: Annotations,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>,
+    annotations: tauri::State<AnnotationsState>
+) -> Result<(), AppError> {
+    if !state.0.lock().contains_key(&book_key) {
+        return Err(AppError::book_not_found(&book_key));
+    }
+
+    assign_bookmark_ids(&mut book_annotations.bookmarks);
+    assign_highlight_ids(&mut book_annotations.highlights);
+    let mut annotations = annotations.0.lock();
+    if book_annotations.highlights.is_empty() && book_annotations.bookmarks.is_empty() {
+        annotations.remove(&book_key);
+    } else {
+        annotations.insert(book_key, book_annotations);
+    }
+    save_json_file(&config_file_path(&app, ANNOTATIONS_FILE)?, &*annotations)
+}
+
+/// Byte range of the first match of `needle` in `haystack` at or after
+/// `from`, where any run of whitespace in one matches any run in the other.
+fn find_text(haystack: &str, needle: &str, from: usize) -> Option<std::ops::Range<usize>> {
+    let needle: Vec<&str> = needle.split_whitespace().collect();
+    let first = *needle.first()?;
+
+    let mut start = from;
+    while let Some(offset) = haystack.get(start..)?.find(first) {
+        let match_start = start + offset;
+        let mut end = match_start + first.len();
+        let matched = needle[1..].iter().all(|word| {
+            let rest = &haystack[end..];
+            let trimmed = rest.trim_start();
+            if trimmed.len() == rest.len() || !trimmed.starts_with(word) {
+                return false;
+            }
+            end += rest.len() - trimmed.len() + word.len();
+            true
+        });
+        if matched {
+            return Some(match_start..end);
+        }
+        start = match_start + first.len();
+    }
+    None
+}
+
+/// Wraps each highlight's text in a `<mark>`. Only text within a single
+/// text node can be matched; returns the markup and how many were placed.
+fn mark_highlights(html_str: &str, highlights: &[&Highlight]) -> (String, usize) {
+    // Text nodes outside the head, scripts and styles
+    const SKIPPED: [&str; 3] = ["head", "script", "style"];
+    let mut text_nodes: Vec<std::ops::Range<usize>> = Vec::new();
+    let mut skip_depth = 0usize;
+    for (token, range) in html::tokenize(html_str) {
+        match token {
+            html::Token::StartTag(tag) if SKIPPED.iter().any(|name| tag.is(name)) && !tag.self_closing => skip_depth += 1,
+            html::Token::EndTag(name) if SKIPPED.iter().any(|n| name.eq_ignore_ascii_case(n)) => {
+                skip_depth = skip_depth.saturating_sub(1)
+            }
+            html::Token::Text if skip_depth == 0 => text_nodes.push(range),
+            _ => {}
+        }
+    }
+
+    let mut marks: Vec<(std::ops::Range<usize>, &Highlight)> = Vec::new();
+    for highlight in highlights {
+        // Markup escapes `&`, `<` and `>` in text, so search for the escaped form
+        let escaped = highlight.text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        let found = text_nodes.iter().find_map(|node| {
+            let text = &html_str[node.clone()];
+            let mut from = 0;
+            while let Some(found) = find_text(text, &escaped, from) {
+                let range = node.start + found.start..node.start + found.end;
+                if marks.iter().all(|(r, _)| r.end <= range.start || range.end <= r.start) {
+                    return Some(range);
+                }
+                from = found.end;
+            }
+            None
+        });
+        if let Some(range) = found {
+            marks.push((range, highlight));
+        }
+    }
+    marks.sort_by_key(|(range, _)| range.start);
+
+    let mut out = String::with_capacity(html_str.len() + marks.len() * 48);
+    let mut last = 0;
+    for (range, highlight) in &marks {
+        out.push_str(&html_str[last..range.start]);
+        match &highlight.note {
+            Some(note) => out.push_str(&format!(
+                "<mark class=\"epub-reader-highlight\" title=\"{}\">",
+                html::escape_attr(note)
+            )),
+            None => out.push_str("<mark class=\"epub-reader-highlight\">"),
+        }
+        out.push_str(&html_str[range.clone()]);
+        out.push_str("</mark>");
+        last = range.end;
+    }
+    out.push_str(&html_str[last..]);
+    (out, marks.len())
+}
+
+/// Archive entry holding the exported highlights and bookmarks
+const ANNOTATIONS_SIDECAR: &str = "META-INF/epub-reader-annotations.json";
+
+#[derive(Serialize)]
+struct AnnotatedExport {
+    chapters_modified: usize,
+    highlights_applied: usize,
+    /// Highlights whose text couldn't be found in their chapter, e.g. because
+    /// it spans elements; they are still recorded in the sidecar
+    highlights_unplaced: usize,
+    bookmarks: usize,
+}
+
+/// Writes a copy of the book to `dest` with highlights baked into the
+/// chapters as `<mark>` elements and all annotations recorded in a JSON
+/// sidecar. Every other entry is copied byte for byte.
+#[tauri::command]
+fn export_annotated_epub(
+    book_key: String,
+    dest: String,
+    state: tauri::State<LibraryState>,
+    annotations: tauri::State<AnnotationsState>
+) -> Result<AnnotatedExport, AppError> {
+    use std::io::Write;
+
+    let source = book_file_path(&state, &book_key)?;
+    let book_annotations = annotations.0.lock().get(&book_key).cloned().unwrap_or_default();
+
+    let dest = PathBuf::from(dest);
+    if dest.exists() && dest.canonicalize().ok() == source.canonicalize().ok() {
+        return Err(AppError::InvalidArgument("Refusing to overwrite the original book".to_string()));
+    }
+
+    let mut highlights_by_chapter: HashMap<&str, Vec<&Highlight>> = HashMap::new();
+    for highlight in &book_annotations.highlights {
+        highlights_by_chapter.entry(highlight.spine_href.as_str()).or_default().push(highlight);
+    }
+
+    let mut archive = open_book_archive(&state, &book_key)?;
+    let file = File::create(&dest).map_err(|e| AppError::Io(format!("Failed to create {}: {}", dest.display(), e)))?;
+    let mut writer = zip::ZipWriter::new(file);
+    let write_error = |e: zip::result::ZipError| AppError::Io(format!("Failed to write {}: {}", dest.display(), e));
+
+    let mut chapters_modified = 0;
+    let mut highlights_applied = 0;
+    for index in 0..archive.len() {
+        let name = archive.by_index_raw(index).map_err(|e| AppError::InvalidEpub(format!("Invalid EPUB archive: {}", e)))?.name().to_string();
+        if name == ANNOTATIONS_SIDECAR {
+            // Replaced below by the current annotations
+            continue;
+        }
+
+        let marked = highlights_by_chapter.get(name.as_str())
+            .and_then(|highlights| {
+                let markup = String::from_utf8(read_archive_entry(&mut archive, &name)?).ok()?;
+                let (marked, placed) = mark_highlights(&markup, highlights);
+                (placed > 0).then_some((marked, placed))
+            });
+
+        match marked {
+            Some((marked, placed)) => {
+                let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+                writer.start_file(name, options).map_err(write_error)?;
+                writer.write_all(marked.as_bytes()).map_err(|e| AppError::Io(format!("Failed to write {}: {}", dest.display(), e)))?;
+                chapters_modified += 1;
+                highlights_applied += placed;
+            }
+            None => {
+                let entry = archive.by_index_raw(index).map_err(|e| AppError::InvalidEpub(format!("Invalid EPUB archive: {}", e)))?;
+                writer.raw_copy_file(entry).map_err(write_error)?;
+            }
+        }
+    }
+
+    let sidecar = serde_json::to_vec_pretty(&book_annotations).map_err(|e| AppError::Other(e.to_string()))?;
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    writer.start_file(ANNOTATIONS_SIDECAR, options).map_err(write_error)?;
+    writer.write_all(&sidecar).map_err(|e| AppError::Io(format!("Failed to write {}: {}", dest.display(), e)))?;
+    writer.finish().map_err(write_error)?;
+
+    Ok(AnnotatedExport {
+        chapters_modified,
+        highlights_applied,
+        highlights_unplaced: book_annotations.highlights.len() - highlights_applied,
+        bookmarks: book_annotations.bookmarks.len(),
+    })
+}
+
+/// All loaded books in a series, in reading order, each with its saved
+/// position so the frontend can roll from one book into the next. Books
+/// with a series index come first in index order; unindexed ones follow by title.
+#[tauri::command]
+fn get_series_playlist(
+    series_name: String,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>
+) -> Result<Vec<SeriesEntry>, AppError> {
+    let wanted = series_name.trim().to_lowercase();
+    let progress = progress.0.lock().clone();
+
+    let mut entries: Vec<SeriesEntry> = state.0.lock().descriptors()
+        .filter(|(_, descriptor)| descriptor.series.as_deref().is_some_and(|series| series.trim().to_lowercase() == wanted))
+        .map(|(book_key, descriptor)| SeriesEntry {
+            book_key: book_key.clone(),
+            title: descriptor.title.clone().unwrap_or(book_key.replace(".epub", "")),
+            series_index: descriptor.series_index,
+            progress: progress.get(book_key).cloned(),
+        })
+        .collect();
+
+    entries.sort_by(|a, b| match (a.series_index, b.series_index) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.title.to_lowercase().cmp(&b.title.to_lowercase())),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+    });
+
+    Ok(entries)
+}
+
+/// The side an itemref's `page-spread-*` property puts the page on.
+fn page_spread_side(item: &opf::SpineItem) -> Option<PageSide> {
+    if item.has_property("page-spread-left") || item.has_property("rendition:page-spread-left") {
+        Some(PageSide::Left)
+    } else if item.has_property("page-spread-right") || item.has_property("rendition:page-spread-right") {
+        Some(PageSide::Right)
+    } else if item.has_property("rendition:page-spread-center") {
+        Some(PageSide::Center)
+    } else {
+        None
+    }
+}
+
+/// The value of an itemref property `<prefix><value>`, e.g. `landscape` for
+/// `rendition:orientation-landscape`.
+fn itemref_override(item: &opf::SpineItem, prefix: &str) -> Option<String> {
+    item.properties.as_deref()?
+        .split_whitespace()
+        .find_map(|property| property.strip_prefix(prefix))
+        .map(str::to_string)
+}
+
+/// How the book's pages should be laid out: fixed-layout or reflowable, the
+/// spread and orientation hints, and each spine item's overrides. Fixed-layout
+/// pages also give their size from `<meta name="viewport">`, for sizing the
+/// viewer's iframe.
+#[tauri::command]
+fn get_rendition_properties(book_key: String, state: tauri::State<LibraryState>) -> Result<RenditionProperties, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+    let root_file = book.root_file.to_str().unwrap_or("").to_string();
+
+    let fixed_layout = package.property("rendition:layout").map(str::trim) == Some("pre-paginated");
+    let global = |property: &str| package.property(property).map(|value| value.trim().to_string()).unwrap_or_else(|| "auto".to_string());
+    let (spread, orientation) = (global("rendition:spread"), global("rendition:orientation"));
+
+    let pages = package.spine.iter().enumerate().map(|(spine_index, item)| {
+        let href = package.manifest_item(&item.idref)
+            .map(|manifest_item| resolve_href(&root_file, &manifest_item.href).0)
+            .unwrap_or_default();
+        let page_fixed_layout = match itemref_override(item, "rendition:layout-").as_deref() {
+            Some("pre-paginated") => true,
+            Some("reflowable") => false,
+            _ => fixed_layout,
+        };
+        let viewport = if page_fixed_layout {
+            book.get_resource_by_path(&href).ok()
+                .and_then(|bytes| html::viewport_size(&html::decode_document(&bytes)))
+                .map(|(width, height)| Viewport { width, height })
+        } else {
+            None
+        };
+        PageRendition {
+            spine_index,
+            fixed_layout: page_fixed_layout,
+            spread: itemref_override(item, "rendition:spread-"),
+            orientation: itemref_override(item, "rendition:orientation-"),
+            page_spread: page_spread_side(item),
+            viewport,
+            href,
+        }
+    }).collect();
+
+    Ok(RenditionProperties { fixed_layout, spread, orientation, pages })
+}
+
+/// Groups fixed-layout pages into two-up spreads from `rendition:spread` and
+/// the `page-spread-left/right/center` itemref properties. Reflowable books,
+/// books without any spread information and `rendition:spread="none"` get
+/// one centered page per spread.
+#[tauri::command]
+fn get_page_spreads(book_key: String, state: tauri::State<LibraryState>) -> Result<PageSpreads, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+    let root_file = book.root_file.to_str().unwrap_or("").to_string();
+
+    let fixed_layout = package.property("rendition:layout").map(str::trim) == Some("pre-paginated");
+    let declared_spread = package.property("rendition:spread").map(|s| s.trim().to_string());
+    let rtl = package.page_progression_direction.as_deref() == Some("rtl");
+    // The side a spread starts on in reading order
+    let (first_side, second_side) = if rtl { (PageSide::Right, PageSide::Left) } else { (PageSide::Left, PageSide::Right) };
+
+    let pages = package.spine.iter().enumerate().map(|(spine_index, item)| {
+        let href = package.manifest_item(&item.idref)
+            .map(|manifest_item| resolve_href(&root_file, &manifest_item.href).0)
+            .unwrap_or_default();
+        (spine_index, href, page_spread_side(item))
+    }).collect::<Vec<_>>();
+
+    let has_spread_info = declared_spread.is_some() || pages.iter().any(|(_, _, side)| side.is_some());
+    let spread = declared_spread.unwrap_or_else(|| "auto".to_string());
+
+    let mut spreads: Vec<Vec<SpreadPage>> = Vec::new();
+    if !fixed_layout || !has_spread_info || spread == "none" {
+        spreads.extend(pages.into_iter().map(|(spine_index, href, _)| vec![SpreadPage { spine_index, href, side: PageSide::Center }]));
+    } else {
+        // A page waiting for its partner on the second side
+        let mut pending: Option<SpreadPage> = None;
+        for (spine_index, href, side) in pages {
+            match side {
+                Some(PageSide::Center) => {
+                    spreads.extend(pending.take().map(|page| vec![page]));
+                    spreads.push(vec![SpreadPage { spine_index, href, side: PageSide::Center }]);
+                }
+                Some(side) if side == second_side => {
+                    let page = SpreadPage { spine_index, href, side };
+                    spreads.push(pending.take().into_iter().chain(std::iter::once(page)).collect());
+                }
+                None if pending.is_some() => {
+                    let page = SpreadPage { spine_index, href, side: second_side };
+                    spreads.push(pending.take().into_iter().chain(std::iter::once(page)).collect());
+                }
+                _ => {
+                    spreads.extend(pending.take().map(|page| vec![page]));
+                    pending = Some(SpreadPage { spine_index, href, side: first_side });
+                }
+            }
+        }
+        spreads.extend(pending.map(|page| vec![page]));
+        if rtl {
+            for spread in spreads.iter_mut() {
+                spread.reverse();
+            }
+        }
+    }
+
+    Ok(PageSpreads {
+        fixed_layout,
+        spread,
+        page_progression: if rtl { "rtl" } else { "ltr" }.to_string(),
+        spreads,
+    })
+}
+
+/// Identifier normalized for comparison: lowercase, without `urn:`/`isbn:`
+/// style prefixes, and ISBNs without hyphens or spaces.
+fn normalize_identifier(identifier: &str) -> String {
+    let mut value = identifier.trim().to_lowercase();
+    for prefix in ["urn:", "isbn:", "uuid:", "doi:"] {
+        if let Some(rest) = value.strip_prefix(prefix) {
+            value = rest.to_string();
+        }
+    }
+    let compact: String = value.chars().filter(|c| !matches!(c, '-' | ' ')).collect();
+    if compact.len() >= 10 && compact.chars().all(|c| c.is_ascii_digit() || c == 'x') {
+        compact
+    } else {
+        value
+    }
+}
+
+fn normalize_title_author(title: &str, author: &str) -> String {
+    let normalize = |s: &str| {
+        s.to_lowercase()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    format!("{} / {}", normalize(title), normalize(author))
+}
+
+/// Groups books that look like the same work: first by unique identifier,
+/// then (for books not already grouped) by title and first author. Only
+/// clusters of two or more are returned.
+#[tauri::command]
+fn find_duplicate_books(
+    state: tauri::State<LibraryState>
+) -> Vec<DuplicateCluster> {
+    // Key, identifier, title and first author of every book
+    let books: Vec<(String, Option<String>, Option<String>, String)> = state.0.lock().descriptors()
+        .map(|(book_key, descriptor)| {
+            let author = descriptor.authors.first().cloned().unwrap_or_default();
+            (book_key.clone(), descriptor.identifier.clone(), descriptor.title.clone(), author)
+        })
+        .collect();
+    let titles: HashMap<&String, &Option<String>> = books.iter().map(|(book_key, _, title, _)| (book_key, title)).collect();
+
+    let mut by_identifier: HashMap<String, Vec<&String>> = HashMap::new();
+    for (book_key, identifier, _, _) in &books {
+        if let Some(identifier) = identifier {
+            let identifier = normalize_identifier(identifier);
+            if !identifier.is_empty() {
+                by_identifier.entry(identifier).or_default().push(book_key);
+            }
+        }
+    }
+
+    let mut clusters: Vec<(String, String, Vec<&String>)> = by_identifier.into_iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .map(|(identifier, keys)| ("identifier".to_string(), identifier, keys))
+        .collect();
+
+    let mut by_title_author: HashMap<String, Vec<&String>> = HashMap::new();
+    for (book_key, _, title, author) in &books {
+        if clusters.iter().any(|(_, _, keys)| keys.contains(&book_key)) {
+            continue;
+        }
+        let Some(title) = title else { continue };
+        by_title_author.entry(normalize_title_author(title, author)).or_default().push(book_key);
+    }
+    clusters.extend(by_title_author.into_iter()
+        .filter(|(_, keys)| keys.len() > 1)
+        .map(|(key, keys)| ("title_author".to_string(), key, keys)));
+
+    let mut clusters: Vec<DuplicateCluster> = clusters.into_iter()
+        .map(|(matched_by, key, mut keys)| {
+            keys.sort();
+            DuplicateCluster {
+                matched_by,
+                key,
+                books: keys.into_iter()
+                    .map(|book_key| DuplicateBook {
+                        book_key: book_key.clone(),
+                        title: titles[book_key].clone().unwrap_or(book_key.replace(".epub", "")),
+                        file_size: book_file_path(&state, book_key).ok()
+                            .and_then(|path| std::fs::metadata(path).ok())
+                            .map(|m| m.len())
+                            .unwrap_or(0),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    clusters.sort_by(|a, b| a.books[0].book_key.cmp(&b.books[0].book_key));
+    clusters
+}
+
+/// Finished once marked so (by hand or by turning past the end); reading once
+/// there's a saved position.
+fn read_status(progress: Option<&Progress>, finished: bool) -> ReadStatus {
+    match progress {
+        _ if finished => ReadStatus::Finished,
+        None => ReadStatus::Unread,
+        Some(_) => ReadStatus::Reading,
+    }
+}
+
+fn book_summary(
+    book_key: &str,
+    descriptor: &library::BookDescriptor,
+    progress: Option<&Progress>,
+    finished: bool,
+) -> BookSummary {
+    BookSummary {
+        book_key: book_key.to_string(),
+        title: descriptor.title.clone().unwrap_or(book_key.replace(".epub", "")),
+        authors: descriptor.authors.clone(),
+        series: descriptor.series.clone(),
+        series_index: descriptor.series_index,
+        language: descriptor.language.clone(),
+        tags: descriptor.tags.clone(),
+        rating: descriptor.rating,
+        read_status: read_status(progress, finished),
+        finished,
+        last_read: progress.map(|p| p.updated_at),
+    }
+}
+
+fn matches_filter(summary: &BookSummary, filter: &BookFilter) -> bool {
+    let contains = |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.trim().to_lowercase());
+    let any_contains = |values: &[String], needle: &str| values.iter().any(|v| contains(v, needle));
+
+    filter.author.as_deref().map(|a| any_contains(&summary.authors, a)).unwrap_or(true)
+        && filter.series.as_deref()
+            .map(|s| summary.series.as_deref().map(|series| contains(series, s)).unwrap_or(false))
+            .unwrap_or(true)
+        && filter.tag.as_deref()
+            .map(|t| summary.tags.iter().any(|tag| tag.eq_ignore_ascii_case(t.trim())))
+            .unwrap_or(true)
+        && filter.language.as_deref()
+            .map(|l| summary.language.as_deref().map(|lang| lang.to_lowercase().starts_with(&l.trim().to_lowercase())).unwrap_or(false))
+            .unwrap_or(true)
+        && filter.min_rating.map(|min| summary.rating.map(|r| r >= min).unwrap_or(false)).unwrap_or(true)
+        && filter.read_status.map(|status| summary.read_status == status).unwrap_or(true)
+        && filter.finished.map(|finished| summary.finished == finished).unwrap_or(true)
+        && filter.text.as_deref()
+            .map(|q| {
+                contains(&summary.title, q)
+                    || any_contains(&summary.authors, q)
+                    || summary.series.as_deref().map(|s| contains(s, q)).unwrap_or(false)
+                    || any_contains(&summary.tags, q)
+            })
+            .unwrap_or(true)
+}
+
+/// Books matching every criterion given in `filter`, for the advanced filter
+/// panel, sorted by `filter.sort`.
+#[tauri::command]
+fn query_books(
+    filter: BookFilter,
+    state: tauri::State<LibraryState>,
+    progress: tauri::State<ProgressState>,
+    finished: tauri::State<FinishedState>
+) -> Result<Vec<BookSummary>, AppError> {
+    let progress = progress.0.lock().clone();
+    let finished = finished.0.lock().clone();
+
+    let mut results: Vec<BookSummary> = state.0.lock().descriptors()
+        .map(|(book_key, descriptor)| book_summary(book_key, descriptor, progress.get(book_key), finished.contains(book_key)))
+        .filter(|summary| matches_filter(summary, &filter))
+        .collect();
+
+    let title_key = |s: &BookSummary| s.title.to_lowercase();
+    match filter.sort.as_deref().unwrap_or("title") {
+        "title" => results.sort_by_key(title_key),
+        "author" => results.sort_by_key(|s| (s.authors.first().map(|a| a.to_lowercase()), title_key(s))),
+        "series" => results.sort_by(|a, b| {
+            // Books outside any series go last
+            let series_key = |s: &BookSummary| (s.series.is_none(), s.series.as_deref().map(str::to_lowercase));
+            series_key(a).cmp(&series_key(b))
+                .then(a.series_index.partial_cmp(&b.series_index).unwrap_or(std::cmp::Ordering::Equal))
+                .then_with(|| title_key(a).cmp(&title_key(b)))
+        }),
+        "rating" => results.sort_by(|a, b| {
+            b.rating.partial_cmp(&a.rating).unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| title_key(a).cmp(&title_key(b)))
+        }),
+        "recent" => results.sort_by_key(|s| (std::cmp::Reverse(s.last_read), title_key(s))),
+        other => return Err(AppError::InvalidArgument(format!("Unknown sort key: {}", other))),
+    }
+
+    Ok(results)
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn catalog_csv(entries: &[CatalogEntry]) -> String {
+    let mut csv = String::from("title,authors,series,series_index,language,publisher,year,tags,identifier,file_path\r\n");
+    for entry in entries {
+        let fields = [
+            entry.title.clone(),
+            entry.authors.join("; "),
+            entry.series.clone().unwrap_or_default(),
+            entry.series_index.map(|i| i.to_string()).unwrap_or_default(),
+            entry.language.clone().unwrap_or_default(),
+            entry.publisher.clone().unwrap_or_default(),
+            entry.year.clone().unwrap_or_default(),
+            entry.tags.join("; "),
+            entry.identifier.clone().unwrap_or_default(),
+            entry.file_path.clone(),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// The whole library as a catalog for spreadsheets, as `format` `"csv"`
+/// (RFC 4180, multi-valued fields joined with `; `) or `"json"`.
+#[tauri::command]
+fn export_library_catalog(
+    format: String,
+    state: tauri::State<LibraryState>
+) -> Result<String, AppError> {
+    let books = state.0.lock();
+    let mut entries: Vec<CatalogEntry> = books.descriptors().map(|(book_key, descriptor)| {
+        let year = descriptor.date.as_deref().and_then(|date| {
+            let year: String = date.trim().chars().take(4).collect();
+            if year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()) { Some(year) } else { None }
+        });
+
+        CatalogEntry {
+            title: descriptor.title.clone().unwrap_or(book_key.replace(".epub", "")),
+            authors: descriptor.authors.clone(),
+            series: descriptor.series.clone(),
+            series_index: descriptor.series_index,
+            language: descriptor.language.clone(),
+            publisher: descriptor.publisher.clone(),
+            year,
+            tags: descriptor.tags.clone(),
+            identifier: descriptor.identifier.clone(),
+            file_path: books.path(book_key).map(|path| path.to_string_lossy().into_owned()).unwrap_or_default(),
+        }
+    }).collect();
+    drop(books);
+    entries.sort_by_key(|entry| entry.title.to_lowercase());
+
+    match format.to_lowercase().as_str() {
+        "csv" => Ok(catalog_csv(&entries)),
+        "json" => serde_json::to_string_pretty(&entries).map_err(|e| AppError::Other(e.to_string())),
+        other => Err(AppError::InvalidArgument(format!("Unsupported catalog format: {}", other))),
+    }
+}
+
+fn config_file_path(app: &tauri::AppHandle, file_name: &str) -> Result<PathBuf, AppError> {
+    app.path()
+        .app_config_dir()
+        .map(|dir| dir.join(file_name))
+        .map_err(|e| AppError::Io(format!("Config directory unavailable: {}", e)))
+}
+
+/// Reads a JSON settings file, treating a missing or malformed file as defaults.
+fn load_json_file<T: serde::de::DeserializeOwned + Default>(path: &std::path::Path) -> T {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Writes JSON atomically: to a temporary sibling first, then renamed over
+/// the target so readers see either the old file or the new one, never a mix.
+fn save_json_file<T: Serialize>(path: &std::path::Path, value: &T) -> Result<(), AppError> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| AppError::Io(format!("Failed to create {}: {}", dir.display(), e)))?;
+    }
+    let json = serde_json::to_vec_pretty(value).map_err(|e| AppError::Other(e.to_string()))?;
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, json).map_err(|e| AppError::Io(format!("Failed to write {}: {}", temp_path.display(), e)))?;
+    std::fs::rename(&temp_path, path).map_err(|e| AppError::Io(format!("Failed to replace {}: {}", path.display(), e)))
+}
+
+/// Loads preferences, migrating the legacy theme file on first run.
+fn load_preferences(app: &tauri::AppHandle) -> Result<Preferences, AppError> {
+    let path = config_file_path(app, PREFERENCES_FILE)?;
+    if path.exists() {
+        return Ok(load_json_file(&path));
+    }
+
+    let legacy_path = config_file_path(app, LEGACY_THEME_FILE)?;
+    if !legacy_path.exists() {
+        return Ok(Preferences::default());
+    }
+    // The legacy file had the same `theme`/`book_overrides` shape
+    let preferences: Preferences = load_json_file(&legacy_path);
+    save_json_file(&path, &preferences)?;
+    let _ = std::fs::remove_file(&legacy_path);
+    Ok(preferences)
+}
+
+fn save_preferences(app: &tauri::AppHandle, preferences: &Preferences) -> Result<(), AppError> {
+    save_json_file(&config_file_path(app, PREFERENCES_FILE)?, preferences)
+}
+
+#[tauri::command]
+fn get_preferences(state: tauri::State<PreferencesState>) -> Preferences {
+    state.0.lock().clone()
+}
+
+/// Replaces every setting in one round-trip. Invalid values are rejected
+/// without changing anything.
+#[tauri::command]
+fn set_preferences(
+    prefs: Preferences,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    prefs.validate()?;
+
+    let mut preferences = state.0.lock();
+    save_preferences(&app, &prefs)?;
+    *preferences = prefs;
+    Ok(())
+}
+
+/// Turns privacy mode on or off: with it on, chapters are served without
+/// references to remote (`http(s)://`) images, scripts, stylesheets and
+/// other resources, so reading can't be tracked. Applies from the next
+/// chapter served.
+#[tauri::command]
+fn set_privacy_mode(
+    enabled: bool,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    let mut preferences = state.0.lock();
+    preferences.privacy_mode = enabled;
+    save_preferences(&app, &preferences)
+}
+
+/// Chooses what the reader injects into chapters, for books whose own
+/// typography the defaults get in the way of. Applies from the next chapter
+/// served.
+#[tauri::command]
+fn set_injection_mode(
+    mode: InjectionMode,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    let mut preferences = state.0.lock();
+    preferences.injection_mode = mode;
+    save_preferences(&app, &preferences)
+}
+
+#[tauri::command]
+fn get_reading_theme(book_key: Option<String>, state: tauri::State<PreferencesState>) -> ReadingTheme {
+    let settings = state.0.lock();
+    match book_key {
+        Some(key) => settings.for_book(&key),
+        None => settings.theme.clone(),
+    }
+}
+
+/// Applies `update` to the global theme, or to one book's overrides when
+/// `book_key` is given (dropping overrides left with nothing set), and saves.
+fn update_theme(
+    app: &tauri::AppHandle,
+    state: &PreferencesState,
+    book_key: Option<String>,
+    update: impl FnOnce(&mut ReadingTheme),
+) -> Result<(), AppError> {
+    let mut settings = state.0.lock();
+    match book_key {
+        Some(key) => {
+            let overrides = settings.book_overrides.entry(key.clone()).or_default();
+            update(overrides);
+            if overrides.is_empty() {
+                settings.book_overrides.remove(&key);
+            }
+        }
+        None => update(&mut settings.theme),
+    }
+
+    save_preferences(app, &settings)
+}
+
+/// Replaces the whole reading theme, globally or for one book's overrides.
+/// Invalid values are rejected without changing anything.
+#[tauri::command]
+fn set_reading_theme(
+    theme: ReadingTheme,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    theme.validate()?;
+    update_theme(&app, &state, book_key, |current| *current = theme)
+}
+
+/// Sets the background, text and link colors from a built-in preset
+/// (`light`, `dark`, `sepia`, `high-contrast` or `eink`), globally or for one
+/// book. `eink` also turns on e-ink mode, which the other presets turn off.
+#[tauri::command]
+fn set_theme_preset(
+    preset: ThemePreset,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    let (background, foreground, link) = preset.colors();
+    update_theme(&app, &state, book_key, |theme| {
+        theme.background = Some(background.to_string());
+        theme.foreground = Some(foreground.to_string());
+        theme.link = Some(link.to_string());
+        theme.eink = matches!(preset, ThemePreset::Eink).then_some(true);
+    })
+}
+
+/// Sets the maximum line length in characters, globally or for one book.
+/// Passing `None` clears the per-book override (or restores the default
+/// column width when applied globally).
+#[tauri::command]
+fn set_reading_width(
+    max_width_ch: Option<u32>,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    validate_reading_width(max_width_ch)?;
+    update_theme(&app, &state, book_key, |theme| theme.max_width_ch = max_width_ch)
+}
+
+/// Sets first-line indents or block spacing between paragraphs, globally or
+/// for one book. Passing `None` clears the per-book override (or keeps each
+/// book's own paragraph styling when applied globally).
+#[tauri::command]
+fn set_paragraph_style(
+    paragraph_style: Option<ParagraphStyle>,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    update_theme(&app, &state, book_key, |theme| theme.paragraph_style = paragraph_style)
+}
+
+/// Turns automatic hyphenation on or off, globally or for one book. `None`
+/// clears the per-book override (or leaves it to the book's own CSS globally).
+#[tauri::command]
+fn set_hyphenation(
+    hyphenate: Option<bool>,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    update_theme(&app, &state, book_key, |theme| theme.hyphenate = hyphenate)
+}
+
+/// Sets justified or left-aligned body text, globally or for one book. `None`
+/// clears the per-book override (or leaves it to the book's own CSS globally).
+#[tauri::command]
+fn set_text_align(
+    text_align: Option<TextAlign>,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    update_theme(&app, &state, book_key, |theme| theme.text_align = text_align)
+}
+
+/// Sets how images are sized, globally or for one book. Images fit the text
+/// column unless set to `natural`, for books designed around full-bleed art.
+/// Passing `None` clears the per-book override (or restores fitting globally).
+#[tauri::command]
+fn set_image_fit(
+    image_fit: Option<ImageFit>,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    update_theme(&app, &state, book_key, |theme| theme.image_fit = image_fit)
+}
+
+/// Splits each page into newspaper-style columns, globally or for one book.
+/// Passing `None` clears the per-book override (or restores a single column
+/// when applied globally).
+///
+/// Columns apply in paginated mode: the injected script reads the count from
+/// the `--epub-columns` CSS variable and divides each page's width between
+/// them, so the frontend only has to give the iframe its full size. Scrolling
+/// mode keeps a single column, since columns taller than the viewport can't
+/// be read without scrolling back up.
+#[tauri::command]
+fn set_columns(
+    columns: Option<usize>,
+    book_key: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<PreferencesState>
+) -> Result<(), AppError> {
+    validate_columns(columns)?;
+    update_theme(&app, &state, book_key, |theme| theme.columns = columns)
+}
+
+fn validate_columns(columns: Option<usize>) -> Result<(), AppError> {
+    match columns {
+        Some(count) if !(1..=4).contains(&count) => Err(AppError::InvalidArgument(format!("Columns must be between 1 and 4, got {}", count))),
+        _ => Ok(()),
+    }
+}
+
+fn validate_reading_width(max_width_ch: Option<u32>) -> Result<(), AppError> {
+    match max_width_ch {
+        Some(width) if !(20..=200).contains(&width) => {
+            Err(AppError::InvalidArgument(format!("Reading width must be between 20 and 200 characters, got {}", width)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// E-ink mode, against ghosting and flashing on e-ink panels: transitions,
+/// animations and smooth scrolling are switched off (page turns included,
+/// via `--epub-page-animation`), box and text shadows are removed, and all
+/// text is pure black on white with links told apart by underlines. Nothing
+/// is inverted, so images keep their colors.
+const EINK_CSS: &str = r#"
+/* E-ink: no motion */
+:root {
+    --epub-page-animation: none;
+}
+
+*, *::before, *::after {
+    transition: none !important;
+    animation: none !important;
+    scroll-behavior: auto !important;
+}
+
+/* E-ink: no shadows */
+*, *::before, *::after {
+    box-shadow: none !important;
+    text-shadow: none !important;
+}
+
+/* E-ink: black text on white */
+html, body {
+    background-color: #ffffff !important;
+}
+
+* {
+    color: #000000 !important;
+}
+
+a, a:visited, a:hover {
+    color: #000000 !important;
+    text-decoration: underline !important;
+}
+"#;
+
+/// Keeps images inside the text column.
+const IMAGE_FIT_CSS: &str = r#"
+/* Images: scale down to the column width */
+img {
+    max-width: 100% !important;
+    height: auto !important;
+}
+"#;
+
+/// CSS for the user's reading theme, layered after the default stylesheet.
+fn theme_css(theme: &ReadingTheme) -> String {
+    let mut css = String::new();
+
+    if let Some(width) = theme.max_width_ch {
+        css.push_str(&format!(
+            r#"
+/* Reading width: cap line length and center the text column */
+html, body {{
+    max-width: {width}ch;
+    margin-left: auto;
+    margin-right: auto;
+}}
+
+body.paginated > * {{
+    max-width: {width}ch;
+    margin-left: auto !important;
+    margin-right: auto !important;
+    box-sizing: border-box;
+}}
+"#,
+            width = width
+        ));
+    }
+
+    match theme.paragraph_style {
+        Some(ParagraphStyle::Indent) => css.push_str(
+            r#"
+/* Paragraphs: first-line indents, except after headings and breaks */
+p {
+    margin-top: 0 !important;
+    margin-bottom: 0 !important;
+    text-indent: 1.5em !important;
+}
+
+h1 + p, h2 + p, h3 + p, h4 + p, h5 + p, h6 + p, hr + p, p:first-child {
+    text-indent: 0 !important;
+}
+"#,
+        ),
+        Some(ParagraphStyle::Spaced) => css.push_str(
+            r#"
+/* Paragraphs: block spacing, no indents */
+p {
+    margin-top: 0 !important;
+    margin-bottom: 1em !important;
+    text-indent: 0 !important;
+}
+"#,
+        ),
+        None => {}
+    }
+
+    if let Some(hyphenate) = theme.hyphenate {
+        // Hyphenation dictionaries are picked by the root's `lang`, which
+        // `set_document_direction` fills in from the book when missing
+        let hyphens = if hyphenate { "auto" } else { "manual" };
+        css.push_str(&format!(
+            r#"
+/* Hyphenation */
+html, body, p {{
+    -webkit-hyphens: {hyphens} !important;
+    hyphens: {hyphens} !important;
+}}
+"#,
+            hyphens = hyphens
+        ));
+    }
+
+    if let Some(columns) = theme.columns.filter(|count| *count > 1) {
+        // Read by the pagination script, which sizes the columns
+        css.push_str(&format!(
+            r#"
+/* Columns per page */
+:root {{
+    --epub-columns: {columns};
+}}
+"#,
+            columns = columns
+        ));
+    }
+
+    if let Some(text_align) = theme.text_align {
+        let align = match text_align {
+            TextAlign::Justify => "justify",
+            TextAlign::Left => "left",
+        };
+        css.push_str(&format!(
+            r#"
+/* Body text alignment; headings and centered blocks keep their own */
+p {{
+    text-align: {align} !important;
+}}
+"#,
+            align = align
+        ));
+    }
+
+    // Colors replace the light and dark defaults alike
+    if let Some(background) = &theme.background {
+        css.push_str(&format!(
+            r#"
+/* Page background */
+html, body {{
+    background-color: {background} !important;
+}}
+"#,
+            background = background
+        ));
+    }
+    if let Some(foreground) = &theme.foreground {
+        css.push_str(&format!(
+            r#"
+/* Text color */
+html, body {{
+    color: {foreground} !important;
+}}
+"#,
+            foreground = foreground
+        ));
+    }
+    if let Some(link) = &theme.link {
+        css.push_str(&format!(
+            r#"
+/* Link color */
+a, a:visited, a:hover {{
+    color: {link} !important;
+}}
+"#,
+            link = link
+        ));
+    }
+
+    if let Some(font_family) = &theme.font_family {
+        css.push_str(&format!(
+            r#"
+/* Font family */
+html, body, p, li, blockquote {{
+    font-family: {font_family} !important;
+}}
+"#,
+            font_family = font_family
+        ));
+    }
+    if let Some(font_size) = theme.font_size {
+        css.push_str(&format!(
+            r#"
+/* Text size */
+html {{
+    font-size: {font_size}% !important;
+}}
+"#,
+            font_size = font_size
+        ));
+    }
+    if let Some(line_height) = theme.line_height {
+        css.push_str(&format!(
+            r#"
+/* Line spacing */
+html, body, p, li {{
+    line-height: {line_height} !important;
+}}
+"#,
+            line_height = line_height
+        ));
+    }
+
+    if theme.eink == Some(true) {
+        css.push_str(EINK_CSS);
+    }
+
+    match theme.image_fit.unwrap_or(ImageFit::Fit) {
+        ImageFit::Fit => css.push_str(IMAGE_FIT_CSS),
+        ImageFit::Center => {
+            css.push_str(IMAGE_FIT_CSS);
+            css.push_str(
+                r#"
+img {
+    display: block !important;
+    margin-left: auto !important;
+    margin-right: auto !important;
+}
+"#,
+            );
+        }
+        ImageFit::Natural => {}
+    }
+
+    if css.is_empty() {
+        css
+    } else {
+        format!("<style>{}</style>", css)
+    }
+}
+
+/// Per-request content modes, selected with query parameters on the
+/// `epub://` URL so the frontend can opt in per view.
+#[derive(Default, Clone, Copy)]
+struct InjectionOptions {
+    /// `links=route`: internal links are handed to the frontend router
+    route_links: bool,
+    /// `notes=inline`: footnote references carry their note text
+    inline_notes: bool,
+    /// `tables=responsive`: wide tables scroll horizontally instead of overflowing
+    responsive_tables: bool,
+    /// `images=srcset`: images shipped at several resolutions get a `srcset`
+    image_srcset: bool,
+    /// `mode=preview`: peeking rather than reading, so position reports are
+    /// flagged and the resource caches are left alone
+    preview: bool,
+    /// From the preferences rather than the query
+    injection: InjectionMode,
+    /// From the preferences: strip remote resources (see `block_remote_resources`)
+    block_remote: bool,
+}
+
+impl InjectionOptions {
+    fn from_query(query: Option<&str>) -> InjectionOptions {
+        InjectionOptions {
+            route_links: query_param(query, "links") == Some("route"),
+            inline_notes: query_param(query, "notes") == Some("inline"),
+            responsive_tables: query_param(query, "tables") == Some("responsive"),
+            image_srcset: query_param(query, "images") == Some("srcset"),
+            preview: query_param(query, "mode") == Some("preview"),
+            injection: InjectionMode::Full,
+            block_remote: false,
+        }
+    }
+}
+
+/// Primary language subtags of languages written right to left.
+const RTL_LANGUAGES: &[&str] = &["ar", "arc", "ckb", "dv", "fa", "he", "iw", "ks", "ku", "ps", "sd", "syr", "ug", "ur", "yi"];
+
+/// Whether text in the BCP 47 `language` runs right to left: an RTL
+/// language, or any language in an RTL script such as `az-Arab`.
+fn is_rtl_language(language: &str) -> bool {
+    let mut subtags = language.trim().split(['-', '_']).map(str::to_ascii_lowercase);
+    let Some(primary) = subtags.next() else { return false };
+    match subtags.find(|subtag| subtag.len() == 4) {
+        Some(script) => matches!(script.as_str(), "arab" | "hebr" | "syrc" | "thaa" | "nkoo" | "adlm" | "rohg"),
+        None => RTL_LANGUAGES.contains(&primary.as_str()),
+    }
+}
+
+/// How a book's text runs, applied to each of its chapters that doesn't
+/// declare otherwise.
+#[derive(Default)]
+struct TextDirection {
+    /// The book's `dc:language`
+    language: Option<String>,
+    rtl: bool,
+    /// `vertical-rl` or `vertical-lr` when the package asks for vertical
+    /// text (`<meta name="primary-writing-mode">`, common in CJK books)
+    writing_mode: Option<&'static str>,
+}
+
+impl TextDirection {
+    fn of(book: &mut library::Book) -> TextDirection {
+        let language = book.mdata("language").map(|language| language.trim().to_string()).filter(|l| !l.is_empty());
+        let primary_writing_mode = read_package(book).ok().and_then(|package| {
+            package.elements("meta")
+                .find(|meta| meta.attr("name") == Some("primary-writing-mode"))
+                .and_then(|meta| meta.attr("content"))
+                .map(|mode| mode.trim().to_ascii_lowercase())
+        });
+        let writing_mode = match primary_writing_mode.as_deref() {
+            Some("vertical-rl") => Some("vertical-rl"),
+            Some("vertical-lr") => Some("vertical-lr"),
+            _ => None,
+        };
+        TextDirection {
+            rtl: primary_writing_mode.as_deref() == Some("horizontal-rl")
+                || language.as_deref().is_some_and(is_rtl_language),
+            language,
+            writing_mode,
+        }
+    }
+
+    /// Styles for vertical text, empty for horizontal books.
+    fn css(&self) -> String {
+        match self.writing_mode {
+            Some(mode) => format!("<style>html {{ -epub-writing-mode: {0}; -webkit-writing-mode: {0}; writing-mode: {0}; }}</style>", mode),
+            None => String::new(),
+        }
+    }
+}
+
+/// How long the webview may reuse a book resource without asking again.
+/// Resources don't change while the app runs, but chapters do (they carry
+/// the theme), so those are always revalidated.
+const RESOURCE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Strong `ETag` for a response body (a hash of its bytes), so a chapter
+/// re-rendered with another theme gets a new tag.
+fn resource_etag(body: &[u8]) -> String {
+    format!("\"{:016x}\"", library::fnv1a(body.iter().copied()))
+}
+
+/// Whether an `If-None-Match` header lists `etag` (or is `*`).
+fn etag_matches(header: Option<&str>, etag: &str) -> bool {
+    header.is_some_and(|header| {
+        header.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+        })
+    })
+}
+
+/// What a `Range` request header asks of a resource `len` bytes long.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No usable range: serve the whole resource
+    Full,
+    Partial(std::ops::Range<usize>),
+    /// Starts past the end of the resource (416)
+    Unsatisfiable,
+}
+
+/// Parses `bytes=start-end`, `bytes=start-` and `bytes=-suffix`. Of several
+/// ranges only the first is honored; other units and malformed headers get
+/// the full resource, as if no range had been asked for.
+fn parse_byte_range(header: Option<&str>, len: usize) -> ByteRange {
+    let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split(',').next().and_then(|range| range.trim().split_once('-')) else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range: the last `end` bytes
+        match end.parse::<usize>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => len.saturating_sub(suffix)..len,
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let Ok(start) = start.parse::<usize>() else { return ByteRange::Full };
+        let end = match end {
+            "" => len,
+            end => match end.parse::<usize>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(len),
+                _ => return ByteRange::Full,
+            },
+        };
+        start..end
+    };
+    if range.start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+/// Entries at least this big are served range by range from the archive
+/// when a range is asked for, rather than read whole.
+const STREAM_MIN_SIZE: usize = 1024 * 1024;
+/// Most bytes sent for one open-ended range (`bytes=start-`) of a streamed
+/// entry; media elements ask for the rest as they play.
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Part of an archive entry read for a range request.
+struct EntryRange {
+    range: ByteRange,
+    data: Vec<u8>,
+    /// Uncompressed size of the whole entry
+    len: usize,
+    /// From the entry's CRC and size, since the whole body is never hashed
+    etag: String,
+}
+
+/// Deflated entries whose decoders are kept between range requests.
+const MAX_ENTRY_DECODERS: usize = 4;
+
+/// A deflated entry's decoder, left where the last range read from it ended.
+/// A deflate stream can't be entered partway, so reaching a range means
+/// decompressing everything before it; media elements read forward a chunk
+/// at a time, and each chunk carries on from the previous one instead of
+/// starting over. Seeking backwards still starts from the beginning.
+struct EntryDecoder {
+    file: PathBuf,
+    name: String,
+    crc32: u32,
+    /// Uncompressed bytes read so far
+    position: usize,
+    decoder: flate2::read::DeflateDecoder<std::io::Take<BufReader<File>>>,
+}
+
+/// Recently used entry decoders, most recent last.
+type EntryDecoders = Vec<EntryDecoder>;
+
+/// Reads the bytes `range_header` asks for from entry `name` of the EPUB at
+/// `file`, so memory use is bounded by the range. Stored entries are read
+/// straight from the range's offset in the file; deflated ones through a
+/// decoder from `decoders` (see `EntryDecoder`). Open-ended ranges are cut to
+/// `STREAM_CHUNK_SIZE`. `None` for small entries and for headers that don't
+/// name a range, which are served the usual way.
+fn read_entry_range(
+    file: &std::path::Path,
+    name: &str,
+    range_header: &str,
+    decoders: &Mutex<EntryDecoders>,
+) -> Option<EntryRange> {
+    use std::io::{Read, Seek};
+
+    let (len, crc32, compression, data_start, compressed_size) = {
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file).ok()?)).ok()?;
+        let entry = archive.by_name(name).ok()?;
+        (entry.size() as usize, entry.crc32(), entry.compression(), entry.data_start(), entry.compressed_size())
+    };
+    if len < STREAM_MIN_SIZE {
+        return None;
+    }
+    let etag = format!("\"{:08x}-{:x}\"", crc32, len);
+
+    let range = match parse_byte_range(Some(range_header), len) {
+        ByteRange::Full => return None,
+        ByteRange::Unsatisfiable => return Some(EntryRange { range: ByteRange::Unsatisfiable, data: Vec::new(), len, etag }),
+        ByteRange::Partial(range) => {
+            let open_ended = range_header.trim_end().ends_with('-');
+            if open_ended { range.start..range.end.min(range.start + STREAM_CHUNK_SIZE) } else { range }
+        }
+    };
+
+    let mut data = Vec::with_capacity(range.len().min(STREAM_CHUNK_SIZE));
+    match compression {
+        zip::CompressionMethod::Stored => {
+            let mut reader = File::open(file).ok()?;
+            reader.seek(std::io::SeekFrom::Start(data_start + range.start as u64)).ok()?;
+            reader.take(range.len() as u64).read_to_end(&mut data).ok()?;
+        }
+        zip::CompressionMethod::Deflated => {
+            let cached = {
+                let mut decoders = decoders.lock();
+                decoders.iter()
+                    .position(|d| d.file == file && d.name == name && d.crc32 == crc32 && d.position <= range.start)
+                    .map(|index| decoders.remove(index))
+            };
+            let mut entry_decoder = match cached {
+                Some(entry_decoder) => entry_decoder,
+                None => {
+                    let mut reader = BufReader::new(File::open(file).ok()?);
+                    reader.seek(std::io::SeekFrom::Start(data_start)).ok()?;
+                    EntryDecoder {
+                        file: file.to_path_buf(),
+                        name: name.to_string(),
+                        crc32,
+                        position: 0,
+                        decoder: flate2::read::DeflateDecoder::new(reader.take(compressed_size)),
+                    }
+                }
+            };
+            let skip = (range.start - entry_decoder.position) as u64;
+            std::io::copy(&mut (&mut entry_decoder.decoder).take(skip), &mut std::io::sink()).ok()?;
+            (&mut entry_decoder.decoder).take(range.len() as u64).read_to_end(&mut data).ok()?;
+            entry_decoder.position = range.start + data.len();
+
+            // Only the most recently used decoder is kept for an entry
+            let mut decoders = decoders.lock();
+            decoders.retain(|d| d.file != file || d.name != name);
+            decoders.push(entry_decoder);
+            if decoders.len() > MAX_ENTRY_DECODERS {
+                decoders.remove(0);
+            }
+        }
+        _ => return None,
+    }
+    (data.len() == range.len()).then_some(EntryRange { range: ByteRange::Partial(range), data, len, etag })
+}
+
+/// The response to a range request read with `read_entry_range`.
+fn entry_range_response(
+    entry_range: EntryRange,
+    mime: &str,
+    if_none_match: Option<&str>,
+) -> http::Result<http::Response<Vec<u8>>> {
+    let EntryRange { range, data, len, etag } = entry_range;
+    let response = ResponseBuilder::new()
+        .header("Content-Type", mime)
+        .header("Accept-Ranges", "bytes")
+        .header("Cache-Control", format!("public, max-age={}", RESOURCE_MAX_AGE_SECS))
+        .header("ETag", &etag);
+    if etag_matches(if_none_match, &etag) {
+        return response.status(304).body(Vec::new());
+    }
+    match range {
+        ByteRange::Partial(range) => response
+            .status(206)
+            .header("Content-Range", format!("bytes {}-{}/{}", range.start, range.end - 1, len))
+            .header("Content-Length", range.len())
+            .body(data),
+        _ => response
+            .status(416)
+            .header("Content-Range", format!("bytes */{}", len))
+            .header("Content-Length", 0)
+            .body(Vec::new()),
+    }
+}
+
+/// The response to a resource read whole: `body` as served, or the part a
+/// `Range` header asks for, or a 304 when `If-None-Match` lists its tag.
+/// Chapters (`injected`) are revalidated on every load; other resources are
+/// cached for `RESOURCE_MAX_AGE_SECS`.
+fn resource_response(
+    body: Vec<u8>,
+    mime: &str,
+    injected: bool,
+    range: Option<&str>,
+    if_none_match: Option<&str>,
+) -> http::Result<http::Response<Vec<u8>>> {
+    let len = body.len();
+    let etag = resource_etag(&body);
+    let cache_control = if injected {
+        "no-cache".to_string()
+    } else {
+        format!("public, max-age={}", RESOURCE_MAX_AGE_SECS)
+    };
+    let response = ResponseBuilder::new()
+        .header("Content-Type", content_type(mime, &body, injected))
+        .header("Accept-Ranges", "bytes")
+        .header("Cache-Control", cache_control)
+        .header("ETag", &etag);
+    if etag_matches(if_none_match, &etag) {
+        return response.status(304).body(Vec::new());
+    }
+    match parse_byte_range(range, len) {
+        ByteRange::Full => response
+            .status(200)
+            .header("Content-Length", len)
+            .body(body),
+        ByteRange::Partial(range) => response
+            .status(206)
+            .header("Content-Range", format!("bytes {}-{}/{}", range.start, range.end - 1, len))
+            .header("Content-Length", range.len())
+            .body(body[range].to_vec()),
+        ByteRange::Unsatisfiable => response
+            .status(416)
+            .header("Content-Range", format!("bytes */{}", len))
+            .header("Content-Length", 0)
+            .body(Vec::new()),
+    }
+}
+
+/// The response to a resource that couldn't be read. A known-damaged book
+/// gets a distinct status (502, with the damage as the body), so the reader
+/// can say so rather than show a missing page; other failures are a 404.
+fn read_failure_response(damage: Option<&str>) -> http::Result<http::Response<Vec<u8>>> {
+    match damage {
+        Some(damage) => ResponseBuilder::new()
+            .status(502)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(format!("This book appears damaged: {}", damage).into_bytes()),
+        None => ResponseBuilder::new().status(404).body(Vec::new()),
+    }
+}
+
+/// Sends `response`, or an empty 500 if it couldn't be built (say, a
+/// manifest media type that isn't a valid header value) rather than
+/// panicking the handler thread.
+fn respond(responder: tauri::UriSchemeResponder, response: http::Result<http::Response<Vec<u8>>>) {
+    let response = response.unwrap_or_else(|e| {
+        log::error!(target: "epub_protocol", "Failed to build response: {}", e);
+        let mut response = http::Response::new(Vec::new());
+        *response.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    });
+    responder.respond(response);
+}
+
+/// The book host and resource path of an `epub://` request URI, the path
+/// without its leading `/` (empty for the host alone). `None` without a
+/// host: the host names the book (see `library::book_host`), so there's no
+/// book to serve from.
+fn request_target(uri: &http::Uri) -> Option<(String, String)> {
+    let host = uri.host().filter(|host| !host.is_empty())?;
+    let path = uri.path();
+    Some((host.to_string(), path.strip_prefix('/').unwrap_or(path).to_string()))
+}
+
+/// Returns the value of `key` in a URI query string like `links=route&x=1`.
+fn query_param<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        if k == key { Some(v) } else { None }
+    })
+}
+
+/// `path` with every character outside `[A-Za-z0-9._-]` in each segment
+/// replaced by `_`, e.g. `OEBPS/Images/Café 50%.jpg` becomes
+/// `OEBPS/Images/Caf__50_.jpg`.
+fn url_safe_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            segment.chars()
+                .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '_' })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Virtual paths for every resource of a book. Paths that are already URL
+/// safe map to themselves; clashes get a `-2`, `-3`, ... before the extension.
+fn build_virtual_paths(book: &EpubDoc<BufReader<File>>) -> VirtualPaths {
+    let mut archive_paths: Vec<String> = book.resources.values()
+        .map(|(path, _)| path.to_str().unwrap_or("").to_string())
+        .collect();
+    // Safe paths first so they keep their names, then a stable order
+    archive_paths.sort_by_key(|path| (url_safe_path(path) != *path, path.clone()));
+
+    let mut paths = VirtualPaths::default();
+    for archive_path in archive_paths {
+        let safe = url_safe_path(&archive_path);
+        let mut candidate = safe.clone();
+        let mut suffix = 2;
+        while paths.to_archive.contains_key(&candidate) {
+            candidate = match safe.rsplit_once('.') {
+                Some((stem, extension)) if !extension.contains('/') => format!("{}-{}.{}", stem, suffix, extension),
+                _ => format!("{}-{}", safe, suffix),
+            };
+            suffix += 1;
+        }
+        paths.to_archive.insert(candidate.clone(), archive_path.clone());
+        paths.to_virtual.insert(archive_path, candidate);
+    }
+    paths
+}
+
+fn cached_virtual_paths(
+    cache: &Mutex<VirtualPathMap>,
+    book_key: &str,
+    book: &EpubDoc<BufReader<File>>,
+) -> Arc<VirtualPaths> {
+    if let Some(paths) = cache.lock().get(book_key) {
+        return paths.clone();
+    }
+    let paths = Arc::new(build_virtual_paths(book));
+    cache.lock().insert(book_key.to_string(), paths.clone());
+    paths
+}
+
+/// The archive path an `epub://` request refers to: its percent-decoded
+/// form, the path as given (for file names that really contain `%`), a
+/// virtual path from `get_virtual_paths`, or the path as
+/// `resolve_resource_path` normalizes it.
+fn archive_path_for_request(
+    book: &EpubDoc<BufReader<File>>,
+    virtual_paths: &VirtualPaths,
+    requested: &str,
+) -> String {
+    let exists = |path: &str| book.resources.values().any(|(res_path, _)| res_path.to_str() == Some(path));
+    let decoded = percent_encoding::percent_decode_str(requested).decode_utf8_lossy();
+    if exists(decoded.as_ref()) {
+        return decoded.into_owned();
+    }
+    if exists(requested) {
+        return requested.to_string();
+    }
+    if let Some(path) = virtual_paths.to_archive.get(decoded.as_ref()) {
+        return path.clone();
+    }
+    // `.`/`..` segments, or a path relative to the content root
+    match resolve_resource_path(book, None, requested) {
+        (path, _) if exists(&path) => path,
+        _ => requested.to_string(),
+    }
+}
+
+/// Map from each archive path to its URL-safe virtual path. The `epub://`
+/// handler accepts either, so `epub://<book>/<virtual path>` always loads
+/// even when the original file name has spaces, `%` or non-ASCII characters.
+#[tauri::command]
+fn get_virtual_paths(
+    book_key: String,
+    state: tauri::State<LibraryState>,
+    virtual_paths: tauri::State<VirtualPathState>
+) -> Result<HashMap<String, String>, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    Ok(cached_virtual_paths(&virtual_paths.0, &book_key, book).to_virtual.clone())
+}
+
+/// The host to load the book's resources from, as in `epub://<host>/<path>`.
+#[tauri::command]
+fn get_book_host(book_key: String, state: tauri::State<LibraryState>) -> Result<String, AppError> {
+    if !state.0.lock().contains_key(&book_key) {
+        return Err(AppError::book_not_found(&book_key));
+    }
+    Ok(library::book_host(&book_key))
+}
+
+/// Whether `epub://<host>/<path>` would be served for the book, checked against the
+/// book's resource map without reading the resource. `path` is normalized as
+/// the protocol handler does: query, fragment and leading slash dropped,
+/// percent-encoding and virtual paths accepted.
+#[tauri::command]
+fn resource_exists(
+    book_key: String,
+    path: String,
+    state: tauri::State<LibraryState>,
+    virtual_paths: tauri::State<VirtualPathState>
+) -> bool {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let Some(book_handle) = Library::handle(&state.0, &book_key) else { return false };
+    let book = &*book_handle.lock();
+    let path = archive_path_for_request(book, &cached_virtual_paths(&virtual_paths.0, &book_key, book), path);
+    book.get_resource_mime_by_path(&path).is_ok() || path == NAV_DOCUMENT_PATH
+}
+
+/// Resolves an href found in the document at `base_path` to an archive path
+/// plus optional fragment, e.g. `("OEBPS/Text/ch1.xhtml", "../Text/ch2.xhtml#s1")`
+/// becomes `("OEBPS/Text/ch2.xhtml", Some("s1"))`.
+fn resolve_href(base_path: &str, href: &str) -> (String, Option<String>) {
+    let (target, fragment) = match href.split_once('#') {
+        Some((t, f)) => (t, Some(f.to_string())),
+        None => (href, None),
+    };
+    // Query strings never address archive entries
+    let target = target.split('?').next().unwrap_or("");
+
+    if target.is_empty() {
+        return (base_path.to_string(), fragment);
+    }
+
+    let decoded = percent_encoding::percent_decode_str(target).decode_utf8_lossy();
+    let mut segments: Vec<&str> = if decoded.starts_with('/') {
+        Vec::new()
+    } else {
+        let mut base: Vec<&str> = base_path.split('/').collect();
+        base.pop();
+        base
+    };
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+
+    (segments.join("/"), fragment)
+}
+
+/// Spine position of the resource stored at archive path `path`.
+fn spine_index_for_path(book: &EpubDoc<BufReader<File>>, path: &str) -> Option<usize> {
+    book.spine.iter().position(|id| {
+        book.resources.get(id)
+            .map(|(res_path, _)| res_path.to_str().unwrap_or("") == path)
+            .unwrap_or(false)
+    })
+}
+
+/// Spine document and anchor an in-book href points at, for navigating from
+/// TOC entries and links. `base_href` is the archive path of the document
+/// the href appears in; without it the href is taken as an archive path, as
+/// TOC `content` values are. `../` segments and percent-encoding are
+/// resolved, and this book's own `epub://` URIs are accepted too.
+#[tauri::command]
+fn resolve_link(
+    book_key: String,
+    href: String,
+    base_href: Option<String>,
+    state: tauri::State<LibraryState>
+) -> Result<LinkTarget, AppError> {
+    if is_external_href(&href) {
+        return Err(AppError::InvalidArgument(format!("Not a link within the book: {}", href)));
+    }
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (path, fragment) = resolve_book_href(book, &book_key, &href, base_href.as_deref());
+    let spine_index = spine_index_for_path(book, &path)
+        .ok_or_else(|| AppError::InvalidArgument(format!("Not a spine document: {}", path)))?;
+
+    Ok(LinkTarget {
+        spine_index,
+        uri: format!("epub://{}/{}", library::book_host(&book_key), path),
+        href: path,
+        fragment: fragment.filter(|fragment| !fragment.is_empty()),
+    })
+}
+
+#[derive(Serialize)]
+struct CfiStep {
+    /// Even for elements (the nth child element is `2n`), odd for text
+    index: u32,
+    /// Id asserted for the node, for finding it when the steps no longer fit
+    id: Option<String>,
+}
+
+/// Where a CFI points: the spine item, and the path within its document for
+/// the frontend to walk.
+#[derive(Serialize)]
+struct CfiTarget {
+    spine_index: usize,
+    href: String,
+    uri: String,
+    /// Steps through the document from its root, after the `!`
+    steps: Vec<CfiStep>,
+    /// Character offset into the text node the steps end at
+    offset: Option<u32>,
+    /// Last id asserted along the steps: the nearest element to scroll to
+    /// without walking them
+    fragment: Option<String>,
+    /// The document path as written in the CFI
+    path: String,
+}
+
+/// Resolves an EPUB CFI (`epubcfi(/6/4[chap01ref]!/4/2/1:10)`) from another
+/// reader to a spine item here, returning the rest of the path for the
+/// frontend to apply. The spine step's id assertion wins over its position,
+/// as the spec intends, so CFIs survive small spine differences.
+#[tauri::command]
+fn resolve_cfi(book_key: String, cfi: String, state: tauri::State<LibraryState>) -> Result<CfiTarget, AppError> {
+    let parsed = cfi::parse(&cfi).map_err(AppError::InvalidArgument)?;
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let package = read_package(book)?;
+    let asserted = parsed.package_steps.last()
+        .and_then(|step| step.assertion.as_deref())
+        .and_then(|id| package.spine.iter().position(|item| item.id.as_deref() == Some(id)));
+    let spine_index = asserted.or_else(|| parsed.spine_position())
+        .filter(|&spine_index| spine_index < book.spine.len())
+        .ok_or_else(|| AppError::InvalidArgument(format!("CFI points outside the spine: {}", cfi)))?;
+    let (path, _) = book.spine.get(spine_index)
+        .and_then(|id| book.resources.get(id))
+        .ok_or_else(|| AppError::InvalidEpub(format!("Spine item {} has no manifest entry", spine_index)))?;
+    let href = path.to_string_lossy().into_owned();
+
+    Ok(CfiTarget {
+        spine_index,
+        uri: format!("epub://{}/{}", library::book_host(&book_key), href),
+        href,
+        fragment: parsed.content_steps.iter().rev().find_map(|step| step.assertion.clone()),
+        steps: parsed.content_steps.into_iter().map(|step| CfiStep { index: step.index, id: step.assertion }).collect(),
+        offset: parsed.offset,
+        path: parsed.content_path,
+    })
+}
+
+/// Archive path and fragment of an in-book href, which may be one of this
+/// book's `epub://` URIs. Relative hrefs, fragment-only ones included,
+/// resolve against `base_href` when given; see `resolve_resource_path`.
+fn resolve_book_href(
+    book: &EpubDoc<BufReader<File>>,
+    book_key: &str,
+    href: &str,
+    base_href: Option<&str>,
+) -> (String, Option<String>) {
+    let epub_prefix = format!("epub://{}/", library::book_host(book_key));
+    match href.strip_prefix(&epub_prefix) {
+        Some(own) => resolve_resource_path(book, None, own),
+        None => resolve_resource_path(book, base_href, href),
+    }
+}
+
+/// Archive path and fragment `href` refers to from the resource at `base`
+/// (or from the archive root without one): joined against `base`'s
+/// directory, percent-decoded and with `.`/`..` segments collapsed. A path
+/// that isn't in the book but is once taken relative to the package
+/// document's directory (`OEBPS/`, `Content/`, ...) resolves to that, for
+/// hrefs written relative to the content root. Otherwise the joined path is
+/// returned even if the book lacks it.
+fn resolve_resource_path(
+    book: &EpubDoc<BufReader<File>>,
+    base: Option<&str>,
+    href: &str,
+) -> (String, Option<String>) {
+    let exists = |path: &str| book.resources.values().any(|(res_path, _)| res_path.to_str() == Some(path));
+    let (path, fragment) = resolve_href(base.unwrap_or(""), href);
+    if path.is_empty() || exists(&path) {
+        return (path, fragment);
+    }
+
+    let root_file = book.root_file.to_str().unwrap_or("");
+    let (content_path, _) = resolve_href(root_file, &path);
+    if exists(&content_path) {
+        (content_path, fragment)
+    } else {
+        (path, fragment)
+    }
+}
+
+/// The archive path `href` refers to from the resource at `base`, resolved
+/// as chapters' links and the `epub://` handler resolve paths, so the
+/// frontend doesn't need its own path handling. Fragments are dropped.
+#[tauri::command]
+fn normalize_resource_path(
+    book_key: String,
+    base: Option<String>,
+    href: String,
+    state: tauri::State<LibraryState>
+) -> Result<String, AppError> {
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (path, _) = resolve_book_href(book, &book_key, &href, base.as_deref());
+    Ok(path)
+}
+
+/// Spine position of the document an href (a TOC entry's, say) points at,
+/// with any fragment ignored. Resolved as in `resolve_link`; a fragment-only
+/// href needs `base_href`, the document it appears in. `None` for hrefs
+/// outside the book or to resources that aren't in the spine.
+#[tauri::command]
+fn get_spine_index_for_href(
+    book_key: String,
+    href: String,
+    base_href: Option<String>,
+    state: tauri::State<LibraryState>
+) -> Result<Option<usize>, AppError> {
+    if is_external_href(&href) {
+        return Ok(None);
+    }
+
+    let book = book_handle(&state.0, &book_key)?;
+    let book = &mut *book.lock();
+    let (path, _) = resolve_book_href(book, &book_key, &href, base_href.as_deref());
+    Ok(spine_index_for_path(book, &path))
+}
+
+/// True for hrefs that leave the book (http:, mailto:, tel:, ...).
+fn is_external_href(href: &str) -> bool {
+    match href.split_once(':') {
+        Some((scheme, _)) => {
+            let is_scheme = !scheme.is_empty()
+                && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+            is_scheme && !scheme.eq_ignore_ascii_case("epub")
+        }
+        None => false,
+    }
+}
+
+/// Tags internal `<a href>`s with their target spine index and fragment so the
+/// injected script can hand navigation to the frontend router instead of
+/// letting the iframe follow the link (`links=route` mode).
+fn annotate_internal_links(
+    html_content: Vec<u8>,
+    chapter_path: &str,
+    book_key: &str,
+    book: &EpubDoc<BufReader<File>>,
+) -> Vec<u8> {
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+    let epub_prefix = format!("epub://{}/", library::book_host(book_key));
+
+    html::rewrite_start_tags(&html_str, |tag| {
+        if !tag.is("a") {
+            return None;
+        }
+        let href = tag.attr("href")?.trim();
+        if href.is_empty() || is_external_href(href) {
+            return None;
+        }
+
+        let (path, fragment) = if let Some(own) = href.strip_prefix(&epub_prefix) {
+            resolve_href("", own)
+        } else if href.starts_with("epub:") {
+            // Links into a different book are left for the iframe to follow
+            return None;
+        } else {
+            resolve_href(chapter_path, href)
+        };
+        let spine_index = spine_index_for_path(book, &path)?.to_string();
+
+        let mut set = vec![("data-epub-spine-index", spine_index.as_str())];
+        if let Some(fragment) = fragment.as_deref() {
+            set.push(("data-epub-fragment", fragment));
+        }
+        Some(tag.rewrite(&set, &[]))
+    }).into_bytes()
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LinkType {
+    /// Another document of the book
+    Internal,
+    /// A web page, mail address or other non-`epub:` URL
+    External,
+    /// A footnote reference (`epub:type="noteref"`)
+    Footnote,
+    /// A spot in the same document
+    Anchor,
+}
+
+impl LinkType {
+    fn as_str(self) -> &'static str {
+        match self {
+            LinkType::Internal => "internal",
+            LinkType::External => "external",
+            LinkType::Footnote => "footnote",
+            LinkType::Anchor => "anchor",
+        }
+    }
+}
+
+/// What an `<a href>` in the document at `chapter_path` points at; `None`
+/// for other tags and links without a target.
+fn classify_link(tag: &html::Tag, chapter_path: &str) -> Option<LinkType> {
+    if !tag.is("a") {
+        return None;
+    }
+    let href = tag.attr("href")?.trim();
+    if href.is_empty() {
+        return None;
+    }
+
+    Some(if is_noteref(tag) {
+        LinkType::Footnote
+    } else if is_external_href(href) {
+        LinkType::External
+    } else if href.starts_with('#') || resolve_href(chapter_path, href).0 == chapter_path {
+        LinkType::Anchor
+    } else {
+        LinkType::Internal
+    })
+}
+
+/// Adds a `data-link-type` attribute to every link so the reader's CSS can
+/// style them by kind and the injected script knows which to hand out.
+fn annotate_link_types(html_content: Vec<u8>, chapter_path: &str) -> Vec<u8> {
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+
+    html::rewrite_start_tags(&html_str, |tag| {
+        let link_type = classify_link(tag, chapter_path)?;
+        Some(tag.rewrite(&[("data-link-type", link_type.as_str())], &[]))
+    }).into_bytes()
+}
+
+/// Second key of a chapter in the chapter cache: its path plus everything
+/// that changes what's served for it. A changed theme or content mode is a
+/// different key, so a stale body is never served.
+fn chapter_cache_key(path: &str, theme: &ReadingTheme, options: &InjectionOptions) -> String {
+    let modes = [options.route_links, options.inline_notes, options.responsive_tables, options.image_srcset, options.block_remote];
+    format!(
+        "{}\n{}\n{}\n{:?}",
+        path,
+        serde_json::to_string(theme).unwrap_or_default(),
+        modes.map(|on| if on { '1' } else { '0' }).iter().collect::<String>(),
+        options.injection,
+    )
+}
+
+/// Runs a chapter's markup through the content modes and theme injection the
+/// `epub://` handler applies to HTML documents.
+fn prepare_chapter(
+    resource: Vec<u8>,
+    path: &str,
+    book_key: &str,
+    book: &mut EpubDoc<BufReader<File>>,
+    theme: &ReadingTheme,
+    options: &InjectionOptions,
+) -> Vec<u8> {
+    // The steps below work on UTF-8 text
+    let decoded = match html::to_utf8_document(&resource) {
+        std::borrow::Cow::Owned(text) => Some(text.into_bytes()),
+        std::borrow::Cow::Borrowed(_) => None,
+    };
+    let resource = decoded.unwrap_or(resource);
+    let resource = if options.block_remote {
+        block_remote_resources(resource)
+    } else {
+        resource
+    };
+    let resource = if options.inline_notes {
+        inline_footnotes(resource, path, book)
+    } else {
+        resource
+    };
+    let resource = if options.route_links {
+        annotate_internal_links(resource, path, book_key, book)
+    } else {
+        resource
+    };
+    let resource = annotate_link_types(resource, path);
+    let resource = if options.responsive_tables {
+        wrap_tables(resource)
+    } else {
+        resource
+    };
+    let resource = if options.image_srcset {
+        add_image_srcsets(resource, path, book)
+    } else {
+        resource
+    };
+    let direction = TextDirection::of(book);
+    let resource = set_document_direction(resource, &direction);
+    inject_link_handler_script(resource, theme, options, &direction)
+}
+
+/// Longest note text inlined into a footnote reference, in characters.
+const MAX_INLINE_NOTE_CHARS: usize = 1000;
+
+fn is_noteref(tag: &html::Tag) -> bool {
+    let has_token = |attr: &str, token: &str| {
+        tag.attr(attr)
+            .map(|value| value.split_whitespace().any(|v| v == token))
+            .unwrap_or(false)
+    };
+    tag.is("a") && (has_token("epub:type", "noteref") || has_token("role", "doc-noteref"))
+}
+
+/// Single-line text of the note with the given id, truncated for a tooltip.
+fn note_text(document: &str, id: &str) -> Option<String> {
+    let content = &document[html::element_content_by_id(document, id)?];
+    let text = html::extract_text(content).split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    Some(truncate_text(&text, MAX_INLINE_NOTE_CHARS))
+}
+
+/// Copies the text of each `epub:type="noteref"` link's target note into its
+/// `title` and `data-note` attributes (`notes=inline` mode), so notes can be
+/// shown on hover/tap without leaving the chapter. Notes may live in the same
+/// document or a separate notes resource; unresolvable references are left
+/// untouched.
+fn inline_footnotes(
+    html_content: Vec<u8>,
+    chapter_path: &str,
+    book: &mut EpubDoc<BufReader<File>>,
+) -> Vec<u8> {
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+    // Other documents referenced by notes, read at most once each
+    let mut documents: HashMap<String, Option<String>> = HashMap::new();
+
+    html::rewrite_start_tags(&html_str, |tag| {
+        if !is_noteref(tag) {
+            return None;
+        }
+        let href = html::decode_entities(tag.attr("href")?.trim()).into_owned();
+        if is_external_href(&href) {
+            return None;
+        }
+        let (path, fragment) = resolve_href(chapter_path, &href);
+        let fragment = percent_encoding::percent_decode_str(&fragment?).decode_utf8_lossy().into_owned();
+
+        let note = if path == chapter_path {
+            note_text(&html_str, &fragment)
+        } else {
+            documents.entry(path.clone())
+                .or_insert_with(|| read_document(book, &path).ok().map(|(_, markup)| markup))
+                .as_deref()
+                .and_then(|document| note_text(document, &fragment))
+        }?;
+
+        Some(tag.rewrite(&[("title", &note), ("data-note", &note)], &[]))
+    }).into_bytes()
+}
+
+/// Sets the book's language (`lang`/`xml:lang`) and, for right-to-left
+/// books, `dir="rtl"` on the root `<html>` element, so the webview lays out
+/// and hyphenates the text correctly. A language or direction the chapter
+/// declares itself is kept.
+fn set_document_direction(html_content: Vec<u8>, direction: &TextDirection) -> Vec<u8> {
+    if direction.language.is_none() && !direction.rtl {
+        return html_content;
+    }
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+
+    let mut done = false;
+    html::rewrite_start_tags(&html_str, |tag| {
+        if done || !tag.is("html") {
+            return None;
+        }
+        done = true;
+        let mut attrs = Vec::new();
+        if let Some(language) = direction.language.as_deref() {
+            if tag.attr("lang").is_none() && tag.attr("xml:lang").is_none() {
+                attrs.extend([("lang", language), ("xml:lang", language)]);
+            }
+        }
+        if direction.rtl && tag.attr("dir").is_none() {
+            attrs.push(("dir", "rtl"));
+        }
+        (!attrs.is_empty()).then(|| tag.rewrite(&attrs, &[]))
+    }).into_bytes()
+}
+
+/// Wraps every `<table>` in a horizontally scrollable container
+/// (`tables=responsive` mode). Table markup itself is left untouched.
+fn wrap_tables(html_content: Vec<u8>) -> Vec<u8> {
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+
+    let mut out = String::with_capacity(html_str.len());
+    let mut copied_to = 0;
+    for (token, range) in html::tokenize(&html_str) {
+        match token {
+            html::Token::StartTag(tag) if tag.is("table") && !tag.self_closing => {
+                out.push_str(&html_str[copied_to..range.start]);
+                out.push_str("<div class=\"epub-table-scroll\">");
+                copied_to = range.start;
+            }
+            html::Token::EndTag(name) if name.eq_ignore_ascii_case("table") => {
+                out.push_str(&html_str[copied_to..range.end]);
+                out.push_str("</div>");
+                copied_to = range.end;
+            }
+            _ => {}
+        }
+    }
+    out.push_str(&html_str[copied_to..]);
+    out.into_bytes()
+}
+
+/// Shown in place of remote images in privacy mode.
+const BLOCKED_IMAGE_PLACEHOLDER: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' width='300' height='200'%3E%3Crect width='300' height='200' fill='%23e0e0e0'/%3E%3Ctext x='50%25' y='50%25' dominant-baseline='middle' text-anchor='middle' font-family='sans-serif' font-size='16' fill='%23999'%3ERemote image blocked%3C/text%3E%3C/svg%3E";
+
+/// Attributes through which an element loads a resource by itself.
+const RESOURCE_ATTRIBUTES: &[&str] = &["src", "srcset", "href", "xlink:href", "poster", "data", "background"];
+/// SVG elements whose `href` loads a resource rather than being a link.
+const RESOURCE_HREF_ELEMENTS: &[&str] = &["image", "use", "feimage"];
+
+fn is_remote_url(url: &str) -> bool {
+    let url = url.trim_start();
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme).unwrap_or("");
+    scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")
+}
+
+/// `css` with every remote `url(...)` emptied and remote `@import`s dropped.
+fn strip_remote_css(css: &str) -> std::borrow::Cow<'_, str> {
+    let lower = css.to_ascii_lowercase();
+    if !lower.contains("http") {
+        return std::borrow::Cow::Borrowed(css);
+    }
+
+    let mut out = String::with_capacity(css.len());
+    let mut pos = 0;
+    while pos < css.len() {
+        let next_url = lower[pos..].find("url(").map(|i| (pos + i, false));
+        let next_import = lower[pos..].find("@import").map(|i| (pos + i, true));
+        let Some((start, is_import)) = [next_url, next_import].into_iter().flatten().min() else { break };
+        out.push_str(&css[pos..start]);
+        if is_import {
+            let end = css[start..].find(';').map(|i| start + i + 1).unwrap_or(css.len());
+            let target = css[start + "@import".len()..end].trim_start();
+            let target = target.strip_prefix("url(").unwrap_or(target);
+            if !is_remote_url(target.trim_start_matches(['"', '\''])) {
+                out.push_str(&css[start..end]);
+            }
+            pos = end;
+        } else {
+            let end = css[start..].find(')').map(|i| start + i + 1).unwrap_or(css.len());
+            let target = css[start + "url(".len()..end].trim_start().trim_start_matches(['"', '\'']);
+            out.push_str(if is_remote_url(target) { "none" } else { &css[start..end] });
+            pos = end;
+        }
+    }
+    out.push_str(&css[pos.min(css.len())..]);
+    if out == css {
+        std::borrow::Cow::Borrowed(css)
+    } else {
+        std::borrow::Cow::Owned(out)
+    }
+}
+
+/// Strips references that would make the webview fetch from the web, so
+/// opening a chapter can't report reading to a third party (privacy mode).
+/// Remote scripts and `<link>`s are dropped, remote images show a
+/// placeholder (the original URL kept in `data-epub-blocked-src`), other
+/// remote resource attributes are removed and remote CSS `url()`s emptied.
+/// `epub://`, `data:` and relative references are left alone, as are
+/// ordinary links, which load nothing until followed.
+fn block_remote_resources(html_content: Vec<u8>) -> Vec<u8> {
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+
+    let mut out = String::with_capacity(html_str.len());
+    let mut copied_to = 0;
+    // Set inside a dropped `<script>` until its end tag
+    let mut skipping_script = false;
+    let mut in_style = false;
+    for (token, range) in html::tokenize(&html_str) {
+        if skipping_script {
+            if matches!(token, html::Token::EndTag(name) if name.eq_ignore_ascii_case("script")) {
+                skipping_script = false;
+                copied_to = range.end;
+            }
+            continue;
+        }
+
+        let replacement = match token {
+            html::Token::StartTag(tag) => {
+                in_style = tag.is("style") && !tag.self_closing;
+                let remote = |name: &str| tag.attr(name).is_some_and(|value| {
+                    let value = html::decode_entities(value);
+                    if name.eq_ignore_ascii_case("srcset") {
+                        value.split(',').any(is_remote_url)
+                    } else {
+                        is_remote_url(&value)
+                    }
+                });
+                let loads_href = RESOURCE_HREF_ELEMENTS.iter().any(|name| tag.is(name));
+                let remote_attrs: Vec<&str> = RESOURCE_ATTRIBUTES.iter().copied()
+                    .filter(|name| loads_href || !name.ends_with("href"))
+                    .filter(|name| remote(name))
+                    .collect();
+                let style = tag.attr("style").map(html::decode_entities);
+                let stripped_style = style.as_deref().map(strip_remote_css).filter(|css| matches!(css, std::borrow::Cow::Owned(_)));
+
+                if tag.is("script") && remote("src") {
+                    skipping_script = !tag.self_closing;
+                    Some(String::new())
+                } else if tag.is("link") && remote("href") {
+                    Some(String::new())
+                } else if tag.is("img") && (remote("src") || remote("srcset")) {
+                    let original = tag.attr("src").map(html::decode_entities).unwrap_or_default().into_owned();
+                    Some(tag.rewrite(&[("src", BLOCKED_IMAGE_PLACEHOLDER), ("data-epub-blocked-src", &original)], &["srcset"]))
+                } else if !remote_attrs.is_empty() || stripped_style.is_some() {
+                    let set: Vec<(&str, &str)> = stripped_style.as_deref().map(|css| ("style", css)).into_iter().collect();
+                    Some(tag.rewrite(&set, &remote_attrs))
+                } else {
+                    None
+                }
+            }
+            html::Token::EndTag(name) if name.eq_ignore_ascii_case("style") => {
+                in_style = false;
+                None
+            }
+            html::Token::Text if in_style => match strip_remote_css(&html_str[range.clone()]) {
+                std::borrow::Cow::Owned(css) => Some(css),
+                std::borrow::Cow::Borrowed(_) => None,
+            },
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            out.push_str(&html_str[copied_to..range.start]);
+            out.push_str(&replacement);
+            copied_to = range.end;
+        }
+    }
+    if !skipping_script {
+        out.push_str(&html_str[copied_to..]);
+    }
+    out.into_bytes()
+}
+
+/// Highest pixel density looked for when building a `srcset`.
+const MAX_IMAGE_DENSITY: u32 = 4;
+
+/// File names (density, name) of the resolution variants of an image that
+/// ship in the same directory, following the `name@2x.png` convention.
+/// Empty unless at least two densities exist.
+fn resolution_variants(archive_paths: &std::collections::HashSet<String>, path: &str) -> Vec<(u32, String)> {
+    let (dir, file) = match path.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), path),
+    };
+    let Some((stem, extension)) = file.rsplit_once('.') else {
+        return Vec::new();
+    };
+    // `img@2x.png` and `img.png` share the base stem `img`
+    let base_stem = stem.rsplit_once('@')
+        .filter(|(_, density)| density.strip_suffix('x').map(|d| d.parse::<u32>().is_ok()).unwrap_or(false))
+        .map(|(base, _)| base)
+        .unwrap_or(stem);
+
+    let variants: Vec<(u32, String)> = (1..=MAX_IMAGE_DENSITY)
+        .filter_map(|density| {
+            let name = if density == 1 {
+                format!("{}.{}", base_stem, extension)
+            } else {
+                format!("{}@{}x.{}", base_stem, density, extension)
+            };
+            archive_paths.contains(&format!("{}{}", dir, name)).then_some((density, name))
+        })
+        .collect();
+    if variants.len() > 1 { variants } else { Vec::new() }
+}
+
+/// Adds a `srcset` to images the book ships at several resolutions
+/// (`images=srcset` mode) so high-DPI displays pick the sharpest one.
+/// Images with a single resolution, or a `srcset` of their own, are untouched.
+fn add_image_srcsets(html_content: Vec<u8>, chapter_path: &str, book: &EpubDoc<BufReader<File>>) -> Vec<u8> {
+    let html_str = match String::from_utf8(html_content) {
+        Ok(s) => s,
+        Err(e) => return e.into_bytes(),
+    };
+    let archive_paths: std::collections::HashSet<String> = book.resources.values()
+        .map(|(path, _)| path.to_str().unwrap_or("").to_string())
+        .collect();
+
+    html::rewrite_start_tags(&html_str, |tag| {
+        if !tag.is("img") || tag.attr("srcset").is_some() {
+            return None;
+        }
+        let src = html::decode_entities(tag.attr("src")?).into_owned();
+        if src.starts_with("data:") || is_external_href(&src) {
+            return None;
+        }
+
+        let (path, _) = resolve_href(chapter_path, &src);
+        let variants = resolution_variants(&archive_paths, &path);
+        if variants.is_empty() {
+            return None;
+        }
+        // Variants sit next to the original, so only the file name changes
+        let src_dir = src.rsplit_once('/').map(|(dir, _)| format!("{}/", dir)).unwrap_or_default();
+        let srcset = variants.iter()
+            .map(|(density, name)| format!("{}{} {}x", src_dir, name, density))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(tag.rewrite(&[("srcset", &srcset)], &[]))
+    }).into_bytes()
+}
+
+const RESPONSIVE_TABLES_CSS: &str = r#"<style>
+.epub-table-scroll {
+    max-width: 100%;
+    overflow-x: auto;
+    -webkit-overflow-scrolling: touch;
+}
+
+/* Let the table keep its natural width; the container scrolls instead */
+.epub-table-scroll > table {
+    max-width: none !important;
+}
+</style>"#;
+
+/// Popover shown when a footnote reference with inlined text is tapped.
+const NOTE_POPOVER_INJECTION: &str = r#"<style>
+.epub-note-popover {
+    position: fixed;
+    z-index: 2147483647;
+    max-width: min(32rem, calc(100vw - 32px));
+    max-height: 40vh;
+    overflow-y: auto;
+    padding: 12px 16px;
+    border-radius: 8px;
+    background-color: #ffffff;
+    color: #1a1a1a;
+    box-shadow: 0 4px 16px rgba(0, 0, 0, 0.25);
+    font-size: 0.9em;
+    line-height: 1.5;
+}
+
+@media (prefers-color-scheme: dark) {
+    .epub-note-popover {
+        background-color: #2d2d2d;
+        color: #e4e4e4;
+    }
+}
+</style>
+<script>
+//<![CDATA[
+(function() {
+    'use strict';
+
+    let popover = null;
+
+    function hidePopover() {
+        if (popover) {
+            popover.remove();
+            popover = null;
+        }
+    }
+
+    function showPopover(link) {
+        hidePopover();
+        popover = document.createElement('div');
+        popover.className = 'epub-note-popover';
+        popover.textContent = link.getAttribute('data-note');
+        // Outside <body> so paginated column layout doesn't affect it
+        document.documentElement.appendChild(popover);
+
+        const rect = link.getBoundingClientRect();
+        const width = popover.offsetWidth;
+        const height = popover.offsetHeight;
+        const left = Math.max(16, Math.min(rect.left, window.innerWidth - width - 16));
+        const below = rect.bottom + 6;
+        const top = below + height > window.innerHeight - 16 ? Math.max(16, rect.top - height - 6) : below;
+        popover.style.left = left + 'px';
+        popover.style.top = top + 'px';
+    }
+
+    // Window capture runs before the page's own link and page-turn handlers
+    window.addEventListener('click', function(event) {
+        const link = event.target.closest && event.target.closest('a[data-note]');
+        if (!link) {
+            if (popover && !popover.contains(event.target)) {
+                hidePopover();
+            }
+            return;
+        }
+        event.preventDefault();
+        event.stopPropagation();
+        showPopover(link);
+    }, true);
+
+    document.addEventListener('keydown', function(event) {
+        if (event.key === 'Escape') {
+            hidePopover();
+        }
+    });
+})();
+//]]>
+</script>"#;
+
+/// Default styling for EPUB content, with dark mode support and the base
+/// rules for paginated layout.
+const DEFAULT_CONTENT_CSS: &str = r#"<style>
+/* Default styling for EPUB content - applied before EPUB's own CSS */
+:root {
+    color-scheme: light dark;
+}
+
+html, body {
+    background-color: #ffffff;
+    color: #1a1a1a;
+
+    /* Responsive margins for comfortable reading */
+    padding-left: max(16px, min(5vw, 80px));
+    padding-right: max(16px, min(5vw, 80px));
+    padding-top: max(16px, min(3vh, 48px));
+    padding-bottom: max(32px, min(5vh, 64px));
+
+    /* Optimal line width for readability */
+    max-width: 50rem;
+    margin-left: auto;
+    margin-right: auto;
+
+    /* Improve text rendering */
+    line-height: 1.6;
+}
+
+a {
+    color: #0066cc;
+}
+
+a:visited {
+    color: #551a8b;
+}
+
+a:hover {
+    color: #003d7a;
+}
+
+@media (prefers-color-scheme: dark) {
+    html, body {
+        background-color: #1e1e1e;
+        color: #e4e4e4;
+
+        /* Responsive margins for comfortable reading */
+        padding-left: max(16px, min(5vw, 80px));
+        padding-right: max(16px, min(5vw, 80px));
+        padding-top: max(16px, min(3vh, 48px));
+        padding-bottom: max(32px, min(5vh, 64px));
+
+        /* Optimal line width for readability */
+        max-width: 50rem;
+        margin-left: auto;
+        margin-right: auto;
+
+        /* Improve text rendering */
+        line-height: 1.6;
+    }
+
+    a {
+        color: #58a6ff;
+    }
+
+    a:visited {
+        color: #d2a8ff;
+    }
+
+    a:hover {
+        color: #79b8ff;
+    }
+}
+
+/* ============================================
+   PAGINATION: CSS Multi-Column Layout
+   ============================================ */
+
+/* Base styles for html/body to ensure full height */
+html {
+    height: 100%;
+    margin: 0;
+    padding: 0;
+    overflow: hidden;
+}
+
+body {
+    height: 100%;
+    margin: 0;
+    padding: 0;
+    overflow: hidden;
+}
+
+body.paginated {
+    max-width: none !important;
+    /* column-width and width set by JavaScript, not CSS */
+    column-gap: 0;
+    column-fill: auto;
+    height: 100%;              /* Changed from 100vh - uses iframe height */
+    /* width is set by JavaScript to match pageWidth */
+    overflow-x: hidden;        /* Hide horizontal scrollbar */
+    overflow-y: hidden;        /* Hide vertical scrollbar */
+    padding: 0 !important;
+    margin: 0 !important;
+    scroll-behavior: smooth;
+}
+
+body.paginated > * {
+    padding-left: max(16px, min(5vw, 80px));
+    padding-right: max(16px, min(5vw, 80px));
+    padding-top: max(16px, min(3vh, 48px));
+    padding-bottom: max(32px, min(5vh, 64px));
+}
+
+/* Prevent images and tables from breaking across columns */
+body.paginated img,
+body.paginated table {
+    -webkit-column-break-inside: avoid;
+    break-inside: avoid;
+    max-width: 100%;
+}
+
+/* Dark mode pagination */
+@media (prefers-color-scheme: dark) {
+    body.paginated {
+        background-color: #1e1e1e;
+        color: #e4e4e4;
+    }
+}
+</style>"#;
+
+/// Tells the injected script the chapter is being previewed (`mode=preview`).
+const PREVIEW_MODE_META: &str = r#"<meta name="epub-reader-mode" content="preview" />"#;
+
+/// Pagination, link routing and gesture handling for served chapters.
+const LINK_HANDLER_SCRIPT: &str = r#"<script>
+//<![CDATA[
+(function() {
+    'use strict';
+
+    // ==========================================
+    // PAGINATION STATE
+    // ==========================================
+    let paginationEnabled = false;
+    let currentPage = 0;
+    let totalPages = 0;
+    let pageWidth = 0;
+    // Distance between page starts: the page plus one column gap
+    let pageStride = 0;
+
+    // ==========================================
+    // INITIALIZATION
+    // ==========================================
+    function initializePagination() {
+        // Wait for DOM to be ready before enabling pagination
+        function init() {
+            // Enable pagination by default
+            enablePagination();
+
+            // Listen for messages from parent
+            window.addEventListener('message', handleParentMessage);
+
+            // Recalculate on resize
+            window.addEventListener('resize', debounce(calculatePages, 250));
+
+            // Initial calculation after content loads
+            setTimeout(calculatePages, 100);
+        }
+
+        // Check if DOM is ready
+        if (document.readyState === 'loading') {
+            document.addEventListener('DOMContentLoaded', init);
+        } else {
+            // DOM is already ready
+            init();
+        }
+    }
+
+    // ==========================================
+    // PAGINATION CONTROL
+    // ==========================================
+    function enablePagination() {
+        if (paginationEnabled) return;
+        if (!document.body) {
+            console.error('[Pagination] document.body is not available yet');
+            return;
+        }
+
+        paginationEnabled = true;
+        document.body.classList.add('paginated');
+
+        setTimeout(function() {
+            calculatePages();
+            sendPaginationUpdate();
+        }, 100);
+    }
+
+    function disablePagination() {
+        if (!paginationEnabled) return;
+        if (!document.body) return;
+
+        paginationEnabled = false;
+        document.body.classList.remove('paginated');
+        document.body.scrollLeft = 0;
+    }
+
+    // ==========================================
     // PAGE CALCULATION
     // ==========================================
     function calculatePages() {
@@ -386,10 +8448,17 @@ body.paginated table {
         pageWidth = document.documentElement.clientWidth;
         const pageHeight = document.documentElement.clientHeight;
 
-        // Set explicit body dimensions - width AND column width must match
-        // This ensures only ONE column is visible at a time
+        // Columns per page come from the reading theme (--epub-columns);
+        // N columns and N-1 gaps exactly fill one page width
+        const columns = Math.max(1, parseInt(getComputedStyle(document.documentElement).getPropertyValue('--epub-columns'), 10) || 1);
+        const columnGap = columns > 1 ? 48 : 0;
+        pageStride = pageWidth + columnGap;
+
+        // Set explicit body dimensions - width and column width must match
+        // so only one page of columns is visible at a time
         document.body.style.width = pageWidth + 'px';
-        document.body.style.columnWidth = pageWidth + 'px';
+        document.body.style.columnWidth = ((pageWidth - columnGap * (columns - 1)) / columns) + 'px';
+        document.body.style.columnGap = columnGap + 'px';
         document.body.style.height = pageHeight + 'px';
 
         // Poll until columns are formed (scrollWidth > pageWidth indicates multiple columns)
@@ -408,7 +8477,7 @@ body.paginated table {
             // Check if columns have formed (scrollWidth > pageWidth indicates content spans multiple columns)
             // OR if we've reached max attempts (fall back to at least 1 page)
             if (scrollWidth > pageWidth || attempts >= maxAttempts) {
-                totalPages = Math.max(1, Math.ceil(scrollWidth / pageWidth));
+                totalPages = Math.max(1, Math.ceil((scrollWidth + pageStride - pageWidth) / pageStride));
                 currentPage = Math.min(currentPage, totalPages - 1);
 
                 console.log('[Pagination Result]', {
@@ -437,8 +8506,10 @@ body.paginated table {
         if (!paginationEnabled) return;
         if (!document.body) return;
 
-        // Default animated to true
-        if (animated === undefined) animated = true;
+        // Animated unless the theme turns page animation off (e-ink mode)
+        if (animated === undefined) {
+            animated = getComputedStyle(document.documentElement).getPropertyValue('--epub-page-animation').trim() !== 'none';
+        }
 
         // Clamp page number
         pageNumber = Math.max(0, Math.min(pageNumber, totalPages - 1));
@@ -447,7 +8518,7 @@ body.paginated table {
         currentPage = pageNumber;
 
         // Calculate scroll position
-        const scrollLeft = pageNumber * pageWidth;
+        const scrollLeft = pageNumber * pageStride;
 
         // Scroll to page
         if (animated) {
@@ -581,222 +8652,1926 @@ body.paginated table {
                 if (typeof message.page === 'number') {
                     navigateToPage(message.page);
                 }
-                break;
-            case 'pagination-enable':
-                enablePagination();
-                break;
-            case 'pagination-disable':
-                disablePagination();
-                break;
+                break;
+            case 'pagination-enable':
+                enablePagination();
+                break;
+            case 'pagination-disable':
+                disablePagination();
+                break;
+        }
+    }
+
+    function sendPaginationUpdate() {
+        if (window.parent && window.parent !== window) {
+            window.parent.postMessage({
+                type: 'pagination-update',
+                currentPage: currentPage,
+                totalPages: totalPages,
+                enabled: paginationEnabled,
+                // Previews must not be saved as the reading position
+                preview: document.querySelector('meta[name="epub-reader-mode"][content="preview"]') !== null
+            }, '*');
+        }
+    }
+
+    // ==========================================
+    // UTILITIES
+    // ==========================================
+    function debounce(func, wait) {
+        let timeout;
+        return function() {
+            const context = this;
+            const args = arguments;
+            clearTimeout(timeout);
+            timeout = setTimeout(function() {
+                func.apply(context, args);
+            }, wait);
+        };
+    }
+
+    // ==========================================
+    // LINK HANDLER (existing functionality)
+    // ==========================================
+    function handleLinkClick(event) {
+        const target = event.target.closest('a');
+        if (!target || !target.href) return;
+
+        const href = target.href;
+
+        // Internal link pre-resolved by the backend (links=route mode):
+        // let the frontend router perform the navigation
+        const spineIndex = target.getAttribute('data-epub-spine-index');
+        if (spineIndex !== null) {
+            event.preventDefault();
+            event.stopPropagation();
+
+            if (window.parent && window.parent !== window) {
+                window.parent.postMessage({
+                    type: 'epub-navigate',
+                    spine_index: parseInt(spineIndex, 10),
+                    fragment: target.getAttribute('data-epub-fragment')
+                }, '*');
+            }
+            return;
+        }
+
+        // Classified by the backend when the chapter was served
+        const linkType = target.getAttribute('data-link-type');
+        if (linkType ? linkType === 'external' : isExternalLink(href)) {
+            event.preventDefault();
+            event.stopPropagation();
+
+            if (window.parent && window.parent !== window) {
+                window.parent.postMessage({
+                    type: 'epub-external-link',
+                    url: href
+                }, '*');
+            }
+        }
+    }
+
+    function isExternalLink(href) {
+        try {
+            const url = new URL(href, window.location.href);
+            const protocol = url.protocol.toLowerCase();
+
+            if (protocol === 'epub:') return false;
+
+            return true;
+        } catch (e) {
+            return false;
+        }
+    }
+
+    // ==========================================
+    // ATTACH EVENT LISTENERS
+    // ==========================================
+    document.addEventListener('click', handleLinkClick, true);
+    document.addEventListener('keydown', handleKeyDown);
+    document.addEventListener('click', handleClick);
+    document.addEventListener('touchstart', handleTouchStart);
+    document.addEventListener('touchend', handleTouchEnd);
+
+    // Initialize pagination
+    initializePagination();
+})();
+//]]>
+</script>"#;
+
+/// Everything injected into a served chapter: the marker, the default and
+/// theme stylesheets, the script and whatever the content modes add, less
+/// the styles or script the injection mode leaves out.
+fn build_injection(theme: &ReadingTheme, options: &InjectionOptions, direction: &TextDirection) -> String {
+    let styles = matches!(options.injection, InjectionMode::Full | InjectionMode::StyleOnly);
+    let script = matches!(options.injection, InjectionMode::Full | InjectionMode::ScriptOnly);
+
+    let mut injection = INJECTION_MARKER.to_string();
+    if styles {
+        // The user's theme follows the defaults so it wins the cascade
+        injection.push_str(&format!("\n{}\n{}\n{}", DEFAULT_CONTENT_CSS, theme_css(theme), direction.css()));
+    }
+    if script {
+        injection.push('\n');
+        injection.push_str(LINK_HANDLER_SCRIPT);
+    }
+    if options.inline_notes && script {
+        injection.push('\n');
+        injection.push_str(NOTE_POPOVER_INJECTION);
+    }
+    if options.responsive_tables && styles {
+        injection.push('\n');
+        injection.push_str(RESPONSIVE_TABLES_CSS);
+    }
+    if options.preview {
+        injection.push('\n');
+        injection.push_str(PREVIEW_MODE_META);
+    }
+    injection
+}
+
+fn inject_link_handler_script(
+    html_content: Vec<u8>,
+    theme: &ReadingTheme,
+    options: &InjectionOptions,
+    direction: &TextDirection,
+) -> Vec<u8> {
+    // Documents in other encodings are served as UTF-8 from here on
+    let html_str = html::to_utf8_document(&html_content);
+    if options.injection == InjectionMode::None || has_injection(&html_str) {
+        return html_str.into_owned().into_bytes();
+    }
+
+    insert_at_head_end(&html_str, &build_injection(theme, options, direction)).into_bytes()
+}
+
+/// Opens every injection, so documents that already carry one (e.g. saved
+/// from the reader) aren't injected twice.
+const INJECTION_MARKER: &str = "<!-- epub-reader injection -->";
+
+/// Whether the document already carries an injection. Only the head, up to
+/// where an injection would go, is searched, so a book whose text quotes
+/// the marker is still injected.
+fn has_injection(html_str: &str) -> bool {
+    match find_injection_point(html_str) {
+        InjectionPoint::At(offset) => {
+            html_str[..offset].contains(INJECTION_MARKER) || html_str[offset..].starts_with(INJECTION_MARKER)
+        }
+        InjectionPoint::EmptyHead { .. } => false,
+    }
+}
+
+/// Where an injection goes in a document.
+#[derive(Debug, PartialEq)]
+enum InjectionPoint {
+    /// Insert at this byte offset
+    At(usize),
+    /// Replace this self-closing `<head/>` with a head holding the injection
+    EmptyHead { range: std::ops::Range<usize>, name: String },
+}
+
+/// Just before `</head>` (after the document's own stylesheets), inside an
+/// empty `<head/>`, else right after `<body>`, else after any XML declaration
+/// and doctype. Tags inside comments, CDATA and scripts don't count.
+fn find_injection_point(html_str: &str) -> InjectionPoint {
+    let mut fallback = 0;
+    for (token, range) in html::tokenize(html_str) {
+        match token {
+            html::Token::EndTag(name) if name.eq_ignore_ascii_case("head") => return InjectionPoint::At(range.start),
+            html::Token::StartTag(tag) if tag.is("head") && tag.self_closing => {
+                return InjectionPoint::EmptyHead { range, name: tag.name.to_string() };
+            }
+            html::Token::StartTag(tag) if tag.is("body") => return InjectionPoint::At(range.end),
+            // Leading declarations, doctype, comments and whitespace
+            html::Token::Other | html::Token::Comment if range.start == fallback => fallback = range.end,
+            html::Token::Text if range.start == fallback && html_str[range.clone()].trim().is_empty() => {
+                fallback = range.end
+            }
+            _ => {}
+        }
+    }
+    InjectionPoint::At(fallback)
+}
+
+/// Inserts `injection` into the document at its `find_injection_point`.
+fn insert_at_head_end(html_str: &str, injection: &str) -> String {
+    let mut result = String::with_capacity(html_str.len() + injection.len() + 16);
+    match find_injection_point(html_str) {
+        InjectionPoint::At(offset) => {
+            result.push_str(&html_str[..offset]);
+            result.push_str(injection);
+            result.push_str(&html_str[offset..]);
+        }
+        InjectionPoint::EmptyHead { range, name } => {
+            result.push_str(&html_str[..range.start]);
+            result.push_str(&format!("<{}>{}</{}>", name, injection, name));
+            result.push_str(&html_str[range.end..]);
+        }
+    }
+    result
+}
+
+/// How many levels of subdirectories of the library are searched for books.
+const MAX_LIBRARY_DEPTH: usize = 8;
+
+/// The book key for the file at `relative`, a path within the library. Books
+/// at the top level are keyed by file name; books in subdirectories by their
+/// relative path with `~` between the parts, e.g. `Tolkien~The Hobbit.epub`,
+/// so a key stays a single name without `/`. `None` for
+/// files the library doesn't take: not `.epub`, inside a hidden directory or
+/// deeper than `MAX_LIBRARY_DEPTH`.
+fn book_key_for(relative: &std::path::Path) -> Option<String> {
+    let parts: Vec<String> = relative.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let (file_name, folders) = parts.split_last()?;
+    if !file_name.ends_with(".epub") || folders.len() > MAX_LIBRARY_DEPTH || folders.iter().any(|folder| folder.starts_with('.')) {
+        return None;
+    }
+    Some(parts.join("~"))
+}
+
+/// `key`, or `key (2)`, `key (3)`, ... if it's already `taken`, so books in
+/// different folders whose joined paths coincide still get their own key.
+fn unique_book_key(key: String, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(&key) {
+        return key;
+    }
+    (2..).map(|n| format!("{} ({})", key, n))
+        .find(|candidate| !taken(candidate))
+        .unwrap_or_default()
+}
+
+/// The `.epub` files in `directory` and its subdirectories (see
+/// `book_key_for`) with their book keys, top-level books first so their
+/// keys never change, then sorted by key.
+fn library_files(directory: &std::path::Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    fn walk(directory: &std::path::Path, depth: usize, files: &mut Vec<PathBuf>) {
+        let Ok(entries) = directory.read_dir() else { return };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if !hidden && depth < MAX_LIBRARY_DEPTH {
+                    walk(&path, depth + 1, files);
+                }
+            } else if entry.metadata().is_ok() {
+                files.push(path);
+            }
+        }
+    }
+
+    // Fails like before when the library directory itself can't be read
+    directory.read_dir()?;
+    let mut paths = Vec::new();
+    walk(directory, 0, &mut paths);
+
+    let mut keyed: Vec<(usize, String, PathBuf)> = paths.into_iter()
+        .filter_map(|path| {
+            let relative = path.strip_prefix(directory).ok()?;
+            let key = book_key_for(relative)?;
+            Some((relative.components().count(), key, path))
+        })
+        .collect();
+    keyed.sort();
+
+    let mut taken = std::collections::HashSet::new();
+    Ok(keyed.into_iter()
+        .map(|(_, key, path)| {
+            let key = unique_book_key(key, |candidate| taken.contains(candidate));
+            taken.insert(key.clone());
+            (key, path)
+        })
+        .collect())
+}
+
+/// Scans `directory` for `.epub` files, opening them on a pool of worker
+/// threads. Keys are assigned by `library_files` before any book loads, so
+/// they don't depend on the order books finish loading.
+fn load_books_from(directory: std::path::PathBuf, open_limit: usize) -> Library {
+    let started = std::time::Instant::now();
+    let mut books = Library::new(open_limit);
+
+    let files = match library_files(&directory) {
+        Ok(files) => files,
+        Err(e) => {
+            log::warn!("Failed to read library directory {}: {}", directory.display(), e);
+            return books;
+        }
+    };
+
+    let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4).min(files.len()).max(1);
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut loaded = 0;
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let (files, next) = (&files, &next);
+            scope.spawn(move || {
+                while let Some((file_name, path)) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = EpubDoc::new(path).map(|doc| (LoadChecks::run(path, &doc), doc));
+                    if sender.send((file_name, path, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // Merged here as they arrive, so the library's open limit applies
+        // during the scan too
+        for (file_name, path, result) in receiver {
+            match result {
+                Ok((checks, doc)) => {
+                    log::info!("Loaded {}", file_name);
+                    books.insert(file_name.clone(), path.clone(), doc);
+                    checks.apply(&mut books, file_name);
+                    loaded += 1;
+                }
+                Err(e) => {
+                    log::error!("Failed to load {}: {}", file_name, e);
+                    books.insert_failure(file_name.clone(), e.to_string());
+                }
+            }
+        }
+    });
+
+    log::info!("Loaded {} of {} books in {:.2?}", loaded, files.len(), started.elapsed());
+    books
+}
+
+/// `Books` in the user's documents folder, used until a library directory
+/// is chosen.
+fn default_library_dir(app: &tauri::AppHandle) -> Option<PathBuf> {
+    app.path().document_dir().ok().map(|dir| dir.join("Books"))
+}
+
+/// Drops everything cached for `book_key`, or for every book when `None`
+/// (for when the keys start referring to other files).
+fn clear_book_caches(app: &tauri::AppHandle, book_key: Option<&str>) {
+    let keep = |key: &str| book_key.is_some_and(|book_key| book_key != key);
+    app.state::<TextStatsState>().0.lock().retain(|key, _| keep(key));
+    app.state::<ChapterPeekState>().0.lock().retain(|(key, _), _| keep(key));
+    app.state::<FingerprintState>().0.lock().retain(|key, _| keep(key));
+    app.state::<CoverPaletteState>().0.lock().retain(|key, _| keep(key));
+    app.state::<PreparedState>().0.lock().retain(|key, _| keep(key));
+    app.state::<VirtualPathState>().0.lock().retain(|key, _| keep(key));
+    app.state::<ReferenceMapState>().0.lock().retain(|key, _| keep(key));
+    app.state::<CoverCacheState>().0.thumbnails.lock().retain(|(key, _), _| keep(key));
+    app.state::<CoverCacheState>().0.info.lock().retain(|key, _| keep(key));
+    *app.state::<CoverCacheState>().0.sprite.lock() = None;
+    app.state::<ResourceCacheState>().0.lock().clear(book_key);
+    app.state::<ChapterCacheState>().0.lock().clear(book_key);
+}
+
+/// Starts watching `directory` for books added or removed while the app
+/// runs, replacing the watch on any previous directory.
+fn watch_library(app: &tauri::AppHandle, directory: &std::path::Path) {
+    let handle = app.clone();
+    let watched = directory.to_path_buf();
+    let watcher = watcher::watch(directory, move |paths| apply_library_changes(&handle, &watched, paths))
+        .inspect_err(|e| log::error!("Failed to watch {}: {}", directory.display(), e))
+        .ok();
+    *app.state::<LibraryWatcherState>().0.lock() = watcher;
+}
+
+/// Loads the new and modified books and unloads the deleted ones among
+/// `paths`, emitting `book-added` or `book-removed` for each. A modified
+/// file keeps its book key; one that no longer opens is unloaded.
+fn apply_library_changes(app: &tauri::AppHandle, directory: &std::path::Path, paths: Vec<PathBuf>) {
+    // Changes that settled after the library moved to another directory
+    if app.state::<LibraryDirState>().0.lock().as_deref() != Some(directory) {
+        return;
+    }
+
+    let state = app.state::<LibraryState>();
+    for path in paths {
+        let Some(key) = path.strip_prefix(directory).ok().and_then(book_key_for) else { continue };
+        if path.is_file() {
+            let is_loaded = |books: &Library| {
+                books.key_for_path(&path).is_some_and(|book_key| books.is_unchanged(book_key, &path))
+            };
+            if is_loaded(&state.0.lock()) {
+                continue;
+            }
+            // Parsed outside the library lock
+            let result = EpubDoc::new(&path).map(|doc| (LoadChecks::run(&path, &doc), doc));
+            let mut books = state.0.lock();
+            // Checked again, as another load may have happened meanwhile
+            if is_loaded(&books) {
+                continue;
+            }
+            let reloaded = books.key_for_path(&path).cloned();
+            let book_key = reloaded
+                .clone()
+                .unwrap_or_else(|| unique_book_key(key, |candidate| books.contains_key(candidate)));
+            match result {
+                Ok((checks, doc)) => {
+                    log::info!("Loaded {}", book_key);
+                    books.insert(book_key.clone(), path, doc);
+                    checks.apply(&mut books, &book_key);
+                    drop(books);
+                    clear_book_caches(app, Some(&book_key));
+                    let _ = app.emit("book-added", LibraryChange { book_key });
+                }
+                Err(e) => {
+                    log::error!("Failed to load {}: {}", book_key, e);
+                    // A modified file that no longer opens is no longer a book
+                    if reloaded.is_some() {
+                        books.remove(&book_key);
+                        books.insert_failure(book_key.clone(), e.to_string());
+                        drop(books);
+                        clear_book_caches(app, Some(&book_key));
+                        let _ = app.emit("book-removed", LibraryChange { book_key });
+                    } else {
+                        books.insert_failure(book_key, e.to_string());
+                    }
+                }
+            }
+        } else {
+            let mut books = state.0.lock();
+            let Some(book_key) = books.key_for_path(&path).cloned() else { continue };
+            books.remove(&book_key);
+            drop(books);
+            clear_book_caches(app, Some(&book_key));
+            let _ = app.emit("book-removed", LibraryChange { book_key });
+        }
+    }
+    sync_recents(app);
+}
+
+/// The `.epub` file among the command-line arguments, which is how Windows
+/// and Linux file managers pass a book opened with the app.
+fn launch_book_path() -> Option<PathBuf> {
+    std::env::args_os().skip(1)
+        .map(PathBuf::from)
+        .find(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("epub")) && path.is_file())
+}
+
+/// Opens the `.epub` at `path` for reading: the library's own book when the
+/// file is in it, else the file is loaded where it is (not copied into the
+/// library), keyed by its file name. Emits `open-book` with the key and
+/// keeps it for `take_opened_book`. A file that doesn't load is recorded
+/// like any other load failure.
+fn open_book_file(app: &tauri::AppHandle, path: &std::path::Path) {
+    let Ok(path) = std::fs::canonicalize(path) else {
+        log::warn!("Failed to open {}: file not found", path.display());
+        return;
+    };
+    let state = app.state::<LibraryState>();
+    let existing = {
+        let books = state.0.lock();
+        let is_file = |key: &str| {
+            books.path(key).and_then(|book_path| std::fs::canonicalize(book_path).ok()).as_deref() == Some(path.as_path())
+        };
+        let existing = books.keys().find(|key| is_file(key)).cloned();
+        existing
+    };
+
+    let book_key = match existing {
+        Some(book_key) => book_key,
+        None => {
+            let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            // Parsed outside the library lock
+            let result = EpubDoc::new(&path).map(|doc| (LoadChecks::run(&path, &doc), doc));
+            let mut books = state.0.lock();
+            let book_key = unique_book_key(file_name, |candidate| books.contains_key(candidate));
+            match result {
+                Ok((checks, doc)) => {
+                    log::info!("Loaded {}", book_key);
+                    books.insert(book_key.clone(), path, doc);
+                    checks.apply(&mut books, &book_key);
+                    drop(books);
+                    clear_book_caches(app, Some(&book_key));
+                    sync_recents(app);
+                    let _ = app.emit("book-added", LibraryChange { book_key: book_key.clone() });
+                }
+                Err(e) => {
+                    log::error!("Failed to load {}: {}", book_key, e);
+                    books.insert_failure(book_key, e.to_string());
+                    return;
+                }
+            }
+            book_key
+        }
+    };
+
+    *app.state::<OpenedBookState>().0.lock() = Some(book_key.clone());
+    let _ = app.emit("open-book", LibraryChange { book_key });
+}
+
+/// The book the app was last asked to open, if the frontend hasn't taken it
+/// yet. Called on startup and on `open-book`, so a book opened before the
+/// frontend was listening isn't missed.
+#[tauri::command]
+fn take_opened_book(opened: tauri::State<OpenedBookState>) -> Option<String> {
+    opened.0.lock().take()
+}
+
+/// Points the library at another directory: rescans it for books, replaces
+/// the loaded library and remembers the choice across restarts.
+#[tauri::command]
+async fn set_library_directory(
+    path: String,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, LibraryState>,
+    library_dir: tauri::State<'_, LibraryDirState>
+) -> Result<(), AppError> {
+    let directory = PathBuf::from(&path);
+    if !directory.is_dir() {
+        return Err(AppError::InvalidArgument(format!("Not a directory: {}", path)));
+    }
+
+    let settings = LibrarySettings { directory: Some(directory.clone()) };
+    save_json_file(&config_file_path(&app, LIBRARY_FILE)?, &settings)?;
+
+    let open_limit = state.0.lock().open_limit();
+    let scanned = directory.clone();
+    let books = run_blocking(move || Ok(load_books_from(scanned, open_limit))).await?;
+    *state.0.lock() = books;
+    *library_dir.0.lock() = Some(directory.clone());
+    clear_book_caches(&app, None);
+    sync_recents(&app);
+    watch_library(&app, &directory);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ReloadReport {
+    added: usize,
+    removed: usize,
+    failed: usize,
+    /// Paths of the books that failed to load, relative to the library
+    /// directory
+    failed_files: Vec<String>,
+}
+
+/// Rescans the library directory: loads new books and ones whose file
+/// changed (including ones that failed before), and unloads books whose file
+/// is gone. Unchanged books are left alone, open documents and caches
+/// included. Emits `book-added` and `book-removed` like the directory watch.
+#[tauri::command]
+async fn reload_library(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, LibraryState>,
+    library_dir: tauri::State<'_, LibraryDirState>
+) -> Result<ReloadReport, AppError> {
+    let directory = library_dir.0.lock().clone()
+        .ok_or_else(|| AppError::Other("No library directory is configured".to_string()))?;
+    let library = Arc::clone(&state.0);
+    run_blocking(move || rescan_library(&app, &library, &directory)).await
+}
+
+/// `reload_library`'s scan of `directory`, run off the invoking thread.
+fn rescan_library(app: &tauri::AppHandle, library: &Mutex<Library>, directory: &std::path::Path) -> Result<ReloadReport, AppError> {
+    let files = library_files(directory)
+        .map_err(|e| AppError::Io(format!("Failed to read {}: {}", directory.display(), e)))?;
+
+    let mut report = ReloadReport { added: 0, removed: 0, failed: 0, failed_files: Vec::new() };
+    let (gone, failures_gone): (Vec<String>, Vec<String>) = {
+        let books = library.lock();
+        let missing = |key: &String| !files.iter().any(|(file_name, _)| file_name == key);
+        // Books opened from elsewhere (see `open_book_file`) stay
+        let in_directory = |key: &String| books.path(key).is_some_and(|path| path.starts_with(directory));
+        (
+            books.keys().filter(|key| missing(key) && in_directory(key)).cloned().collect(),
+            books.failures().map(|(key, _)| key).filter(|key| missing(key)).cloned().collect(),
+        )
+    };
+    for book_key in gone {
+        if library.lock().remove(&book_key).is_some() {
+            clear_book_caches(app, Some(&book_key));
+            let _ = app.emit("book-removed", LibraryChange { book_key });
+            report.removed += 1;
+        }
+    }
+    for book_key in failures_gone {
+        library.lock().remove(&book_key);
+    }
+
+    for (book_key, path) in files {
+        if library.lock().is_unchanged(&book_key, &path) {
+            continue;
+        }
+        // Parsed outside the library lock
+        let result = EpubDoc::new(&path).map(|doc| (LoadChecks::run(&path, &doc), doc));
+        let mut books = library.lock();
+        match result {
+            Ok((checks, doc)) => {
+                log::info!("Loaded {}", book_key);
+                books.insert(book_key.clone(), path, doc);
+                checks.apply(&mut books, &book_key);
+                drop(books);
+                clear_book_caches(app, Some(&book_key));
+                let _ = app.emit("book-added", LibraryChange { book_key });
+                report.added += 1;
+            }
+            Err(e) => {
+                log::error!("Failed to load {}: {}", book_key, e);
+                // A changed file that no longer opens is no longer a book
+                if books.remove(&book_key).is_some() {
+                    drop(books);
+                    clear_book_caches(app, Some(&book_key));
+                    let _ = app.emit("book-removed", LibraryChange { book_key: book_key.clone() });
+                    report.removed += 1;
+                    books = library.lock();
+                }
+                report.failed += 1;
+                report.failed_files.push(path.strip_prefix(directory).unwrap_or(&path).to_string_lossy().into_owned());
+                books.insert_failure(book_key, e.to_string());
+            }
+        }
+    }
+    sync_recents(app);
+    Ok(report)
+}
+
+/// The directory books are loaded from: the chosen one, else the default.
+#[tauri::command]
+fn get_library_directory(library_dir: tauri::State<LibraryDirState>) -> Option<String> {
+    library_dir.0.lock().as_ref().map(|dir| dir.to_string_lossy().into_owned())
+}
+
+/// Why the library has the books it has, so an empty grid can explain itself.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum LibraryStatus {
+    /// At least one book loaded
+    Ready { books: usize, failed: usize },
+    /// No directory chosen and no default one available
+    NotConfigured,
+    NotFound { directory: String },
+    /// The directory exists but can't be listed
+    Unreadable { directory: String, reason: String },
+    /// No `.epub` files were found in the directory
+    Empty { directory: String },
+    /// Every `.epub` file in the directory failed to load
+    AllFailed { directory: String, failed: usize },
+}
+
+/// The library's state, for choosing between the grid and an onboarding
+/// message ("Choose your books folder") on first run.
+#[tauri::command]
+fn get_library_status(
+    state: tauri::State<LibraryState>,
+    library_dir: tauri::State<LibraryDirState>
+) -> LibraryStatus {
+    let (books, failed) = {
+        let books = state.0.lock();
+        (books.keys().count(), books.failures().count())
+    };
+    if books > 0 {
+        return LibraryStatus::Ready { books, failed };
+    }
+
+    let Some(path) = library_dir.0.lock().clone() else {
+        return LibraryStatus::NotConfigured;
+    };
+    let directory = path.to_string_lossy().into_owned();
+    if !path.is_dir() {
+        return LibraryStatus::NotFound { directory };
+    }
+    if let Err(e) = path.read_dir() {
+        return LibraryStatus::Unreadable { directory, reason: e.to_string() };
+    }
+    if failed > 0 {
+        LibraryStatus::AllFailed { directory, failed }
+    } else {
+        LibraryStatus::Empty { directory }
+    }
+}
+
+/// Unloads a book, closing its file and dropping everything cached for it.
+/// The file itself stays in the library directory, so the book is back after
+/// a restart. Pending `epub://` requests for it get a 404.
+#[tauri::command]
+fn remove_book(book_key: String, app: tauri::AppHandle, state: tauri::State<LibraryState>) -> Result<(), AppError> {
+    // The handler looks the book up under this same lock before using any
+    // cache, so it can't serve the removed book
+    state.0.lock().remove(&book_key).ok_or_else(|| AppError::book_not_found(&book_key))?;
+    clear_book_caches(&app, Some(&book_key));
+    Ok(())
+}
+
+/// Sets how many books are kept open at once (at least one). Books are
+/// opened when first used and the least recently used closed beyond this,
+/// trading file handles and memory for reopening cost.
+#[tauri::command]
+fn set_open_book_limit(limit: usize, state: tauri::State<LibraryState>) -> Result<(), AppError> {
+    if limit == 0 {
+        return Err(AppError::InvalidArgument("At least one book must be kept open".to_string()));
+    }
+    state.0.lock().set_open_limit(limit);
+    Ok(())
+}
+
+const IDPF_OBFUSCATION: &str = "http://www.idpf.org/2008/embedding";
+const ADOBE_OBFUSCATION: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+/// Encryption algorithms that only obfuscate embedded fonts, which readers
+/// undo themselves; anything else in `encryption.xml` means DRM.
+const FONT_OBFUSCATION_ALGORITHMS: &[&str] = &[IDPF_OBFUSCATION, ADOBE_OBFUSCATION];
+
+/// Algorithm `encryption.xml` lists for the resource at archive path `path`.
+fn resource_encryption(book: &mut EpubDoc<BufReader<File>>, path: &str) -> Option<String> {
+    let bytes = book.get_resource_by_path("META-INF/encryption.xml").ok()?;
+    let xml = String::from_utf8_lossy(&bytes);
+
+    // Each EncryptedData names its method before the resource it applies to
+    let mut algorithm = None;
+    for (token, _) in html::tokenize(&xml) {
+        let html::Token::StartTag(tag) = token else { continue };
+        match tag.name.rsplit(':').next().unwrap_or(tag.name) {
+            "EncryptionMethod" => algorithm = tag.attr("Algorithm").map(|algorithm| algorithm.trim().to_string()),
+            "CipherReference" => {
+                let uri = tag.attr("URI").map(html::decode_entities).unwrap_or_default();
+                if resolve_href("", &uri).0 == path {
+                    return algorithm;
+                }
+            }
+            _ => {}
         }
     }
+    None
+}
 
-    function sendPaginationUpdate() {
-        if (window.parent && window.parent !== window) {
-            window.parent.postMessage({
-                type: 'pagination-update',
-                currentPage: currentPage,
-                totalPages: totalPages,
-                enabled: paginationEnabled
-            }, '*');
+/// Undoes IDPF or Adobe font obfuscation of `data`, the resource at archive
+/// path `path`, when `encryption.xml` lists it. Both XOR the start of the
+/// file with a key derived from the book's unique identifier: its SHA-1 over
+/// 1040 bytes (IDPF), or the UUID's 16 bytes over 1024 bytes (Adobe).
+fn deobfuscate_font(book: &mut EpubDoc<BufReader<File>>, path: &str, data: &mut [u8]) {
+    use sha1::Digest;
+
+    let Some(algorithm) = resource_encryption(book, path) else { return };
+    let Some(identifier) = book.unique_identifier.clone() else { return };
+    let (key, length): (Vec<u8>, usize) = match algorithm.as_str() {
+        IDPF_OBFUSCATION => {
+            let identifier: String = identifier.chars().filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n')).collect();
+            (sha1::Sha1::digest(identifier.as_bytes()).to_vec(), 1040)
         }
-    }
+        ADOBE_OBFUSCATION => {
+            let hex: String = identifier.trim().trim_start_matches("urn:uuid:")
+                .chars()
+                .filter(char::is_ascii_hexdigit)
+                .collect();
+            let key: Vec<u8> = (0..hex.len() / 2)
+                .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+                .collect();
+            if key.len() != 16 {
+                return;
+            }
+            (key, 1024)
+        }
+        _ => return,
+    };
 
-    // ==========================================
-    // UTILITIES
-    // ==========================================
-    function debounce(func, wait) {
-        let timeout;
-        return function() {
-            const context = this;
-            const args = arguments;
-            clearTimeout(timeout);
-            timeout = setTimeout(function() {
-                func.apply(context, args);
-            }, wait);
-        };
+    for (byte, key_byte) in data.iter_mut().take(length).zip(key.iter().cycle()) {
+        *byte ^= key_byte;
     }
+}
 
-    // ==========================================
-    // LINK HANDLER (existing functionality)
-    // ==========================================
-    function handleLinkClick(event) {
-        const target = event.target.closest('a');
-        if (!target || !target.href) return;
+/// Why a book's content is locked away, if it is: a rights file, or
+/// resources encrypted with something other than font obfuscation.
+fn drm_problem(source: &std::path::Path) -> Option<String> {
+    let file = File::open(source).ok()?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file)).ok()?;
+    if archive.by_name("META-INF/rights.xml").is_ok() {
+        return Some("DRM-protected: the book has a rights file".to_string());
+    }
 
-        const href = target.href;
+    let encrypted = encryption_algorithms(&mut archive).iter()
+        .any(|algorithm| !FONT_OBFUSCATION_ALGORITHMS.contains(&algorithm.as_str()));
+    encrypted.then(|| "DRM-protected: the book's content is encrypted".to_string())
+}
 
-        if (isExternalLink(href)) {
-            event.preventDefault();
-            event.stopPropagation();
+/// Why a book's file looks damaged, if it does: a partly copied or truncated
+/// file can still open, then fail once a missing chapter is read. Checks
+/// that every spine document's entry is in the archive, has a readable local
+/// header and ends within the file. Nothing is decompressed.
+fn damage_problem(source: &std::path::Path, book: &library::Book) -> Option<String> {
+    let file = match File::open(source) {
+        Ok(file) => file,
+        Err(e) => return Some(format!("The file can't be read: {}", e)),
+    };
+    let file_size = file.metadata().map(|metadata| metadata.len()).ok()?;
+    let mut archive = match zip::ZipArchive::new(BufReader::new(file)) {
+        Ok(archive) => archive,
+        Err(e) => return Some(format!("The archive's directory is unreadable: {}", e)),
+    };
 
-            if (window.parent && window.parent !== window) {
-                window.parent.postMessage({
-                    type: 'epub-external-link',
-                    url: href
-                }, '*');
+    for id in &book.spine {
+        let Some((path, _)) = book.resources.get(id) else { continue };
+        let path = path.to_string_lossy();
+        match archive.by_name(&path) {
+            Ok(entry) if entry.data_start() + entry.compressed_size() > file_size => {
+                return Some(format!("The file is truncated: {} is cut off", path));
             }
+            Ok(_) => {}
+            Err(zip::result::ZipError::FileNotFound) => {
+                return Some(format!("{} is missing from the archive", path));
+            }
+            Err(e) => return Some(format!("{} can't be read: {}", path, e)),
         }
     }
+    None
+}
 
-    function isExternalLink(href) {
-        try {
-            const url = new URL(href, window.location.href);
-            const protocol = url.protocol.toLowerCase();
+/// What opening a library file found besides the book itself, recorded on
+/// its descriptor once it's added.
+struct LoadChecks {
+    drm: bool,
+    damage: Option<String>,
+}
 
-            if (protocol === 'epub:') return false;
+impl LoadChecks {
+    fn run(source: &std::path::Path, book: &library::Book) -> LoadChecks {
+        LoadChecks { drm: drm_problem(source).is_some(), damage: damage_problem(source, book) }
+    }
 
-            return true;
-        } catch (e) {
-            return false;
+    fn apply(self, books: &mut Library, book_key: &str) {
+        if self.drm {
+            books.mark_drm(book_key);
+        }
+        if let Some(damage) = self.damage {
+            log::warn!("{} looks damaged: {}", book_key, damage);
+            books.mark_damaged(book_key, damage);
         }
     }
+}
 
-    // ==========================================
-    // ATTACH EVENT LISTENERS
-    // ==========================================
-    document.addEventListener('click', handleLinkClick, true);
-    document.addEventListener('keydown', handleKeyDown);
-    document.addEventListener('click', handleClick);
-    document.addEventListener('touchstart', handleTouchStart);
-    document.addEventListener('touchend', handleTouchEnd);
+/// The `Algorithm` of every encrypted resource in `META-INF/encryption.xml`.
+fn encryption_algorithms(archive: &mut BookArchive) -> Vec<String> {
+    let Some(bytes) = read_archive_entry(archive, "META-INF/encryption.xml") else { return Vec::new() };
+    html::tokenize(&String::from_utf8_lossy(&bytes))
+        .filter_map(|(token, _)| match token {
+            html::Token::StartTag(tag) => tag.attr("Algorithm").map(|algorithm| algorithm.trim().to_string()),
+            _ => None,
+        })
+        .collect()
+}
 
-    // Initialize pagination
-    initializePagination();
-})();
-//]]>
-</script>"#;
+/// Opens a file to import, failing if it isn't a readable EPUB.
+fn open_import(source: &std::path::Path) -> Result<library::Book, AppError> {
+    let is_epub = source.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("epub"));
+    if !is_epub {
+        return Err(AppError::InvalidEpub(format!("Not an EPUB file: {}", source.display())));
+    }
+    if !source.is_file() {
+        return Err(AppError::Io(format!("File not found: {}", source.display())));
+    }
+    if let Some(problem) = drm_problem(source) {
+        return Err(AppError::InvalidEpub(problem));
+    }
+    EpubDoc::new(source).map_err(|e| AppError::InvalidEpub(format!("Failed to parse {}: {}", source.display(), e)))
+}
 
-    // Find </head> or <body> tag to inject before
-    let injection_point = if let Some(head_close) = html_str.find("</head>") {
-        head_close
-    } else if let Some(body_open) = html_str.find("<body") {
-        // Find the end of the <body> tag
-        html_str[body_open..].find('>').map(|pos| body_open + pos + 1)
-            .unwrap_or(0)
-    } else {
-        // No ideal location found, inject at start
-        0
-    };
+/// Key an imported file would have before collisions: its file name.
+fn import_base_key(source: &std::path::Path) -> String {
+    let stem = source.file_stem().and_then(|stem| stem.to_str()).unwrap_or("book");
+    format!("{}.epub", stem)
+}
 
-    // Combine CSS and script for injection
-    let combined_injection = format!("{}\n{}", default_css, script);
+/// Key for an imported file: its file name, made unique in the library by
+/// `unique_book_key` as for books found in the library directory.
+fn import_book_key(books: &Library, source: &std::path::Path) -> String {
+    unique_book_key(import_base_key(source), |candidate| books.contains_key(candidate))
+}
 
-    if injection_point == 0 {
-        // Prepend both CSS and script
-        let mut result = combined_injection;
-        result.push_str(&html_str);
-        result.into_bytes()
-    } else {
-        // Insert at injection point
-        let mut result = String::new();
-        result.push_str(&html_str[..injection_point]);
-        result.push_str(&combined_injection);
-        result.push_str(&html_str[injection_point..]);
-        result.into_bytes()
-    }
+#[derive(Serialize)]
+struct ImportCheck {
+    /// `add_book` would succeed
+    valid: bool,
+    title: Option<String>,
+    author: Option<String>,
+    /// Library book with the same identifier, else the one whose key the
+    /// import would take (it then gets a numbered key instead)
+    would_collide_with: Option<String>,
+    errors: Vec<String>,
 }
 
-fn load_books_from(directory: std::path::PathBuf) -> HashMap<String, EpubDoc<BufReader<File>>>{
-    let mut books = HashMap::new();
+/// Dry run of `add_book` for the import dialog: whether the file can be
+/// imported, what it is and which library book it duplicates. Changes
+/// nothing.
+#[tauri::command]
+fn validate_import(path: String, state: tauri::State<LibraryState>) -> ImportCheck {
+    let source = PathBuf::from(&path);
+    let mut errors = Vec::new();
+    let book = open_import(&source).map_err(|e| errors.push(e.to_string())).ok();
 
-    if let Ok(entries) = directory.read_dir() {
-        for file in entries {
-            if let Ok(file) = file {
-                if let Ok(_md) = file.metadata() {
-                    let file_name = file.file_name().into_string().unwrap_or_default();
-                    // Only process .epub files
-                    if file_name.ends_with(".epub") {
-                        match EpubDoc::new(file.path()) {
-                            Ok(doc) => {
-                                println!("✓ Loaded: {}", file_name);
-                                books.insert(file_name, doc);
-                            }
-                            Err(e) => {
-                                eprintln!("✗ Failed to load {}: {}", file_name, e);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    let books = state.0.lock();
+    let identifier = book.as_ref().and_then(|book| book.unique_identifier.clone().or_else(|| book.mdata("identifier")));
+    let same_identifier = identifier.and_then(|identifier| {
+        books.descriptors()
+            .find(|(_, descriptor)| descriptor.identifier.as_deref().map(str::trim) == Some(identifier.trim()))
+            .map(|(book_key, _)| book_key.clone())
+    });
+    let same_key = Some(import_base_key(&source)).filter(|book_key| books.contains_key(book_key));
+
+    ImportCheck {
+        valid: errors.is_empty(),
+        title: book.as_ref().and_then(|book| book.mdata("title")),
+        author: book.as_ref().and_then(|book| book.mdata("creator")),
+        would_collide_with: same_identifier.or(same_key),
+        errors,
     }
+}
 
-    books
+/// Imports one EPUB into the library, loaded where it is (not copied into
+/// the library directory). The book key is the file name, numbered by
+/// `unique_book_key` when that key is taken. Returns the key.
+#[tauri::command]
+fn add_book(
+    path: String,
+    app: tauri::AppHandle,
+    state: tauri::State<LibraryState>
+) -> Result<String, AppError> {
+    // Parsed and checked outside the library lock
+    let source = PathBuf::from(&path);
+    let book = open_import(&source)?;
+    let checks = LoadChecks::run(&source, &book);
+
+    let mut books = state.0.lock();
+    let book_key = import_book_key(&books, &source);
+    books.insert(book_key.clone(), source, book);
+    checks.apply(&mut books, &book_key);
+    drop(books);
+    clear_book_caches(&app, Some(&book_key));
+    sync_recents(&app);
+    Ok(book_key)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
 
-    let books = Arc::new(Mutex::new(load_books_from(std::path::PathBuf::from("/Users/richardwooding/books"))));
+    let books = Arc::new(Mutex::new(Library::new(library::DEFAULT_OPEN_LIMIT)));
+    let library = Arc::clone(&books);
+    let library_dir = Arc::new(Mutex::new(None));
+
+    let preferences = Arc::new(Mutex::new(Preferences::default()));
+    let protocol_preferences = Arc::clone(&preferences);
+    let progress = Arc::new(Mutex::new(HashMap::new()));
+    let annotations = Arc::new(Mutex::new(HashMap::new()));
+    let finished = Arc::new(Mutex::new(std::collections::HashSet::new()));
+    let recents = Arc::new(Mutex::new(HashMap::new()));
+    let virtual_paths: Arc<Mutex<VirtualPathMap>> = Arc::new(Mutex::new(HashMap::new()));
+    let resource_cache = Arc::new(Mutex::new(ResourceCache::new(DEFAULT_CACHE_BUDGET)));
+    let chapter_cache = Arc::new(Mutex::new(ResourceCache::new(CHAPTER_CACHE_BUDGET)));
+    let entry_decoders: Arc<Mutex<EntryDecoders>> = Arc::new(Mutex::new(Vec::new()));
 
     tauri::Builder::default()
+        .plugin(log_plugin())
         .plugin(tauri_plugin_opener::init())
         .manage(LibraryState(books.clone()))
+        .manage(LibraryDirState(library_dir.clone()))
+        .manage(LibraryWatcherState(Mutex::new(None)))
+        .manage(OpenedBookState(Mutex::new(None)))
+        .manage(PreferencesState(preferences.clone()))
+        .manage(ProgressState(progress.clone()))
+        .manage(AnnotationsState(annotations.clone()))
+        .manage(FinishedState(finished.clone()))
+        .manage(RecentsState(recents.clone()))
+        .manage(TextStatsState(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(ChapterPeekState(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(FingerprintState(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(CoverPaletteState(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(PreparedState(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(VirtualPathState(virtual_paths.clone()))
+        .manage(ReferenceMapState(Arc::new(Mutex::new(HashMap::new()))))
+        .manage(ResourceCacheState(resource_cache.clone()))
+        .manage(ChapterCacheState(chapter_cache.clone()))
+        .manage(SearchState(Arc::new(AtomicU64::new(0))))
+        .manage(CoverCacheState(Arc::new(CoverCache {
+            thumbnails: Mutex::new(HashMap::new()),
+            info: Mutex::new(HashMap::new()),
+            disk_directory: Mutex::new(None),
+            sprite: Mutex::new(None),
+            prewarm_generation: AtomicU64::new(0),
+        })))
+        .setup(move |app| {
+            let directory = config_file_path(app.handle(), LIBRARY_FILE).ok()
+                .and_then(|path| load_json_file::<LibrarySettings>(&path).directory)
+                .or_else(|| default_library_dir(app.handle()));
+            if let Some(directory) = &directory {
+                let mut library = library.lock();
+                *library = load_books_from(directory.clone(), library.open_limit());
+            }
+            *library_dir.lock() = directory.clone();
+            if let Some(directory) = &directory {
+                watch_library(app.handle(), directory);
+            }
+            if let Some(path) = launch_book_path() {
+                open_book_file(app.handle(), &path);
+            }
+            *app.state::<CoverCacheState>().0.disk_directory.lock() =
+                app.path().app_cache_dir().ok().map(|dir| dir.join(COVER_CACHE_DIR));
+            match load_preferences(app.handle()) {
+                Ok(loaded) => *preferences.lock() = loaded,
+                Err(e) => log::warn!("Failed to load preferences: {}", e),
+            }
+            if let Ok(path) = config_file_path(app.handle(), PROGRESS_FILE) {
+                *progress.lock() = load_json_file(&path);
+            }
+            if let Ok(path) = config_file_path(app.handle(), ANNOTATIONS_FILE) {
+                *annotations.lock() = load_json_file(&path);
+            }
+            if let Ok(path) = config_file_path(app.handle(), FINISHED_FILE) {
+                *finished.lock() = load_json_file(&path);
+            }
+            if let Ok(path) = config_file_path(app.handle(), RECENTS_FILE) {
+                *recents.lock() = load_json_file(&path);
+            }
+            sync_recents(app.handle());
+            Ok(())
+        })
         .register_asynchronous_uri_scheme_protocol("epub",  move |_ctx, request, responder| {
             let books = Arc::clone(&books);
-            let host = request.uri().host().unwrap().to_string();
-            // skip leading `/`
-            let path = request.uri().path()[1..].to_string();
+            let preferences = Arc::clone(&protocol_preferences);
+            let virtual_paths = Arc::clone(&virtual_paths);
+            let resource_cache = Arc::clone(&resource_cache);
+            let chapter_cache = Arc::clone(&chapter_cache);
+            let entry_decoders = Arc::clone(&entry_decoders);
+            let Some((host, path)) = request_target(request.uri()) else {
+                log::warn!(target: "epub_protocol", "No book host in {}", request.uri());
+                respond(responder, ResponseBuilder::new().status(400).body(Vec::new()));
+                return;
+            };
+            let options = InjectionOptions::from_query(request.uri().query());
+            // Media elements seek with range requests
+            let range = request.headers().get(http::header::RANGE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let if_none_match = request.headers().get(http::header::IF_NONE_MATCH)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
 
             std::thread::spawn(move || {
-                let mut books_guard = books.lock().unwrap();
-                let book_result = books_guard.get_mut(&host);
-                match book_result {
-                    Some(book) => {
+                let Some(book_key) = books.lock().key_for_host(&host) else {
+                    log::warn!(target: "epub_protocol", "No book for host {}", host);
+                    respond(responder, ResponseBuilder::new().status(404).body(Vec::new()));
+                    return;
+                };
+                // Snapshot the theme before taking the library lock again
+                let (theme, injection, block_remote) = {
+                    let preferences = preferences.lock();
+                    (preferences.for_book(&book_key), preferences.injection_mode, preferences.privacy_mode)
+                };
+                let options = InjectionOptions { injection, block_remote, ..options };
+                // The library lock is only held to find the book, so requests
+                // for other books don't queue behind this one
+                let (book_file, damage) = {
+                    let books = books.lock();
+                    let damage = books.descriptor(&book_key).and_then(|descriptor| descriptor.damage.clone());
+                    (books.path(&book_key).map(|path| path.to_path_buf()), damage)
+                };
+                let book_handle = Library::handle(&books, &book_key);
+                match book_handle {
+                    Some(book_handle) => {
+                        let mut book_guard = book_handle.lock();
+                        let book = &mut *book_guard;
+                        let path = archive_path_for_request(book, &cached_virtual_paths(&virtual_paths, &book_key, book), &path);
                         let mime: String;
                         match book.get_resource_mime_by_path(&path) {
                             Ok(found_mime) => {
                                 mime = found_mime
                             }
-                            Err(_e) => {
-                                responder.respond(ResponseBuilder::new().status(404).body(Vec::new()).unwrap());
+                            // Generated, as the book has nothing there itself
+                            Err(_e) if path == NAV_DOCUMENT_PATH => {
+                                mime = "text/html".to_string()
+                            }
+                            Err(e) => {
+                                log::warn!(target: "epub_protocol", "Not found in {}: {} ({})", book_key, path, e);
+                                respond(responder, ResponseBuilder::new().status(404).body(Vec::new()));
                                 return
                             }
                         }
-                        match book.get_resource_by_path(&path) {
+                        // Large media is read a range at a time straight from
+                        // the archive, never whole
+                        let streamable = !is_html_mime(&mime) && !is_font_resource(std::path::Path::new(&path), &mime);
+                        let entry_range = range.as_deref()
+                            .zip(book_file.as_deref())
+                            .filter(|_| streamable)
+                            .and_then(|(range, file)| read_entry_range(file, &path, range, &entry_decoders));
+                        if let Some(entry_range) = entry_range {
+                            respond(responder, entry_range_response(entry_range, &mime, if_none_match.as_deref()));
+                            return;
+                        }
+
+                        // Check if content is HTML/XHTML
+                        let should_inject_script = is_html_mime(&mime);
+                        // Previews are marked as such, so they neither use
+                        // nor fill the chapter cache
+                        let chapter_key = (should_inject_script && !options.preview)
+                            .then(|| (book_key.clone(), chapter_cache_key(&path, &theme, &options)));
+                        let cached_chapter = chapter_key.as_ref().and_then(|key| chapter_cache.lock().get(key));
+                        let from_chapter_cache = cached_chapter.is_some();
+
+                        let key = (book_key.clone(), path.clone());
+                        // Previews don't fill the resource cache, so they don't
+                        // evict the reader's resources
+                        let resource = match cached_chapter {
+                            Some(chapter) => Ok(chapter),
+                            None => {
+                                let cached_resource = resource_cache.lock().get(&key);
+                                match cached_resource {
+                                    Some(resource) => Ok(resource),
+                                    None if path == NAV_DOCUMENT_PATH && book.get_resource_mime_by_path(&path).is_err() => {
+                                        Ok(nav_document(book).into_bytes())
+                                    }
+                                    None => book.get_resource_by_path(&path).map(|mut resource| {
+                                        // Cached deobfuscated, so it's only done once
+                                        if is_font_resource(std::path::Path::new(&path), &mime) {
+                                            deobfuscate_font(book, &path, &mut resource);
+                                        }
+                                        resource
+                                    }).inspect(|resource| {
+                                        if !options.preview {
+                                            resource_cache.lock().insert(key, resource.clone());
+                                        }
+                                    }),
+                                }
+                            }
+                        };
+                        match resource {
                             Ok(resource) => {
-                                // Check if content is HTML/XHTML
-                                let should_inject_script = mime == "text/html"
-                                    || mime == "application/xhtml+xml"
-                                    || mime == "application/xhtml"
-                                    || mime == "text/xhtml";
-
-                                let final_body = if should_inject_script {
-                                    inject_link_handler_script(resource)
+                                let final_body = if should_inject_script && !from_chapter_cache {
+                                    let chapter = prepare_chapter(resource, &path, &book_key, book, &theme, &options);
+                                    if let Some(chapter_key) = chapter_key {
+                                        chapter_cache.lock().insert(chapter_key, chapter.clone());
+                                    }
+                                    chapter
                                 } else {
                                     resource
                                 };
 
-                                responder.respond(
-                                    ResponseBuilder::new()
-                                        .status(200)
-                                        .header("Content-Type", &mime)
-                                        .body(final_body)
-                                        .unwrap()
-                                )
+                                let response = resource_response(
+                                    final_body,
+                                    &mime,
+                                    should_inject_script,
+                                    range.as_deref(),
+                                    if_none_match.as_deref(),
+                                );
+                                respond(responder, response)
                             }
-                            Err(_e) => {
-                                responder.respond(ResponseBuilder::new().status(404).body(Vec::new()).unwrap())
+                            Err(e) => {
+                                match damage {
+                                    Some(_) => log::error!(target: "epub_protocol", "Failed to read {} from damaged {}: {}", path, book_key, e),
+                                    None => log::error!(target: "epub_protocol", "Failed to read {} from {}: {}", path, book_key, e),
+                                }
+                                respond(responder, read_failure_response(damage.as_deref()))
                             }
                         }
                     }
                     None => {
-                        responder.respond(ResponseBuilder::new().status(404).body(Vec::new()).unwrap())
+                        log::error!(target: "epub_protocol", "Failed to open {}", book_key);
+                        respond(responder, ResponseBuilder::new().status(404).body(Vec::new()))
                     }
 
                 }
             });
         })
         .invoke_handler(tauri::generate_handler![
-            greet,
+            app_info,
             all_book_covers,
             get_book_title,
             get_book_toc,
             get_spine,
             get_current_spine_index,
-            get_spine_item
+            get_spine_item,
+            get_reading_theme,
+            set_reading_width,
+            get_book_identifiers,
+            get_cover_thumbnail,
+            prewarm_covers,
+            cancel_cover_prewarm,
+            get_toc_breadcrumb,
+            save_progress,
+            get_progress,
+            get_series_playlist,
+            get_chapter_styles,
+            goto_percent,
+            list_embedded_fonts,
+            analyze_compression,
+            check_conformance,
+            render_chapter_themed,
+            export_library_catalog,
+            get_page_spreads,
+            get_readability,
+            get_book_readability,
+            get_spine_items,
+            get_preferences,
+            set_preferences,
+            get_chapter_chunks,
+            get_chapter_peek,
+            find_duplicate_books,
+            get_front_matter,
+            render_share_cover,
+            query_books,
+            search_library,
+            search_library_streaming,
+            cancel_search,
+            get_estimated_pages,
+            get_book_metadata,
+            get_annotations,
+            set_annotations,
+            export_annotated_epub,
+            analyze_chapter_load,
+            export_book_text,
+            get_book_fingerprint,
+            get_image_full,
+            list_scripted_chapters,
+            get_cover_palette,
+            set_paragraph_style,
+            get_book_parts,
+            prepare_book,
+            set_hyphenation,
+            set_text_align,
+            set_book_finished,
+            get_nav_document,
+            get_virtual_paths,
+            get_chapter_times,
+            validate_toc,
+            set_columns,
+            get_audience,
+            get_chapter_slice,
+            resources_changed_since,
+            get_chapter_sanitized,
+            render_text_cover,
+            set_cache_budget,
+            get_cache_budget,
+            set_library_directory,
+            get_library_directory,
+            add_book,
+            get_toc_depth,
+            remove_book,
+            set_image_fit,
+            find_containing_chapter,
+            get_position_locator,
+            goto_locator,
+            set_open_book_limit,
+            validate_import,
+            get_chapter_links,
+            search_book,
+            get_unsupported_features,
+            generate_cover_sprite,
+            add_bookmark,
+            list_bookmarks,
+            remove_bookmark,
+            get_book_content,
+            set_reading_theme,
+            set_theme_preset,
+            get_book_states,
+            preview_chapter,
+            repair_positions,
+            get_cover_image,
+            get_spine_sizes,
+            get_chapter_text,
+            resolve_link,
+            get_book_toc_flat,
+            resource_exists,
+            reload_library,
+            get_load_errors,
+            get_rendition_properties,
+            list_resources,
+            export_chapter_html,
+            export_book_html,
+            get_book_host,
+            validate_epub,
+            next_resource,
+            prev_resource,
+            set_injection_mode,
+            add_highlight,
+            list_highlights,
+            remove_highlight,
+            clear_cover_cache,
+            get_library_stats,
+            take_opened_book,
+            get_log_path,
+            get_media_overlay,
+            get_library_status,
+            export_resource,
+            get_book_cover,
+            get_spine_index_for_href,
+            set_privacy_mode,
+            normalize_resource_path,
+            get_reading_estimates,
+            set_words_per_minute,
+            resolve_cfi,
+            get_package_document,
+            get_container_xml,
+            peek_cover,
+            open_book,
+            get_recent_books,
+            get_book_spine
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|_app, _event| {
+            // macOS and iOS send files opened with the app as an event, also
+            // while it's running, rather than as arguments
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            if let tauri::RunEvent::Opened { urls } = _event {
+                for path in urls.into_iter().filter_map(|url| url.to_file_path().ok()) {
+                    open_book_file(_app, &path);
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Writes a minimal EPUB 3 to the temp directory and returns its path.
+    /// `resources` are (path under `OEBPS/`, media type, contents), all in
+    /// the manifest and the XHTML ones in the spine, in order. `files` are
+    /// written as they are, outside the manifest.
+    fn fixture_epub(name: &str, metadata: &str, resources: &[(&str, &str, &[u8])], files: &[(&str, &[u8])]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("epub-reader-test-{}-{}.epub", std::process::id(), name));
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let stored = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let mut add = |name: &str, contents: &[u8]| {
+            zip.start_file(name, stored).unwrap();
+            zip.write_all(contents).unwrap();
+        };
+
+        add("mimetype", b"application/epub+zip");
+        add("META-INF/container.xml", br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#);
+        let manifest: String = resources.iter().enumerate()
+            .map(|(i, (href, media_type, _))| format!("<item id=\"item{}\" href=\"{}\" media-type=\"{}\"/>", i, href, media_type))
+            .collect();
+        let spine: String = resources.iter().enumerate()
+            .filter(|(_, (_, media_type, _))| *media_type == "application/xhtml+xml")
+            .map(|(i, _)| format!("<itemref idref=\"item{}\"/>", i))
+            .collect();
+        add("OEBPS/content.opf", format!(r#"<?xml version="1.0"?>
+<package version="3.0" unique-identifier="uid" xmlns="http://www.idpf.org/2007/opf">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:{}</dc:identifier>
+    <dc:title>{}</dc:title>
+    {}
+  </metadata>
+  <manifest>{}</manifest>
+  <spine>{}</spine>
+</package>"#, FIXTURE_UUID, name, metadata, manifest, spine).as_bytes());
+        for (href, _, contents) in resources {
+            add(&format!("OEBPS/{}", href), contents);
+        }
+        for (name, contents) in files {
+            add(name, contents);
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    /// The identifier of every `fixture_epub`.
+    const FIXTURE_UUID: &str = "12345678-9abc-def0-1234-56789abcdef0";
+
+    fn open_fixture(path: &std::path::Path) -> library::Book {
+        EpubDoc::new(path).unwrap()
+    }
+
+    const FIXTURE_CHAPTER: &[u8] = b"<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>One</title></head><body><p>Text</p></body></html>";
+
+    #[test]
+    fn injection_point_skips_commented_out_head_end() {
+        let html = "<html><head><!-- </head> --><title>T</title></head><body></body></html>";
+        let end = html.rfind("</head>").unwrap();
+        assert_eq!(find_injection_point(html), InjectionPoint::At(end));
+    }
+
+    #[test]
+    fn injection_point_after_uppercase_body() {
+        let html = "<HTML><BODY><p>Text</p></BODY></HTML>";
+        assert_eq!(find_injection_point(html), InjectionPoint::At("<HTML><BODY>".len()));
+    }
+
+    #[test]
+    fn injection_point_after_body_with_attributes() {
+        let html = "<html><body class=\"chapter\" dir=\"rtl\"><p>Text</p></body></html>";
+        let body_end = html.find("<p>").unwrap();
+        assert_eq!(find_injection_point(html), InjectionPoint::At(body_end));
+    }
+
+    #[test]
+    fn injection_without_head_goes_after_body() {
+        let html = "<html><body><p>Text</p></body></html>";
+        assert_eq!(insert_at_head_end(html, "<style/>"), "<html><body><style/><p>Text</p></body></html>");
+    }
+
+    #[test]
+    fn injection_fills_self_closing_head() {
+        let html = "<html><head/><body><p>Text</p></body></html>";
+        assert_eq!(insert_at_head_end(html, "<style/>"), "<html><head><style/></head><body><p>Text</p></body></html>");
+    }
+
+    #[test]
+    fn injection_into_xhtml_with_xml_declaration() {
+        let bytes = b"<?xml version=\"1.0\" encoding=\"windows-1252\"?><html><head><title>T</title></head><body><p>\xe9</p></body></html>".to_vec();
+        let options = InjectionOptions::from_query(None);
+        let html = String::from_utf8(inject_link_handler_script(bytes, &ReadingTheme::default(), &options, &TextDirection::default()))
+            .expect("served as UTF-8");
+        assert!(html.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+        assert!(html.find(INJECTION_MARKER).unwrap() < html.find("</head>").unwrap());
+        assert!(html.contains("<p>é</p>"));
+    }
+
+    #[test]
+    fn injected_document_is_not_injected_again() {
+        let options = InjectionOptions::from_query(None);
+        let inject = |html: Vec<u8>| inject_link_handler_script(html, &ReadingTheme::default(), &options, &TextDirection::default());
+        let once = inject(b"<html><head></head><body><p>Text</p></body></html>".to_vec());
+        assert_eq!(inject(once.clone()), once);
+    }
+
+    #[test]
+    fn quoted_marker_in_body_does_not_block_injection() {
+        let html = format!("<html><head></head><body><pre>{}</pre></body></html>", INJECTION_MARKER);
+        assert!(!has_injection(&html));
+        let options = InjectionOptions::from_query(None);
+        let injected = inject_link_handler_script(html.into_bytes(), &ReadingTheme::default(), &options, &TextDirection::default());
+        assert_eq!(String::from_utf8(injected).unwrap().matches(INJECTION_MARKER).count(), 2);
+    }
+
+    #[test]
+    fn single_byte_range() {
+        assert_eq!(parse_byte_range(Some("bytes=0-99"), 1000), ByteRange::Partial(0..100));
+        assert_eq!(parse_byte_range(Some("bytes=900-2000"), 1000), ByteRange::Partial(900..1000));
+    }
+
+    #[test]
+    fn open_ended_byte_range() {
+        assert_eq!(parse_byte_range(Some("bytes=500-"), 1000), ByteRange::Partial(500..1000));
+    }
+
+    #[test]
+    fn suffix_byte_range() {
+        assert_eq!(parse_byte_range(Some("bytes=-100"), 1000), ByteRange::Partial(900..1000));
+        assert_eq!(parse_byte_range(Some("bytes=-5000"), 1000), ByteRange::Partial(0..1000));
+    }
+
+    #[test]
+    fn multiple_byte_ranges_honor_the_first() {
+        assert_eq!(parse_byte_range(Some("bytes=0-9, 20-29"), 1000), ByteRange::Partial(0..10));
+    }
+
+    #[test]
+    fn unsatisfiable_byte_ranges() {
+        assert_eq!(parse_byte_range(Some("bytes=1000-"), 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range(Some("bytes=2000-3000"), 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range(Some("bytes=-0"), 1000), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn unusable_range_headers_get_the_full_resource() {
+        assert_eq!(parse_byte_range(None, 1000), ByteRange::Full);
+        assert_eq!(parse_byte_range(Some("items=0-9"), 1000), ByteRange::Full);
+        assert_eq!(parse_byte_range(Some("bytes=9-0"), 1000), ByteRange::Full);
+        assert_eq!(parse_byte_range(Some("bytes=abc"), 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn request_target_without_host() {
+        let uri: http::Uri = "/OEBPS/chapter1.xhtml".parse().unwrap();
+        assert_eq!(request_target(&uri), None);
+    }
+
+    #[test]
+    fn request_target_with_empty_path() {
+        let uri: http::Uri = "epub://b0123456789abcdef".parse().unwrap();
+        assert_eq!(request_target(&uri), Some(("b0123456789abcdef".to_string(), String::new())));
+        let uri: http::Uri = "epub://b0123456789abcdef/".parse().unwrap();
+        assert_eq!(request_target(&uri), Some(("b0123456789abcdef".to_string(), String::new())));
+    }
+
+    #[test]
+    fn request_target_splits_host_and_path() {
+        let uri: http::Uri = "epub://b0123456789abcdef/OEBPS/Text/ch%201.xhtml?links=route".parse().unwrap();
+        assert_eq!(request_target(&uri), Some(("b0123456789abcdef".to_string(), "OEBPS/Text/ch%201.xhtml".to_string())));
+    }
+
+    #[test]
+    fn request_path_with_encoded_space_finds_resource() {
+        let path = fixture_epub("space", "", &[
+            ("chapter.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("images/cover art.jpg", "image/jpeg", b"jpeg"),
+        ], &[]);
+        let book = open_fixture(&path);
+        let virtual_paths = build_virtual_paths(&book);
+
+        assert_eq!(archive_path_for_request(&book, &virtual_paths, "OEBPS/images/cover%20art.jpg"), "OEBPS/images/cover art.jpg");
+        assert_eq!(archive_path_for_request(&book, &virtual_paths, "OEBPS/images/cover art.jpg"), "OEBPS/images/cover art.jpg");
+        let virtual_path = &virtual_paths.to_virtual["OEBPS/images/cover art.jpg"];
+        assert_eq!(archive_path_for_request(&book, &virtual_paths, virtual_path), "OEBPS/images/cover art.jpg");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn resource_paths_resolve_across_nested_directories() {
+        let path = fixture_epub("nested", "", &[
+            ("Text/ch.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("images/x.png", "image/png", b"png"),
+        ], &[]);
+        let book = open_fixture(&path);
+
+        let resolve = |base: Option<&str>, href: &str| resolve_resource_path(&book, base, href);
+        assert_eq!(resolve(Some("OEBPS/Text/ch.xhtml"), "../images/x.png"), ("OEBPS/images/x.png".to_string(), None));
+        assert_eq!(resolve(Some("OEBPS/Text/ch.xhtml"), "./../images/x.png#top"), ("OEBPS/images/x.png".to_string(), Some("top".to_string())));
+        assert_eq!(resolve(Some("OEBPS/Text/ch.xhtml"), "#note"), ("OEBPS/Text/ch.xhtml".to_string(), Some("note".to_string())));
+        // Relative to the package document's directory when not found as written
+        assert_eq!(resolve(None, "images/x.png"), ("OEBPS/images/x.png".to_string(), None));
+        assert_eq!(resolve(Some("OEBPS/Text/ch.xhtml"), "/images/x.png"), ("OEBPS/images/x.png".to_string(), None));
+        // Missing resources keep the joined path
+        assert_eq!(resolve(Some("OEBPS/Text/ch.xhtml"), "../missing.png"), ("OEBPS/missing.png".to_string(), None));
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// `bytes` of a zip cut off partway into the entry `name`, with its
+    /// central directory kept (as when a copy stops early but the directory
+    /// was written first), so it still opens.
+    fn truncate_zip_entry(bytes: &[u8], name: &str, keep: usize) -> Vec<u8> {
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let eocd = bytes.len() - 22;
+        let (directory_size, directory_offset) = (u32_at(eocd + 12), u32_at(eocd + 16));
+        let name_at = bytes.windows(name.len()).position(|window| window == name.as_bytes()).unwrap();
+
+        let cut = name_at + name.len() + keep;
+        let mut truncated = bytes[..cut].to_vec();
+        truncated.extend_from_slice(&bytes[directory_offset..directory_offset + directory_size]);
+        truncated.extend_from_slice(&bytes[eocd..eocd + 16]);
+        truncated.extend_from_slice(&(cut as u32).to_le_bytes());
+        truncated.extend_from_slice(&bytes[eocd + 20..]);
+        truncated
+    }
+
+    #[test]
+    fn truncated_book_is_marked_damaged_and_served_as_502() {
+        let long_chapter = format!("<html><head><title>Two</title></head><body>{}</body></html>", "<p>Text</p>".repeat(500));
+        let path = fixture_epub("truncated", "", &[
+            ("ch1.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("ch2.xhtml", "application/xhtml+xml", long_chapter.as_bytes()),
+        ], &[]);
+        let truncated = truncate_zip_entry(&std::fs::read(&path).unwrap(), "OEBPS/ch2.xhtml", 100);
+        std::fs::write(&path, truncated).unwrap();
+
+        let mut book = open_fixture(&path);
+        let checks = LoadChecks::run(&path, &book);
+        assert!(checks.damage.as_deref().is_some_and(|damage| damage.contains("truncated")), "{:?}", checks.damage);
+        assert!(book.get_resource_by_path("OEBPS/ch2.xhtml").is_err());
+
+        let mut library = Library::new(library::DEFAULT_OPEN_LIMIT);
+        library.insert("truncated.epub".to_string(), path.clone(), book);
+        checks.apply(&mut library, "truncated.epub");
+        let damage = library.descriptor("truncated.epub").unwrap().damage.clone();
+        assert!(damage.is_some());
+
+        let response = read_failure_response(damage.as_deref()).unwrap();
+        assert_eq!(response.status(), 502);
+        assert!(String::from_utf8_lossy(response.body()).starts_with("This book appears damaged"));
+        assert_eq!(read_failure_response(None).unwrap().status(), 404);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn arabic_book_chapters_are_served_right_to_left() {
+        let chapter = "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<html xmlns=\"http://www.w3.org/1999/xhtml\"><head><title>واحد</title></head><body><p>نص</p></body></html>";
+        let path = fixture_epub("arabic", "<dc:language>ar</dc:language>", &[
+            ("ch1.xhtml", "application/xhtml+xml", chapter.as_bytes()),
+        ], &[]);
+        let mut book = open_fixture(&path);
+        let direction = TextDirection::of(&mut book);
+        assert!(direction.rtl);
+
+        let resource = book.get_resource_by_path("OEBPS/ch1.xhtml").unwrap();
+        let served = prepare_chapter(resource, "OEBPS/ch1.xhtml", "arabic.epub", &mut book, &ReadingTheme::default(), &InjectionOptions::default());
+        let served = String::from_utf8(served).unwrap();
+        let html_tag = &served[served.find("<html").unwrap()..];
+        let html_tag = &html_tag[..html_tag.find('>').unwrap()];
+        assert!(html_tag.contains("dir=\"rtl\""), "{}", html_tag);
+        assert!(html_tag.contains("lang=\"ar\""), "{}", html_tag);
+
+        // A direction the chapter declares itself is kept
+        let declared = set_document_direction(b"<html dir=\"ltr\"><body></body></html>".to_vec(), &direction);
+        assert_eq!(String::from_utf8(declared).unwrap(), "<html dir=\"ltr\" lang=\"ar\" xml:lang=\"ar\"><body></body></html>");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn content_type_carries_the_charset_of_text_resources() {
+        assert_eq!(content_type("text/css", b"@charset \"ISO-8859-1\";\nbody { }", false), "text/css; charset=windows-1252");
+        assert_eq!(content_type("text/css", b"body { }", false), "text/css; charset=utf-8");
+        assert_eq!(content_type("text/css", b"p::before { content: \"\xa7\" }", false), "text/css; charset=windows-1252");
+        assert_eq!(
+            content_type("application/xhtml+xml", b"<?xml version=\"1.0\" encoding=\"iso-8859-1\"?><html/>", true),
+            "application/xhtml+xml; charset=utf-8",
+        );
+        assert_eq!(content_type("text/css; charset=UTF-8", b"body { }", false), "text/css; charset=UTF-8");
+        assert_eq!(content_type("image/png", b"\x89PNG", false), "image/png");
+    }
+
+    #[test]
+    fn toc_is_synthesized_from_headings_then_titles() {
+        let path = fixture_epub("no-toc", "", &[
+            ("heading.xhtml", "application/xhtml+xml", b"<html><head><title>Title</title></head><body><h1><img src=\"a.png\"/></h1><h2>The  Heading</h2></body></html>"),
+            ("title.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("style.css", "text/css", b"body { }"),
+            ("untitled.xhtml", "application/xhtml+xml", b"<html><head></head><body><p>Text</p></body></html>"),
+        ], &[]);
+        let mut book = open_fixture(&path);
+        assert!(book.toc.is_empty());
+
+        let toc = book_toc(&mut book);
+        let labels: Vec<&str> = toc.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, ["The Heading", "One", "Chapter 3"]);
+        assert_eq!(toc[2].content, "OEBPS/untitled.xhtml");
+        assert!(toc.iter().all(|item| item.synthesized));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn obfuscated_fonts_are_restored() {
+        let font = include_bytes!("../tests/fixtures/font.ttf");
+        let fixtures: [(&str, &[u8]); 2] = [
+            ("idpf", include_bytes!("../tests/fixtures/font-idpf.epub")),
+            ("adobe", include_bytes!("../tests/fixtures/font-adobe.epub")),
+        ];
+        for (name, epub) in fixtures {
+            let path = std::env::temp_dir().join(format!("epub-reader-test-{}-font-{}.epub", std::process::id(), name));
+            std::fs::write(&path, epub).unwrap();
+            assert_eq!(drm_problem(&path), None, "{}", name);
+
+            let mut book = open_fixture(&path);
+            let mut data = book.get_resource_by_path("OEBPS/font.ttf").unwrap();
+            assert_ne!(data[..4], [0, 1, 0, 0], "{}", name);
+            deobfuscate_font(&mut book, "OEBPS/font.ttf", &mut data);
+            assert_eq!(data[..4], [0, 1, 0, 0], "{}", name);
+            assert_eq!(data, font, "{}", name);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn wrap_tables_wraps_each_table() {
+        let wrap = |html: &str| String::from_utf8(wrap_tables(html.as_bytes().to_vec())).unwrap();
+        assert_eq!(
+            wrap("<p>Before</p><table><tr><td>1</td></tr></table><p>After</p>"),
+            "<p>Before</p><div class=\"epub-table-scroll\"><table><tr><td>1</td></tr></table></div><p>After</p>",
+        );
+        assert_eq!(
+            wrap("<TABLE class=\"data\"><tr><td>1</td></tr></TABLE>"),
+            "<div class=\"epub-table-scroll\"><TABLE class=\"data\"><tr><td>1</td></tr></TABLE></div>",
+        );
+        assert_eq!(wrap("<p>No tables</p>"), "<p>No tables</p>");
+    }
+
+    #[test]
+    fn descriptor_holds_the_library_listing_fields() {
+        let metadata = r#"<dc:creator> Ann Author </dc:creator><dc:creator>Bo Writer</dc:creator>
+    <dc:language>en</dc:language><dc:subject>Fiction</dc:subject><dc:date>2001-02-03</dc:date>
+    <meta name="calibre:series" content="Saga"/><meta name="calibre:series_index" content="2"/>
+    <meta name="calibre:rating" content="8"/>"#;
+        let path = fixture_epub("descriptor", metadata, &[
+            ("ch1.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("font.otf", "font/otf", b"OTTO"),
+        ], &[]);
+        let mut library = Library::new(library::DEFAULT_OPEN_LIMIT);
+        library.insert("descriptor.epub".to_string(), path.clone(), open_fixture(&path));
+
+        let descriptor = library.descriptor("descriptor.epub").unwrap();
+        assert_eq!(descriptor.authors, ["Ann Author", "Bo Writer"]);
+        assert_eq!(descriptor.tags, ["Fiction"]);
+        assert_eq!(descriptor.language.as_deref(), Some("en"));
+        assert_eq!(descriptor.date.as_deref(), Some("2001-02-03"));
+        assert_eq!(descriptor.series.as_deref(), Some("Saga"));
+        assert_eq!(descriptor.series_index, Some(2.0));
+        assert_eq!(descriptor.rating, Some(4.0));
+        assert!(descriptor.has_fonts);
+
+        let summary = book_summary("descriptor.epub", descriptor, None, false);
+        assert_eq!(summary.title, "descriptor");
+        assert!(matches_filter(&summary, &BookFilter { series: Some("saga".to_string()), ..BookFilter::default() }));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn sprite_size_covers_every_cell() {
+        assert_eq!(sprite_size(0, 8, 100), (100, 100));
+        assert_eq!(sprite_size(3, 8, 100), (300, 100));
+        assert_eq!(sprite_size(17, 8, 100), (800, 300));
+        let (width, height) = sprite_size(100, 64, 4096);
+        assert!(width > MAX_SPRITE_SIDE && width * height > MAX_SPRITE_PIXELS);
+    }
+
+    #[test]
+    fn entry_ranges_read_stored_and_deflated_entries() {
+        let media: Vec<u8> = (0..STREAM_MIN_SIZE + 500_000).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("epub-reader-test-{}-ranges.epub", std::process::id()));
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        for (name, method) in [("stored.mp3", zip::CompressionMethod::Stored), ("deflated.mp3", zip::CompressionMethod::Deflated)] {
+            zip.start_file(name, zip::write::FileOptions::default().compression_method(method)).unwrap();
+            zip.write_all(&media).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let decoders = Mutex::new(EntryDecoders::new());
+        for name in ["stored.mp3", "deflated.mp3"] {
+            // Forward through the entry as a media element would, then back
+            for (header, expected) in [
+                ("bytes=0-", 0..STREAM_CHUNK_SIZE),
+                ("bytes=1048576-", STREAM_CHUNK_SIZE..media.len()),
+                ("bytes=1200000-1200099", 1_200_000..1_200_100),
+                ("bytes=10-19", 10..20),
+            ] {
+                let entry_range = read_entry_range(&path, name, header, &decoders).unwrap();
+                assert_eq!(entry_range.range, ByteRange::Partial(expected.clone()), "{} {}", name, header);
+                assert!(entry_range.data == media[expected], "{} {}", name, header);
+                assert_eq!(entry_range.len, media.len());
+            }
+        }
+        assert_eq!(decoders.lock().len(), 1);
+        assert!(read_entry_range(&path, "stored.mp3", "bytes=9999999-", &decoders).is_some_and(|r| r.range == ByteRange::Unsatisfiable));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn nav_links_survive_a_stray_nav_end_tag() {
+        let markup = r#"</nav><nav epub:type="toc"><a href="ch1.xhtml">One</a></nav>
+<nav epub:type="landmarks"><a epub:type="titlepage" href="title.xhtml"> Title
+  page </a></nav></nav>"#;
+        let links = nav_links(markup, "landmarks");
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].epub_type.as_deref(), Some("titlepage"));
+        assert_eq!(links[0].href, "title.xhtml");
+        assert_eq!(links[0].label, "Title page");
+    }
+
+    /// A book with two chapters whose spine starts with an item missing from
+    /// the manifest.
+    fn open_with_missing_spine_item(name: &str) -> (PathBuf, library::Book) {
+        let path = fixture_epub(name, "", &[
+            ("ch1.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("ch2.xhtml", "application/xhtml+xml", b"<html><head><title>Two</title></head><body><p>Needle in chapter two</p></body></html>"),
+        ], &[]);
+        let mut book = open_fixture(&path);
+        book.spine.insert(0, "missing".to_string());
+        (path, book)
+    }
+
+    #[test]
+    fn chapter_stats_are_indexed_by_spine_position() {
+        let (path, mut book) = open_with_missing_spine_item("missing-item");
+        let text_stats = TextStatsState(Arc::new(Mutex::new(HashMap::new())));
+
+        let stats = cached_text_stats(&text_stats, "missing-item.epub", &mut book);
+        assert_eq!(stats.iter().map(|stats| stats.words).collect::<Vec<_>>(), [0, 1, 4]);
+
+        let times = chapter_times(&text_stats, "missing-item.epub", &mut book, 200);
+        let times: Vec<(usize, &str, usize)> = times.iter().map(|time| (time.spine_index, time.href.as_str(), time.words)).collect();
+        assert_eq!(times, [(0, "", 0), (1, "OEBPS/ch1.xhtml", 1), (2, "OEBPS/ch2.xhtml", 4)]);
+
+        let result = search_library_book("missing-item.epub", &mut book, "needle").unwrap();
+        assert_eq!(result.hits.iter().map(|hit| hit.spine_index).collect::<Vec<_>>(), [2]);
+
+        let at_chapter_two = Progress { spine_index: 2, scroll_fraction: 0.0, updated_at: 0, anchor: PositionAnchor::default() };
+        let chars_before = stats[1].chars as f64 / stats.iter().map(|stats| stats.chars).sum::<usize>() as f64;
+        assert_eq!(read_fraction(&stats, &at_chapter_two), chars_before);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn relocated_positions_use_real_spine_indexes() {
+        let (path, mut book) = open_with_missing_spine_item("relocate");
+        let saved = |spine_href: Option<&str>, context: Option<&str>| Progress {
+            spine_index: 7,
+            scroll_fraction: 0.5,
+            updated_at: 0,
+            anchor: PositionAnchor {
+                spine_href: spine_href.map(str::to_string),
+                context: context.map(str::to_string),
+                spine_signature: Some("old".to_string()),
+            },
+        };
+
+        let (status, progress) = relocate_position(&mut book, &saved(Some("OEBPS/gone.xhtml"), Some("chapter two")));
+        assert!(matches!(status, RepairStatus::Relocated));
+        assert_eq!(progress.spine_index, 2);
+        assert_eq!(progress.anchor.spine_href.as_deref(), Some("OEBPS/ch2.xhtml"));
+
+        let (_, progress) = relocate_position(&mut book, &saved(Some("OEBPS/ch2.xhtml"), None));
+        assert_eq!((progress.spine_index, progress.scroll_fraction), (2, 0.5));
+
+        let (status, progress) = relocate_position(&mut book, &saved(None, Some("not in the book")));
+        assert!(matches!(status, RepairStatus::Reset));
+        assert_eq!(progress.spine_index, 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn commands_survive_a_panic_while_the_library_is_locked() {
+        let path = fixture_epub("poisoned", "", &[("one.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER)], &[]);
+        let mut library = Library::new(library::DEFAULT_OPEN_LIMIT);
+        library.insert("poisoned.epub".to_string(), path.clone(), open_fixture(&path));
+        let books = Arc::new(Mutex::new(library));
+
+        // `parking_lot` locks aren't poisoned by a panicking holder
+        let panicking = Arc::clone(&books);
+        let result = std::thread::spawn(move || {
+            let _library = panicking.lock();
+            panic!("panicked while holding the library lock");
+        }).join();
+        assert!(result.is_err());
+        let panicking = book_handle(&books, "poisoned.epub").unwrap();
+        let result = std::thread::spawn(move || {
+            let _book = panicking.lock();
+            panic!("panicked while holding the book lock");
+        }).join();
+        assert!(result.is_err());
+
+        let book = book_handle(&books, "poisoned.epub").unwrap();
+        assert_eq!(book.lock().spine.len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn library_search_decodes_legacy_encodings() {
+        let chapter = b"<?xml version=\"1.0\" encoding=\"windows-1252\"?><html><head><title>T</title></head><body><p>Un caf\xe9 noir</p></body></html>";
+        let path = fixture_epub("latin1-search", "", &[("one.xhtml", "application/xhtml+xml", chapter)], &[]);
+        let mut book = open_fixture(&path);
+
+        let result = search_library_book("latin1-search.epub", &mut book, "café").unwrap();
+        assert_eq!(result.hits.len(), 1);
+        assert_eq!(result.hits[0].offset, 3);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn stylesheet_hrefs_are_unescaped() {
+        let page = r#"<link rel="alternate stylesheet" href="a&amp;b.css"/><link rel="icon" href="i.png"/>"#;
+        let hrefs: Vec<String> = html::tokenize(page)
+            .filter_map(|(token, _)| match token {
+                html::Token::StartTag(tag) => stylesheet_href(&tag),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(hrefs, ["a&b.css"]);
+    }
+
+    #[test]
+    fn toc_depth_counts_the_synthesized_toc() {
+        let path = fixture_epub("depth-no-toc", "", &[
+            ("one.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("two.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+        ], &[]);
+        let mut book = open_fixture(&path);
+        assert!(book.toc.is_empty());
+
+        let depth = toc_depth(&book_toc(&mut book));
+        assert_eq!(depth.max_depth, 1);
+        assert_eq!(depth.entries_per_level, [2]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn resource_responses_serve_single_ranges() {
+        let css: &[u8] = b"body { margin: 0 }";
+        let path = fixture_epub("range", "", &[
+            ("one.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER),
+            ("style.css", "text/css", css),
+        ], &[]);
+        let mut book = open_fixture(&path);
+        let body = book.get_resource_by_path("OEBPS/style.css").unwrap();
+
+        let response = resource_response(body.clone(), "text/css", false, Some("bytes=5-10"), None).unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.body(), &css[5..11]);
+        assert_eq!(response.headers()["Content-Range"], format!("bytes 5-10/{}", css.len()));
+        assert_eq!(response.headers()["Content-Length"], "6");
+
+        let response = resource_response(body.clone(), "text/css", false, Some("bytes=100-"), None).unwrap();
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.headers()["Content-Range"], format!("bytes */{}", css.len()));
+        assert!(response.body().is_empty());
+
+        let etag = resource_etag(&body);
+        let response = resource_response(body, "text/css", false, Some("bytes=5-10"), Some(&etag)).unwrap();
+        assert_eq!(response.status(), 304);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn imports_are_numbered_like_library_books() {
+        let path = fixture_epub("import-key", "", &[("one.xhtml", "application/xhtml+xml", FIXTURE_CHAPTER)], &[]);
+        let mut library = Library::new(library::DEFAULT_OPEN_LIMIT);
+        let key = import_book_key(&library, &path);
+        library.insert(key.clone(), path.clone(), open_fixture(&path));
+
+        assert_eq!(import_book_key(&library, &path), unique_book_key(key.clone(), |candidate| library.contains_key(candidate)));
+        assert_eq!(import_book_key(&library, &path), format!("{} (2)", key));
+        let _ = std::fs::remove_file(path);
+    }
 }
@@ -0,0 +1,351 @@
+//! The book collection: a lightweight descriptor for every book found, with
+//! the full `EpubDoc` opened on first use and closed again, least recently
+//! used first, once too many are open.
+//!
+//! Each open document has its own lock, so work on one book (serving its
+//! resources) can continue after the library lock is released and doesn't
+//! hold up other books. Take a book's handle under the library lock and
+//! release that before locking the book; never take the library lock while
+//! holding a book's.
+
+use epub::doc::EpubDoc;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub type Book = EpubDoc<BufReader<File>>;
+
+/// Open documents kept by default; each holds a file handle and its parsed
+/// package.
+pub const DEFAULT_OPEN_LIMIT: usize = 20;
+
+/// What the library grid and whole-library listings show, read once when the
+/// book is added so listing the library doesn't open every book.
+pub struct BookDescriptor {
+    pub title: Option<String>,
+    /// Every `dc:creator`, trimmed
+    pub authors: Vec<String>,
+    pub identifier: Option<String>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    /// Every `dc:subject`, trimmed
+    pub tags: Vec<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub rating: Option<f64>,
+    /// The manifest lists fonts
+    pub has_fonts: bool,
+    /// Archive path of the cover image, or of the cover page when no image
+    /// could be found
+    pub cover_href: Option<String>,
+    /// Some spine items have no manifest entry, so chapters are missing
+    pub partial: bool,
+    /// The content is DRM-protected; set by whoever added the book
+    pub drm: bool,
+    /// Why the file looks damaged (truncated or partly copied), if it does;
+    /// set by whoever added the book
+    pub damage: Option<String>,
+}
+
+impl BookDescriptor {
+    fn read(book: &mut Book) -> BookDescriptor {
+        let root_file = book.root_file.clone();
+        let package = book.get_resource_by_path(&root_file).ok()
+            .and_then(|xml| crate::opf::Package::parse(&xml).ok());
+        let (series, series_index) = match package.as_ref().and_then(|package| package.series()) {
+            Some((series, index)) => (Some(series), index),
+            None => (None, None),
+        };
+        BookDescriptor {
+            title: book.mdata("title"),
+            authors: metadata_values(book, "creator"),
+            identifier: book.unique_identifier.clone().or_else(|| book.mdata("identifier")),
+            language: book.mdata("language"),
+            publisher: book.mdata("publisher"),
+            date: book.mdata("date"),
+            tags: metadata_values(book, "subject"),
+            series,
+            series_index,
+            rating: package.as_ref().and_then(|package| package.rating()),
+            has_fonts: book.resources.values().any(|(path, mime)| crate::is_font_resource(path, mime)),
+            cover_href: grid_cover_href(book),
+            partial: book.spine.iter().any(|id| !book.resources.contains_key(id)),
+            drm: false,
+            damage: None,
+        }
+    }
+}
+
+/// Trimmed, non-empty values of a metadata field such as `creator` or `subject`.
+pub fn metadata_values(book: &Book, key: &str) -> Vec<String> {
+    book.metadata.get(key)
+        .map(|values| values.iter().map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// The cover for the library grid: the cover item if it's an image, else the
+/// first image named like a cover, else the cover page itself.
+fn grid_cover_href(book: &Book) -> Option<String> {
+    let cover_id = book.get_cover_id().ok()?;
+    let (cover_path, mime_type) = book.resources.get(&cover_id)?;
+    if mime_type.starts_with("image/") {
+        return cover_path.to_str().map(str::to_string);
+    }
+
+    book.resources.iter()
+        .find(|(id, (_, mime))| mime.starts_with("image/") && (id.contains("cover") || id.contains("Cover")))
+        .map(|(_, (path, _))| path)
+        .unwrap_or(cover_path)
+        .to_str()
+        .map(str::to_string)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// 64-bit FNV-1a hash of `bytes`: stable across runs and platforms, unlike
+/// `DefaultHasher`, so it can name things that outlive the process.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3))
+}
+
+/// The `epub://` host for a book: `b` and a hash of the key in hex. Keys come
+/// from file names, which may hold spaces, Unicode or uppercase letters that
+/// don't survive as a URI host; the host is always safe, and the same on
+/// every run so cached responses stay valid.
+pub fn book_host(book_key: &str) -> String {
+    format!("b{:016x}", fnv1a(book_key.bytes()))
+}
+
+struct Entry {
+    path: PathBuf,
+    /// The file's modification time when the book was added
+    modified: Option<SystemTime>,
+    descriptor: BookDescriptor,
+    /// The open document and the tick it was last used at
+    open: Option<(Arc<Mutex<Book>>, u64)>,
+}
+
+pub struct Library {
+    entries: HashMap<String, Entry>,
+    /// Files that failed to load, with the reason
+    failures: HashMap<String, String>,
+    open_limit: usize,
+    tick: u64,
+}
+
+impl Library {
+    pub fn new(open_limit: usize) -> Library {
+        Library { entries: HashMap::new(), failures: HashMap::new(), open_limit: open_limit.max(1), tick: 0 }
+    }
+
+    pub fn open_limit(&self) -> usize {
+        self.open_limit
+    }
+
+    /// Changes how many documents stay open, closing any over the new limit.
+    pub fn set_open_limit(&mut self, open_limit: usize) {
+        self.open_limit = open_limit.max(1);
+        self.close_excess(None);
+    }
+
+    /// Adds a book from the file at `path`, keeping `book` (already opened
+    /// from it) open for now.
+    pub fn insert(&mut self, book_key: String, path: PathBuf, mut book: Book) {
+        self.tick += 1;
+        let descriptor = BookDescriptor::read(&mut book);
+        self.failures.remove(&book_key);
+        let open = Some((Arc::new(Mutex::new(book)), self.tick));
+        let modified = modified_time(&path);
+        self.entries.insert(book_key.clone(), Entry { path, modified, descriptor, open });
+        self.close_excess(Some(&book_key));
+    }
+
+    pub fn remove(&mut self, book_key: &str) -> Option<PathBuf> {
+        self.failures.remove(book_key);
+        self.entries.remove(book_key).map(|entry| entry.path)
+    }
+
+    /// Records a file that couldn't be loaded as a book.
+    pub fn insert_failure(&mut self, book_key: String, reason: String) {
+        self.failures.insert(book_key, reason);
+    }
+
+    /// Files that couldn't be loaded, with the reason for each.
+    pub fn failures(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.failures.iter()
+    }
+
+    pub fn mark_drm(&mut self, book_key: &str) {
+        if let Some(entry) = self.entries.get_mut(book_key) {
+            entry.descriptor.drm = true;
+        }
+    }
+
+    pub fn mark_damaged(&mut self, book_key: &str, damage: String) {
+        if let Some(entry) = self.entries.get_mut(book_key) {
+            entry.descriptor.damage = Some(damage);
+        }
+    }
+
+    /// Whether the book was added from `path` and the file hasn't been
+    /// modified since.
+    pub fn is_unchanged(&self, book_key: &str, path: &Path) -> bool {
+        self.entries.get(book_key).is_some_and(|entry| {
+            entry.path == path && entry.modified.is_some() && entry.modified == modified_time(path)
+        })
+    }
+
+    pub fn contains_key(&self, book_key: &str) -> bool {
+        self.entries.contains_key(book_key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    /// The key of the book an `epub://` host refers to: a host from
+    /// `book_host`, or a key that's usable as a host as it is.
+    pub fn key_for_host(&self, host: &str) -> Option<String> {
+        if self.entries.contains_key(host) {
+            return Some(host.to_string());
+        }
+        self.entries.keys().find(|key| book_host(key) == host).cloned()
+    }
+
+    /// The key of the book added from `path`.
+    pub fn key_for_path(&self, path: &Path) -> Option<&String> {
+        self.entries.iter().find(|(_, entry)| entry.path == path).map(|(key, _)| key)
+    }
+
+    /// The file the book was added from.
+    pub fn path(&self, book_key: &str) -> Option<&Path> {
+        self.entries.get(book_key).map(|entry| entry.path.as_path())
+    }
+
+    pub fn descriptor(&self, book_key: &str) -> Option<&BookDescriptor> {
+        self.entries.get(book_key).map(|entry| &entry.descriptor)
+    }
+
+    /// Every book's descriptor, without opening anything.
+    pub fn descriptors(&self) -> impl Iterator<Item = (&String, &BookDescriptor)> {
+        self.entries.iter().map(|(key, entry)| (key, &entry.descriptor))
+    }
+
+    /// The book's document, opened if it isn't already, to be locked once
+    /// the library lock is released. It stays usable even if the library
+    /// closes it meanwhile. The file is parsed without the library lock held,
+    /// so opening one book doesn't hold up the others. `None` for unknown
+    /// keys and for files that no longer open.
+    pub fn handle(library: &Mutex<Library>, book_key: &str) -> Option<Arc<Mutex<Book>>> {
+        let path = {
+            let mut library = library.lock();
+            if let Some(book) = library.open_handle(book_key) {
+                return Some(book);
+            }
+            library.entries.get(book_key)?.path.clone()
+        };
+
+        let book = match EpubDoc::new(&path) {
+            Ok(book) => book,
+            Err(e) => {
+                log::error!("Failed to open {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        library.lock().adopt(book_key, &path, book)
+    }
+
+    /// The book's document if it's open, marked as just used.
+    fn open_handle(&mut self, book_key: &str) -> Option<Arc<Mutex<Book>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (book, last_used) = self.entries.get_mut(book_key)?.open.as_mut()?;
+        *last_used = tick;
+        Some(Arc::clone(book))
+    }
+
+    /// Keeps a document opened from `path`, unless another thread opened the
+    /// book first (its document is used instead) or the book was removed or
+    /// moved to another file meanwhile.
+    fn adopt(&mut self, book_key: &str, path: &Path, book: Book) -> Option<Arc<Mutex<Book>>> {
+        if let Some(book) = self.open_handle(book_key) {
+            return Some(book);
+        }
+        let entry = self.entries.get_mut(book_key).filter(|entry| entry.path == path)?;
+        let book = Arc::new(Mutex::new(book));
+        entry.open = Some((Arc::clone(&book), self.tick));
+        self.close_excess(Some(book_key));
+        Some(book)
+    }
+
+    /// The book's document for a one-off look at its content: the open one
+    /// if it is open, else one opened just for the caller and not kept, so
+    /// scanning the library doesn't close the books being read. The file is
+    /// parsed without the library lock held.
+    pub fn scan_handle(library: &Mutex<Library>, book_key: &str) -> Option<Arc<Mutex<Book>>> {
+        let path = {
+            let library = library.lock();
+            let entry = library.entries.get(book_key)?;
+            if let Some((book, _)) = &entry.open {
+                return Some(Arc::clone(book));
+            }
+            entry.path.clone()
+        };
+        match EpubDoc::new(&path) {
+            Ok(book) => Some(Arc::new(Mutex::new(book))),
+            Err(e) => {
+                log::error!("Failed to open {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Runs `f` on every book whose descriptor is `wanted`, collecting its
+    /// results, for listings that need the books' content. Books are opened
+    /// and locked one at a time through `scan_handle`, with the library lock
+    /// only held to look each one up, so other commands aren't held up.
+    /// Listings of what descriptors hold should read `descriptors` instead.
+    pub fn filter_map_books<T>(
+        library: &Mutex<Library>,
+        wanted: impl Fn(&BookDescriptor) -> bool,
+        mut f: impl FnMut(&String, &mut Book) -> Option<T>,
+    ) -> Vec<T> {
+        let keys: Vec<String> = library.lock().entries.iter()
+            .filter(|(_, entry)| wanted(&entry.descriptor))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter()
+            .filter_map(|key| {
+                let book = Library::scan_handle(library, key)?;
+                let book = &mut *book.lock();
+                f(key, book)
+            })
+            .collect()
+    }
+
+    /// Closes the least recently used documents, other than `keep`'s, until
+    /// no more than the limit are open.
+    fn close_excess(&mut self, keep: Option<&str>) {
+        let mut open: Vec<(String, u64)> = self.entries.iter()
+            .filter_map(|(key, entry)| entry.open.as_ref().map(|(_, last_used)| (key.clone(), *last_used)))
+            .collect();
+        if open.len() <= self.open_limit {
+            return;
+        }
+        open.sort_by_key(|(_, last_used)| *last_used);
+
+        let excess = open.len() - self.open_limit;
+        for (key, _) in open.into_iter().filter(|(key, _)| Some(key.as_str()) != keep).take(excess) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.open = None;
+            }
+        }
+    }
+}
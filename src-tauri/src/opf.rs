@@ -0,0 +1,344 @@
+//! Parsing of the OPF package document.
+//!
+//! The `epub` crate flattens metadata into `name -> values` and drops element
+//! attributes such as `opf:scheme` or `refines`, so anything that needs them
+//! reads the package document again through this module.
+
+use xml::reader::{EventReader, XmlEvent};
+
+/// A child of `<metadata>`: either a Dublin Core element or a `<meta>`.
+pub struct MetaElement {
+    /// Local element name, e.g. `identifier`, `creator` or `meta`
+    pub name: String,
+    /// Attributes keyed by local name (`opf:scheme` is stored as `scheme`)
+    pub attrs: Vec<(String, String)>,
+    pub text: String,
+}
+
+impl MetaElement {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// A manifest `<item>`, as declared (the href is not resolved).
+pub struct ManifestItem {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    /// Space-separated EPUB3 properties such as `nav` or `cover-image`
+    pub properties: Option<String>,
+    /// Id of the item's media overlay (SMIL) document
+    pub media_overlay: Option<String>,
+}
+
+impl ManifestItem {
+    pub fn has_property(&self, property: &str) -> bool {
+        has_token(self.properties.as_deref(), property)
+    }
+}
+
+/// A spine `<itemref>`.
+pub struct SpineItem {
+    pub idref: String,
+    /// The `<itemref>`'s own id, which CFIs assert
+    pub id: Option<String>,
+    /// `false` for `linear="no"` items, which sit outside the reading order
+    /// (answers, pop-ups and the like)
+    pub linear: bool,
+    /// Space-separated properties such as `page-spread-left`
+    pub properties: Option<String>,
+}
+
+impl SpineItem {
+    pub fn has_property(&self, property: &str) -> bool {
+        has_token(self.properties.as_deref(), property)
+    }
+}
+
+fn has_token(list: Option<&str>, token: &str) -> bool {
+    list.map(|l| l.split_whitespace().any(|v| v == token)).unwrap_or(false)
+}
+
+/// An EPUB2 `<guide><reference>`.
+pub struct GuideReference {
+    /// Reference type such as `dedication`, `preface` or `title-page`
+    pub kind: String,
+    pub href: String,
+    pub title: Option<String>,
+}
+
+pub struct Package {
+    /// `<package version="...">`, e.g. `2.0` or `3.0`
+    pub version: Option<String>,
+    /// Id of the identifier named by `<package unique-identifier="...">`
+    pub unique_identifier: Option<String>,
+    pub metadata: Vec<MetaElement>,
+    pub manifest: Vec<ManifestItem>,
+    /// Spine `<itemref>`s, in reading order
+    pub spine: Vec<SpineItem>,
+    /// Manifest id of the NCX named by `<spine toc="...">`
+    pub spine_toc: Option<String>,
+    /// `<spine page-progression-direction="...">`: `ltr`, `rtl` or `default`
+    pub page_progression_direction: Option<String>,
+    pub guide: Vec<GuideReference>,
+}
+
+impl Package {
+    pub fn parse(xml: &[u8]) -> Result<Package, String> {
+        let mut package = Package {
+            version: None,
+            unique_identifier: None,
+            metadata: Vec::new(),
+            manifest: Vec::new(),
+            spine: Vec::new(),
+            spine_toc: None,
+            page_progression_direction: None,
+            guide: Vec::new(),
+        };
+        // Element path from the root, by local name
+        let mut path: Vec<String> = Vec::new();
+        let mut current: Option<MetaElement> = None;
+
+        for event in EventReader::new(xml) {
+            match event.map_err(|e| format!("Invalid package document: {}", e))? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let attrs: Vec<(String, String)> = attributes
+                        .into_iter()
+                        .map(|a| (a.name.local_name, a.value))
+                        .collect();
+
+                    let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+                    let parent = path.last().map(String::as_str);
+
+                    if path.is_empty() && name.local_name == "package" {
+                        package.version = attr("version");
+                        package.unique_identifier = attr("unique-identifier");
+                    } else if parent == Some("package") && name.local_name == "spine" {
+                        package.spine_toc = attr("toc");
+                        package.page_progression_direction = attr("page-progression-direction");
+                    } else if parent == Some("manifest") && name.local_name == "item" {
+                        package.manifest.push(ManifestItem {
+                            id: attr("id").unwrap_or_default(),
+                            href: attr("href").unwrap_or_default(),
+                            media_type: attr("media-type").unwrap_or_default(),
+                            properties: attr("properties"),
+                            media_overlay: attr("media-overlay"),
+                        });
+                    } else if parent == Some("guide") && name.local_name == "reference" {
+                        package.guide.push(GuideReference {
+                            kind: attr("type").unwrap_or_default(),
+                            href: attr("href").unwrap_or_default(),
+                            title: attr("title"),
+                        });
+                    } else if parent == Some("spine") && name.local_name == "itemref" {
+                        package.spine.push(SpineItem {
+                            idref: attr("idref").unwrap_or_default(),
+                            id: attr("id"),
+                            linear: attr("linear").is_none_or(|linear| linear.trim() != "no"),
+                            properties: attr("properties"),
+                        });
+                    } else if parent == Some("metadata") {
+                        current = Some(MetaElement {
+                            name: name.local_name.clone(),
+                            attrs,
+                            text: String::new(),
+                        });
+                    }
+                    path.push(name.local_name);
+                }
+                XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                    if let Some(element) = current.as_mut() {
+                        element.text.push_str(&text);
+                    }
+                }
+                XmlEvent::EndElement { .. } => {
+                    path.pop();
+                    if path.last().map(String::as_str) == Some("metadata") {
+                        if let Some(mut element) = current.take() {
+                            element.text = element.text.trim().to_string();
+                            package.metadata.push(element);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(package)
+    }
+
+    pub fn manifest_item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    /// Text of the first `<meta property="...">` for the whole publication.
+    pub fn property(&self, property: &str) -> Option<&str> {
+        self.elements("meta")
+            .find(|m| m.attr("property") == Some(property) && m.attr("refines").is_none())
+            .map(|m| m.text.as_str())
+    }
+
+    /// Metadata elements with the given local name, in document order.
+    pub fn elements<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a MetaElement> + 'a {
+        self.metadata.iter().filter(move |m| m.name == name)
+    }
+
+    /// Value of the first EPUB3 `<meta refines="#id" property="...">` for an element id.
+    pub fn refinement(&self, id: &str, property: &str) -> Option<&str> {
+        self.elements("meta")
+            .find(|m| {
+                m.attr("refines").map(|r| r.trim_start_matches('#')) == Some(id)
+                    && m.attr("property") == Some(property)
+            })
+            .map(|m| m.text.as_str())
+    }
+
+    /// Identifier scheme (ISBN, UUID, DOI, ...) of a `dc:identifier`, taken
+    /// from the EPUB2 `opf:scheme` attribute, an EPUB3 `identifier-type`
+    /// refinement, or a recognizable URN prefix on the value itself.
+    pub fn identifier_scheme(&self, identifier: &MetaElement) -> Option<String> {
+        if let Some(scheme) = identifier.attr("scheme") {
+            return Some(scheme.to_string());
+        }
+
+        if let Some(kind) = identifier.attr("id").and_then(|id| self.refinement(id, "identifier-type")) {
+            // ONIX code list 5 values used by EPUB3 identifier-type refinements
+            let scheme = match kind {
+                "02" | "15" => "ISBN",
+                "06" => "DOI",
+                "22" => "URN",
+                other => other,
+            };
+            return Some(scheme.to_string());
+        }
+
+        let value = identifier.text.to_ascii_lowercase();
+        let scheme = if value.starts_with("urn:isbn:") || value.starts_with("isbn:") {
+            "ISBN"
+        } else if value.starts_with("urn:uuid:") || value.starts_with("uuid:") {
+            "UUID"
+        } else if value.starts_with("doi:") || value.starts_with("urn:doi:") || value.starts_with("https://doi.org/") {
+            "DOI"
+        } else {
+            return None;
+        };
+        Some(scheme.to_string())
+    }
+
+    /// The declared EPUB version as `major.minor`, e.g. `2.0` for a package
+    /// that says `2` and `3.0` for one that says `3.0`. `None` when missing
+    /// or not a number.
+    pub fn epub_version(&self) -> Option<String> {
+        let version = self.version.as_deref()?.trim();
+        let mut parts = version.split('.');
+        let major: u32 = parts.next()?.parse().ok()?;
+        let minor: u32 = parts.next().map_or(Some(0), |minor| minor.parse().ok())?;
+        Some(format!("{}.{}", major, minor))
+    }
+
+    /// Star rating (0–5) stored by Calibre as `calibre:rating` (0–10).
+    pub fn rating(&self) -> Option<f64> {
+        self.elements("meta")
+            .find(|m| m.attr("name") == Some("calibre:rating"))
+            .and_then(|m| m.attr("content"))
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .map(|v| (v / 2.0).clamp(0.0, 5.0))
+    }
+
+    /// Declared target audience as (age range, label): `schema:typicalAgeRange`,
+    /// a Dublin Core `audience`/`schema:audience`, or a Calibre custom column
+    /// named `#audience`, `#age_range` or `#age`. `None` when nothing is declared.
+    pub fn audience(&self) -> Option<(Option<String>, Option<String>)> {
+        let non_empty = |v: &str| Some(v.trim().to_string()).filter(|v| !v.is_empty());
+        let mut age_range = self.property("schema:typicalAgeRange").and_then(non_empty);
+        let mut label = self.elements("audience").find_map(|m| non_empty(&m.text))
+            .or_else(|| self.property("schema:audience").and_then(non_empty));
+
+        // Calibre stores custom columns as JSON with the value under `#value#`
+        for column in ["#audience", "#age_range", "#age"] {
+            let name = format!("calibre:user_metadata:{}", column);
+            let value = self.elements("meta")
+                .find(|m| m.attr("name") == Some(name.as_str()))
+                .and_then(|m| m.attr("content"))
+                .and_then(|content| serde_json::from_str::<serde_json::Value>(content).ok())
+                .and_then(|json| match &json["#value#"] {
+                    serde_json::Value::String(v) => non_empty(v),
+                    serde_json::Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                });
+            let Some(value) = value else { continue };
+            // "8-12", "12+" and "14" are age ranges; anything else is a label
+            if value.chars().all(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '–' | ' ')) {
+                age_range.get_or_insert(value);
+            } else {
+                label.get_or_insert(value);
+            }
+        }
+
+        if age_range.is_none() && label.is_none() {
+            return None;
+        }
+        Some((age_range, label))
+    }
+
+    /// Series name and position, from Calibre's `calibre:series` /
+    /// `calibre:series_index` metas or an EPUB3 `belongs-to-collection`
+    /// (with its `group-position` refinement). A collection typed `series`
+    /// wins over untyped ones; collections nested in another (with
+    /// `refines`) are skipped.
+    pub fn series(&self) -> Option<(String, Option<f64>)> {
+        let named = |name: &str| {
+            self.elements("meta")
+                .find(|m| m.attr("name") == Some(name))
+                .and_then(|m| m.attr("content"))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+        if let Some(series) = named("calibre:series") {
+            let index = named("calibre:series_index").and_then(|i| i.parse().ok());
+            return Some((series.to_string(), index));
+        }
+
+        let collections = || {
+            self.elements("meta").filter(|m| {
+                m.attr("property") == Some("belongs-to-collection") && m.attr("refines").is_none() && !m.text.is_empty()
+            })
+        };
+        let collection_type = |m: &MetaElement| {
+            m.attr("id").and_then(|id| self.refinement(id, "collection-type")).map(str::trim)
+        };
+        collections()
+            .find(|m| collection_type(m) == Some("series"))
+            // Collections without a type are commonly used for series too
+            .or_else(|| collections().find(|m| collection_type(m).is_none()))
+            .map(|m| {
+                let index = m.attr("id")
+                    .and_then(|id| self.refinement(id, "group-position"))
+                    .and_then(|p| p.trim().parse().ok());
+                (m.text.clone(), index)
+            })
+    }
+}
+
+/// `(full-path, media-type)` of each `<rootfile>` in `META-INF/container.xml`.
+pub fn container_rootfiles(xml: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut rootfiles = Vec::new();
+    for event in EventReader::new(xml) {
+        if let XmlEvent::StartElement { name, attributes, .. } =
+            event.map_err(|e| format!("Invalid container.xml: {}", e))?
+        {
+            if name.local_name == "rootfile" {
+                let attr = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                        .unwrap_or_default()
+                };
+                rootfiles.push((attr("full-path"), attr("media-type")));
+            }
+        }
+    }
+    Ok(rootfiles)
+}
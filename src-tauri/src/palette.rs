@@ -0,0 +1,133 @@
+//! Color palettes for theming the reader around a book's cover, in the spirit
+//! of Android's Palette API: a handful of swatches picked by population,
+//! saturation and lightness.
+
+/// The cover is sampled at this size; more detail doesn't change the swatches.
+const SAMPLE_SIZE: u32 = 64;
+/// Buckets with less than this share of the pixels can't be a swatch.
+const MIN_SWATCH_SHARE: f64 = 0.01;
+
+pub struct Palette {
+    /// Most common color
+    pub dominant: [u8; 3],
+    /// Saturated mid-lightness color
+    pub vibrant: [u8; 3],
+    /// Desaturated mid-lightness color
+    pub muted: [u8; 3],
+    /// Light color suitable as a background
+    pub light: [u8; 3],
+    /// Dark color suitable as a background or text
+    pub dark: [u8; 3],
+}
+
+/// Hue in degrees (0–360), saturation and lightness (0.0–1.0).
+fn to_hsl(color: [u8; 3]) -> (f64, f64, f64) {
+    let [r, g, b] = color.map(|c| c as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return (0.0, 0.0, lightness);
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    (hue, saturation.min(1.0), lightness)
+}
+
+fn from_hsl(hue: f64, saturation: f64, lightness: f64) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match (hue.rem_euclid(360.0) / 60.0) as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    [r, g, b].map(|c| ((c + m) * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+/// The palette of a cover image, or `None` if it can't be decoded.
+pub fn from_image(bytes: &[u8]) -> Option<Palette> {
+    let image = image::load_from_memory(bytes).ok()?
+        .thumbnail(SAMPLE_SIZE, SAMPLE_SIZE)
+        .to_rgba8();
+
+    // Quantize to 4 bits per channel, averaging the pixels in each bucket
+    let mut buckets: std::collections::HashMap<u16, ([u64; 3], u64)> = std::collections::HashMap::new();
+    for pixel in image.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+        let key = ((r as u16 >> 4) << 8) | ((g as u16 >> 4) << 4) | (b as u16 >> 4);
+        let (sums, count) = buckets.entry(key).or_insert(([0; 3], 0));
+        for (sum, value) in sums.iter_mut().zip([r, g, b]) {
+            *sum += value as u64;
+        }
+        *count += 1;
+    }
+    let total: u64 = buckets.values().map(|(_, count)| count).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let swatches: Vec<([u8; 3], f64)> = buckets.into_values()
+        .map(|(sums, count)| (sums.map(|sum| (sum / count) as u8), count as f64 / total as f64))
+        .collect();
+    let dominant = swatches.iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(color, _)| *color)?;
+    let candidates: Vec<&([u8; 3], f64)> = swatches.iter().filter(|(_, share)| *share >= MIN_SWATCH_SHARE).collect();
+
+    // Best-scoring candidate, where `score` rates (saturation, lightness)
+    let pick = |score: &dyn Fn(f64, f64) -> f64| {
+        candidates.iter()
+            .map(|(color, share)| {
+                let (_, saturation, lightness) = to_hsl(*color);
+                (*color, score(saturation, lightness) * share.sqrt())
+            })
+            .filter(|(_, score)| *score > 0.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(color, _)| color)
+    };
+    let closeness = |value: f64, target: f64| (1.0 - (value - target).abs() * 2.0).max(0.0);
+
+    let (hue, saturation, _) = to_hsl(dominant);
+    Some(Palette {
+        dominant,
+        vibrant: pick(&|s, l| if s >= 0.35 { s * closeness(l, 0.5) } else { 0.0 })
+            .unwrap_or_else(|| from_hsl(hue, saturation.max(0.6), 0.5)),
+        muted: pick(&|s, l| if s < 0.35 { (1.0 - s) * closeness(l, 0.5) } else { 0.0 })
+            .unwrap_or_else(|| from_hsl(hue, saturation.min(0.25), 0.5)),
+        light: pick(&|_, l| if l >= 0.7 { l } else { 0.0 })
+            .unwrap_or_else(|| from_hsl(hue, saturation.min(0.4), 0.9)),
+        dark: pick(&|_, l| if l <= 0.3 { 1.0 - l } else { 0.0 })
+            .unwrap_or_else(|| from_hsl(hue, saturation.min(0.5), 0.15)),
+    })
+}
+
+/// A palette derived from `title` alone, for books without a cover. The same
+/// title always gives the same colors.
+pub fn from_title(title: &str) -> Palette {
+    let hash = crate::library::fnv1a(title.trim().to_lowercase().bytes());
+    let hue = (hash % 360) as f64;
+
+    Palette {
+        dominant: from_hsl(hue, 0.45, 0.4),
+        vibrant: from_hsl(hue, 0.75, 0.5),
+        muted: from_hsl(hue, 0.2, 0.5),
+        light: from_hsl(hue, 0.35, 0.9),
+        dark: from_hsl(hue, 0.4, 0.15),
+    }
+}
@@ -0,0 +1,99 @@
+//! Readability statistics for extracted chapter text.
+//!
+//! Uses the Flesch formulas:
+//!
+//! - Reading Ease = 206.835 − 1.015 × (words / sentences) − 84.6 × (syllables / words)
+//! - Flesch–Kincaid Grade = 0.39 × (words / sentences) + 11.8 × (syllables / words) − 15.59
+//!
+//! Syllables are estimated from vowel groups (English heuristics), so scores
+//! for other languages are only indicative.
+
+/// Below this many words the scores swing too much to be meaningful.
+pub const MIN_RELIABLE_WORDS: usize = 100;
+
+#[derive(Default, Clone, Copy)]
+pub struct TextCounts {
+    pub sentences: usize,
+    pub words: usize,
+    pub syllables: usize,
+}
+
+impl TextCounts {
+    /// Counts text as produced by `html::extract_text`: each line is a block,
+    /// and a block that doesn't end in punctuation (a heading, a caption)
+    /// still counts as a sentence.
+    pub fn of(text: &str) -> TextCounts {
+        let mut counts = TextCounts::default();
+        for line in text.lines() {
+            let mut open_sentence = false;
+            for token in line.split_whitespace() {
+                let letters: String = token.chars().filter(|c| c.is_alphanumeric()).collect();
+                if !letters.is_empty() {
+                    counts.words += 1;
+                    counts.syllables += syllables(&letters);
+                    open_sentence = true;
+                }
+                let ends_sentence = token
+                    .trim_end_matches(['"', '\'', '”', '’', ')', ']'])
+                    .ends_with(['.', '!', '?', '…']);
+                if ends_sentence && open_sentence {
+                    counts.sentences += 1;
+                    open_sentence = false;
+                }
+            }
+            if open_sentence {
+                counts.sentences += 1;
+            }
+        }
+        counts
+    }
+
+    pub fn add(&mut self, other: TextCounts) {
+        self.sentences += other.sentences;
+        self.words += other.words;
+        self.syllables += other.syllables;
+    }
+
+    fn ratios(&self) -> Option<(f64, f64)> {
+        if self.words == 0 || self.sentences == 0 {
+            return None;
+        }
+        let words = self.words as f64;
+        Some((words / self.sentences as f64, self.syllables as f64 / words))
+    }
+
+    pub fn flesch_reading_ease(&self) -> Option<f64> {
+        self.ratios().map(|(wps, spw)| 206.835 - 1.015 * wps - 84.6 * spw)
+    }
+
+    pub fn flesch_kincaid_grade(&self) -> Option<f64> {
+        self.ratios().map(|(wps, spw)| 0.39 * wps + 11.8 * spw - 15.59)
+    }
+}
+
+/// Estimated syllables in a word: vowel groups, less a silent final `e`,
+/// never fewer than one.
+fn syllables(word: &str) -> usize {
+    let word = word.to_lowercase();
+    if !word.chars().any(|c| c.is_alphabetic()) {
+        // Numbers and symbols
+        return 1;
+    }
+
+    let is_vowel = |c: char| matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y' | 'à'..='æ' | 'è'..='ï' | 'ò'..='ö' | 'ù'..='ü');
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for c in word.chars() {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+
+    // "make", "tune" — but not "the", "be" or "table"
+    if count > 1 && word.ends_with('e') && !word.ends_with("le") && !word.ends_with("ee") {
+        count -= 1;
+    }
+    count.max(1)
+}
@@ -0,0 +1,300 @@
+//! "Currently reading" share images: the cover with a title/author band, or
+//! a text-only card for books without a cover.
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+
+/// Output width in pixels; height follows the cover's aspect ratio.
+const CARD_WIDTH: u32 = 1080;
+const MAX_CARD_HEIGHT: u32 = 1920;
+/// Size of the text-only card (2:3, like most covers).
+const TEXT_CARD_HEIGHT: u32 = 1620;
+/// Accent for text-only cards, which have no cover to take colors from.
+const DEFAULT_ACCENT: [u8; 3] = [0x2f, 0x4b, 0x6e];
+/// Band opacity over the cover
+const BAND_ALPHA: f32 = 0.88;
+/// Size of a generated library cover (2:3).
+const TEXT_COVER_WIDTH: u32 = 600;
+const TEXT_COVER_HEIGHT: u32 = 900;
+
+/// Ubuntu Light, bundled so rendering doesn't depend on system fonts.
+fn font() -> FontRef<'static> {
+    FontRef::try_from_slice(epaint_default_fonts::UBUNTU_LIGHT).expect("bundled font is valid")
+}
+
+/// Renders the share image as PNG bytes.
+pub fn render(cover: Option<&[u8]>, title: &str, author: &str) -> Result<Vec<u8>, String> {
+    let cover = cover.and_then(|bytes| image::load_from_memory(bytes).ok());
+    let canvas = match cover {
+        Some(cover) => {
+            let cover = cover.resize(CARD_WIDTH, MAX_CARD_HEIGHT, image::imageops::FilterType::Triangle).to_rgba8();
+            cover_card(cover, title, author)
+        }
+        None => text_card(title, author),
+    };
+    encode_png(&canvas)
+}
+
+/// Renders a library cover for a book without one as PNG bytes: title and
+/// author on an `accent` band, above the book's opening `excerpt` on a
+/// `background` page.
+pub fn render_text_cover(
+    title: &str,
+    author: &str,
+    excerpt: &str,
+    background: [u8; 3],
+    accent: [u8; 3],
+) -> Result<Vec<u8>, String> {
+    let font = font();
+    let width = TEXT_COVER_WIDTH as f32;
+    let height = TEXT_COVER_HEIGHT as f32;
+    let padding = width * 0.08;
+    let title_scale = PxScale::from(width / 11.0);
+    let author_scale = PxScale::from(width / 22.0);
+    let excerpt_scale = PxScale::from(width / 26.0);
+    let max_text_width = width - padding * 2.0;
+
+    // The band holds the title and author, and takes at least 40% of the height
+    let title_lines = wrap(&font, title_scale, title, max_text_width, 4);
+    let author_line = wrap(&font, author_scale, author, max_text_width, 1);
+    let gap = if author_line.is_empty() { 0.0 } else { padding * 0.4 };
+    let text_height = line_height(&font, title_scale) * title_lines.len() as f32
+        + gap
+        + line_height(&font, author_scale) * author_line.len() as f32;
+    let band_height = (text_height + padding * 2.0).max(height * 0.4);
+    let mut canvas = RgbaImage::new(TEXT_COVER_WIDTH, TEXT_COVER_HEIGHT);
+    fill_from(&mut canvas, 0, accent, 1.0);
+    fill_from(&mut canvas, band_height as u32, background, 1.0);
+    let band = text_color(accent);
+
+    // Text sits at the bottom of the band, like a title page
+    let mut y = band_height - padding - text_height;
+    for line in &title_lines {
+        draw_line(&mut canvas, &font, title_scale, padding, y, line, band);
+        y += line_height(&font, title_scale);
+    }
+    y += gap;
+    for line in &author_line {
+        draw_line(&mut canvas, &font, author_scale, padding, y, line, band);
+    }
+
+    let excerpt_top = band_height + padding;
+    let excerpt_lines = ((height - excerpt_top - padding) / line_height(&font, excerpt_scale)).max(0.0) as usize;
+    let mut y = excerpt_top;
+    for line in wrap(&font, excerpt_scale, excerpt, max_text_width, excerpt_lines) {
+        draw_line(&mut canvas, &font, excerpt_scale, padding, y, &line, text_color(background));
+        y += line_height(&font, excerpt_scale);
+    }
+    encode_png(&canvas)
+}
+
+fn encode_png(canvas: &RgbaImage) -> Result<Vec<u8>, String> {
+    let mut png = std::io::Cursor::new(Vec::new());
+    canvas
+        .write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode image: {}", e))?;
+    Ok(png.into_inner())
+}
+
+fn cover_card(mut canvas: RgbaImage, title: &str, author: &str) -> RgbaImage {
+    let font = font();
+    let width = canvas.width() as f32;
+    let padding = width * 0.06;
+    let title_scale = PxScale::from(width / 14.0);
+    let author_scale = PxScale::from(width / 24.0);
+    let max_text_width = width - padding * 2.0;
+
+    let title_lines = wrap(&font, title_scale, title, max_text_width, 3);
+    let author_line = wrap(&font, author_scale, author, max_text_width, 1);
+    let title_height = line_height(&font, title_scale) * title_lines.len() as f32;
+    let author_height = line_height(&font, author_scale) * author_line.len() as f32;
+    let gap = if author_line.is_empty() { 0.0 } else { padding * 0.3 };
+    let band_height = (padding * 2.0 + title_height + gap + author_height).min(canvas.height() as f32);
+    let band_top = canvas.height() as f32 - band_height;
+
+    // The band takes the cover's own (darkened) average color
+    let accent = darken(average_color(&canvas), 0.55);
+    fill_from(&mut canvas, band_top as u32, accent, BAND_ALPHA);
+    let text = text_color(accent);
+
+    let mut y = band_top + padding;
+    for line in &title_lines {
+        draw_line(&mut canvas, &font, title_scale, padding, y, line, text);
+        y += line_height(&font, title_scale);
+    }
+    y += gap;
+    for line in &author_line {
+        draw_line(&mut canvas, &font, author_scale, padding, y, line, text);
+    }
+    canvas
+}
+
+fn text_card(title: &str, author: &str) -> RgbaImage {
+    let mut canvas = RgbaImage::new(CARD_WIDTH, TEXT_CARD_HEIGHT);
+    fill_from(&mut canvas, 0, DEFAULT_ACCENT, 1.0);
+    let text = text_color(DEFAULT_ACCENT);
+
+    let font = font();
+    let width = CARD_WIDTH as f32;
+    let padding = width * 0.1;
+    let title_scale = PxScale::from(width / 10.0);
+    let author_scale = PxScale::from(width / 20.0);
+    let max_text_width = width - padding * 2.0;
+
+    let title_lines = wrap(&font, title_scale, title, max_text_width, 6);
+    let author_line = wrap(&font, author_scale, author, max_text_width, 1);
+    let gap = if author_line.is_empty() { 0.0 } else { padding * 0.6 };
+    let total_height = line_height(&font, title_scale) * title_lines.len() as f32
+        + gap
+        + line_height(&font, author_scale) * author_line.len() as f32;
+
+    // Centered block of centered lines
+    let mut y = (TEXT_CARD_HEIGHT as f32 - total_height) / 2.0;
+    for line in &title_lines {
+        let x = (width - line_width(&font, title_scale, line)) / 2.0;
+        draw_line(&mut canvas, &font, title_scale, x, y, line, text);
+        y += line_height(&font, title_scale);
+    }
+    y += gap;
+    for line in &author_line {
+        let x = (width - line_width(&font, author_scale, line)) / 2.0;
+        draw_line(&mut canvas, &font, author_scale, x, y, line, text);
+    }
+    canvas
+}
+
+fn line_height(font: &FontRef, scale: PxScale) -> f32 {
+    let scaled = font.as_scaled(scale);
+    (scaled.ascent() - scaled.descent() + scaled.line_gap()) * 1.1
+}
+
+fn line_width(font: &FontRef, scale: PxScale, text: &str) -> f32 {
+    let scaled = font.as_scaled(scale);
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// Greedy word wrap into at most `max_lines`; text that doesn't fit ends
+/// with an ellipsis.
+fn wrap(font: &FontRef, scale: PxScale, text: &str, max_width: f32, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut words = text.split_whitespace().peekable();
+    while let Some(word) = words.next() {
+        let mut line = word.to_string();
+        while let Some(next) = words.peek() {
+            let candidate = format!("{} {}", line, next);
+            if line_width(font, scale, &candidate) > max_width {
+                break;
+            }
+            line = candidate;
+            words.next();
+        }
+
+        if lines.len() + 1 == max_lines && words.peek().is_some() {
+            line.push_str(" …");
+            while line_width(font, scale, &line) > max_width {
+                match line.trim_end_matches(" …").rsplit_once(' ') {
+                    Some((shorter, _)) => line = format!("{} …", shorter),
+                    None => break,
+                }
+            }
+            lines.push(line);
+            break;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draws one line of text with its top edge at `top`.
+fn draw_line(canvas: &mut RgbaImage, font: &FontRef, scale: PxScale, x: f32, top: f32, text: &str, color: [u8; 3]) {
+    let scaled = font.as_scaled(scale);
+    let baseline = top + scaled.ascent();
+    let mut caret = x;
+    let mut previous = None;
+
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(scale, point(caret, baseline));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+
+        if let Some(outlined) = font.outline_glyph(glyph) {
+            let bounds = outlined.px_bounds();
+            outlined.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+                if px >= 0 && py >= 0 && (px as u32) < canvas.width() && (py as u32) < canvas.height() {
+                    blend(canvas.get_pixel_mut(px as u32, py as u32), color, coverage);
+                }
+            });
+        }
+    }
+}
+
+fn blend(pixel: &mut Rgba<u8>, color: [u8; 3], alpha: f32) {
+    let alpha = alpha.clamp(0.0, 1.0);
+    for (channel, value) in pixel.0.iter_mut().take(3).zip(color) {
+        *channel = (*channel as f32 * (1.0 - alpha) + value as f32 * alpha).round() as u8;
+    }
+    pixel.0[3] = 255;
+}
+
+/// Blends `color` over every row from `top` to the bottom edge.
+fn fill_from(canvas: &mut RgbaImage, top: u32, color: [u8; 3], alpha: f32) {
+    for y in top..canvas.height() {
+        for x in 0..canvas.width() {
+            blend(canvas.get_pixel_mut(x, y), color, alpha);
+        }
+    }
+}
+
+fn average_color(image: &RgbaImage) -> [u8; 3] {
+    let mut sums = [0u64; 3];
+    let mut count = 0u64;
+    // Every 4th pixel in each direction is plenty for an average
+    for y in (0..image.height()).step_by(4) {
+        for x in (0..image.width()).step_by(4) {
+            let pixel = image.get_pixel(x, y);
+            for (sum, value) in sums.iter_mut().zip(pixel.0) {
+                *sum += value as u64;
+            }
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return DEFAULT_ACCENT;
+    }
+    sums.map(|sum| (sum / count) as u8)
+}
+
+fn darken(color: [u8; 3], factor: f32) -> [u8; 3] {
+    color.map(|c| (c as f32 * factor).round() as u8)
+}
+
+/// WCAG relative luminance of an sRGB color, 0.0–1.0.
+fn luminance(color: [u8; 3]) -> f32 {
+    let [r, g, b] = color.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.03928 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    });
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Near-black text on light backgrounds, white on dark ones.
+fn text_color(background: [u8; 3]) -> [u8; 3] {
+    // Crossover where contrast against black and white is equal
+    if luminance(background) > 0.179 { [0x1a, 0x1a, 0x1a] } else { [0xff, 0xff, 0xff] }
+}
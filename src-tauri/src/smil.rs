@@ -0,0 +1,86 @@
+//! Parsing of EPUB3 media overlay (SMIL) documents, which pair fragments of
+//! a chapter's text with the stretch of audio narrating them.
+
+use xml::reader::{EventReader, XmlEvent};
+
+/// A `<par>`: a text fragment and the audio clip that reads it, with hrefs
+/// as written (relative to the SMIL document).
+pub struct Par {
+    /// `<text src="...">`, e.g. `chapter1.xhtml#sentence-3`
+    pub text_src: String,
+    /// `<audio src="...">`; `None` for text with no narration
+    pub audio_src: Option<String>,
+    /// `clipBegin`/`clipEnd` in seconds; a missing begin means the start of
+    /// the file and a missing end its end
+    pub clip_begin: Option<f64>,
+    pub clip_end: Option<f64>,
+}
+
+/// Every `<par>` in the document, in playback order (nested `<seq>`s are
+/// flattened).
+pub fn parse(xml: &[u8]) -> Result<Vec<Par>, String> {
+    let mut pars = Vec::new();
+    let mut current: Option<Par> = None;
+
+    for event in EventReader::new(xml) {
+        match event.map_err(|e| format!("Invalid media overlay: {}", e))? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                let attr = |key: &str| {
+                    attributes.iter().find(|a| a.name.local_name == key).map(|a| a.value.clone())
+                };
+                match name.local_name.as_str() {
+                    "par" => {
+                        current = Some(Par { text_src: String::new(), audio_src: None, clip_begin: None, clip_end: None });
+                    }
+                    "text" => {
+                        if let Some(par) = current.as_mut() {
+                            par.text_src = attr("src").unwrap_or_default();
+                        }
+                    }
+                    "audio" => {
+                        if let Some(par) = current.as_mut() {
+                            par.audio_src = attr("src");
+                            par.clip_begin = attr("clipBegin").as_deref().and_then(parse_clock_value);
+                            par.clip_end = attr("clipEnd").as_deref().and_then(parse_clock_value);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::EndElement { name } if name.local_name == "par" => {
+                if let Some(par) = current.take().filter(|par| !par.text_src.is_empty()) {
+                    pars.push(par);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(pars)
+}
+
+/// A SMIL clock value in seconds: `hh:mm:ss.fff`, `mm:ss.fff`, or a number
+/// with an optional `h`, `min`, `s` or `ms` unit (seconds when there's none).
+fn parse_clock_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.contains(':') {
+        let parts: Vec<f64> = value.split(':').map(|part| part.trim().parse().ok()).collect::<Option<_>>()?;
+        return match parts[..] {
+            [hours, minutes, seconds] => Some(hours * 3600.0 + minutes * 60.0 + seconds),
+            [minutes, seconds] => Some(minutes * 60.0 + seconds),
+            _ => None,
+        };
+    }
+
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let scale = match unit.trim() {
+        "h" => 3600.0,
+        "min" => 60.0,
+        "s" | "" => 1.0,
+        "ms" => 0.001,
+        _ => return None,
+    };
+    Some(number * scale)
+}
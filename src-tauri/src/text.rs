@@ -0,0 +1,150 @@
+//! Segmentation of plain text produced by `html::extract_text`.
+
+use std::ops::Range;
+
+/// Byte ranges of the whitespace-separated tokens in `text`.
+fn tokens(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let start = pos + text[pos..].find(|c: char| !c.is_whitespace())?;
+        let len = text[start..].find(char::is_whitespace).unwrap_or(text.len() - start);
+        pos = start + len;
+        Some(start..pos)
+    })
+}
+
+fn is_word(token: &str) -> bool {
+    token.chars().any(char::is_alphanumeric)
+}
+
+/// Number of word tokens (containing a letter or digit) in `text`.
+pub fn word_count(text: &str) -> usize {
+    tokens(text).filter(|token| is_word(&text[token.clone()])).count()
+}
+
+fn ends_sentence(token: &str) -> bool {
+    token
+        .trim_end_matches(['"', '\'', '”', '’', ')', ']'])
+        .ends_with(['.', '!', '?', '…'])
+}
+
+/// A run of text and the number of words in it.
+pub struct Segment {
+    pub range: Range<usize>,
+    pub words: usize,
+}
+
+/// Sentences in `text`. A line break always ends a sentence, so headings and
+/// other unpunctuated blocks stand on their own.
+pub fn sentences(text: &str) -> Vec<Segment> {
+    let mut sentences = Vec::new();
+    let mut current: Option<Segment> = None;
+
+    for token in tokens(text) {
+        if let Some(open) = current.as_ref() {
+            if text[open.range.end..token.start].contains('\n') {
+                sentences.extend(current.take());
+            }
+        }
+        let segment = current.get_or_insert(Segment { range: token.start..token.end, words: 0 });
+        segment.range.end = token.end;
+        if is_word(&text[token.clone()]) {
+            segment.words += 1;
+        }
+        if ends_sentence(&text[token]) {
+            sentences.extend(current.take());
+        }
+    }
+    sentences.extend(current);
+    sentences
+}
+
+/// Splits `text` into chunks of at most `max_words` words, breaking between
+/// sentences where possible (longer sentences are cut at word boundaries).
+/// Consecutive chunks share up to `overlap_words` words of trailing sentences.
+pub fn chunks(text: &str, max_words: usize, overlap_words: usize) -> Vec<Range<usize>> {
+    let max_words = max_words.max(1);
+
+    // Sentences, with any that exceed the limit cut into limit-sized pieces
+    let mut units: Vec<Segment> = Vec::new();
+    for sentence in sentences(text) {
+        if sentence.words <= max_words {
+            units.push(sentence);
+            continue;
+        }
+        let mut piece: Option<Segment> = None;
+        for token in tokens(&text[sentence.range.clone()]) {
+            let token = sentence.range.start + token.start..sentence.range.start + token.end;
+            let segment = piece.get_or_insert(Segment { range: token.clone(), words: 0 });
+            segment.range.end = token.end;
+            if is_word(&text[token]) {
+                segment.words += 1;
+            }
+            if segment.words == max_words {
+                units.extend(piece.take());
+            }
+        }
+        units.extend(piece);
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < units.len() {
+        let mut end = start;
+        let mut words = 0;
+        while end < units.len() && (end == start || words + units[end].words <= max_words) {
+            words += units[end].words;
+            end += 1;
+        }
+        chunks.push(units[start].range.start..units[end - 1].range.end);
+        if end == units.len() {
+            break;
+        }
+
+        // Step back over trailing units that fit in the overlap, always moving forward
+        let mut next = end;
+        let mut overlap = 0;
+        while next - 1 > start && overlap + units[next - 1].words <= overlap_words {
+            overlap += units[next - 1].words;
+            next -= 1;
+        }
+        start = next;
+    }
+    chunks
+}
+
+/// Writing system of a letter, by Unicode block. Letters from scripts not
+/// listed here (and everything that isn't a letter) return `None`.
+pub fn script(c: char) -> Option<&'static str> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    let script = match c as u32 {
+        0x0041..=0x024F | 0x1E00..=0x1EFF => "Latin",
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => "Greek",
+        0x0400..=0x052F => "Cyrillic",
+        0x0530..=0x058F => "Armenian",
+        0x0590..=0x05FF => "Hebrew",
+        0x0600..=0x06FF | 0x0750..=0x077F | 0xFB50..=0xFDFF | 0xFE70..=0xFEFF => "Arabic",
+        0x0900..=0x097F => "Devanagari",
+        0x0980..=0x09FF => "Bengali",
+        0x0B80..=0x0BFF => "Tamil",
+        0x0E00..=0x0E7F => "Thai",
+        0x10A0..=0x10FF => "Georgian",
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => "Hangul",
+        0x3040..=0x309F => "Hiragana",
+        0x30A0..=0x30FF => "Katakana",
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F => "Han",
+        _ => return None,
+    };
+    Some(script)
+}
+
+/// Adds the number of letters of each script in `text` to `counts`.
+pub fn count_scripts(text: &str, counts: &mut std::collections::HashMap<&'static str, usize>) {
+    for c in text.chars() {
+        if let Some(script) = script(c) {
+            *counts.entry(script).or_default() += 1;
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Watches the library directory for `.epub` files being added or removed.
+//! Events are debounced per file, so a file that's still being copied in is
+//! only reported once writes to it have stopped.
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long a file must go without events before it's reported.
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+
+/// Watches `directory` and its subdirectories, calling `on_settled` with
+/// the `.epub` files whose events have settled; they may since have been
+/// created, changed or deleted. Watching, and the thread calling
+/// `on_settled`, stop when the returned watcher is dropped.
+pub fn watch(
+    directory: &Path,
+    on_settled: impl Fn(Vec<PathBuf>) + Send + 'static,
+) -> notify::Result<RecommendedWatcher> {
+    let (sender, receiver) = mpsc::channel::<PathBuf>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else { return };
+        for path in event.paths {
+            if path.extension().is_some_and(|extension| extension == "epub") {
+                let _ = sender.send(path);
+            }
+        }
+    })?;
+    watcher.watch(directory, RecursiveMode::Recursive)?;
+
+    std::thread::spawn(move || {
+        // Each file with events not yet reported, and when the last one came
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        loop {
+            match receiver.recv_timeout(QUIET_PERIOD / 4) {
+                Ok(path) => {
+                    pending.insert(path, Instant::now());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let settled: Vec<PathBuf> = pending.iter()
+                .filter(|(_, last_event)| last_event.elapsed() >= QUIET_PERIOD)
+                .map(|(path, _)| path.clone())
+                .collect();
+            if !settled.is_empty() {
+                pending.retain(|path, _| !settled.contains(path));
+                on_settled(settled);
+            }
+        }
+    });
+
+    Ok(watcher)
+}
@@ -0,0 +1,66 @@
+#!/usr/bin/env python3
+"""Writes the obfuscated-font fixtures: font.ttf, and the same font
+obfuscated with the IDPF and Adobe algorithms in font-idpf.epub and
+font-adobe.epub. Run from this directory."""
+
+import hashlib
+import zipfile
+
+IDENTIFIER = "urn:uuid:12345678-9abc-def0-1234-56789abcdef0"
+ALGORITHMS = {
+    "idpf": "http://www.idpf.org/2008/embedding",
+    "adobe": "http://ns.adobe.com/pdf/enc#RC",
+}
+
+CONTAINER = """<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"""
+
+ENCRYPTION = """<?xml version="1.0"?>
+<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="{}"/>
+    <enc:CipherData><enc:CipherReference URI="OEBPS/font.ttf"/></enc:CipherData>
+  </enc:EncryptedData>
+</encryption>"""
+
+PACKAGE = """<?xml version="1.0"?>
+<package version="3.0" unique-identifier="uid" xmlns="http://www.idpf.org/2007/opf">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">{}</dc:identifier>
+    <dc:title>Obfuscated font</dc:title>
+  </metadata>
+  <manifest>
+    <item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml"/>
+    <item id="font" href="font.ttf" media-type="font/ttf"/>
+  </manifest>
+  <spine><itemref idref="chapter"/></spine>
+</package>"""
+
+CHAPTER = """<html xmlns="http://www.w3.org/1999/xhtml"><head><title>One</title></head><body><p>Text</p></body></html>"""
+
+# An sfnt version header followed by filler, longer than either obfuscated prefix
+FONT = b"\x00\x01\x00\x00" + bytes((i * 7) % 256 for i in range(1496))
+
+
+def obfuscate(font, algorithm):
+    if algorithm == "idpf":
+        key, length = hashlib.sha1(IDENTIFIER.encode()).digest(), 1040
+    else:
+        key, length = bytes.fromhex(IDENTIFIER.removeprefix("urn:uuid:").replace("-", "")), 1024
+    head = bytes(byte ^ key[i % len(key)] for i, byte in enumerate(font[:length]))
+    return head + font[length:]
+
+
+with open("font.ttf", "wb") as f:
+    f.write(FONT)
+
+for name, algorithm in ALGORITHMS.items():
+    with zipfile.ZipFile(f"font-{name}.epub", "w") as epub:
+        epub.writestr("mimetype", "application/epub+zip", zipfile.ZIP_STORED)
+        epub.writestr("META-INF/container.xml", CONTAINER, zipfile.ZIP_DEFLATED)
+        epub.writestr("META-INF/encryption.xml", ENCRYPTION.format(algorithm), zipfile.ZIP_DEFLATED)
+        epub.writestr("OEBPS/content.opf", PACKAGE.format(IDENTIFIER), zipfile.ZIP_DEFLATED)
+        epub.writestr("OEBPS/chapter.xhtml", CHAPTER, zipfile.ZIP_DEFLATED)
+        epub.writestr("OEBPS/font.ttf", obfuscate(FONT, name), zipfile.ZIP_DEFLATED)
@@ -1,10 +1,36 @@
-import { BrowserRouter, Routes, Route } from "react-router-dom";
+import { useEffect } from "react";
+import { BrowserRouter, Routes, Route, useNavigate } from "react-router-dom";
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import "./App.css";
 import { BookLibrary, BookReader } from "./components";
 
+// Opens a book the app was launched with or sent by the OS (file association)
+function OpenedBookHandler() {
+  const navigate = useNavigate();
+
+  useEffect(() => {
+    const openPending = async () => {
+      const bookKey = await invoke<string | null>("take_opened_book");
+      if (bookKey) {
+        navigate(`/book/${bookKey}`);
+      }
+    };
+
+    openPending();
+    const unlisten = listen("open-book", openPending);
+    return () => {
+      unlisten.then((stop) => stop());
+    };
+  }, [navigate]);
+
+  return null;
+}
+
 function App() {
   return (
     <BrowserRouter>
+      <OpenedBookHandler />
       <main className="container">
         <Routes>
           <Route path="/" element={<BookLibrary />} />
@@ -1,14 +1,56 @@
 import { useState, useEffect } from "react";
 import { useNavigate } from "react-router-dom";
 import { invoke } from "@tauri-apps/api/core";
+import { errorMessage } from "../types/book";
 import "./BookLibrary.css";
 
+interface BookCover {
+  key: string;
+  title: string;
+  author: string | null;
+  cover_uri: string | null;
+  placeholder: { background: string; foreground: string } | null;
+  cover_info: { width: number; height: number; color: string } | null;
+}
+
+interface LibraryPage {
+  books: BookCover[];
+  total: number;
+}
+
+type LibraryStatus =
+  | { status: "ready"; books: number; failed: number }
+  | { status: "not-configured" }
+  | { status: "not-found"; directory: string }
+  | { status: "unreadable"; directory: string; reason: string }
+  | { status: "empty"; directory: string }
+  | { status: "all-failed"; directory: string; failed: number };
+
+// What to tell the user when there are no books to show
+function emptyLibraryMessage(status: LibraryStatus | null): string[] {
+  switch (status?.status) {
+    case "not-configured":
+      return ["Choose your books folder to get started."];
+    case "not-found":
+      return [`The books folder ${status.directory} doesn't exist.`, "Choose your books folder to get started."];
+    case "unreadable":
+      return [`The books folder ${status.directory} can't be read: ${status.reason}`];
+    case "all-failed":
+      return [`None of the ${status.failed} EPUB files in ${status.directory} could be opened.`];
+    case "empty":
+      return [`No books found in ${status.directory}.`, "Add EPUB files there to get started."];
+    default:
+      return ["No books found in your library."];
+  }
+}
+
 function BookLibrary() {
   const navigate = useNavigate();
-  const [books, setBooks] = useState<[string, string, string][]>([]);
+  const [books, setBooks] = useState<BookCover[]>([]);
   const [loading, setLoading] = useState(true);
   const [error, setError] = useState<string | null>(null);
   const [searchTerm, setSearchTerm] = useState("");
+  const [status, setStatus] = useState<LibraryStatus | null>(null);
 
   useEffect(() => {
     loadBookCovers();
@@ -19,10 +61,13 @@ function BookLibrary() {
       setLoading(true);
       setError(null);
       // Call the Rust command
-      const bookCovers = await invoke<[string, string, string][]>("all_book_covers");
-      setBooks(bookCovers);
+      const page = await invoke<LibraryPage>("all_book_covers", { sort: "title" });
+      setBooks(page.books);
+      if (page.books.length === 0) {
+        setStatus(await invoke<LibraryStatus>("get_library_status"));
+      }
     } catch (err) {
-      setError(`Failed to load books: ${err}`);
+      setError(`Failed to load books: ${errorMessage(err)}`);
       console.error("Error loading book covers:", err);
     } finally {
       setLoading(false);
@@ -49,8 +94,8 @@ function BookLibrary() {
   }
 
   // Filter books based on search term
-  const filteredBooks = books.filter(([, bookTitle]) =>
-    bookTitle.toLowerCase().includes(searchTerm.toLowerCase())
+  const filteredBooks = books.filter(({ title }) =>
+    title.toLowerCase().includes(searchTerm.toLowerCase())
   );
 
   return (
@@ -58,8 +103,9 @@ function BookLibrary() {
       <h2>My Library</h2>
       {books.length === 0 ? (
         <div className="empty-state">
-          <p>No books found in your library.</p>
-          <p>Add EPUB files to ~/books to get started.</p>
+          {emptyLibraryMessage(status).map((line) => (
+            <p key={line}>{line}</p>
+          ))}
         </div>
       ) : (
         <>
@@ -87,23 +133,35 @@ function BookLibrary() {
             </div>
           ) : (
             <div className="book-grid">
-              {filteredBooks.map(([bookKey, bookTitle, coverUri]) => {
+              {filteredBooks.map(({ key: bookKey, title: bookTitle, author, cover_uri: coverUri, placeholder, cover_info: coverInfo }) => {
             const handleBookClick = () => {
               navigate(`/book/${bookKey}`);
             };
 
             return (
               <div key={bookKey} className="book-card" onClick={handleBookClick}>
-                <div className="book-cover">
-                  <img
-                    src={coverUri}
-                    alt={`${bookTitle} cover`}
-                    onError={(e) => {
-                      // Fallback to placeholder if image fails to load
-                      (e.target as HTMLImageElement).src =
-                        "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' width='300' height='450'%3E%3Crect width='300' height='450' fill='%23e0e0e0'/%3E%3Ctext x='50%25' y='50%25' dominant-baseline='middle' text-anchor='middle' font-family='sans-serif' font-size='18' fill='%23999'%3ENo Cover%3C/text%3E%3C/svg%3E";
-                    }}
-                  />
+                <div className="book-cover" style={{ background: coverInfo?.color }}>
+                  {coverUri === null ? (
+                    <div
+                      className="book-cover-placeholder"
+                      style={{ background: placeholder?.background, color: placeholder?.foreground }}
+                    >
+                      <span className="placeholder-title">{bookTitle}</span>
+                      {author && <span className="placeholder-author">{author}</span>}
+                    </div>
+                  ) : (
+                    <img
+                      src={coverUri}
+                      alt={`${bookTitle} cover`}
+                      width={coverInfo?.width}
+                      height={coverInfo?.height}
+                      onError={(e) => {
+                        // Fallback to placeholder if image fails to load
+                        (e.target as HTMLImageElement).src =
+                          "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' width='300' height='450'%3E%3Crect width='300' height='450' fill='%23e0e0e0'/%3E%3Ctext x='50%25' y='50%25' dominant-baseline='middle' text-anchor='middle' font-family='sans-serif' font-size='18' fill='%23999'%3ENo Cover%3C/text%3E%3C/svg%3E";
+                      }}
+                    />
+                  )}
                 </div>
                 <div className="book-info">
                   <h3 className="book-title">{bookTitle}</h3>
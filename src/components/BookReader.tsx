@@ -1,7 +1,7 @@
 import { useState, useEffect, useRef } from "react";
 import { useParams, useNavigate } from "react-router-dom";
 import { invoke } from "@tauri-apps/api/core";
-import { TocItem, ReadingPosition } from "../types/book";
+import { TocItem, ReadingPosition, errorMessage } from "../types/book";
 import TableOfContents from "./TableOfContents";
 import IframeViewer, { IframeViewerRef } from "./IframeViewer";
 import "./BookReader.css";
@@ -13,6 +13,8 @@ function BookReader() {
   const [toc, setToc] = useState<TocItem[]>([]);
   const [currentContent, setCurrentContent] = useState<string>("");
   const [bookTitle, setBookTitle] = useState<string>("");
+  // epub:// host for the book's resources; keys aren't always URL-safe
+  const [bookHost, setBookHost] = useState<string>("");
   const [loading, setLoading] = useState(true);
   const [error, setError] = useState<string | null>(null);
 
@@ -43,7 +45,7 @@ function BookReader() {
 
     const position: ReadingPosition = {
       bookKey,
-      contentPath: currentContent.replace(`epub://${bookKey}/`, ''),
+      contentPath: currentContent.replace(`epub://${bookHost}/`, ''),
       page: currentPage,
       timestamp: Date.now()
     };
@@ -69,10 +71,16 @@ function BookReader() {
       setLoading(true);
       setError(null);
 
+      // Records the book as last opened, for the "Continue reading" shelf
+      await invoke("open_book", { bookKey });
+
       // Fetch the real book title from metadata
       const title = await invoke<string>("get_book_title", { bookKey });
       setBookTitle(title);
 
+      const host = await invoke<string>("get_book_host", { bookKey });
+      setBookHost(host);
+
       // Fetch the table of contents
       const tocData = await invoke<TocItem[]>("get_book_toc", { bookKey });
       setToc(tocData);
@@ -87,7 +95,7 @@ function BookReader() {
       if (savedPosition && savedPosition.bookKey === bookKey) {
         // Resume from saved position
         const contentPath = savedPosition.contentPath;
-        setCurrentContent(`epub://${bookKey}/${contentPath}`);
+        setCurrentContent(`epub://${host}/${contentPath}`);
 
         // Find position in spine
         const spineIdx = await invoke<number | null>("get_current_spine_index", {
@@ -100,11 +108,11 @@ function BookReader() {
       } else if (spineData.length > 0) {
         // Start from first spine item (canonical beginning)
         const firstContent = spineData[0];
-        setCurrentContent(`epub://${bookKey}/${firstContent}`);
+        setCurrentContent(`epub://${host}/${firstContent}`);
         setCurrentSpineIndex(0);
       }
     } catch (err) {
-      setError(`Failed to load book: ${err}`);
+      setError(`Failed to load book: ${errorMessage(err)}`);
       console.error("Error loading book:", err);
     } finally {
       setLoading(false);
@@ -126,7 +134,7 @@ function BookReader() {
         const nextSpineIndex = currentSpineIndex + 1;
         const nextContentPath = spine[nextSpineIndex];
 
-        setCurrentContent(`epub://${bookKey}/${nextContentPath}`);
+        setCurrentContent(`epub://${bookHost}/${nextContentPath}`);
         setCurrentSpineIndex(nextSpineIndex);
         setCurrentPage(0); // Reset to first page of new chapter
       }
@@ -145,7 +153,7 @@ function BookReader() {
         const prevSpineIndex = currentSpineIndex - 1;
         const prevContentPath = spine[prevSpineIndex];
 
-        setCurrentContent(`epub://${bookKey}/${prevContentPath}`);
+        setCurrentContent(`epub://${bookHost}/${prevContentPath}`);
         setCurrentSpineIndex(prevSpineIndex);
         // Will start at page 0 of previous chapter
         setCurrentPage(0);
@@ -158,7 +166,7 @@ function BookReader() {
   }
 
   async function handleTocItemClick(content: string) {
-    const fullUri = `epub://${bookKey}/${content}`;
+    const fullUri = `epub://${bookHost}/${content}`;
     setCurrentContent(fullUri);
     setCurrentPage(0); // Reset to first page of new chapter
 
@@ -9,6 +9,7 @@ interface IframeViewerProps {
   style?: CSSProperties;
   className?: string;
   onPaginationUpdate?: (currentPage: number, totalPages: number) => void;
+  onNavigate?: (spineIndex: number, fragment: string | null) => void;
 }
 
 export interface IframeViewerRef {
@@ -23,6 +24,7 @@ const IframeViewer = forwardRef<IframeViewerRef, IframeViewerProps>(({
   style,
   className,
   onPaginationUpdate,
+  onNavigate,
 }, ref) => {
   const iframeRef = useRef<HTMLIFrameElement>(null);
 
@@ -71,6 +73,14 @@ const IframeViewer = forwardRef<IframeViewerRef, IframeViewerProps>(({
         return;
       }
 
+      // Handle internal links resolved by the backend (links=route mode)
+      if (message.type === 'epub-navigate') {
+        if (onNavigate && typeof message.spine_index === 'number') {
+          onNavigate(message.spine_index, typeof message.fragment === 'string' ? message.fragment : null);
+        }
+        return;
+      }
+
       // Handle pagination updates
       if (message.type === 'pagination-update') {
         if (onPaginationUpdate && typeof message.currentPage === 'number' && typeof message.totalPages === 'number') {
@@ -97,7 +107,7 @@ const IframeViewer = forwardRef<IframeViewerRef, IframeViewerProps>(({
     return () => {
       window.removeEventListener('message', handleMessage);
     };
-  }, [onPaginationUpdate]);
+  }, [onPaginationUpdate, onNavigate]);
 
   return (
     <iframe
@@ -3,6 +3,7 @@ export interface TocItem {
   content: string;
   play_order: number;
   children: TocItem[];
+  synthesized: boolean;   // Generated from the spine; the book has no TOC
 }
 
 export interface ReadingPosition {
@@ -11,3 +12,16 @@ export interface ReadingPosition {
   page: number;           // 0-indexed
   timestamp: number;
 }
+
+// What a failed command rejects with
+export interface AppError {
+  code: string;           // e.g. "book-not-found", "invalid-epub"
+  message: string;
+}
+
+export function errorMessage(err: unknown): string {
+  if (typeof err === "object" && err !== null && "message" in err) {
+    return String((err as AppError).message);
+  }
+  return String(err);
+}